@@ -1,16 +1,33 @@
 use std::{
+    collections::HashSet,
     fmt::Display,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
 
 use camino::Utf8PathBuf;
+use corro_agent::{
+    agent::{export_change_log, import_change_log, ImportOptions},
+    api::peer::{parallel_sync, parallel_sync_tables},
+    transport::Transport,
+};
+use corro_client::CorrosionApiClient;
 use corro_types::{
     actor::{ActorId, ClusterId},
-    agent::{Agent, BookedVersions, Bookie, LockKind, LockMeta, LockState},
+    agent::{
+        Agent, Booked, BookedVersions, Bookie, LockKind, LockMeta, LockState, ReplicationDirection,
+    },
+    api::{ColumnName, SqliteValue, TableName},
     base::{CrsqlDbVersion, CrsqlSeq},
-    broadcast::{FocaCmd, FocaInput},
+    broadcast::{ChangeSource, FocaCmd, FocaInput},
+    change::{compact_changes, quarantined_chunks, row_changes, take_quarantined_chunk},
+    config::{Config, PeerAccessConfig, PeerMatcher},
+    pubsub::pack_columns,
+    schema::table_digest,
     sqlite::SqlitePoolError,
-    sync::generate_sync,
+    sync::{acked_versions, generate_sync},
     updates::Handle,
 };
 use futures::{SinkExt, TryStreamExt};
@@ -49,6 +66,7 @@ pub type TracingHandle = ReloadHandle<FilterLayer<Filter>, Registry>;
 pub fn start_server(
     agent: Agent,
     bookie: Bookie,
+    transport: Transport,
     config: AdminConfig,
     tracing_handle: Option<TracingHandle>,
     mut tripwire: Tripwire,
@@ -62,6 +80,9 @@ pub fn start_server(
 
     let ln = UnixListener::bind(&config.listen_path)?;
 
+    // peers currently being resynced from, shared by all admin connections
+    let resyncs: Arc<Mutex<HashSet<SocketAddr>>> = Default::default();
+
     spawn_counted(async move {
         loop {
             let stream = tokio::select! {
@@ -82,11 +103,21 @@ pub fn start_server(
             tokio::spawn({
                 let agent = agent.clone();
                 let bookie = bookie.clone();
+                let transport = transport.clone();
+                let resyncs = resyncs.clone();
                 let config = config.clone();
                 let tracing_handle = tracing_handle.clone();
                 async move {
-                    if let Err(e) =
-                        handle_conn(agent, &bookie, config, stream, tracing_handle).await
+                    if let Err(e) = handle_conn(
+                        agent,
+                        &bookie,
+                        &transport,
+                        &resyncs,
+                        config,
+                        stream,
+                        tracing_handle,
+                    )
+                    .await
                     {
                         error!("could not handle admin connection: {e}");
                     }
@@ -103,11 +134,43 @@ pub fn start_server(
 pub enum Command {
     Ping,
     Sync(SyncCommand),
-    Locks { top: usize },
+    Locks {
+        top: usize,
+    },
+    Gaps {
+        actor_id: Option<ActorId>,
+    },
+    Resync {
+        addr: SocketAddr,
+        tables: Vec<String>,
+    },
+    Compact {
+        dry_run: bool,
+    },
+    CompareTable {
+        table: String,
+        peer_api_addr: SocketAddr,
+    },
     Cluster(ClusterCommand),
     Actor(ActorCommand),
     Subs(SubsCommand),
     Log(LogCommand),
+    PeerAccess(PeerAccessCommand),
+    Snapshot(SnapshotCommand),
+    /// Replays a change log on the agent's host for recovery, reporting the
+    /// changesets that fail to apply
+    ApplyLog {
+        path: Utf8PathBuf,
+    },
+    Quarantine(QuarantineCommand),
+    /// Stops applying changes from peers and/or broadcasting ours
+    Pause(ReplicationDirection),
+    Resume,
+    /// Changes of a row, by primary key values in the table's pk order
+    RowHistory {
+        table: String,
+        pk: Vec<SqliteValue>,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -131,6 +194,41 @@ pub enum LogCommand {
     Reset,
 }
 
+/// Changes to the peers allowed to sync with us last until a restart, the
+/// config file has to be updated to keep them.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum PeerAccessCommand {
+    Show,
+    Allow(PeerMatcher),
+    Deny(PeerMatcher),
+    /// Removes the peer from both the allow and the deny lists
+    Remove(PeerMatcher),
+}
+
+/// Change log files, paths are on the agent's host
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SnapshotCommand {
+    /// Exports the whole database if `tables` is empty
+    Export {
+        path: Utf8PathBuf,
+        tables: Vec<String>,
+        compress: bool,
+    },
+    Import {
+        path: Utf8PathBuf,
+    },
+}
+
+/// Chunks that kept failing to apply
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum QuarantineCommand {
+    List,
+    /// Releases a chunk and applies it again, with all its attempts
+    Requeue {
+        id: i64,
+    },
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClusterCommand {
     Rejoin,
@@ -190,6 +288,191 @@ type FramedStream = Framed<
     Json<Command, Response>,
 >;
 
+/// Missing versions for an actor, as reported by the `gaps` command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ActorGaps {
+    pub actor_id: ActorId,
+    pub max_version: Option<CrsqlDbVersion>,
+    pub gaps: Vec<RangeInclusive<CrsqlDbVersion>>,
+}
+
+/// Collects gaps for a single actor, or every known actor sorted by id
+async fn actor_gaps(bookie: &Bookie, actor_id: Option<ActorId>) -> Result<Vec<ActorGaps>, String> {
+    let mut actors: Vec<(ActorId, Booked)> = {
+        let bookie = bookie.read::<&str, _>("admin gaps", None).await;
+        match actor_id {
+            Some(actor_id) => match bookie.get(&actor_id) {
+                Some(booked) => vec![(actor_id, booked.clone())],
+                None => return Err(format!("unknown actor id: {actor_id}")),
+            },
+            None => bookie.iter().map(|(k, v)| (*k, v.clone())).collect(),
+        }
+    };
+    actors.sort_by_key(|(actor_id, _)| *actor_id);
+
+    let mut gaps = Vec::with_capacity(actors.len());
+    for (actor_id, booked) in actors {
+        let booked = booked.read("admin gaps booked", actor_id.as_simple()).await;
+        gaps.push(ActorGaps {
+            actor_id,
+            max_version: booked.last(),
+            gaps: booked.gaps().collect(),
+        });
+    }
+
+    Ok(gaps)
+}
+
+/// Every peer persisted in the member list, whether it's currently up or not
+async fn known_peers(agent: &Agent) -> Result<Vec<ActorId>, String> {
+    let conn = agent.pool().read().await.map_err(|e| e.to_string())?;
+    let peers = block_in_place(|| {
+        conn.prepare_cached("SELECT actor_id FROM __corro_members")?
+            .query_map([], |row| row.get::<_, ActorId>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()
+    })
+    .map_err(|e| e.to_string())?;
+    Ok(peers
+        .into_iter()
+        .filter(|actor_id| *actor_id != agent.actor_id())
+        .collect())
+}
+
+/// A resync with a peer, removed from the in-flight set when dropped, even if
+/// the admin connection goes away midway
+struct InFlightResync<'a> {
+    resyncs: &'a Mutex<HashSet<SocketAddr>>,
+    addr: SocketAddr,
+}
+
+impl<'a> InFlightResync<'a> {
+    fn start(resyncs: &'a Mutex<HashSet<SocketAddr>>, addr: SocketAddr) -> Option<Self> {
+        resyncs
+            .lock()
+            .unwrap()
+            .insert(addr)
+            .then_some(Self { resyncs, addr })
+    }
+}
+
+impl Drop for InFlightResync<'_> {
+    fn drop(&mut self) {
+        self.resyncs.lock().unwrap().remove(&self.addr);
+    }
+}
+
+/// Applies `f` to the live peer access lists, returns the updated lists
+fn update_peer_access(agent: &Agent, f: impl FnOnce(&mut PeerAccessConfig)) -> PeerAccessConfig {
+    let mut config = Config::clone(&agent.config());
+    f(&mut config.gossip.peer_access);
+    let access = config.gossip.peer_access.clone();
+    agent.set_config(config);
+    access
+}
+
+/// Outcome of the `compare-table` command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TableComparison {
+    pub table: String,
+    pub peer_api_addr: SocketAddr,
+    pub matches: bool,
+    pub hash: u64,
+    pub peer_hash: u64,
+    pub rows: usize,
+    pub peer_rows: usize,
+    /// Primary keys missing on one side or with different rows
+    pub differing_pks: usize,
+}
+
+/// Compares our digest of `table` with the one computed by the peer at
+/// `peer_api_addr`
+async fn compare_table(
+    agent: &Agent,
+    table: &str,
+    peer_api_addr: SocketAddr,
+) -> Result<TableComparison, String> {
+    let schema_table = agent
+        .schema()
+        .read()
+        .tables
+        .get(table)
+        .cloned()
+        .ok_or_else(|| format!("unknown table '{table}'"))?;
+
+    let digest = {
+        let conn = agent.pool().read().await.map_err(|e| e.to_string())?;
+        block_in_place(|| table_digest(&conn, &schema_table)).map_err(|e| e.to_string())?
+    };
+    let peer_digest = CorrosionApiClient::new(peer_api_addr)
+        .table_digest(table)
+        .await
+        .map_err(|e| format!("could not get digest from {peer_api_addr}: {e}"))?;
+
+    Ok(TableComparison {
+        table: table.to_owned(),
+        peer_api_addr,
+        matches: digest == peer_digest,
+        hash: digest.hash,
+        peer_hash: peer_digest.hash,
+        rows: digest.rows.len(),
+        peer_rows: peer_digest.rows.len(),
+        differing_pks: digest.differing_pks(&peer_digest),
+    })
+}
+
+/// A change of a row, as reported by the `row-history` command
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RowChange {
+    pub cid: ColumnName,
+    pub val: SqliteValue,
+    pub col_version: i64,
+    pub db_version: CrsqlDbVersion,
+    pub seq: CrsqlSeq,
+    pub site_id: ActorId,
+    pub cl: i64,
+}
+
+/// Changes of the row of `table` with primary key `pk`, oldest first. Rows
+/// that don't exist or never did have none.
+async fn row_history(
+    agent: &Agent,
+    table: &str,
+    pk: &[SqliteValue],
+) -> Result<Vec<RowChange>, String> {
+    let pk_len = agent
+        .schema()
+        .read()
+        .tables
+        .get(table)
+        .map(|table| table.pk.len())
+        .ok_or_else(|| format!("unknown table '{table}'"))?;
+    if pk.len() != pk_len {
+        return Err(format!(
+            "table '{table}' has {pk_len} primary key columns, got {} values",
+            pk.len()
+        ));
+    }
+    let pk = pack_columns(pk).map_err(|e| format!("could not encode primary key: {e}"))?;
+
+    let changes = {
+        let conn = agent.pool().read().await.map_err(|e| e.to_string())?;
+        block_in_place(|| row_changes(&conn, table, &pk)).map_err(|e| e.to_string())?
+    };
+
+    Ok(changes
+        .into_iter()
+        .map(|change| RowChange {
+            cid: change.cid,
+            val: change.val,
+            col_version: change.col_version,
+            db_version: change.db_version,
+            seq: change.seq,
+            site_id: ActorId::from_bytes(change.site_id),
+            cl: change.cl,
+        })
+        .collect())
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct LockMetaElapsed {
     pub label: String,
@@ -212,6 +495,8 @@ impl From<LockMeta> for LockMetaElapsed {
 async fn handle_conn(
     agent: Agent,
     bookie: &Bookie,
+    transport: &Transport,
+    resyncs: &Mutex<HashSet<SocketAddr>>,
     _config: AdminConfig,
     stream: UnixStream,
     tracing_handle: Option<TracingHandle>,
@@ -301,6 +586,189 @@ async fn handle_conn(
                     }
                     send_success(&mut stream).await;
                 }
+                Command::Gaps { actor_id } => {
+                    info_log(&mut stream, "gathering gaps").await;
+                    let gaps = match actor_gaps(bookie, actor_id).await {
+                        Ok(gaps) => gaps,
+                        Err(e) => {
+                            send_error(&mut stream, e).await;
+                            continue;
+                        }
+                    };
+
+                    match serde_json::to_value(&gaps) {
+                        Ok(json) => send(&mut stream, Response::Json(json)).await,
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                    send_success(&mut stream).await;
+                }
+                Command::Resync { addr, tables } => {
+                    let actor_id = agent.members().read().by_addr.get(&addr).copied();
+                    let actor_id = match actor_id {
+                        Some(actor_id) => actor_id,
+                        None => {
+                            send_error(&mut stream, format!("unknown peer address: {addr}")).await;
+                            continue;
+                        }
+                    };
+
+                    // reject instead of piling up syncs with the same peer
+                    let Some(_resync) = InFlightResync::start(resyncs, addr) else {
+                        send_error(
+                            &mut stream,
+                            format!("a resync with {addr} is already in flight"),
+                        )
+                        .await;
+                        continue;
+                    };
+
+                    info_log(&mut stream, format!("resyncing from {actor_id} ({addr})")).await;
+
+                    let sync_state = generate_sync(bookie, agent.actor_id()).await;
+                    let members = vec![(actor_id, addr)];
+                    let res = if tables.is_empty() {
+                        parallel_sync(&agent, transport, members, sync_state).await
+                    } else {
+                        let tables = tables
+                            .iter()
+                            .map(|table| TableName::from(table.as_str()))
+                            .collect();
+                        parallel_sync_tables(&agent, transport, members, sync_state, tables).await
+                    };
+
+                    match res {
+                        Ok(count) => {
+                            info_log(
+                                &mut stream,
+                                "received changes are applied in the background",
+                            )
+                            .await;
+                            send(
+                                &mut stream,
+                                Response::Json(json!({
+                                    "actor_id": actor_id,
+                                    "addr": addr,
+                                    "received": count,
+                                })),
+                            )
+                            .await;
+                            send_success(&mut stream).await;
+                        }
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                }
+                Command::CompareTable {
+                    table,
+                    peer_api_addr,
+                } => {
+                    info_log(
+                        &mut stream,
+                        format!("comparing table '{table}' with {peer_api_addr}"),
+                    )
+                    .await;
+                    match compare_table(&agent, &table, peer_api_addr).await {
+                        Ok(comparison) => {
+                            if !comparison.matches {
+                                warn!(
+                                    "table '{table}' differs from {peer_api_addr}'s on {} primary keys",
+                                    comparison.differing_pks
+                                );
+                            }
+                            match serde_json::to_value(&comparison) {
+                                Ok(json) => send(&mut stream, Response::Json(json)).await,
+                                Err(e) => {
+                                    send_error(&mut stream, e).await;
+                                    continue;
+                                }
+                            }
+                            send_success(&mut stream).await;
+                        }
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                }
+                Command::RowHistory { table, pk } => match row_history(&agent, &table, &pk).await {
+                    Ok(history) => {
+                        match serde_json::to_value(&history) {
+                            Ok(json) => send(&mut stream, Response::Json(json)).await,
+                            Err(e) => {
+                                send_error(&mut stream, e).await;
+                                continue;
+                            }
+                        }
+                        send_success(&mut stream).await;
+                    }
+                    Err(e) => send_error(&mut stream, e).await,
+                },
+                Command::Compact { dry_run } => {
+                    // down peers and peers we haven't synced with since we
+                    // started still need our tombstones
+                    let peers = match known_peers(&agent).await {
+                        Ok(peers) => peers,
+                        Err(e) => {
+                            send_error(&mut stream, e).await;
+                            continue;
+                        }
+                    };
+                    let states = agent.peer_sync_states();
+                    let unacked: Vec<String> = peers
+                        .iter()
+                        .filter(|peer| !states.contains_key(peer))
+                        .map(ActorId::to_string)
+                        .collect();
+                    if !unacked.is_empty() {
+                        send_error(
+                            &mut stream,
+                            format!(
+                                "not compacting, no recorded sync state for known peers: {}",
+                                unacked.join(", ")
+                            ),
+                        )
+                        .await;
+                        continue;
+                    }
+
+                    let acked = acked_versions(&peers, &states);
+                    if acked.is_empty() {
+                        info_log(&mut stream, "no version is acknowledged by every peer").await;
+                    }
+
+                    let mut conn = match agent.pool().write_low().await {
+                        Ok(conn) => conn,
+                        Err(e) => {
+                            send_error(&mut stream, e).await;
+                            continue;
+                        }
+                    };
+
+                    let retention = agent.config().db.retention;
+                    let now = agent.clock().new_timestamp().into();
+                    let res = block_in_place(|| {
+                        let tx = conn.transaction()?;
+                        let report = compact_changes(&tx, &acked, &retention, now, dry_run)?;
+                        tx.commit()?;
+                        Ok::<_, rusqlite::Error>(report)
+                    });
+
+                    match res {
+                        Ok(report) => {
+                            info_log(
+                                &mut stream,
+                                format!(
+                                    "{} {} tombstones",
+                                    if dry_run { "would remove" } else { "removed" },
+                                    report.rows()
+                                ),
+                            )
+                            .await;
+                            match serde_json::to_value(&report) {
+                                Ok(json) => send(&mut stream, Response::Json(json)).await,
+                                Err(e) => send_error(&mut stream, e).await,
+                            }
+                            send_success(&mut stream).await;
+                        }
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                }
                 Command::Cluster(ClusterCommand::Rejoin) => {
                     let (cb_tx, cb_rx) = oneshot::channel();
 
@@ -530,6 +998,198 @@ async fn handle_conn(
                         }
                     };
                 }
+                Command::PeerAccess(cmd) => {
+                    let access = match cmd {
+                        PeerAccessCommand::Show => agent.config().gossip.peer_access.clone(),
+                        PeerAccessCommand::Allow(peer) => {
+                            info_log(&mut stream, format!("allowing peer {peer:?}")).await;
+                            update_peer_access(&agent, |access| {
+                                access.deny.retain(|m| *m != peer);
+                                if !access.allow.contains(&peer) {
+                                    access.allow.push(peer);
+                                }
+                            })
+                        }
+                        PeerAccessCommand::Deny(peer) => {
+                            info_log(&mut stream, format!("denying peer {peer:?}")).await;
+                            update_peer_access(&agent, |access| {
+                                access.allow.retain(|m| *m != peer);
+                                if !access.deny.contains(&peer) {
+                                    access.deny.push(peer);
+                                }
+                            })
+                        }
+                        PeerAccessCommand::Remove(peer) => {
+                            info_log(&mut stream, format!("removing peer {peer:?}")).await;
+                            update_peer_access(&agent, |access| {
+                                access.allow.retain(|m| *m != peer);
+                                access.deny.retain(|m| *m != peer);
+                            })
+                        }
+                    };
+                    match serde_json::to_value(&access) {
+                        Ok(json) => send(&mut stream, Response::Json(json)).await,
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                    send_success(&mut stream).await;
+                }
+                Command::Snapshot(cmd) => {
+                    let res = match cmd {
+                        SnapshotCommand::Export {
+                            path,
+                            tables,
+                            compress,
+                        } => {
+                            info_log(&mut stream, format!("exporting change log to {path}")).await;
+                            let tables = (!tables.is_empty()).then(|| {
+                                tables
+                                    .iter()
+                                    .map(|table| TableName::from(table.as_str()))
+                                    .collect()
+                            });
+                            export_change_log(&agent, tables, path.as_std_path(), compress).await
+                        }
+                        SnapshotCommand::Import { path } => {
+                            info_log(&mut stream, format!("importing change log from {path}"))
+                                .await;
+                            import_change_log(
+                                &agent,
+                                bookie,
+                                path.as_std_path(),
+                                ImportOptions::default(),
+                            )
+                            .await
+                        }
+                    };
+                    match res {
+                        Ok(summary) => {
+                            match serde_json::to_value(summary) {
+                                Ok(json) => send(&mut stream, Response::Json(json)).await,
+                                Err(e) => send_error(&mut stream, e).await,
+                            }
+                            send_success(&mut stream).await;
+                        }
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                }
+                Command::ApplyLog { path } => {
+                    info_log(&mut stream, format!("applying change log from {path}")).await;
+                    let opts = ImportOptions {
+                        continue_on_error: true,
+                        skip_applied: true,
+                    };
+                    match import_change_log(&agent, bookie, path.as_std_path(), opts).await {
+                        Ok(summary) => match serde_json::to_value(summary) {
+                            Ok(json) => {
+                                send(&mut stream, Response::Json(json)).await;
+                                send_success(&mut stream).await;
+                            }
+                            Err(e) => send_error(&mut stream, e).await,
+                        },
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                }
+                Command::Quarantine(QuarantineCommand::List) => {
+                    let chunks = match agent.pool().read().await {
+                        Ok(conn) => block_in_place(|| quarantined_chunks(&conn)),
+                        Err(e) => {
+                            send_error(&mut stream, e).await;
+                            continue;
+                        }
+                    };
+                    match chunks {
+                        Ok(chunks) => {
+                            for chunk in chunks {
+                                let json = json!({
+                                    "id": chunk.id,
+                                    "actor_id": chunk.actor_id,
+                                    "start_version": chunk.versions.start(),
+                                    "end_version": chunk.versions.end(),
+                                    "start_seq": chunk.seqs.map(|seqs| seqs.start()),
+                                    "end_seq": chunk.seqs.map(|seqs| seqs.end()),
+                                    "attempts": chunk.attempts,
+                                    "error": chunk.error,
+                                    "quarantined_at": chunk.quarantined_at,
+                                });
+                                send(&mut stream, Response::Json(json)).await;
+                            }
+                            send_success(&mut stream).await;
+                        }
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                }
+                Command::Quarantine(QuarantineCommand::Requeue { id }) => {
+                    let taken = match agent.pool().write_priority().await {
+                        Ok(conn) => block_in_place(|| take_quarantined_chunk(&conn, id)),
+                        Err(e) => {
+                            send_error(&mut stream, e).await;
+                            continue;
+                        }
+                    };
+                    let change = match taken {
+                        Ok(Some(change)) => change,
+                        Ok(None) => {
+                            send_error(&mut stream, format!("unknown quarantined chunk: {id}"))
+                                .await;
+                            continue;
+                        }
+                        Err(e) => {
+                            send_error(&mut stream, e).await;
+                            continue;
+                        }
+                    };
+
+                    let key = (change.actor_id, change.versions(), change.seqs());
+                    agent.chunk_retries().release(&key);
+                    info_log(
+                        &mut stream,
+                        format!(
+                            "requeueing chunk {id} ({} {:?})",
+                            change.actor_id,
+                            change.versions()
+                        ),
+                    )
+                    .await;
+
+                    match agent.tx_changes().send((change, ChangeSource::Sync)).await {
+                        Ok(_) => send_success(&mut stream).await,
+                        Err(e) => send_error(&mut stream, e).await,
+                    }
+                }
+                Command::Pause(direction) => {
+                    agent.pause_replication(direction);
+                    info!("replication paused ({direction:?})");
+                    info_log(
+                        &mut stream,
+                        format!(
+                            "paused replication, inbound: {}, outbound: {}",
+                            agent.inbound_paused(),
+                            agent.outbound_paused()
+                        ),
+                    )
+                    .await;
+                    send_success(&mut stream).await;
+                }
+                Command::Resume => {
+                    let dropped = agent.resume_replication();
+                    info!("replication resumed");
+                    if !dropped.is_empty() {
+                        let versions: usize = dropped
+                            .values()
+                            .flat_map(|versions| versions.iter())
+                            .map(|versions| (versions.end().0 - versions.start().0 + 1) as usize)
+                            .sum();
+                        info_log(
+                            &mut stream,
+                            format!(
+                                "{versions} versions from {} actors were dropped while paused, syncing them",
+                                dropped.len()
+                            ),
+                        )
+                        .await;
+                    }
+                    send_success(&mut stream).await;
+                }
                 Command::Log(cmd) => match cmd {
                     LogCommand::Set { filter } => {
                         if let Some(ref handle) = tracing_handle {
@@ -716,3 +1376,523 @@ async fn collapse_gaps(
     bv.commit_snapshot(snap);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use corro_agent::api::public::make_broadcastable_changes;
+    use corro_tests::launch_test_agent;
+    use corro_types::{agent::ChangeError, config::PerfConfig};
+    use rangemap::RangeInclusiveSet;
+
+    use super::*;
+
+    type ClientStream = Framed<
+        tokio_util::codec::Framed<UnixStream, LengthDelimitedCodec>,
+        Response,
+        Command,
+        Json<Response, Command>,
+    >;
+
+    async fn compare_table_json(
+        stream: &mut ClientStream,
+        table: &str,
+        peer_api_addr: SocketAddr,
+    ) -> eyre::Result<TableComparison> {
+        stream
+            .send(Command::CompareTable {
+                table: table.into(),
+                peer_api_addr,
+            })
+            .await?;
+        let mut comparison = None;
+        loop {
+            match stream.try_next().await? {
+                Some(Response::Json(value)) => comparison = Some(serde_json::from_value(value)?),
+                Some(Response::Log { .. }) => continue,
+                Some(Response::Error { msg }) => eyre::bail!(msg),
+                Some(Response::Success) | None => break,
+            }
+        }
+        comparison.ok_or_else(|| eyre::eyre!("no comparison"))
+    }
+
+    async fn row_history_json(
+        stream: &mut ClientStream,
+        table: &str,
+        pk: Vec<SqliteValue>,
+    ) -> eyre::Result<Vec<RowChange>> {
+        stream
+            .send(Command::RowHistory {
+                table: table.into(),
+                pk,
+            })
+            .await?;
+        let mut history = None;
+        loop {
+            match stream.try_next().await? {
+                Some(Response::Json(value)) => history = Some(serde_json::from_value(value)?),
+                Some(Response::Log { .. }) => continue,
+                Some(Response::Error { msg }) => eyre::bail!(msg),
+                Some(Response::Success) | None => break,
+            }
+        }
+        history.ok_or_else(|| eyre::eyre!("no history"))
+    }
+
+    async fn compact_result(stream: &mut ClientStream) -> eyre::Result<Result<(), String>> {
+        stream.send(Command::Compact { dry_run: true }).await?;
+        loop {
+            match stream.try_next().await? {
+                Some(Response::Json(_)) | Some(Response::Log { .. }) => continue,
+                Some(Response::Error { msg }) => return Ok(Err(msg)),
+                Some(Response::Success) | None => return Ok(Ok(())),
+            }
+        }
+    }
+
+    async fn gaps_json(
+        stream: &mut ClientStream,
+        actor_id: Option<ActorId>,
+    ) -> eyre::Result<Option<serde_json::Value>> {
+        stream.send(Command::Gaps { actor_id }).await?;
+        let mut json = None;
+        loop {
+            match stream.try_next().await? {
+                Some(Response::Json(value)) => json = Some(value),
+                Some(Response::Log { .. }) => continue,
+                Some(Response::Error { msg }) => eyre::bail!(msg),
+                Some(Response::Success) | None => break,
+            }
+        }
+        Ok(json)
+    }
+
+    #[test]
+    fn test_in_flight_resync_released_on_drop() {
+        let resyncs = Mutex::new(HashSet::new());
+        let addr: SocketAddr = "127.0.0.1:8787".parse().unwrap();
+
+        let resync = InFlightResync::start(&resyncs, addr).expect("first resync");
+        assert!(InFlightResync::start(&resyncs, addr).is_none());
+
+        // e.g. the resync future is dropped when its admin connection closes
+        drop(resync);
+        assert!(resyncs.lock().unwrap().is_empty());
+        assert!(InFlightResync::start(&resyncs, addr).is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_gaps_command() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        // induce a gap: 3..=4 is missing
+        let actor_id = ActorId(Uuid::new_v4());
+        {
+            let booked = ta
+                .bookie
+                .write::<&str, _>("test", None)
+                .await
+                .ensure(actor_id);
+            let mut bv = booked.write::<&str, _>("test", None).await;
+            let mut conn = ta.agent.pool().write_priority().await?;
+            let tx = conn.transaction()?;
+            let mut snap = bv.snapshot();
+            snap.insert_db(
+                &tx,
+                RangeInclusiveSet::from_iter([CrsqlDbVersion(1)..=CrsqlDbVersion(2)]),
+            )?;
+            snap.insert_db(
+                &tx,
+                RangeInclusiveSet::from_iter([CrsqlDbVersion(5)..=CrsqlDbVersion(6)]),
+            )?;
+            tx.commit()?;
+            bv.commit_snapshot(snap);
+        }
+
+        let listen_path = Utf8PathBuf::from_path_buf(ta.tmpdir.path().join("gaps.sock")).unwrap();
+        start_server(
+            ta.agent.clone(),
+            ta.bookie.clone(),
+            ta.transport.clone(),
+            AdminConfig {
+                listen_path: listen_path.clone(),
+                config_path: Utf8PathBuf::new(),
+            },
+            None,
+            tripwire.clone(),
+        )?;
+
+        let mut stream: ClientStream = tokio_serde::Framed::new(
+            tokio_util::codec::Framed::new(
+                UnixStream::connect(&listen_path).await?,
+                LengthDelimitedCodec::new(),
+            ),
+            Json::<Response, Command>::default(),
+        );
+
+        let json = gaps_json(&mut stream, Some(actor_id))
+            .await?
+            .expect("no gaps json");
+        assert_eq!(
+            json,
+            json!([{
+                "actor_id": actor_id,
+                "max_version": 6,
+                "gaps": [{"start": 3, "end": 4}],
+            }])
+        );
+
+        let all: Vec<ActorGaps> =
+            serde_json::from_value(gaps_json(&mut stream, None).await?.expect("no gaps json"))?;
+        assert!(all.contains(&ActorGaps {
+            actor_id,
+            max_version: Some(CrsqlDbVersion(6)),
+            gaps: vec![CrsqlDbVersion(3)..=CrsqlDbVersion(4)],
+        }));
+        assert!(all.windows(2).all(|w| w[0].actor_id < w[1].actor_id));
+
+        assert!(gaps_json(&mut stream, Some(ActorId(Uuid::new_v4())))
+            .await
+            .is_err());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_compact_requires_every_known_peer() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let listen_path =
+            Utf8PathBuf::from_path_buf(ta.tmpdir.path().join("compact.sock")).unwrap();
+        start_server(
+            ta.agent.clone(),
+            ta.bookie.clone(),
+            ta.transport.clone(),
+            AdminConfig {
+                listen_path: listen_path.clone(),
+                config_path: Utf8PathBuf::new(),
+            },
+            None,
+            tripwire.clone(),
+        )?;
+
+        let mut stream: ClientStream = tokio_serde::Framed::new(
+            tokio_util::codec::Framed::new(
+                UnixStream::connect(&listen_path).await?,
+                LengthDelimitedCodec::new(),
+            ),
+            Json::<Response, Command>::default(),
+        );
+
+        // alone in the cluster
+        assert_eq!(compact_result(&mut stream).await?, Ok(()));
+
+        // a peer that's down: not a live member and never synced with us
+        let peer = ActorId(Uuid::new_v4());
+        {
+            let conn = ta.agent.pool().write_priority().await?;
+            conn.execute(
+                "INSERT INTO __corro_members (actor_id, address) VALUES (?, '127.0.0.1:1')",
+                [peer],
+            )?;
+        }
+        let err = compact_result(&mut stream).await?.unwrap_err();
+        assert!(err.contains(&peer.to_string()), "{err}");
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_resync_command() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        // written before the second agent exists, it can only get it by syncing
+        make_broadcastable_changes(&ta1.agent, None, |tx| {
+            tx.execute("INSERT INTO tests (id, text) VALUES (1, 'hello')", ())
+                .map_err(|source| ChangeError::Rusqlite {
+                    source,
+                    actor_id: None,
+                    version: None,
+                })
+        })
+        .await?;
+
+        // stall periodic syncs
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.bootstrap(vec![ta1.agent.gossip_addr().to_string()])
+                    .perf(PerfConfig {
+                        min_sync_backoff: 3600,
+                        max_sync_backoff: 3600,
+                        ..Default::default()
+                    })
+                    .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+
+        let peer_addr = ta1.agent.gossip_addr();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !ta2.agent.members().read().by_addr.contains_key(&peer_addr) {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?;
+
+        let count_rows = || async {
+            let conn = ta2.agent.pool().read().await?;
+            let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests", (), |row| row.get(0))?;
+            Ok::<_, eyre::Report>(count)
+        };
+        assert_eq!(count_rows().await?, 0);
+
+        let listen_path =
+            Utf8PathBuf::from_path_buf(ta2.tmpdir.path().join("resync.sock")).unwrap();
+        start_server(
+            ta2.agent.clone(),
+            ta2.bookie.clone(),
+            ta2.transport.clone(),
+            AdminConfig {
+                listen_path: listen_path.clone(),
+                config_path: Utf8PathBuf::new(),
+            },
+            None,
+            tripwire.clone(),
+        )?;
+
+        let mut stream: ClientStream = tokio_serde::Framed::new(
+            tokio_util::codec::Framed::new(
+                UnixStream::connect(&listen_path).await?,
+                LengthDelimitedCodec::new(),
+            ),
+            Json::<Response, Command>::default(),
+        );
+
+        stream
+            .send(Command::Resync {
+                addr: peer_addr,
+                tables: vec![],
+            })
+            .await?;
+        let mut report = None;
+        loop {
+            match stream.try_next().await? {
+                Some(Response::Json(value)) => report = Some(value),
+                Some(Response::Log { .. }) => continue,
+                Some(Response::Error { msg }) => eyre::bail!(msg),
+                Some(Response::Success) | None => break,
+            }
+        }
+        let report = report.expect("no resync report");
+        assert_eq!(report["actor_id"], json!(ta1.agent.actor_id()));
+        assert!(report["received"].as_u64().unwrap() >= 1);
+
+        // changes are applied asynchronously
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while count_rows().await? != 1 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Ok::<_, eyre::Report>(())
+        })
+        .await??;
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_row_history_command() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let write = |sql: &'static str| {
+            let agent = ta.agent.clone();
+            async move {
+                let (_, version, _) = make_broadcastable_changes(&agent, None, |tx| {
+                    tx.execute(sql, ()).map_err(|source| ChangeError::Rusqlite {
+                        source,
+                        actor_id: None,
+                        version: None,
+                    })
+                })
+                .await?;
+                Ok::<_, ChangeError>(version.expect("no version written"))
+            }
+        };
+        let inserted = write("INSERT INTO tests3 (id, text) VALUES (1, 'one')").await?;
+        let first = write("UPDATE tests3 SET text2 = 'uno' WHERE id = 1").await?;
+        let second = write("UPDATE tests3 SET num = 1 WHERE id = 1").await?;
+        write("INSERT INTO tests3 (id, text) VALUES (2, 'two')").await?;
+
+        let listen_path =
+            Utf8PathBuf::from_path_buf(ta.tmpdir.path().join("history.sock")).unwrap();
+        start_server(
+            ta.agent.clone(),
+            ta.bookie.clone(),
+            ta.transport.clone(),
+            AdminConfig {
+                listen_path: listen_path.clone(),
+                config_path: Utf8PathBuf::new(),
+            },
+            None,
+            tripwire.clone(),
+        )?;
+
+        let mut stream: ClientStream = tokio_serde::Framed::new(
+            tokio_util::codec::Framed::new(
+                UnixStream::connect(&listen_path).await?,
+                LengthDelimitedCodec::new(),
+            ),
+            Json::<Response, Command>::default(),
+        );
+
+        let history = row_history_json(&mut stream, "tests3", vec![1i64.into()]).await?;
+        assert!(history
+            .windows(2)
+            .all(|w| (w[0].db_version, w[0].seq) < (w[1].db_version, w[1].seq)));
+        assert!(history
+            .iter()
+            .all(|change| change.site_id == ta.agent.actor_id()));
+        assert!(history
+            .iter()
+            .any(|change| change.cid.is_crsql_sentinel() && change.db_version == inserted));
+        assert!(history
+            .iter()
+            .any(|change| change.cid.as_str() == "text" && change.val == "one".into()));
+
+        // both updates, in order
+        let updates: Vec<_> = history
+            .iter()
+            .filter(|change| change.db_version > inserted)
+            .map(|change| (change.cid.as_str(), change.val.clone(), change.db_version))
+            .collect();
+        assert_eq!(
+            updates,
+            vec![("text2", "uno".into(), first), ("num", 1i64.into(), second)]
+        );
+
+        // the tombstone remains
+        let deleted = write("DELETE FROM tests3 WHERE id = 1").await?;
+        let history = row_history_json(&mut stream, "tests3", vec![1i64.into()]).await?;
+        assert_eq!(history.len(), 1, "{history:?}");
+        assert!(history[0].cid.is_crsql_sentinel());
+        assert_eq!(history[0].db_version, deleted);
+        assert_eq!(history[0].cl, 2);
+
+        assert!(row_history_json(&mut stream, "tests3", vec![3i64.into()])
+            .await?
+            .is_empty());
+        assert!(row_history_json(&mut stream, "nope", vec![1i64.into()])
+            .await
+            .is_err());
+        assert!(row_history_json(&mut stream, "tests3", vec![])
+            .await
+            .is_err());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_compare_table_command() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        // not clustered, each has its own history for the same data
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let write = |agent: Agent, sql: &'static str| async move {
+            make_broadcastable_changes(&agent, None, |tx| {
+                tx.execute(sql, ()).map_err(|source| ChangeError::Rusqlite {
+                    source,
+                    actor_id: None,
+                    version: None,
+                })
+            })
+            .await
+        };
+        for agent in [ta1.agent.clone(), ta2.agent.clone()] {
+            write(
+                agent.clone(),
+                "INSERT INTO tests (id, text) VALUES (1, 'one'), (2, 'two')",
+            )
+            .await?;
+            write(agent, "INSERT INTO tests (id, text) VALUES (3, 'three')").await?;
+        }
+
+        let listen_path =
+            Utf8PathBuf::from_path_buf(ta1.tmpdir.path().join("compare.sock")).unwrap();
+        start_server(
+            ta1.agent.clone(),
+            ta1.bookie.clone(),
+            ta1.transport.clone(),
+            AdminConfig {
+                listen_path: listen_path.clone(),
+                config_path: Utf8PathBuf::new(),
+            },
+            None,
+            tripwire.clone(),
+        )?;
+
+        let mut stream: ClientStream = tokio_serde::Framed::new(
+            tokio_util::codec::Framed::new(
+                UnixStream::connect(&listen_path).await?,
+                LengthDelimitedCodec::new(),
+            ),
+            Json::<Response, Command>::default(),
+        );
+
+        let peer_api_addr = ta2.agent.api_addr();
+
+        let comparison = compare_table_json(&mut stream, "tests", peer_api_addr).await?;
+        assert!(comparison.matches, "{comparison:?}");
+        assert_eq!(comparison.hash, comparison.peer_hash);
+        assert_eq!(comparison.rows, 3);
+        assert_eq!(comparison.differing_pks, 0);
+
+        // diverge: a changed row and an extra one
+        write(
+            ta2.agent.clone(),
+            "UPDATE tests SET text = 'deux' WHERE id = 2",
+        )
+        .await?;
+        write(
+            ta2.agent.clone(),
+            "INSERT INTO tests (id, text) VALUES (4, 'four')",
+        )
+        .await?;
+
+        let comparison = compare_table_json(&mut stream, "tests", peer_api_addr).await?;
+        assert!(!comparison.matches);
+        assert_ne!(comparison.hash, comparison.peer_hash);
+        assert_eq!((comparison.rows, comparison.peer_rows), (3, 4));
+        assert_eq!(comparison.differing_pks, 2);
+
+        assert!(compare_table_json(&mut stream, "nope", peer_api_addr)
+            .await
+            .is_err());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+
+        Ok(())
+    }
+}
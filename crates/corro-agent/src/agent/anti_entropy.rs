@@ -0,0 +1,362 @@
+//! Anti-entropy
+//!
+//! Safety net for versions booked without their changes being applied, e.g.
+//! when bookkeeping drifted: regular syncs only request versions we haven't
+//! booked, so those would never be synced again. Every
+//! `perf.anti_entropy_interval` seconds we compare digests of what a peer and
+//! us booked and applied of each actor's versions, narrowing down the ranges
+//! that differ. Those are needed again and synced from that peer.
+//!
+//! A version's changes disappear from `crsql_changes` as later writes
+//! overwrite them, so digests only compare while both of us booked exactly
+//! the same versions of every actor: the same overwrites were applied then.
+
+use std::{
+    cmp,
+    hash::Hasher,
+    net::SocketAddr,
+    ops::RangeInclusive,
+    time::{Duration, Instant},
+};
+
+use corro_types::{
+    actor::ActorId,
+    agent::{Agent, BookedVersions, Bookie, PoolError},
+    base::{CrsqlDbVersion, CrsqlSeq},
+    sqlite::SqlitePoolError,
+    sync::{generate_sync, SyncStateV1, VersionsDigestV1},
+};
+use metrics::counter;
+use rand::seq::SliceRandom;
+use rusqlite::named_params;
+use seahash::SeaHasher;
+use tokio::task::block_in_place;
+use tracing::{debug, info, warn};
+use tripwire::{Outcome, PreemptibleFutureExt, Tripwire};
+
+use crate::{
+    api::peer::{parallel_sync, request_digests, SyncError},
+    transport::Transport,
+};
+
+/// How long a pass waits for the requested versions to be applied
+const REPAIR_TIMEOUT: Duration = Duration::from_secs(10);
+/// Parts a range whose digests differ is split into, to be compared next
+const DIGEST_SPLITS: u64 = 16;
+/// Ranges of up to this many versions are needed whole when they differ
+const MIN_DIGEST_VERSIONS: u64 = 16;
+/// Ranges compared per request, the rest are left to the next pass
+pub const MAX_DIGEST_RANGES: usize = 256;
+
+#[derive(Debug, thiserror::Error)]
+pub enum AntiEntropyError {
+    #[error(transparent)]
+    Sync(#[from] SyncError),
+    #[error(transparent)]
+    Pool(#[from] SqlitePoolError),
+    #[error(transparent)]
+    WritePool(#[from] PoolError),
+    #[error(transparent)]
+    Rusqlite(#[from] rusqlite::Error),
+}
+
+pub async fn anti_entropy_loop(
+    agent: Agent,
+    bookie: Bookie,
+    transport: Transport,
+    mut tripwire: Tripwire,
+) {
+    let interval = agent.config().perf.anti_entropy_interval;
+    if interval == 0 {
+        return;
+    }
+    let mut interval = tokio::time::interval(Duration::from_secs(interval as u64));
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // the first tick is immediate, syncs already run on startup
+    interval.tick().await;
+
+    while let Outcome::Completed(_) = interval.tick().preemptible(&mut tripwire).await {
+        // low priority: leave the sync permits to regular syncs
+        if agent.limits().sync.active() > 0 || agent.limits().outbound_sync.active() > 0 {
+            debug!("skipping anti-entropy pass, syncs are running");
+            continue;
+        }
+        if agent.inbound_paused() {
+            debug!("skipping anti-entropy pass, inbound replication is paused");
+            continue;
+        }
+
+        let candidates = sync_candidates(&agent);
+        if let Err(e) = anti_entropy_pass(&agent, &bookie, &transport, candidates).await {
+            warn!("anti-entropy pass failed: {e}");
+        }
+    }
+}
+
+fn sync_candidates(agent: &Agent) -> Vec<(ActorId, SocketAddr)> {
+    let config = agent.config();
+    let members = agent.members().read();
+    members
+        .states
+        .iter()
+        .filter(|(id, state)| **id != agent.actor_id() && state.cluster_id == agent.cluster_id())
+        .filter(|(id, state)| {
+            config
+                .gossip
+                .peer_access
+                .check(**id, state.addr.ip())
+                .is_ok()
+        })
+        .map(|(id, state)| (*id, state.addr))
+        .collect()
+}
+
+/// Compares the digests of the versions we booked and applied with those of
+/// the candidate we know the heads of most actors of, a random one if none.
+/// Versions both of us booked but applied differently are needed again and
+/// synced from it. Returns the number of ranges repaired, does nothing when
+/// the digests match or when either of us booked versions the other didn't.
+pub async fn anti_entropy_pass(
+    agent: &Agent,
+    bookie: &Bookie,
+    transport: &Transport,
+    candidates: Vec<(ActorId, SocketAddr)>,
+) -> Result<usize, AntiEntropyError> {
+    if candidates.is_empty() {
+        return Ok(0);
+    }
+
+    let peer_states = agent.peer_sync_states();
+    let peer = candidates
+        .choose_multiple(&mut rand::thread_rng(), candidates.len())
+        .max_by_key(|(actor_id, _)| {
+            peer_states
+                .get(actor_id)
+                .map_or(0, |state| state.heads.len())
+        })
+        .copied()
+        .expect("candidates are not empty");
+    let their_heads = peer_states.get(&peer.0).map(|state| &state.heads);
+
+    // versions past their head are left to the regular syncs
+    let ours = generate_sync(bookie, agent.actor_id()).await;
+    let mut ranges: Vec<_> = ours
+        .heads
+        .iter()
+        .filter_map(|(actor_id, head)| {
+            let head = match their_heads {
+                Some(heads) => cmp::min(*head, *heads.get(actor_id)?),
+                None => *head,
+            };
+            (head >= CrsqlDbVersion(1)).then_some((*actor_id, CrsqlDbVersion(1)..=head))
+        })
+        .flat_map(|(actor_id, versions)| split(versions).map(move |versions| (actor_id, versions)))
+        .collect();
+
+    let mut differing = vec![];
+    while !ranges.is_empty() {
+        ranges.truncate(MAX_DIGEST_RANGES);
+        let (their_state, theirs) =
+            request_digests(agent, transport, peer.1, ranges.clone()).await?;
+        let our_state = generate_sync(bookie, agent.actor_id()).await;
+        let ours = versions_digests(agent, bookie, std::mem::take(&mut ranges)).await?;
+        if !same_versions(&our_state, &their_state)
+            || !same_versions(&our_state, &generate_sync(bookie, agent.actor_id()).await)
+        {
+            debug!(
+                "skipping anti-entropy with {}, versions were booked since the last sync",
+                peer.0
+            );
+            return Ok(0);
+        }
+
+        for (ours, theirs) in ours.into_iter().zip(theirs) {
+            // versions either of us needs are for the regular syncs
+            if ours.actor_id != theirs.actor_id
+                || ours.versions != theirs.versions
+                || !ours.booked
+                || !theirs.booked
+                || ours.applied == theirs.applied
+            {
+                continue;
+            }
+            if len(&ours.versions) <= MIN_DIGEST_VERSIONS {
+                differing.push((ours.actor_id, ours.versions));
+            } else {
+                ranges.extend(split(ours.versions).map(|versions| (ours.actor_id, versions)));
+            }
+        }
+    }
+
+    if differing.is_empty() {
+        return Ok(0);
+    }
+
+    info!(
+        "anti-entropy: {} ranges applied differently than {} ({}), syncing them again",
+        differing.len(),
+        peer.0,
+        peer.1
+    );
+
+    let mut conn = agent.pool().write_low().await?;
+    for (actor_id, versions) in differing.iter() {
+        let booked = bookie
+            .write("anti_entropy_pass(ensure)", actor_id.as_simple())
+            .await
+            .ensure(*actor_id);
+        let mut booked_write = booked
+            .write("anti_entropy_pass(needed)", actor_id.as_simple())
+            .await;
+        let mut snap = booked_write.snapshot();
+        block_in_place(|| {
+            let tx = conn.immediate_transaction()?;
+            snap.insert_gaps_db(&tx, versions.clone())?;
+            tx.commit()
+        })?;
+        booked_write.commit_snapshot(snap);
+    }
+    drop(conn);
+
+    let before = generate_sync(bookie, agent.actor_id()).await;
+    parallel_sync(agent, transport, vec![peer], before).await?;
+
+    // synced changes are applied in the background
+    let start = Instant::now();
+    let mut repaired;
+    loop {
+        let after = generate_sync(bookie, agent.actor_id()).await;
+        repaired = differing
+            .iter()
+            .filter(|(actor_id, versions)| !still_needed(&after, actor_id, versions))
+            .count();
+        if repaired == differing.len() || start.elapsed() >= REPAIR_TIMEOUT {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    if repaired > 0 {
+        counter!("corro.antientropy.repairs").increment(repaired as u64);
+        info!(
+            "anti-entropy: repaired {repaired} of {} ranges",
+            differing.len()
+        );
+    }
+
+    Ok(repaired)
+}
+
+/// Digests of `ranges`, as booked in `bookie` and applied in the db
+pub async fn versions_digests(
+    agent: &Agent,
+    bookie: &Bookie,
+    ranges: Vec<(ActorId, RangeInclusive<CrsqlDbVersion>)>,
+) -> Result<Vec<VersionsDigestV1>, AntiEntropyError> {
+    let mut booked = Vec::with_capacity(ranges.len());
+    for (actor_id, versions) in ranges.iter() {
+        let actor = bookie
+            .read("versions_digests", actor_id.as_simple())
+            .await
+            .get(actor_id)
+            .cloned();
+        booked.push(match actor {
+            Some(actor) => fully_booked(
+                &*actor.read("versions_digests", actor_id.as_simple()).await,
+                versions,
+            ),
+            None => false,
+        });
+    }
+
+    let conn = agent.pool().read().await?;
+    let digests = block_in_place(|| {
+        let mut prepped = conn.prepare_cached(
+            "
+            SELECT db_version, COUNT(*), MAX(seq)
+                FROM crsql_changes
+                WHERE site_id = :actor_id
+                  AND db_version BETWEEN :start AND :end
+                GROUP BY db_version
+                ORDER BY db_version ASC
+            ",
+        )?;
+        ranges
+            .into_iter()
+            .zip(booked)
+            .map(|((actor_id, versions), booked)| {
+                let mut hasher = SeaHasher::new();
+                let mut rows = prepped.query(named_params! {
+                    ":actor_id": actor_id,
+                    ":start": versions.start(),
+                    ":end": versions.end(),
+                })?;
+                while let Some(row) = rows.next()? {
+                    hasher.write_u64(row.get::<_, CrsqlDbVersion>(0)?.0);
+                    hasher.write_i64(row.get(1)?);
+                    hasher.write_u64(row.get::<_, CrsqlSeq>(2)?.0);
+                }
+                Ok(VersionsDigestV1 {
+                    actor_id,
+                    versions,
+                    booked,
+                    applied: hasher.finish(),
+                })
+            })
+            .collect::<rusqlite::Result<Vec<_>>>()
+    })?;
+
+    Ok(digests)
+}
+
+/// Whether both states booked the same versions of every actor, in which
+/// case their changes were overwritten alike
+fn same_versions(ours: &SyncStateV1, theirs: &SyncStateV1) -> bool {
+    ours.heads == theirs.heads
+        && ours.need == theirs.need
+        && ours.partial_need == theirs.partial_need
+}
+
+/// Whether every version of `versions` is booked as cleared or applied
+fn fully_booked(booked: &BookedVersions, versions: &RangeInclusive<CrsqlDbVersion>) -> bool {
+    booked.last() >= Some(*versions.end())
+        && !booked
+            .needed()
+            .iter()
+            .any(|range| range.start() <= versions.end() && versions.start() <= range.end())
+        && booked.partials.range(versions.clone()).next().is_none()
+}
+
+fn len(versions: &RangeInclusive<CrsqlDbVersion>) -> u64 {
+    versions.end().0 - versions.start().0 + 1
+}
+
+/// Splits `versions` in up to [`DIGEST_SPLITS`] ranges of the same length
+fn split(
+    versions: RangeInclusive<CrsqlDbVersion>,
+) -> impl Iterator<Item = RangeInclusive<CrsqlDbVersion>> {
+    let step = len(&versions).div_ceil(DIGEST_SPLITS);
+    let end = *versions.end();
+    (versions.start().0..=end.0)
+        .step_by(step as usize)
+        .map(move |start| CrsqlDbVersion(start)..=cmp::min(CrsqlDbVersion(start + step - 1), end))
+}
+
+fn still_needed(
+    state: &SyncStateV1,
+    actor_id: &ActorId,
+    versions: &RangeInclusive<CrsqlDbVersion>,
+) -> bool {
+    let overlaps = |other: &RangeInclusive<CrsqlDbVersion>| {
+        other.start() <= versions.end() && versions.start() <= other.end()
+    };
+    state
+        .need
+        .get(actor_id)
+        .is_some_and(|ranges| ranges.iter().any(overlaps))
+        || state.partial_need.get(actor_id).is_some_and(|partials| {
+            partials
+                .keys()
+                .any(|version| overlaps(&(*version..=*version)))
+        })
+}
@@ -1,4 +1,4 @@
-use crate::api::peer::serve_sync;
+use crate::api::peer::{serve_digests, serve_sync};
 use corro_types::{
     agent::{Agent, Bookie},
     broadcast::{BiPayload, BiPayloadV1},
@@ -17,7 +17,7 @@ use tripwire::Tripwire;
 ///
 /// For every incoming stream, spawn another task to handle the
 /// stream.  Valid incoming BiPayload messages are passed to
-/// `crate::api::peer::serve_sync()`
+/// `crate::api::peer::serve_sync()` or `crate::api::peer::serve_digests()`
 pub fn spawn_bipayload_handler(
     agent: &Agent,
     bookie: &Bookie,
@@ -55,11 +55,12 @@ pub fn spawn_bipayload_handler(
             tokio::spawn({
                 let agent = agent.clone();
                 let bookie = bookie.clone();
+                let conn = conn.clone();
                 async move {
                     let mut framed = FramedRead::new(
                         rx,
                         LengthDelimitedCodec::builder()
-                            .max_frame_length(100 * 1_024 * 1_024)
+                            .max_frame_length(agent.config().perf.max_frame_bytes)
                             .new_codec(),
                     );
 
@@ -77,7 +78,11 @@ pub fn spawn_bipayload_handler(
                                     match BiPayload::read_from_buffer(&b) {
                                         Ok(payload) => {
                                             match payload {
-                                                BiPayload::V1 { data, cluster_id } => match data {
+                                                BiPayload::V1 {
+                                                    data,
+                                                    cluster_id,
+                                                    encodings,
+                                                } => match data {
                                                     BiPayloadV1::SyncStart {
                                                         actor_id,
                                                         trace_ctx,
@@ -89,8 +94,15 @@ pub fn spawn_bipayload_handler(
 
                                                         // println!("got sync state: {state:?}");
                                                         if let Err(e) = serve_sync(
-                                                            &agent, &bookie, actor_id, trace_ctx,
-                                                            cluster_id, framed, tx,
+                                                            &agent,
+                                                            &bookie,
+                                                            actor_id,
+                                                            conn.remote_address(),
+                                                            trace_ctx,
+                                                            cluster_id,
+                                                            encodings,
+                                                            framed,
+                                                            tx,
                                                         )
                                                         .await
                                                         {
@@ -98,6 +110,22 @@ pub fn spawn_bipayload_handler(
                                                         }
                                                         break;
                                                     }
+                                                    BiPayloadV1::Digests { actor_id, ranges } => {
+                                                        if let Err(e) = serve_digests(
+                                                            &agent,
+                                                            &bookie,
+                                                            actor_id,
+                                                            conn.remote_address(),
+                                                            cluster_id,
+                                                            ranges,
+                                                            tx,
+                                                        )
+                                                        .await
+                                                        {
+                                                            warn!("could not serve digests: {e}");
+                                                        }
+                                                        break;
+                                                    }
                                                 },
                                             }
                                         }
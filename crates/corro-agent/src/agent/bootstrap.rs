@@ -35,13 +35,12 @@ pub async fn generate_bootstrap(
                 node_addrs
                     .flatten()
                     .flat_map(|addr| addr.parse())
-                    .filter(|addr| match (our_addr, addr) {
-                        (SocketAddr::V6(our_ip), SocketAddr::V6(ip)) if our_ip != *ip => true,
-                        (SocketAddr::V4(our_ip), SocketAddr::V4(ip)) if our_ip != *ip => true,
-                        _ => {
+                    .filter(|addr| {
+                        let reachable = is_reachable_peer(our_addr, *addr);
+                        if !reachable {
                             debug!("ignore node with addr: {addr}");
-                            false
                         }
+                        reachable
                     })
                     .collect(),
             )
@@ -96,51 +95,42 @@ async fn resolve_bootstrap(
                 debug!("using resolver: {dns_server}");
             }
             if let Some(hostname) = host_port.next() {
-                debug!("Resolving '{hostname}' to an IP");
-                match resolver
-                    .as_ref()
-                    .unwrap_or(&system_resolver)
-                    .lookup(
-                        hostname,
-                        if our_addr.is_ipv6() {
-                            RecordType::AAAA
-                        } else {
-                            RecordType::A
-                        },
-                    )
-                    .await
-                {
-                    Ok(response) => {
-                        debug!("Successfully resolved things: {response:?}");
-                        let port: u16 = host_port
-                            .next()
-                            .and_then(|p| p.parse().ok())
-                            .unwrap_or(DEFAULT_GOSSIP_PORT);
-                        for addr in response.iter().filter_map(|rdata| match rdata {
-                            RData::A(ip) => Some(SocketAddr::from((ip.0, port))),
-                            RData::AAAA(ip) => Some(SocketAddr::from((ip.0, port))),
-                            _ => None,
-                        }) {
-                            match (our_addr, addr) {
-                                (SocketAddr::V4(our_ip), SocketAddr::V4(ip)) if our_ip != ip => {}
-                                (SocketAddr::V6(our_ip), SocketAddr::V6(ip)) if our_ip != ip => {}
-                                _ => {
+                let port: u16 = host_port
+                    .next()
+                    .and_then(|p| p.parse().ok())
+                    .unwrap_or(DEFAULT_GOSSIP_PORT);
+                for record_type in lookup_record_types(our_addr) {
+                    debug!("Resolving '{hostname}' to an IP ({record_type})");
+                    match resolver
+                        .as_ref()
+                        .unwrap_or(&system_resolver)
+                        .lookup(hostname, record_type)
+                        .await
+                    {
+                        Ok(response) => {
+                            debug!("Successfully resolved things: {response:?}");
+                            for addr in response.iter().filter_map(|rdata| match rdata {
+                                RData::A(ip) => Some(SocketAddr::from((ip.0, port))),
+                                RData::AAAA(ip) => Some(SocketAddr::from((ip.0, port))),
+                                _ => None,
+                            }) {
+                                if !is_reachable_peer(our_addr, addr) {
                                     debug!("ignore node with addr: {addr}");
                                     continue;
                                 }
+                                addrs.insert(addr);
                             }
-                            addrs.insert(addr);
                         }
+                        Err(e) => match e.kind() {
+                            ResolveErrorKind::NoRecordsFound { .. } => {
+                                // do nothing, that might be fine!
+                            }
+                            _ => {
+                                error!("could not resolve '{hostname}': {e}");
+                                return Err(e.into());
+                            }
+                        },
                     }
-                    Err(e) => match e.kind() {
-                        ResolveErrorKind::NoRecordsFound { .. } => {
-                            // do nothing, that might be fine!
-                        }
-                        _ => {
-                            error!("could not resolve '{hostname}': {e}");
-                            return Err(e.into());
-                        }
-                    },
                 }
             }
         }
@@ -148,3 +138,99 @@ async fn resolve_bootstrap(
 
     Ok(addrs)
 }
+
+/// A gossip socket bound to the unspecified IPv6 address is dual-stack and
+/// can reach IPv4 peers too, otherwise peers need to be of the same family.
+fn is_dual_stack(our_addr: SocketAddr) -> bool {
+    match our_addr {
+        SocketAddr::V6(addr) => addr.ip().is_unspecified(),
+        SocketAddr::V4(_) => false,
+    }
+}
+
+fn lookup_record_types(our_addr: SocketAddr) -> Vec<RecordType> {
+    if is_dual_stack(our_addr) {
+        vec![RecordType::AAAA, RecordType::A]
+    } else if our_addr.is_ipv6() {
+        vec![RecordType::AAAA]
+    } else {
+        vec![RecordType::A]
+    }
+}
+
+/// Whether `addr` is another node we can gossip with from `our_addr`.
+/// IPv4-mapped IPv6 addresses are compared as IPv4.
+fn is_reachable_peer(our_addr: SocketAddr, addr: SocketAddr) -> bool {
+    let canonical = |addr: SocketAddr| SocketAddr::new(addr.ip().to_canonical(), addr.port());
+    let (ours, theirs) = (canonical(our_addr), canonical(addr));
+
+    if ours == theirs {
+        return false;
+    }
+
+    match (ours, theirs) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => true,
+        (SocketAddr::V6(_), SocketAddr::V4(_)) => is_dual_stack(our_addr),
+        (SocketAddr::V4(_), SocketAddr::V6(_)) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_reachable_peer() {
+        let addr = |s: &str| s.parse::<SocketAddr>().unwrap();
+
+        // same family
+        assert!(is_reachable_peer(
+            addr("127.0.0.1:8787"),
+            addr("127.0.0.2:8787")
+        ));
+        assert!(is_reachable_peer(addr("[::1]:8787"), addr("[::1]:8788")));
+        assert!(is_reachable_peer(
+            addr("[fd00::1]:8787"),
+            addr("[fd00::2]:8787")
+        ));
+
+        // ourselves, including as a v4-mapped address
+        assert!(!is_reachable_peer(addr("[::1]:8787"), addr("[::1]:8787")));
+        assert!(!is_reachable_peer(
+            addr("10.0.0.1:8787"),
+            addr("[::ffff:10.0.0.1]:8787")
+        ));
+
+        // dual-stack reaches both families
+        assert!(is_reachable_peer(addr("[::]:8787"), addr("10.0.0.1:8787")));
+        assert!(is_reachable_peer(addr("[::]:8787"), addr("[fd00::1]:8787")));
+
+        // no crossing families otherwise
+        assert!(!is_reachable_peer(
+            addr("[fd00::1]:8787"),
+            addr("10.0.0.1:8787")
+        ));
+        assert!(!is_reachable_peer(
+            addr("0.0.0.0:8787"),
+            addr("[fd00::1]:8787")
+        ));
+        // mapped addresses are v4 peers
+        assert!(is_reachable_peer(
+            addr("10.0.0.2:8787"),
+            addr("[::ffff:10.0.0.1]:8787")
+        ));
+
+        assert_eq!(
+            lookup_record_types(addr("[::]:8787")),
+            vec![RecordType::AAAA, RecordType::A]
+        );
+        assert_eq!(
+            lookup_record_types(addr("[::1]:8787")),
+            vec![RecordType::AAAA]
+        );
+        assert_eq!(
+            lookup_record_types(addr("0.0.0.0:8787")),
+            vec![RecordType::A]
+        );
+    }
+}
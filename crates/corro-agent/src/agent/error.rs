@@ -44,12 +44,16 @@ pub enum SyncRecvError {
     Change(#[from] ChangeError),
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error("sync frame exceeds the maximum allowed size")]
+    FrameTooLarge,
     #[error("expected sync state message, received something else")]
     ExpectedSyncState,
     #[error("unexpected end of stream")]
     UnexpectedEndOfStream,
     #[error("expected sync clock message, received something else")]
     ExpectedClockMessage,
+    #[error("expected digests message, received something else")]
+    ExpectedDigests,
     #[error("timed out waiting for sync message")]
     TimedOut(#[from] Elapsed),
     #[error("changes channel is closed")]
@@ -23,9 +23,10 @@ use camino::Utf8Path;
 use corro_types::{
     actor::{Actor, ActorId},
     agent::{Agent, Bookie, SplitPool},
-    base::CrsqlSeq,
+    base::{CrsqlDbVersion, CrsqlSeq},
     broadcast::{BroadcastInput, BroadcastV1, ChangeSource, ChangeV1, FocaInput},
     channel::CorroReceiver,
+    config::{PerfConfig, WalCheckpointMode},
     members::MemberAddedResult,
     sync::generate_sync,
 };
@@ -108,6 +109,7 @@ pub fn spawn_incoming_connection_handlers(
             Ok(conn) => conn,
             Err(e) => {
                 error!("could not handshake connection from {remote_addr}: {e}");
+                counter!("corro.peer.connection.handshake.failed.total").increment(1);
                 return;
             }
         };
@@ -118,12 +120,7 @@ pub fn spawn_incoming_connection_handlers(
 
         // Spawn handler tasks for this connection
         spawn_foca_handler(&agent, &tripwire, &conn);
-        uni::spawn_unipayload_handler(
-            &tripwire,
-            &conn,
-            agent.cluster_id(),
-            agent.tx_changes().clone(),
-        );
+        uni::spawn_unipayload_handler(&agent, &tripwire, &conn, agent.tx_changes().clone());
         bi::spawn_bipayload_handler(&agent, &bookie, &tripwire, &conn);
     });
 }
@@ -366,24 +363,37 @@ pub async fn handle_notifications(
 }
 
 /// We keep a write-ahead-log, which under write-pressure can grow to
-/// multiple gigabytes and needs periodic truncation.  We don't want
-/// to schedule this task too often since it locks the whole DB.
-// TODO: can we get around the lock somehow?
-fn wal_checkpoint(conn: &rusqlite::Connection, timeout: u64) -> eyre::Result<()> {
-    debug!("handling db_cleanup (WAL truncation)");
+/// multiple gigabytes and needs periodic checkpointing. Besides `PASSIVE`,
+/// checkpoints wait on readers for up to `timeout` milliseconds while
+/// holding the write connection, so we don't want to run them too often.
+fn wal_checkpoint(
+    conn: &rusqlite::Connection,
+    mode: WalCheckpointMode,
+    timeout: u64,
+) -> eyre::Result<()> {
+    debug!(
+        "handling db_cleanup (WAL checkpoint {})",
+        mode.as_pragma_arg()
+    );
     let start = Instant::now();
+    let mode_label: &'static str = mode.into();
 
-    assert_sometimes!(true, "Corrosion truncates WAL");
+    assert_sometimes!(true, "Corrosion checkpoints WAL");
     let orig: u64 = conn.pragma_query_value(None, "busy_timeout", |row| row.get(0))?;
     conn.pragma_update(None, "busy_timeout", timeout)?;
 
-    let busy: bool = conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |row| row.get(0))?;
+    let busy: bool = conn.query_row(
+        &format!("PRAGMA wal_checkpoint({});", mode.as_pragma_arg()),
+        [],
+        |row| row.get(0),
+    )?;
     if busy {
-        warn!("could not truncate sqlite WAL, database busy - with timeout: {timeout}");
-        counter!("corro.db.wal.truncate.busy").increment(1);
+        warn!("could not checkpoint sqlite WAL, database busy - with timeout: {timeout}");
+        counter!("corro.db.wal.checkpoint.busy", "mode" => mode_label).increment(1);
     } else {
-        debug!("successfully truncated sqlite WAL!");
-        histogram!("corro.db.wal.truncate.seconds").record(start.elapsed().as_secs_f64());
+        debug!("successfully checkpointed sqlite WAL!");
+        histogram!("corro.db.wal.checkpoint.seconds", "mode" => mode_label)
+            .record(start.elapsed().as_secs_f64());
     }
 
     _ = conn.pragma_update(None, "busy_timeout", orig);
@@ -451,29 +461,71 @@ async fn vacuum_db(pool: &SplitPool, lim: u64) -> eyre::Result<()> {
     Ok::<_, eyre::Report>(())
 }
 
-/// See `db_cleanup` and `vacuum_db`
+/// How often the WAL size is checked against the checkpoint policy
+#[cfg(test)]
+const WAL_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+#[cfg(not(test))]
+const WAL_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// When and how the WAL gets checkpointed, from the perf config
+#[derive(Debug, Clone)]
+struct WalCheckpointPolicy {
+    mode: WalCheckpointMode,
+    /// size in bytes over which to checkpoint
+    threshold: u64,
+    /// checkpoint at least this often, whatever the size
+    interval: Option<Duration>,
+    /// caps the busy timeout
+    max_block: Option<Duration>,
+}
+
+impl WalCheckpointPolicy {
+    fn new(perf: &PerfConfig) -> Self {
+        Self {
+            mode: perf.wal_checkpoint_mode,
+            threshold: perf.wal_threshold_mb as u64 * 1024 * 1024,
+            interval: perf
+                .wal_checkpoint_interval
+                .map(|secs| Duration::from_secs(secs as u64)),
+            max_block: perf.wal_checkpoint_max_block_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// See `wal_checkpoint` and `vacuum_db`
 pub fn spawn_handle_db_maintenance(agent: &Agent) {
     let mut wal_path = agent.config().db.path.clone();
-    let wal_threshold = agent.config().perf.wal_threshold_mb as u64;
     wal_path.set_extension(format!("{}-wal", wal_path.extension().unwrap_or_default()));
+    let policy = WalCheckpointPolicy::new(&agent.config().perf);
 
     let pool = agent.pool().clone();
 
-    tokio::spawn(async move {
-        let truncate_wal_threshold: u64 = wal_threshold * 1024 * 1024;
+    // checkpoints run on their own task, off the apply path
+    tokio::spawn({
+        let pool = pool.clone();
+        async move {
+            let mut check_interval = tokio::time::interval(WAL_CHECK_INTERVAL);
+            let mut last_checkpoint = Instant::now();
 
-        // try to initially truncate the WAL
-        match wal_checkpoint_over_threshold(wal_path.as_path(), &pool, truncate_wal_threshold).await
-        {
-            Ok(truncated) if truncated => {
-                info!("initially truncated WAL");
-            }
-            Err(e) => {
-                error!("could not initially truncate WAL: {e}");
+            loop {
+                check_interval.tick().await;
+                let due = policy
+                    .interval
+                    .is_some_and(|interval| last_checkpoint.elapsed() >= interval);
+                match wal_checkpoint_with_policy(wal_path.as_path(), &pool, &policy, due).await {
+                    Ok(true) => {
+                        last_checkpoint = Instant::now();
+                    }
+                    Ok(false) => {}
+                    Err(e) => {
+                        error!("could not wal_checkpoint: {e}");
+                    }
+                }
             }
-            _ => {}
         }
+    });
 
+    tokio::spawn(async move {
         // large sleep right at the start to give node time to sync
         sleep(Duration::from_secs(60)).await;
 
@@ -486,37 +538,45 @@ pub fn spawn_handle_db_maintenance(agent: &Agent) {
             if let Err(e) = vacuum_db(&pool, MAX_DB_FREE_PAGES).await {
                 error!("could not check freelist and vacuum: {e}");
             }
-
-            if let Err(e) =
-                wal_checkpoint_over_threshold(wal_path.as_path(), &pool, truncate_wal_threshold)
-                    .await
-            {
-                error!("could not wal_checkpoint truncate: {e}");
-            }
         }
     });
 }
 
-async fn wal_checkpoint_over_threshold(
+/// Checkpoints the WAL if `due` and not empty, or if it's over the policy's
+/// threshold in the `TRUNCATE` mode. The other modes leave the file as large
+/// as it was (up to `journal_size_limit`), its size would trigger them again
+/// and again. Returns whether a checkpoint ran.
+async fn wal_checkpoint_with_policy(
     wal_path: &Utf8Path,
     pool: &SplitPool,
-    threshold: u64,
+    policy: &WalCheckpointPolicy,
+    due: bool,
 ) -> eyre::Result<bool> {
     let wal_size = wal_path.metadata()?.len();
-    let should_truncate = wal_size > threshold;
+    gauge!("corro.db.wal.size.bytes").set(wal_size as f64);
+
+    let truncates = policy.mode == WalCheckpointMode::Truncate;
+    let should_checkpoint = (truncates && wal_size > policy.threshold) || (due && wal_size > 0);
 
-    if should_truncate {
-        let conn = if wal_size > (5 * threshold) {
+    if should_checkpoint {
+        let conn = if truncates && wal_size > (5 * policy.threshold) {
             warn!("wal_size is over 5x the threshold, trying to get a priority conn");
             pool.write_priority().await?
         } else {
             pool.write_low().await?
         };
 
-        let timeout = calc_busy_timeout(wal_path.metadata()?.len(), threshold);
-        block_in_place(|| wal_checkpoint(&conn, timeout))?;
+        let mut timeout = calc_busy_timeout(wal_path.metadata()?.len(), policy.threshold);
+        if let Some(max_block) = policy.max_block {
+            timeout = cmp::min(timeout, max_block.as_millis() as u64);
+        }
+        block_in_place(|| wal_checkpoint(&conn, policy.mode, timeout))?;
+
+        if let Ok(meta) = wal_path.metadata() {
+            gauge!("corro.db.wal.size.bytes").set(meta.len() as f64);
+        }
     }
-    Ok(should_truncate)
+    Ok(should_checkpoint)
 }
 
 fn calc_busy_timeout(wal_size: u64, threshold: u64) -> u64 {
@@ -539,6 +599,28 @@ fn calc_busy_timeout(wal_size: u64, threshold: u64) -> u64 {
     timeout
 }
 
+/// Moves queued changes into `buf` until the batch reaches `max_cost` or
+/// `max_bytes`, the whole batch gets applied in a single transaction.
+/// Returns the batch's cost.
+fn take_apply_batch(
+    queue: &mut VecDeque<(ChangeV1, ChangeSource, Instant)>,
+    buf: &mut Vec<(ChangeV1, ChangeSource, Instant)>,
+    max_cost: usize,
+    max_bytes: Option<usize>,
+) -> usize {
+    let mut cost = 0;
+    let mut bytes = 0;
+    while let Some((change, src, queued_at)) = queue.pop_front() {
+        cost += change.processing_cost();
+        bytes += change.estimated_byte_size();
+        buf.push((change, src, queued_at));
+        if cost >= max_cost || max_bytes.is_some_and(|max| bytes >= max) {
+            break;
+        }
+    }
+    cost
+}
+
 /// Bundle incoming changes to optimise transaction sizes with SQLite
 ///
 /// *Performance tradeoff*: introduce latency (with a max timeout) to
@@ -552,6 +634,7 @@ pub async fn handle_changes(
     mut tripwire: Tripwire,
 ) {
     let max_changes_chunk: usize = agent.config().perf.apply_queue_len;
+    let max_changes_bytes: Option<usize> = agent.config().perf.apply_queue_bytes;
     let max_queue_len: usize = agent.config().perf.processing_queue_len;
     let tx_timeout: Duration = Duration::from_secs(agent.config().perf.sql_tx_timeout as u64);
     let mut queue: VecDeque<(ChangeV1, ChangeSource, Instant)> = VecDeque::new();
@@ -577,21 +660,18 @@ pub async fn handle_changes(
     let mut seen: IndexMap<_, RangeInclusiveSet<CrsqlSeq>> = IndexMap::new();
 
     let mut drop_log_count: u64 = 0;
+    let mut own_version_check: Option<tokio::task::JoinHandle<bool>> = None;
     // complicated loop to process changes efficiently w/ a max concurrency
     // and a minimum chunk size for bigger and faster SQLite transactions
     loop {
-        while (buf_cost >= max_changes_chunk || (!queue.is_empty() && join_set.is_empty()))
+        // while paused, changes keep being queued but aren't applied
+        while !agent.inbound_paused()
+            && (buf_cost >= max_changes_chunk || (!queue.is_empty() && join_set.is_empty()))
             && join_set.len() < MAX_CONCURRENT
         {
             // Process if we hit the chunk size OR if we have any items and available capacity
-            let mut tmp_cost = 0;
-            while let Some((change, src, queued_at)) = queue.pop_front() {
-                tmp_cost += change.processing_cost();
-                buf.push((change, src, queued_at));
-                if tmp_cost >= max_changes_chunk {
-                    break;
-                }
-            }
+            let tmp_cost =
+                take_apply_batch(&mut queue, &mut buf, max_changes_chunk, max_changes_bytes);
 
             if buf.is_empty() {
                 break;
@@ -637,16 +717,20 @@ pub async fn handle_changes(
                 gauge!("corro.agent.changesets.in_queue").set(queue.len() as f64);
                 gauge!("corro.agent.changes.processing.jobs").set(join_set.len() as f64);
 
-                if buf_cost < max_changes_chunk && !queue.is_empty() && join_set.len() < MAX_CONCURRENT {
-                    // we can process this right away
-                    debug!(%buf_cost, "spawning processing multiple changes from max wait interval");
-                    assert_sometimes!(true, "Corrosion processes changes");
-                    let changes: Vec<_> = queue.drain(..).collect();
-                    let agent = agent.clone();
-                    let bookie = bookie.clone();
-                    join_set.spawn(process_multiple_changes(agent, bookie, changes.clone(), tx_timeout));
-                    counter!("corro.agent.changes.batch.spawned").increment(1);
-                    buf_cost = 0;
+                if buf_cost < max_changes_chunk && !agent.inbound_paused() {
+                    // drain what's queued, in as many batches as the byte
+                    // budget needs and the concurrency allows
+                    while !queue.is_empty() && join_set.len() < MAX_CONCURRENT {
+                        debug!(%buf_cost, "spawning processing multiple changes from max wait interval");
+                        assert_sometimes!(true, "Corrosion processes changes");
+                        let mut changes = vec![];
+                        let cost = take_apply_batch(&mut queue, &mut changes, max_changes_chunk, max_changes_bytes);
+                        let agent = agent.clone();
+                        let bookie = bookie.clone();
+                        join_set.spawn(process_multiple_changes(agent, bookie, changes, tx_timeout));
+                        counter!("corro.agent.changes.batch.spawned").increment(1);
+                        buf_cost = buf_cost.saturating_sub(cost);
+                    }
                 }
 
                 if seen.len() > max_seen_cache_len {
@@ -665,6 +749,30 @@ pub async fn handle_changes(
         counter!("corro.agent.changes.recv").increment(std::cmp::max(change_len, 1) as u64); // count empties...
 
         if change.actor_id == agent.actor_id() {
+            // our own changes coming back, unless another node has our actor id.
+            // versions past the last one checked are checked off this loop, one
+            // at a time: the booked lock is held during local writes
+            let version = change.versions().end();
+            if !agent.own_version_checked(version)
+                && own_version_check
+                    .as_ref()
+                    .map_or(true, |check| check.is_finished())
+            {
+                let agent = agent.clone();
+                let source: &'static str = src.into();
+                own_version_check = Some(tokio::spawn(async move {
+                    agent.check_own_version(version, source).await
+                }));
+            }
+            continue;
+        }
+
+        // versions are booked as sqlite integers, and one past the end of
+        // the change has to exist to compute gaps
+        if change.versions().end() > CrsqlDbVersion(i64::MAX as u64) {
+            warn!(actor_id = %change.actor_id, versions = ?change.versions(), "rejecting change with an out of range version");
+            counter!("corro.agent.changes.rejected", "reason" => "version_out_of_range")
+                .increment(1);
             continue;
         }
 
@@ -742,6 +850,13 @@ pub async fn handle_changes(
 
                 buf_cost -= dropped_change.processing_cost();
                 dropped_count += 1;
+
+                if agent.inbound_paused() {
+                    agent.track_dropped_while_paused(
+                        dropped_change.actor_id,
+                        dropped_change.versions(),
+                    );
+                }
             }
             counter!("corro.agent.changes.dropped").increment(dropped_count);
 
@@ -807,6 +922,7 @@ pub async fn handle_sync(
 
     let chosen: Vec<(ActorId, SocketAddr)> = {
         let candidates = {
+            let config = agent.config();
             let members = agent.members().read();
 
             members
@@ -816,6 +932,14 @@ pub async fn handle_sync(
                 .filter(|(id, state)| {
                     **id != agent.actor_id() && state.cluster_id == agent.cluster_id()
                 })
+                // and peers we're not allowed to sync with
+                .filter(|(id, state)| {
+                    config
+                        .gossip
+                        .peer_access
+                        .check(**id, state.addr.ip())
+                        .is_ok()
+                })
                 // Grab a ring-buffer index to the member RTT range
                 .map(|(id, state)| {
                     (
@@ -896,12 +1020,12 @@ pub async fn handle_sync(
 #[cfg(test)]
 mod tests {
     use crate::agent::setup;
-    use crate::api::public::api_v1_db_schema;
+    use crate::api::public::{api_v1_db_schema, api_v1_transactions, TimeoutParams};
 
     use super::*;
     use axum::{http::StatusCode, Extension, Json};
     use corro_tests::TEST_SCHEMA;
-    use corro_types::api::{ColumnName, TableName};
+    use corro_types::api::{ColumnName, Statement, TableName};
     use corro_types::{
         base::{dbsr, dbvr, CrsqlDbVersion},
         broadcast::Changeset,
@@ -910,7 +1034,10 @@ mod tests {
         pubsub::pack_columns,
     };
     use rusqlite::Connection;
-    use std::sync::Arc;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
     use tokio::sync::Semaphore;
     use tokio::time::{timeout, Duration};
 
@@ -922,7 +1049,7 @@ mod tests {
         let pragma_value = 12345u64;
         conn.pragma_update(None, "busy_timeout", pragma_value)?;
 
-        wal_checkpoint(&conn, 60000)?;
+        wal_checkpoint(&conn, WalCheckpointMode::Truncate, 60000)?;
         assert_eq!(
             conn.pragma_query_value(None, "busy_timeout", |row| row.get::<_, u64>(0))?,
             pragma_value
@@ -1113,4 +1240,181 @@ mod tests {
     fn to_bytes(gb: u64) -> u64 {
         gb * 1024 * 1024 * 1024
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_take_apply_batch() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+        let dir = tempfile::tempdir()?;
+
+        let config = Config::builder()
+            .db_path(dir.path().join("corrosion.db").display().to_string())
+            .gossip_addr("127.0.0.1:0".parse()?)
+            .api_addr("127.0.0.1:0".parse()?)
+            .build()?;
+
+        let (agent, _agent_options) = setup(config, tripwire.clone()).await?;
+
+        let (status_code, _res) =
+            api_v1_db_schema(Extension(agent.clone()), Json(vec![TEST_SCHEMA.to_owned()])).await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bookie = Bookie::new(Default::default());
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let chunk = |version: u64| -> eyre::Result<_> {
+            Ok((
+                ChangeV1 {
+                    actor_id,
+                    changeset: Changeset::Full {
+                        version: CrsqlDbVersion(version),
+                        changes: vec![Change {
+                            table: TableName("tests".into()),
+                            pk: pack_columns(&vec![(version as i64).into()])?,
+                            cid: ColumnName("text".into()),
+                            val: "batched".into(),
+                            col_version: 1,
+                            db_version: CrsqlDbVersion(version),
+                            seq: CrsqlSeq(0),
+                            site_id: actor_id.to_bytes(),
+                            cl: 1,
+                        }],
+                        seqs: dbsr!(0, 0),
+                        last_seq: CrsqlSeq(0),
+                        ts: agent.clock().new_timestamp().into(),
+                    },
+                },
+                ChangeSource::Sync,
+                Instant::now(),
+            ))
+        };
+
+        // there's a single write connection, count its commits
+        let commits = Arc::new(AtomicUsize::new(0));
+        agent.pool().write_priority().await?.commit_hook(Some({
+            let commits = commits.clone();
+            move || {
+                commits.fetch_add(1, Ordering::SeqCst);
+                false
+            }
+        }));
+
+        // three chunks with a batch size of 2 are applied in two transactions
+        let mut queue = (1..=3).map(chunk).collect::<eyre::Result<VecDeque<_>>>()?;
+        let mut batches = vec![];
+        while !queue.is_empty() {
+            let mut buf = vec![];
+            take_apply_batch(&mut queue, &mut buf, 2, None);
+            batches.push(buf.iter().map(|(c, _, _)| c.versions()).collect::<Vec<_>>());
+            process_multiple_changes(agent.clone(), bookie.clone(), buf, Duration::from_secs(5))
+                .await?;
+        }
+        assert_eq!(
+            batches,
+            vec![vec![dbvr!(1, 1), dbvr!(2, 2)], vec![dbvr!(3, 3)]]
+        );
+        assert_eq!(commits.load(Ordering::SeqCst), 2);
+
+        let booked = bookie.read::<&str, _>("test", None).await;
+        let booked = booked
+            .get(&actor_id)
+            .unwrap()
+            .read::<&str, _>("test", None)
+            .await;
+        assert!(booked.contains_all(dbvr!(1, 3), None));
+
+        // the byte budget cuts batches short too
+        let mut queue = (1..=3).map(chunk).collect::<eyre::Result<VecDeque<_>>>()?;
+        let bytes = queue[0].0.estimated_byte_size();
+        let mut buf = vec![];
+        assert_eq!(take_apply_batch(&mut queue, &mut buf, 50, Some(bytes)), 1);
+        assert_eq!(buf.len(), 1);
+        assert_eq!(queue.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_wal_checkpoint_policy() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        // default threshold, the maintenance task leaves the WAL alone
+        let ta = corro_tests::launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let mut wal_path = ta.agent.config().db.path.clone();
+        wal_path.set_extension(format!("{}-wal", wal_path.extension().unwrap_or_default()));
+
+        let grow = |start: i64| {
+            let agent = ta.agent.clone();
+            async move {
+                let text = "x".repeat(64 * 1024);
+                for i in start..start + 64 {
+                    let (status_code, _) = api_v1_transactions(
+                        Extension(agent.clone()),
+                        axum::extract::Query(TimeoutParams { timeout: None }),
+                        Json(vec![Statement::WithParams(
+                            "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                            vec![i.into(), text.clone().into()],
+                        )]),
+                    )
+                    .await;
+                    assert_eq!(status_code, StatusCode::OK);
+                }
+            }
+        };
+        grow(0).await;
+
+        const MB: u64 = 1024 * 1024;
+        let grown = wal_path.metadata()?.len();
+        assert!(grown > 2 * MB, "WAL did not grow: {grown}");
+
+        let policy = |mode| WalCheckpointPolicy {
+            mode,
+            threshold: MB,
+            interval: None,
+            max_block: Some(Duration::from_secs(5)),
+        };
+
+        // under the threshold and not due
+        let lenient = WalCheckpointPolicy {
+            threshold: 4 * grown,
+            ..policy(WalCheckpointMode::Truncate)
+        };
+        assert!(!wal_checkpoint_with_policy(&wal_path, ta.agent.pool(), &lenient, false).await?);
+        assert!(wal_path.metadata()?.len() >= grown);
+
+        // passive checkpoints don't shrink the file, its size doesn't
+        // trigger them
+        let passive = policy(WalCheckpointMode::Passive);
+        assert!(!wal_checkpoint_with_policy(&wal_path, ta.agent.pool(), &passive, false).await?);
+        assert!(wal_checkpoint_with_policy(&wal_path, ta.agent.pool(), &passive, true).await?);
+        assert!(wal_path.metadata()?.len() >= grown);
+        // still over the threshold once checkpointed, but not run again
+        assert!(!wal_checkpoint_with_policy(&wal_path, ta.agent.pool(), &passive, false).await?);
+
+        // interval trigger, under the threshold
+        assert!(wal_checkpoint_with_policy(&wal_path, ta.agent.pool(), &lenient, true).await?);
+        let size = wal_path.metadata()?.len();
+        assert!(size < MB, "WAL was not truncated: {size}");
+
+        // size trigger
+        grow(64).await;
+        assert!(wal_path.metadata()?.len() > MB);
+        assert!(
+            wal_checkpoint_with_policy(
+                &wal_path,
+                ta.agent.pool(),
+                &policy(WalCheckpointMode::Truncate),
+                false
+            )
+            .await?
+        );
+        let size = wal_path.metadata()?.len();
+        assert!(size < MB, "WAL was not truncated: {size}");
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
 }
@@ -4,6 +4,7 @@
 //! clients), manages cluster memberships, and applies propagated
 //! changesets to local data.
 
+mod anti_entropy;
 mod bi;
 mod bootstrap;
 mod error;
@@ -11,6 +12,8 @@ mod handlers;
 mod metrics;
 mod run_root;
 mod setup;
+mod shutdown;
+mod snapshot;
 mod uni;
 pub mod util;
 
@@ -24,9 +27,15 @@ use tokio::sync::{broadcast::Sender, RwLock};
 use uuid::Uuid;
 
 // Public exports
+pub use anti_entropy::{anti_entropy_pass, versions_digests, MAX_DIGEST_RANGES};
 pub use error::{SyncClientError, SyncRecvError};
-pub use run_root::start_with_config;
-pub use setup::{setup, AgentOptions};
+pub use run_root::{start_with_config, start_with_observers};
+pub use setup::{setup, setup_with_observers, AgentOptions};
+pub use shutdown::{shutdown, ShutdownSummary, SHUTDOWN_DRAIN_TIMEOUT};
+pub use snapshot::{
+    export_change_log, import_change_log, ChangeLogSummary, ImportOptions, RejectedChange,
+    SnapshotError,
+};
 pub use uni::spawn_unipayload_handler;
 pub use util::process_multiple_changes;
 
@@ -5,6 +5,7 @@ use std::time::Instant;
 use crate::api::public::execute_schema;
 use crate::{
     agent::{
+        anti_entropy,
         handlers::{self, spawn_handle_db_maintenance},
         metrics, setup, util, AgentOptions,
     },
@@ -17,6 +18,7 @@ use corro_types::{
     base::CrsqlSeq,
     channel::bounded,
     config::{Config, PerfConfig},
+    observer::ChangeObservers,
 };
 
 use futures::{FutureExt, StreamExt, TryStreamExt};
@@ -33,7 +35,18 @@ pub async fn start_with_config(
     conf: Config,
     tripwire: Tripwire,
 ) -> eyre::Result<(Agent, Bookie, Transport, Vec<JoinHandle<()>>)> {
-    let (agent, opts) = setup(conf.clone(), tripwire.clone()).await?;
+    start_with_observers(conf, ChangeObservers::default(), tripwire).await
+}
+
+/// Like [`start_with_config`], with observers called for every version
+/// applied from a peer
+pub async fn start_with_observers(
+    conf: Config,
+    change_observers: ChangeObservers,
+    tripwire: Tripwire,
+) -> eyre::Result<(Agent, Bookie, Transport, Vec<JoinHandle<()>>)> {
+    let (agent, opts) =
+        setup::setup_with_observers(conf.clone(), change_observers, tripwire.clone()).await?;
     let transport = opts.transport.clone();
 
     let (bookie, handles) = run(agent.clone(), opts, conf.perf).await?;
@@ -112,10 +125,17 @@ async fn run(
         }
     }
 
+    let bookie = Bookie::new_with_registry(Default::default(), lock_registry);
+    {
+        let mut w = bookie.write::<&str, _>("init", None).await;
+        w.insert(agent.actor_id(), agent.booked().clone());
+    }
+
     let mut handles = vec![];
     // Setup client http API
     let mut http_handles = util::setup_http_api_handler(
         &agent,
+        &bookie,
         &tripwire,
         subs_bcast_cache,
         updates_bcast_cache,
@@ -139,12 +159,6 @@ async fn run(
 
     spawn_handle_db_maintenance(&agent);
 
-    let bookie = Bookie::new_with_registry(Default::default(), lock_registry);
-    {
-        let mut w = bookie.write::<&str, _>("init", None).await;
-        w.insert(agent.actor_id(), agent.booked().clone());
-    }
-
     let start = Instant::now();
     {
         let conn = agent.pool().read().await?;
@@ -210,6 +224,7 @@ async fn run(
     }
 
     info!("Bookkeeping fully loaded in {:?}", start.elapsed());
+    agent.set_bookkeeping_loaded();
 
     spawn_counted(
         util::sync_loop(
@@ -231,6 +246,26 @@ async fn run(
         .inspect(|_| info!("corrosion buffered changes loop is done")),
     );
 
+    spawn_counted(
+        util::request_missing_chunks_loop(agent.clone(), bookie.clone(), tripwire.clone())
+            .inspect(|_| info!("corrosion missing chunks loop is done")),
+    );
+
+    spawn_counted(
+        util::quarantine_loop(agent.clone(), bookie.clone(), tripwire.clone())
+            .inspect(|_| info!("corrosion quarantine loop is done")),
+    );
+
+    spawn_counted(
+        anti_entropy::anti_entropy_loop(
+            agent.clone(),
+            bookie.clone(),
+            transport.clone(),
+            tripwire.clone(),
+        )
+        .inspect(|_| info!("corrosion anti-entropy loop is done")),
+    );
+
     info!("Starting peer API on udp/{gossip_addr} (QUIC)");
 
     //// Start an incoming (corrosion) connection handler.  This
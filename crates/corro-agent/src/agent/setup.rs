@@ -38,12 +38,14 @@ use corro_types::{
     },
     base::{CrsqlDbVersion, CrsqlDbVersionRange},
     broadcast::{BroadcastInput, ChangeSource, ChangeV1, FocaInput},
+    change::quarantined_chunks,
     channel::{bounded, CorroReceiver},
     config::Config,
     members::Members,
+    observer::ChangeObservers,
     pubsub::{Matcher, SubsManager},
     schema::{init_schema, Schema},
-    sqlite::CrConn,
+    sqlite::{probe_crsqlite, CrConn},
     updates::UpdatesManager,
 };
 
@@ -67,8 +69,20 @@ pub struct AgentOptions {
 
 /// Setup an agent runtime and state with a configuration
 pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, AgentOptions)> {
+    setup_with_observers(conf, ChangeObservers::default(), tripwire).await
+}
+
+/// Like [`setup`], with observers called for every version applied from a
+/// peer
+pub async fn setup_with_observers(
+    conf: Config,
+    change_observers: ChangeObservers,
+    tripwire: Tripwire,
+) -> eyre::Result<(Agent, AgentOptions)> {
     debug!("setting up corrosion @ {}", conf.db.path);
 
+    conf.validate()?;
+
     if let Some(parent) = conf.db.path.parent() {
         tokio::fs::create_dir_all(parent).await?;
     }
@@ -82,16 +96,53 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
         db_conn.execute_batch("PRAGMA auto_vacuum = INCREMENTAL")?;
 
         let conn = CrConn::init(db_conn)?;
-        conn.query_row("SELECT crsql_site_id();", [], |row| {
+        let crsql_version = probe_crsqlite(&conn)?;
+        info!("cr-sqlite extension version {crsql_version}");
+        let actor_id = conn.query_row("SELECT crsql_site_id();", [], |row| {
             row.get::<_, ActorId>(0)
-        })
-    }?;
+        })?;
+
+        match conf.db.node_name.as_deref() {
+            Some(name) if ActorId::from_node_name(name) != actor_id => {
+                let named = ActorId::from_node_name(name);
+                let db_version: CrsqlDbVersion =
+                    conn.query_row("SELECT crsql_db_version();", [], |row| row.get(0))?;
+                if db_version.0 == 0 {
+                    conn.execute(
+                        "UPDATE crsql_site_id SET site_id = ? WHERE ordinal = 0",
+                        [named],
+                    )?;
+                    // the extension cached the previous id when loaded, read
+                    // it back from a fresh connection
+                    drop(conn);
+                    let conn = CrConn::init(Connection::open(&conf.db.path)?)?;
+                    let actor_id = conn.query_row("SELECT crsql_site_id();", [], |row| {
+                        row.get::<_, ActorId>(0)
+                    })?;
+                    if actor_id != named {
+                        eyre::bail!("could not set actor id {named} derived from node name '{name}', still using {actor_id}");
+                    }
+                    info!("using actor id {named} derived from node name '{name}'");
+                    named
+                } else {
+                    warn!("database already has changes from actor id {actor_id}, ignoring the one derived from node name '{name}' ({named})");
+                    actor_id
+                }
+            }
+            _ => actor_id,
+        }
+    };
 
     info!("Actor ID: {actor_id}");
 
     let write_sema = Arc::new(Semaphore::new(1));
 
-    let pool = SplitPool::create(&conf.db.path, write_sema.clone()).await?;
+    let pool = SplitPool::create_with_read_size(
+        &conf.db.path,
+        write_sema.clone(),
+        conf.perf.read_pool_size,
+    )
+    .await?;
 
     let clock = Arc::new(
         uhlc::HLCBuilder::default()
@@ -137,6 +188,14 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
 
     info!("Cluster ID: {cluster_id}");
 
+    let quarantined = {
+        let conn = pool.read().await?;
+        quarantined_chunks(&conn)?
+    };
+    if !quarantined.is_empty() {
+        warn!("{} chunks are quarantined", quarantined.len());
+    }
+
     let (tx_apply, rx_apply) = bounded(conf.perf.apply_channel_len, "apply");
     let (tx_clear_buf, rx_clear_buf) = bounded(conf.perf.clearbuf_channel_len, "clear_buf");
 
@@ -277,9 +336,14 @@ pub async fn setup(conf: Config, tripwire: Tripwire) -> eyre::Result<(Agent, Age
         cluster_id,
         subs_manager,
         updates_manager,
+        change_observers,
         tripwire,
     });
 
+    for chunk in quarantined {
+        agent.chunk_retries().quarantine(chunk.key());
+    }
+
     Ok((agent, opts))
 }
 
@@ -0,0 +1,149 @@
+//! Graceful shutdown
+//!
+//! Flushes local state once the tripwire has been tripped, so peers
+//! don't have to rediscover a gap after a restart.
+
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use corro_types::{
+    actor::ActorId,
+    agent::{Agent, ChangeError},
+    base::CrsqlDbVersion,
+};
+use rusqlite::{params, Connection};
+use spawn::PENDING_HANDLES;
+use tokio::task::block_in_place;
+use tracing::{info, warn};
+
+/// How long [`shutdown`] waits for in-flight broadcasts and syncs by default
+pub const SHUTDOWN_DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ShutdownSummary {
+    /// Local versions booked by the flush, for changes committed without
+    /// being booked
+    pub flushed_versions: Vec<CrsqlDbVersion>,
+    /// Our last booked version, as persisted
+    pub last_version: Option<CrsqlDbVersion>,
+    /// Counted tasks (broadcasts, sync sends) still running after the drain
+    /// timed out
+    pub pending_tasks: usize,
+    /// Whether the WAL was checkpointed into the database file
+    pub checkpointed: bool,
+}
+
+/// Our versions committed past the last booked one, they were written
+/// without going through
+/// [`insert_local_changes`](corro_types::change::insert_local_changes) or it
+/// didn't finish
+fn unbooked_local_versions(
+    conn: &Connection,
+    actor_id: ActorId,
+    last: Option<CrsqlDbVersion>,
+) -> rusqlite::Result<Vec<CrsqlDbVersion>> {
+    conn.prepare_cached(
+        "SELECT DISTINCT db_version FROM crsql_changes WHERE site_id = ? AND db_version > ? ORDER BY db_version",
+    )?
+    .query_map(params![actor_id, last.unwrap_or_default()], |row| row.get(0))?
+    .collect()
+}
+
+/// Stops accepting local writes, books any unbooked local changes, waits up
+/// to `drain_timeout` for in-flight sends and checkpoints the database.
+pub async fn shutdown(
+    agent: &Agent,
+    drain_timeout: Duration,
+) -> Result<ShutdownSummary, ChangeError> {
+    agent.stop_writes();
+
+    let mut summary = ShutdownSummary::default();
+    let actor_id = agent.actor_id();
+
+    {
+        // waits for the write in progress, if any
+        let mut conn = agent.pool().write_priority().await?;
+        let mut book_writer = agent
+            .booked()
+            .write::<&str, _>("shutdown(booked writer)", None)
+            .await;
+
+        summary.flushed_versions = block_in_place(|| {
+            let tx = conn
+                .immediate_transaction()
+                .map_err(|source| ChangeError::Rusqlite {
+                    source,
+                    actor_id: Some(actor_id),
+                    version: None,
+                })?;
+
+            let versions =
+                unbooked_local_versions(&tx, actor_id, book_writer.last()).map_err(|source| {
+                    ChangeError::Rusqlite {
+                        source,
+                        actor_id: Some(actor_id),
+                        version: None,
+                    }
+                })?;
+
+            let mut snap = book_writer.snapshot();
+            snap.insert_db(
+                &tx,
+                versions.iter().map(|version| version.range_to(*version)),
+            )
+            .map_err(|source| ChangeError::Rusqlite {
+                source,
+                actor_id: Some(actor_id),
+                version: versions.last().copied(),
+            })?;
+
+            tx.commit().map_err(|source| ChangeError::Rusqlite {
+                source,
+                actor_id: Some(actor_id),
+                version: versions.last().copied(),
+            })?;
+            book_writer.commit_snapshot(snap);
+
+            Ok::<_, ChangeError>(versions)
+        })?;
+
+        summary.last_version = book_writer.last();
+
+        if !summary.flushed_versions.is_empty() {
+            warn!(%actor_id, versions = ?summary.flushed_versions, "booked unbooked local changes on shutdown");
+        }
+    }
+
+    let start = Instant::now();
+    loop {
+        summary.pending_tasks = PENDING_HANDLES.load(Ordering::SeqCst);
+        if summary.pending_tasks == 0 || start.elapsed() >= drain_timeout {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    if summary.pending_tasks > 0 {
+        warn!(
+            "{} tasks still running after {drain_timeout:?}",
+            summary.pending_tasks
+        );
+    }
+
+    // bookkeeping is written along with the changes, make sure it's all in
+    // the database file rather than the WAL
+    let conn = agent.pool().write_priority().await?;
+    let busy: bool =
+        block_in_place(|| conn.query_row("PRAGMA wal_checkpoint(TRUNCATE);", [], |row| row.get(0)))
+            .map_err(|source| ChangeError::Rusqlite {
+                source,
+                actor_id: Some(actor_id),
+                version: None,
+            })?;
+    summary.checkpointed = !busy;
+
+    info!("shutdown flush complete: {summary:?}");
+
+    Ok(summary)
+}
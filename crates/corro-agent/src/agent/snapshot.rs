@@ -0,0 +1,479 @@
+//! Change log export and import
+//!
+//! Seeds a node from a file written by another node instead of syncing
+//! every version from its peers, see [`corro_types::snapshot`] for the
+//! format.
+
+use std::{
+    cmp,
+    collections::HashMap,
+    fs::File,
+    io::BufWriter,
+    ops::RangeInclusive,
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use corro_types::{
+    actor::ActorId,
+    agent::{Agent, BookedVersions, Bookie, ChangeError},
+    api::TableName,
+    base::{CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeq, CrsqlSeqRange},
+    broadcast::{ChangeSource, ChangeV1, Changeset, Timestamp},
+    change::{row_to_change, Change},
+    snapshot::{ChangeLogError, ChangeLogHeader, ChangeLogReader, ChangeLogWriter},
+    sqlite::SqlitePoolError,
+};
+use rangemap::RangeInclusiveSet;
+use rusqlite::{named_params, params_from_iter, Connection};
+use serde::{Deserialize, Serialize};
+use tokio::task::block_in_place;
+use tracing::info;
+
+use super::{process_multiple_changes, util::is_already_applied};
+use crate::api::peer::apply_table_scoped_changes;
+
+// processing cost of the changesets applied in a single batch on import
+const IMPORT_BATCH_COST: usize = 1000;
+
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    ChangeLog(#[from] ChangeLogError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error(transparent)]
+    Pool(#[from] SqlitePoolError),
+    #[error(transparent)]
+    Rusqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Change(#[from] ChangeError),
+    #[error("unknown table '{0}'")]
+    UnknownTable(TableName),
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChangeLogSummary {
+    /// Number of versions, across actors, including cleared versions
+    pub versions: usize,
+    pub changes: usize,
+    /// Number of versions an import skipped as already applied, see
+    /// [`ImportOptions::skip_applied`]
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub skipped: usize,
+    /// Changesets an import failed to apply, see
+    /// [`ImportOptions::continue_on_error`]
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub rejected: Vec<RejectedChange>,
+}
+
+impl ChangeLogSummary {
+    fn counting(change: &ChangeV1) -> Self {
+        Self {
+            versions: change.versions().len(),
+            changes: change.changeset.len(),
+            ..Default::default()
+        }
+    }
+
+    fn add(&mut self, other: Self) {
+        self.versions += other.versions;
+        self.changes += other.changes;
+    }
+}
+
+impl FromIterator<ChangeLogSummary> for ChangeLogSummary {
+    fn from_iter<T: IntoIterator<Item = ChangeLogSummary>>(iter: T) -> Self {
+        let mut summary = Self::default();
+        for other in iter {
+            summary.add(other);
+        }
+        summary
+    }
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+/// How [`import_change_log`] handles what's already applied or fails to apply
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ImportOptions {
+    /// Reports the changesets failing to apply instead of stopping the
+    /// import, the rest of the log is still applied
+    pub continue_on_error: bool,
+    /// Skips the versions already booked, so an interrupted import can be
+    /// run again
+    pub skip_applied: bool,
+}
+
+/// Writes every current change of `tables` (or of the whole database) to a
+/// change log at `path`, from a single read transaction.
+///
+/// Whole database exports also hold the versions known to be cleared, the
+/// way a sync sends them, so an import books every version we have. A table
+/// export is imported the way a table-scoped sync is applied.
+pub async fn export_change_log(
+    agent: &Agent,
+    tables: Option<Vec<TableName>>,
+    path: &Path,
+    compress: bool,
+) -> Result<ChangeLogSummary, SnapshotError> {
+    if let Some(tables) = tables.as_deref() {
+        let schema = agent.schema().read();
+        if let Some(table) = tables
+            .iter()
+            .find(|table| !schema.tables.contains_key(table.as_str()))
+        {
+            return Err(SnapshotError::UnknownTable(table.clone()));
+        }
+    }
+
+    let mut conn = agent.pool().read().await?;
+
+    let summary = block_in_place(|| {
+        // changes and bookkeeping are read from the same transaction so
+        // concurrent writes can't show up halfway through the export
+        let tx = conn.transaction()?;
+
+        let mut query = String::from(
+            r#"SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl, ts FROM crsql_changes"#,
+        );
+        if let Some(tables) = tables.as_deref() {
+            query.push_str(&format!(
+                r#" WHERE "table" IN ({})"#,
+                vec!["?"; tables.len()].join(",")
+            ));
+        }
+        query.push_str(" ORDER BY site_id, db_version, seq");
+
+        let mut prepped = tx.prepare(&query)?;
+        let mut rows = prepped.query(params_from_iter(
+            tables.iter().flatten().map(|table| table.as_str()),
+        ))?;
+
+        let header = ChangeLogHeader {
+            tables: tables.clone(),
+        };
+        let mut writer =
+            ChangeLogWriter::new(BufWriter::new(File::create(path)?), &header, compress)?;
+        let mut summary = ChangeLogSummary::default();
+        let mut written: HashMap<ActorId, RangeInclusiveSet<CrsqlDbVersion>> = HashMap::new();
+
+        let mut current: Option<(ActorId, CrsqlDbVersion, Vec<Change>, Timestamp)> = None;
+        while let Some(row) = rows.next()? {
+            let change = row_to_change(row)?;
+            let ts: Timestamp = row.get(9)?;
+            let actor_id = ActorId::from_bytes(change.site_id);
+
+            match current.as_mut() {
+                Some((current_actor, version, changes, max_ts))
+                    if *current_actor == actor_id && *version == change.db_version =>
+                {
+                    *max_ts = cmp::max(*max_ts, ts);
+                    changes.push(change);
+                }
+                _ => {
+                    let version = change.db_version;
+                    written
+                        .entry(actor_id)
+                        .or_default()
+                        .insert(version..=version);
+                    if let Some(done) = current.replace((actor_id, version, vec![change], ts)) {
+                        write_version(&tx, &mut writer, &mut summary, done)?;
+                    }
+                }
+            }
+        }
+        if let Some(done) = current {
+            write_version(&tx, &mut writer, &mut summary, done)?;
+        }
+
+        if tables.is_none() {
+            let actor_ids: Vec<ActorId> = tx
+                .prepare("SELECT site_id FROM crsql_site_id")?
+                .query_map([], |row| row.get(0))?
+                .collect::<rusqlite::Result<_>>()?;
+
+            for actor_id in actor_ids {
+                // bookkeeping from the same transaction as the changes
+                let booked = BookedVersions::from_conn(&tx, actor_id)?;
+                let Some(last) = booked.last() else {
+                    continue;
+                };
+
+                let mut cleared = RangeInclusiveSet::new();
+                cleared.insert(CrsqlDbVersion(1)..=last);
+                for range in booked.needed().iter() {
+                    cleared.remove(range.clone());
+                }
+                for version in booked.partials.keys() {
+                    cleared.remove(*version..=*version);
+                }
+                for range in written.get(&actor_id).into_iter().flatten() {
+                    cleared.remove(range.clone());
+                }
+
+                for versions in cleared {
+                    let versions = CrsqlDbVersionRange::from(versions);
+                    summary.versions += versions.len();
+                    writer.write(&ChangeV1 {
+                        actor_id,
+                        changeset: Changeset::Empty { versions, ts: None },
+                    })?;
+                }
+            }
+        }
+
+        writer
+            .finish()?
+            .into_inner()
+            .map_err(|e| e.into_error())?
+            .sync_all()?;
+
+        Ok::<_, SnapshotError>(summary)
+    })?;
+
+    info!(
+        "exported {} versions ({} changes) to {}",
+        summary.versions,
+        summary.changes,
+        path.display()
+    );
+
+    Ok(summary)
+}
+
+fn write_version(
+    conn: &Connection,
+    writer: &mut ChangeLogWriter<BufWriter<File>>,
+    summary: &mut ChangeLogSummary,
+    (actor_id, version, changes, ts): (ActorId, CrsqlDbVersion, Vec<Change>, Timestamp),
+) -> Result<(), SnapshotError> {
+    // overwritten seqs leave gaps within the range, which are expected when
+    // a version is synced too, but its last seq is the one it was booked
+    // with, not the last one left in the exported tables
+    let last_seq: Option<CrsqlSeq> = conn
+        .prepare_cached(
+            "SELECT COALESCE(
+                (SELECT last_seq FROM __corro_seq_bookkeeping WHERE site_id = :actor_id AND db_version = :version LIMIT 1),
+                (SELECT MAX(seq) FROM crsql_changes WHERE site_id = :actor_id AND db_version = :version)
+            )",
+        )?
+        .query_row(
+            named_params! {
+                ":actor_id": actor_id,
+                ":version": version,
+            },
+            |row| row.get(0),
+        )?;
+    let last_seq = last_seq
+        .or_else(|| changes.last().map(|change| change.seq))
+        .unwrap_or_default();
+
+    summary.versions += 1;
+    summary.changes += changes.len();
+
+    writer.write(&ChangeV1 {
+        actor_id,
+        changeset: Changeset::Full {
+            version,
+            changes,
+            seqs: CrsqlSeqRange::new(CrsqlSeq(0), last_seq),
+            last_seq,
+            ts,
+        },
+    })?;
+
+    Ok(())
+}
+
+/// Changesets of a change log that failed to apply
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RejectedChange {
+    pub actor_id: ActorId,
+    pub versions: RangeInclusive<CrsqlDbVersion>,
+    pub error: String,
+}
+
+impl RejectedChange {
+    fn new(
+        actor_id: ActorId,
+        versions: CrsqlDbVersionRange,
+        error: impl std::fmt::Display,
+    ) -> Self {
+        Self {
+            actor_id,
+            versions: versions.start()..=versions.end(),
+            error: error.to_string(),
+        }
+    }
+}
+
+/// Applies a change log written by [`export_change_log`]. Whole database
+/// logs go through the same path as synced changes and book their versions,
+/// table logs are applied like a table-scoped sync.
+pub async fn import_change_log(
+    agent: &Agent,
+    bookie: &Bookie,
+    path: &Path,
+    opts: ImportOptions,
+) -> Result<ChangeLogSummary, SnapshotError> {
+    let mut reader = block_in_place(|| ChangeLogReader::new(File::open(path)?))?;
+    let tables = reader.header().tables.clone();
+    let tx_timeout = Duration::from_secs(agent.config().perf.sql_tx_timeout as u64);
+
+    let mut summary = ChangeLogSummary::default();
+    loop {
+        if !agent.accepts_writes() {
+            return Err(ChangeError::ShuttingDown.into());
+        }
+
+        let batch = block_in_place(|| read_batch(&mut reader))?;
+        if batch.is_empty() {
+            break;
+        }
+
+        // nothing is booked for table logs, applying them again is harmless
+        let mut pending = vec![];
+        for change in batch {
+            if opts.skip_applied && tables.is_none() && is_booked(bookie, &change).await {
+                summary.skipped += change.versions().len();
+            } else {
+                pending.push(change);
+            }
+        }
+
+        match tables.as_deref() {
+            Some(tables) => {
+                for change in pending {
+                    let counted = ChangeLogSummary::counting(&change);
+                    let (actor_id, versions) = (change.actor_id, change.versions());
+                    match apply_table_scoped_changes(agent, tables, change).await {
+                        Ok(_) => summary.add(counted),
+                        Err(e) if opts.continue_on_error => summary
+                            .rejected
+                            .push(RejectedChange::new(actor_id, versions, e)),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+            }
+            None => {
+                let counted = pending.iter().map(ChangeLogSummary::counting).collect();
+                // kept to retry them one at a time
+                let changes = if opts.continue_on_error {
+                    pending.clone()
+                } else {
+                    std::mem::take(&mut pending)
+                };
+                let changes = changes
+                    .into_iter()
+                    .map(|change| (change, ChangeSource::Sync, Instant::now()))
+                    .collect();
+                match process_multiple_changes(agent.clone(), bookie.clone(), changes, tx_timeout)
+                    .await
+                {
+                    Ok(()) if !opts.continue_on_error => summary.add(counted),
+                    Err(e) if !opts.continue_on_error => return Err(e.into()),
+                    Ok(()) => {
+                        for change in pending {
+                            add_if_booked(agent, bookie, &mut summary, change).await;
+                        }
+                    }
+                    Err(_) => {
+                        // one at a time to find the changesets failing the batch
+                        for change in pending {
+                            let (actor_id, versions) = (change.actor_id, change.versions());
+                            let changes =
+                                vec![(change.clone(), ChangeSource::Sync, Instant::now())];
+                            match process_multiple_changes(
+                                agent.clone(),
+                                bookie.clone(),
+                                changes,
+                                tx_timeout,
+                            )
+                            .await
+                            {
+                                Ok(()) => add_if_booked(agent, bookie, &mut summary, change).await,
+                                Err(e) => summary
+                                    .rejected
+                                    .push(RejectedChange::new(actor_id, versions, e)),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    info!(
+        "imported {} versions ({} changes) from {}, {} already applied, {} rejected changesets",
+        summary.versions,
+        summary.changes,
+        path.display(),
+        summary.skipped,
+        summary.rejected.len()
+    );
+
+    Ok(summary)
+}
+
+/// Counts `change` as imported if its versions got booked, rejects it
+/// otherwise: processing changes only fails on errors rolling back the whole
+/// batch, a version failing on its own is logged and left unbooked.
+async fn add_if_booked(
+    agent: &Agent,
+    bookie: &Bookie,
+    summary: &mut ChangeLogSummary,
+    change: ChangeV1,
+) {
+    if is_booked(bookie, &change).await {
+        summary.add(ChangeLogSummary::counting(&change));
+        return;
+    }
+
+    let mismatch = {
+        let schema = agent.schema().read();
+        change
+            .changes()
+            .iter()
+            .find_map(|change| change.schema_mismatch(&schema))
+    };
+    let error = match mismatch {
+        Some(mismatch) => format!("quarantined until the schema matches: {mismatch}"),
+        None => "failed to apply, see the agent's logs".to_owned(),
+    };
+    summary.rejected.push(RejectedChange::new(
+        change.actor_id,
+        change.versions(),
+        error,
+    ));
+}
+
+async fn is_booked(bookie: &Bookie, change: &ChangeV1) -> bool {
+    let booked = bookie
+        .write("import_change_log(ensure)", change.actor_id.as_simple())
+        .await
+        .ensure(change.actor_id);
+    let booked = booked
+        .read(
+            "import_change_log(is_already_applied)",
+            change.actor_id.as_simple(),
+        )
+        .await;
+    is_already_applied(&booked, change)
+}
+
+/// Reads changesets up to a batch's processing cost
+fn read_batch(reader: &mut ChangeLogReader<File>) -> Result<Vec<ChangeV1>, ChangeLogError> {
+    let mut batch = vec![];
+    let mut cost = 0;
+    while cost < IMPORT_BATCH_COST {
+        let Some(change) = reader.next().transpose()? else {
+            break;
+        };
+        cost += change.changeset.processing_cost();
+        batch.push(change);
+    }
+    Ok(batch)
+}
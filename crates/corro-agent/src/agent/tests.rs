@@ -2,6 +2,10 @@ use std::{
     collections::{BTreeMap, HashMap},
     net::SocketAddr,
     ops::{Deref, RangeInclusive},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
     time::{Duration, Instant},
 };
 
@@ -12,6 +16,7 @@ use rand::{
     distributions::Uniform, prelude::Distribution, rngs::StdRng, seq::IteratorRandom, SeedableRng,
 };
 use rangemap::RangeInclusiveSet;
+use rusqlite::OptionalExtension;
 use serde::Deserialize;
 use serde_json::json;
 use spawn::wait_for_all_pending_handles;
@@ -24,7 +29,10 @@ use tripwire::Tripwire;
 use uuid::Uuid;
 
 use crate::{
-    agent::process_multiple_changes,
+    agent::{
+        anti_entropy_pass, export_change_log, import_change_log, process_multiple_changes,
+        start_with_observers, ImportOptions, SnapshotError,
+    },
     api::{
         peer::parallel_sync,
         public::{api_v1_db_schema, api_v1_transactions, TimeoutParams},
@@ -36,15 +44,21 @@ use corro_types::change::Change;
 use corro_types::{
     actor::ActorId,
     api::{ExecResponse, ExecResult, Statement},
-    base::{dbsr, dbsri, dbvri, CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeq},
+    base::{dbsr, dbsri, dbvri, CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeq, CrsqlSeqRange},
     broadcast::{ChangeSource, ChangeV1, Changeset},
+    config::{Config, PerfConfig, SeqMode},
+    observer::{ChangeObserver, ChangeObservers},
     sync::generate_sync,
 };
 use corro_types::{
-    agent::Agent,
-    api::{ColumnName, TableName},
-    change::row_to_change,
+    agent::{Agent, ChangeError, ReplicationDirection},
+    api::{ColumnName, SqliteValue, TableName},
+    change::{
+        quarantined_chunks, row_to_change, take_quarantined_chunk, ChunkedChanges,
+        MAX_CHANGES_BYTE_SIZE,
+    },
     pubsub::pack_columns,
+    snapshot::{ChangeLogReader, ChangeLogWriter},
 };
 
 #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
@@ -1419,3 +1433,2337 @@ async fn many_small_changes() -> eyre::Result<()> {
 
     Ok(())
 }
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+async fn test_reject_oversized_http_body() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(
+        |conf| {
+            conf.perf(PerfConfig {
+                max_frame_bytes: 1024,
+                ..Default::default()
+            })
+            .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+
+    let req_body: Vec<Statement> = serde_json::from_value(json!([[
+        "INSERT INTO tests (id,text) VALUES (?,?)",
+        [1, "a".repeat(4096)]
+    ],]))?;
+
+    let res = timeout(
+        Duration::from_secs(5),
+        client.request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/transactions", ta1.agent.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&req_body)?.into())?,
+        ),
+    )
+    .await??;
+    assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+    // nothing was written
+    let count: i64 =
+        ta1.agent
+            .pool()
+            .read()
+            .await?
+            .query_row("SELECT COUNT(*) FROM tests", (), |row| row.get(0))?;
+    assert_eq!(count, 0);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_shutdown_flushes_without_gaps() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    for i in 1i64..=3 {
+        let (status_code, _) = api_v1_transactions(
+            Extension(ta1.agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                vec![i.into(), format!("hello world {i}").into()],
+            )]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+    }
+
+    // a write that got committed but never booked, like one interrupted
+    // between the two
+    {
+        let conn = ta1.agent.pool().write_priority().await?;
+        conn.execute(
+            "INSERT INTO tests (id,text) VALUES (?,?)",
+            (4i64, "unbooked"),
+        )?;
+    }
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+
+    let summary = super::shutdown(&ta1.agent, Duration::from_secs(1)).await?;
+    assert_eq!(summary.flushed_versions, vec![CrsqlDbVersion(4)]);
+    assert_eq!(summary.last_version, Some(CrsqlDbVersion(4)));
+    assert!(summary.checkpointed);
+
+    // writes are refused from now on
+    let (status_code, _) = api_v1_transactions(
+        Extension(ta1.agent.clone()),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::Json(vec![Statement::WithParams(
+            "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+            vec![5i64.into(), "refused".into()],
+        )]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::SERVICE_UNAVAILABLE);
+
+    // so are schema changes
+    let (status_code, _) = api_v1_db_schema(
+        Extension(ta1.agent.clone()),
+        axum::Json(vec![
+            "CREATE TABLE refused (id INTEGER NOT NULL PRIMARY KEY);".into(),
+        ]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::SERVICE_UNAVAILABLE);
+
+    wait_for_all_pending_handles().await;
+
+    // restart on the same database
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let (agent, bookie, _transport, _handles) =
+        crate::agent::start_with_config(ta1.config.clone(), tripwire).await?;
+    assert_eq!(agent.actor_id(), ta1.agent.actor_id());
+
+    {
+        let booked = bookie
+            .write::<&str, _>("test_shutdown_flushes_without_gaps", None)
+            .await
+            .ensure(agent.actor_id());
+        let versions = booked.read::<&str, _>("test", None).await;
+        assert_eq!(versions.last(), Some(CrsqlDbVersion(4)));
+        assert!(
+            versions.needed().is_empty(),
+            "gaps: {:?}",
+            versions.needed()
+        );
+    }
+
+    let (status_code, body) = api_v1_transactions(
+        Extension(agent.clone()),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::Json(vec![Statement::WithParams(
+            "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+            vec![6i64.into(), "after restart".into()],
+        )]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+    assert_eq!(body.0.version, Some(5));
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn ipv6_agents_gossip() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+    // only listens on IPv6 loopback, for gossip and the API
+    async fn launch_v6_agent(
+        bootstrap: Vec<String>,
+        tripwire: Tripwire,
+    ) -> eyre::Result<(tempfile::TempDir, Agent)> {
+        let tmpdir = tempfile::tempdir()?;
+        let schema_path = tmpdir.path().join("schema");
+        tokio::fs::create_dir(&schema_path).await?;
+        tokio::fs::write(schema_path.join("tests.sql"), TEST_SCHEMA.as_bytes()).await?;
+
+        let conf = Config::builder()
+            .api_addr("[::1]:0".parse()?)
+            .gossip_addr("[::1]:0".parse()?)
+            .db_path(tmpdir.path().join("corrosion.db").display().to_string())
+            .add_schema_path(schema_path.display().to_string())
+            .bootstrap(bootstrap)
+            .build()?;
+
+        let (agent, _, _, _) = crate::agent::start_with_config(conf, tripwire).await?;
+        Ok((tmpdir, agent))
+    }
+
+    let (_dir1, agent1) = launch_v6_agent(vec![], tripwire.clone()).await?;
+    assert!(agent1.gossip_addr().is_ipv6());
+    assert!(agent1.api_addr().is_ipv6());
+
+    let (_dir2, agent2) =
+        launch_v6_agent(vec![agent1.gossip_addr().to_string()], tripwire.clone()).await?;
+    assert!(agent2.gossip_addr().is_ipv6());
+
+    let client = hyper::Client::builder().build_http::<hyper::Body>();
+    let req_body: Vec<Statement> = serde_json::from_value(json!([[
+        "INSERT INTO tests (id,text) VALUES (?,?)",
+        [1, "hello from v6"]
+    ]]))?;
+
+    // formats as http://[::1]:port/...
+    let res = timeout(
+        Duration::from_secs(5),
+        client.request(
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri(format!("http://{}/v1/transactions", agent1.api_addr()))
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .body(serde_json::to_vec(&req_body)?.into())?,
+        ),
+    )
+    .await??;
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let mut text = None;
+    for _ in 0..50 {
+        text = agent2
+            .pool()
+            .read()
+            .await?
+            .query_row("SELECT text FROM tests WHERE id = 1", [], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?;
+        if text.is_some() {
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(text.as_deref(), Some("hello from v6"));
+
+    // the peer is known by its IPv6 address
+    assert_eq!(
+        agent2.members().read().by_addr.get(&agent1.gossip_addr()),
+        Some(&agent1.actor_id())
+    );
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_apply_duration_per_table() -> eyre::Result<()> {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use sqlite_pool::InterruptibleTransaction;
+    use tokio::task::block_in_place;
+
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    // a single version touching two tables
+    let (status_code, _) = api_v1_transactions(
+        Extension(ta1.agent.clone()),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::Json(vec![
+            Statement::WithParams(
+                "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                vec![1i64.into(), "one".into()],
+            ),
+            Statement::WithParams(
+                "INSERT INTO tests2 (id,text) VALUES (?,?)".into(),
+                vec![1i64.into(), "two".into()],
+            ),
+        ]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+
+    let mut rows = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 1), None)]).await?;
+    assert_eq!(rows.len(), 1);
+    let (change, _, _) = rows.remove(0);
+
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+
+    let mut conn = ta2.agent.pool().write_priority().await?;
+    block_in_place(|| {
+        metrics::with_local_recorder(&recorder, || {
+            let mut tx =
+                InterruptibleTransaction::new(conn.immediate_transaction()?, None, "test_apply");
+            crate::agent::util::process_single_version(&ta2.agent, &mut tx, change)?;
+            tx.commit()
+        })
+    })?;
+
+    let mut tables: Vec<String> = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .filter(|(key, _, _, _)| key.key().name() == "corro.apply.duration.seconds")
+        .map(|(key, _, _, value)| {
+            let DebugValue::Histogram(values) = value else {
+                panic!("expected a histogram");
+            };
+            assert_eq!(values.len(), 1);
+            key.key()
+                .labels()
+                .find(|label| label.key() == "table")
+                .map(|label| label.value().to_string())
+                .unwrap()
+        })
+        .collect();
+    tables.sort();
+    assert_eq!(tables, vec!["tests".to_string(), "tests2".to_string()]);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[derive(Clone, Default)]
+struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+impl std::io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_log_conflict_winner() -> eyre::Result<()> {
+    use corro_types::config::LogConfig;
+    use sqlite_pool::InterruptibleTransaction;
+    use tokio::task::block_in_place;
+
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let log_conflicts = || LogConfig {
+        conflicts: true,
+        ..Default::default()
+    };
+    let ta1 = launch_test_agent(|conf| conf.log(log_conflicts()).build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.log(log_conflicts()).build(), tripwire.clone()).await?;
+
+    let exec = |agent: Agent, sql: &'static str, text: &'static str| async move {
+        let (status_code, _) = api_v1_transactions(
+            Extension(agent),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                sql.into(),
+                vec![1i64.into(), text.into()],
+            )]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+    };
+
+    // ta1 writes the column once, ta2 twice: ta2's value has a higher col_version
+    exec(
+        ta1.agent.clone(),
+        "INSERT INTO tests (id,text) VALUES (?,?)",
+        "from ta1",
+    )
+    .await;
+    exec(
+        ta2.agent.clone(),
+        "INSERT INTO tests (id,text) VALUES (?,?)",
+        "from ta2",
+    )
+    .await;
+    exec(
+        ta2.agent.clone(),
+        "UPDATE tests SET text = ? WHERE id = ?",
+        "from ta2 again",
+    )
+    .await;
+
+    let apply = |agent: Agent, change: ChangeV1| -> eyre::Result<String> {
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .finish();
+
+        block_in_place(|| {
+            tracing::subscriber::with_default(subscriber, || {
+                let mut conn =
+                    tokio::runtime::Handle::current().block_on(agent.pool().write_priority())?;
+                let mut tx = InterruptibleTransaction::new(
+                    conn.immediate_transaction()?,
+                    None,
+                    "test_conflict",
+                );
+                crate::agent::util::process_single_version(&agent, &mut tx, change)?;
+                tx.commit()?;
+                Ok::<_, eyre::Report>(())
+            })
+        })?;
+
+        let logs = logs.0.lock().unwrap().clone();
+        Ok(String::from_utf8(logs)?)
+    };
+
+    // read both before either is overwritten by the other
+    let (ta1_insert, _, _) = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 1), None)])
+        .await?
+        .remove(0);
+    let (ta2_update, _, _) = get_rows(ta2.agent.clone(), vec![(dbvri!(2, 2), None)])
+        .await?
+        .remove(0);
+
+    // ta2's update beats ta1's value
+    let logs = apply(ta1.agent.clone(), ta2_update)?;
+    let line = logs
+        .lines()
+        .find(|line| line.contains("conflicting change on tests.text"))
+        .expect("no conflict logged");
+    assert!(line.contains("incoming value won by col_version"), "{line}");
+    assert!(line.contains("existing_col_version=1"), "{line}");
+    assert!(line.contains("incoming_col_version=2"), "{line}");
+
+    // ta1's insert loses against ta2's update
+    let logs = apply(ta2.agent.clone(), ta1_insert)?;
+    let line = logs
+        .lines()
+        .find(|line| line.contains("conflicting change on tests.text"))
+        .expect("no conflict logged");
+    assert!(line.contains("existing value won by col_version"), "{line}");
+
+    let conn = ta1.agent.pool().read().await?;
+    let text: String =
+        conn.query_row("SELECT text FROM tests WHERE id = 1", [], |row| row.get(0))?;
+    assert_eq!(text, "from ta2 again");
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_skip_already_applied_changes() -> eyre::Result<()> {
+    use corro_types::agent::{BookedVersions, PartialVersion};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    use crate::agent::util::is_already_applied;
+
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let tx_timeout = Duration::from_secs(60);
+
+    insert_rows(ta1.agent.clone(), 1, 1).await;
+    let rows = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 1), None)]).await?;
+
+    let local_db_version = |agent: Agent| async move {
+        let conn = agent.pool().read().await?;
+        Ok::<CrsqlDbVersion, eyre::Report>(conn.query_row(
+            "SELECT crsql_db_version()",
+            [],
+            |row| row.get(0),
+        )?)
+    };
+
+    process_multiple_changes(
+        ta2.agent.clone(),
+        ta2.bookie.clone(),
+        rows.clone(),
+        tx_timeout,
+    )
+    .await?;
+    let applied_at = local_db_version(ta2.agent.clone()).await?;
+
+    // delivered again, nothing is written
+    process_multiple_changes(ta2.agent.clone(), ta2.bookie.clone(), rows, tx_timeout).await?;
+    assert_eq!(local_db_version(ta2.agent.clone()).await?, applied_at);
+
+    let last = ta2
+        .bookie
+        .write::<&str, _>("test", None)
+        .await
+        .ensure(ta1.agent.actor_id())
+        .read::<&str, _>("test", None)
+        .await
+        .last();
+    assert_eq!(last, Some(CrsqlDbVersion(1)));
+
+    // only skipped when every seq was applied
+    let actor_id = ActorId(Uuid::new_v4());
+    let mut versions = BookedVersions::new(actor_id);
+    versions.insert_partial(
+        CrsqlDbVersion(1),
+        PartialVersion {
+            seqs: [CrsqlSeq(0)..=CrsqlSeq(4)].into_iter().collect(),
+            last_seq: CrsqlSeq(9),
+            ts: ta1.agent.clock().new_timestamp().into(),
+        },
+    );
+    let change = |seqs| ChangeV1 {
+        actor_id,
+        changeset: Changeset::Full {
+            version: CrsqlDbVersion(1),
+            changes: vec![],
+            seqs,
+            last_seq: CrsqlSeq(9),
+            ts: ta1.agent.clock().new_timestamp().into(),
+        },
+    };
+
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+    metrics::with_local_recorder(&recorder, || {
+        assert!(is_already_applied(&versions, &change(dbsr!(0, 4))));
+        assert!(is_already_applied(&versions, &change(dbsr!(1, 2))));
+        assert!(!is_already_applied(&versions, &change(dbsr!(3, 6))));
+        assert!(!is_already_applied(&versions, &change(dbsr!(5, 9))));
+    });
+
+    let skipped = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .find(|(key, _, _, _)| key.key().name() == "corro.changes.skipped")
+        .map(|(_, _, _, value)| value);
+    assert_eq!(skipped, Some(DebugValue::Counter(2)));
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_apply_write_amplification() -> eyre::Result<()> {
+    use corro_types::change::{total_changes, WriteAmplification};
+    use sqlite_pool::InterruptibleTransaction;
+
+    use crate::agent::util::process_single_version;
+
+    // applies the versions, returns the rows changed doing so
+    async fn rows_changed(
+        agent: &Agent,
+        rows: &[(ChangeV1, ChangeSource, Instant)],
+    ) -> eyre::Result<u64> {
+        let mut conn = agent.pool().write_priority().await?;
+        let before = total_changes(&conn)?;
+        let mut tx = InterruptibleTransaction::new(conn.transaction()?, None, "test");
+        for (change, _, _) in rows {
+            process_single_version(agent, &mut tx, change.clone())?;
+        }
+        tx.commit()?;
+        Ok(total_changes(&conn)?.saturating_sub(before))
+    }
+
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta3 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    insert_rows(ta1.agent.clone(), 1, 2).await;
+    let rows = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 2), None)]).await?;
+    let changes_applied: u64 = rows
+        .iter()
+        .map(|(change, _, _)| change.changeset.len() as u64)
+        .sum();
+
+    // every applied row gets copied over by a trigger on ta2 only
+    ta2.agent.pool().write_priority().await?.execute_batch(
+        "CREATE TABLE tests3_audit (id INTEGER NOT NULL);
+        CREATE TRIGGER tests3_audit_insert AFTER INSERT ON tests3 BEGIN
+            INSERT INTO tests3_audit (id) VALUES (NEW.id);
+        END;",
+    )?;
+
+    let without = WriteAmplification {
+        changes_applied,
+        rows_changed: rows_changed(&ta3.agent, &rows).await?,
+    };
+    let with = WriteAmplification {
+        changes_applied,
+        rows_changed: rows_changed(&ta2.agent, &rows).await?,
+    };
+
+    // exactly one more row per inserted row
+    assert_eq!(with.rows_changed, without.rows_changed + 2);
+    assert_eq!(
+        with.write_amplification().unwrap(),
+        (without.rows_changed + 2) as f64 / changes_applied as f64
+    );
+    assert!(with.write_amplification() > without.write_amplification());
+
+    let audited: i64 = ta2.agent.pool().read().await?.query_row(
+        "SELECT COUNT(*) FROM tests3_audit",
+        [],
+        |row| row.get(0),
+    )?;
+    assert_eq!(audited, 2);
+
+    assert_eq!(WriteAmplification::default().write_amplification(), None);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_cell_value_at() -> eyre::Result<()> {
+    use corro_types::change::cell_value_at;
+
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let tx_timeout = Duration::from_secs(60);
+
+    // writes a version on ta1 and returns it, before a later version
+    // overwrites its changes
+    let write = |statements: Vec<Statement>| {
+        let agent = ta1.agent.clone();
+        async move {
+            let (status_code, body) = api_v1_transactions(
+                Extension(agent.clone()),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::Json(statements),
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+            let version = CrsqlDbVersion(body.0.version.unwrap());
+            let mut rows = get_rows(agent, vec![(version..=version, None)]).await?;
+            Ok::<_, eyre::Report>(rows.remove(0))
+        }
+    };
+    // only the chunk with the change to `cid` of `pk` makes it to ta2, the
+    // version stays buffered
+    let chunk_of =
+        |(change, source, instant): (ChangeV1, ChangeSource, Instant), pk: &[u8], cid: &str| {
+            let ChangeV1 {
+                actor_id,
+                changeset:
+                    Changeset::Full {
+                        version,
+                        changes,
+                        last_seq,
+                        ts,
+                        ..
+                    },
+            } = change
+            else {
+                panic!("not a full changeset");
+            };
+            let change = changes
+                .into_iter()
+                .find(|change| change.pk == pk && change.cid.as_str() == cid)
+                .unwrap();
+            assert!(last_seq > CrsqlSeq(0));
+            (
+                ChangeV1 {
+                    actor_id,
+                    changeset: Changeset::Full {
+                        version,
+                        seqs: CrsqlSeqRange::single(change.seq),
+                        changes: vec![change],
+                        last_seq,
+                        ts,
+                    },
+                },
+                source,
+                instant,
+            )
+        };
+    let set_text = |text: &str| {
+        Statement::WithParams(
+            "UPDATE tests3 SET text = ?, text2 = ? WHERE id = 1".into(),
+            vec![text.into(), text.into()],
+        )
+    };
+    let v1 = write(vec![Statement::WithParams(
+        "INSERT INTO tests3 (id, text, text2, num, num2) VALUES (1, ?, 'a', 1, 1)".into(),
+        vec!["one".into()],
+    )])
+    .await?;
+    let v2 = write(vec![set_text("two")]).await?;
+    let v3 = write(vec![set_text("three")]).await?;
+    let v4 = write(vec![
+        Statement::Simple("DELETE FROM tests3 WHERE id = 1".into()),
+        Statement::Simple("INSERT INTO tests3 (id, text) VALUES (2, 'other')".into()),
+    ])
+    .await?;
+
+    let versions: Vec<CrsqlDbVersion> = [&v1, &v2, &v3, &v4]
+        .iter()
+        .map(|(change, _, _)| change.changeset.versions().start())
+        .collect();
+    let pk = v1.0.changeset.changes()[0].pk.clone();
+
+    // versions 1, 2 and 4 are partially received, 3 is applied
+    let rows = vec![
+        chunk_of(v1, &pk, "text"),
+        chunk_of(v2, &pk, "text"),
+        v3,
+        chunk_of(v4, &pk, "-1"),
+    ];
+    process_multiple_changes(ta2.agent.clone(), ta2.bookie.clone(), rows, tx_timeout).await?;
+
+    let conn = ta2.agent.pool().read().await?;
+    let value_at = |version: CrsqlDbVersion| {
+        cell_value_at(
+            &conn,
+            ta1.agent.actor_id(),
+            &TableName::from("tests3"),
+            &pk,
+            &ColumnName::from("text"),
+            version,
+        )
+    };
+    assert_eq!(value_at(CrsqlDbVersion(0))?, None);
+    assert_eq!(
+        value_at(versions[0])?,
+        Some(SqliteValue::Text("one".into()))
+    );
+    assert_eq!(
+        value_at(versions[1])?,
+        Some(SqliteValue::Text("two".into()))
+    );
+    assert_eq!(
+        value_at(versions[2])?,
+        Some(SqliteValue::Text("three".into()))
+    );
+    assert_eq!(value_at(versions[3])?, None);
+    drop(conn);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_read_does_not_block_write() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+    // reads need at least one connection
+    let res = launch_test_agent(
+        |conf| {
+            conf.perf(PerfConfig {
+                read_pool_size: 0,
+                ..Default::default()
+            })
+            .build()
+        },
+        tripwire.clone(),
+    )
+    .await;
+    assert!(res.is_err());
+
+    let ta = launch_test_agent(
+        |conf| {
+            conf.perf(PerfConfig {
+                read_pool_size: 2,
+                ..Default::default()
+            })
+            .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    insert_rows(ta.agent.clone(), 1, 1).await;
+
+    // a long read: holds its snapshot until committed
+    let conn = ta.agent.pool().read().await?;
+    conn.execute_batch("BEGIN")?;
+    let count = |conn: &rusqlite::Connection| -> rusqlite::Result<i64> {
+        conn.query_row("SELECT COUNT(*) FROM tests3", [], |row| row.get(0))
+    };
+    assert_eq!(count(&conn)?, 1);
+
+    timeout(Duration::from_secs(5), insert_rows(ta.agent.clone(), 2, 2))
+        .await
+        .expect("write was blocked by a read");
+
+    // still reading from the same snapshot
+    assert_eq!(count(&conn)?, 1);
+    conn.execute_batch("COMMIT")?;
+    assert_eq!(count(&conn)?, 2);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_apply_shuffled_chunks() -> eyre::Result<()> {
+    use rand::seq::SliceRandom;
+
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta3 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta4 = launch_test_agent(
+        |conf| {
+            conf.perf(PerfConfig {
+                max_partial_versions: 1,
+                ..Default::default()
+            })
+            .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+    let tx_timeout = Duration::from_secs(60);
+
+    // two versions of 20 rows each
+    for version in 0..2 {
+        let (status_code, _) = api_v1_transactions(
+            Extension(ta1.agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(
+                (1..=20)
+                    .map(|i: i64| {
+                        let id = version * 20 + i;
+                        Statement::WithParams(
+                            "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                            vec![id.into(), format!("row {id}").into()],
+                        )
+                    })
+                    .collect(),
+            ),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+    }
+
+    let mut chunked = vec![];
+    for (change, _, _) in get_rows(ta1.agent.clone(), vec![(dbvri!(1, 2), None)]).await? {
+        let Changeset::Full {
+            version,
+            mut changes,
+            last_seq,
+            ts,
+            ..
+        } = change.changeset
+        else {
+            panic!("expected a full changeset");
+        };
+        changes.sort_by_key(|change| change.seq);
+
+        let chunks: Vec<(ChangeV1, ChangeSource, Instant)> = changes
+            .chunks(3)
+            .map(|chunk| {
+                (
+                    ChangeV1 {
+                        actor_id: change.actor_id,
+                        changeset: Changeset::Full {
+                            version,
+                            seqs: CrsqlSeqRange::new(chunk[0].seq, chunk[chunk.len() - 1].seq),
+                            changes: chunk.to_vec(),
+                            last_seq,
+                            ts,
+                        },
+                    },
+                    ChangeSource::Broadcast,
+                    Instant::now(),
+                )
+            })
+            .collect();
+        assert!(chunks.len() > 2);
+        chunked.push(chunks);
+    }
+    let in_order = chunked[0].clone();
+    let mut shuffled = in_order.clone();
+    shuffled.shuffle(&mut StdRng::seed_from_u64(7));
+    assert_ne!(
+        shuffled
+            .iter()
+            .map(|(c, _, _)| c.seqs())
+            .collect::<Vec<_>>(),
+        in_order
+            .iter()
+            .map(|(c, _, _)| c.seqs())
+            .collect::<Vec<_>>()
+    );
+
+    for (ta, chunks) in [(&ta2, shuffled), (&ta3, in_order)] {
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            process_multiple_changes(
+                ta.agent.clone(),
+                ta.bookie.clone(),
+                vec![chunk.clone()],
+                tx_timeout,
+            )
+            .await?;
+        }
+
+        // held until the version is complete
+        let count: i64 =
+            ta.agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?;
+        assert_eq!(count, 0);
+
+        process_multiple_changes(
+            ta.agent.clone(),
+            ta.bookie.clone(),
+            vec![last.clone()],
+            tx_timeout,
+        )
+        .await?;
+    }
+
+    let read_tests = |agent: Agent| async move {
+        let conn = agent.pool().read().await?;
+        let mut prepped = conn.prepare("SELECT id, text FROM tests ORDER BY id")?;
+        let rows = prepped
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()?;
+        Ok::<_, eyre::Report>(rows)
+    };
+
+    // completed versions are applied in the background
+    let expected: Vec<(i64, String)> = (1..=20).map(|id| (id, format!("row {id}"))).collect();
+    for ta in [&ta2, &ta3] {
+        timeout(Duration::from_secs(5), async {
+            while read_tests(ta.agent.clone()).await.unwrap() != expected {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+    }
+
+    let changes = |agent: Agent| async move {
+        let conn = agent.pool().read().await?;
+        let mut prepped = conn.prepare(
+            r#"SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl
+                FROM crsql_changes ORDER BY "table", pk, cid"#,
+        )?;
+        let changes = prepped
+            .query_map([], row_to_change)?
+            .collect::<rusqlite::Result<Vec<Change>>>()?;
+        Ok::<_, eyre::Report>(
+            changes
+                .into_iter()
+                .map(|change| (change.table, change.pk, change.cid, change.val))
+                .collect::<Vec<_>>(),
+        )
+    };
+    assert_eq!(
+        changes(ta2.agent.clone()).await?,
+        changes(ta3.agent.clone()).await?
+    );
+
+    // a second incomplete version doesn't fit, it's left to sync
+    let actor_id = ta1.agent.actor_id();
+    for chunks in &chunked {
+        process_multiple_changes(
+            ta4.agent.clone(),
+            ta4.bookie.clone(),
+            vec![chunks[0].clone()],
+            tx_timeout,
+        )
+        .await?;
+    }
+    {
+        let booked = ta4
+            .bookie
+            .write::<&str, _>("test", None)
+            .await
+            .ensure(actor_id);
+        let read = booked.read::<&str, _>("test", None).await;
+        assert!(read.get_partial(&CrsqlDbVersion(1)).is_some());
+        assert!(read.get_partial(&CrsqlDbVersion(2)).is_none());
+    }
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_quarantine_until_schema_change() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    // from a node already running with the new column
+    let actor_id = ActorId(Uuid::new_v4());
+    let changes = vec![
+        Change {
+            table: TableName("tests".into()),
+            pk: pack_columns(&[1i64.into()])?,
+            cid: ColumnName("text".into()),
+            val: "one".into(),
+            col_version: 1,
+            db_version: CrsqlDbVersion(1),
+            seq: CrsqlSeq(0),
+            site_id: actor_id.to_bytes(),
+            cl: 1,
+        },
+        Change {
+            table: TableName("tests".into()),
+            pk: pack_columns(&[1i64.into()])?,
+            cid: ColumnName("extra".into()),
+            val: "uno".into(),
+            col_version: 1,
+            db_version: CrsqlDbVersion(1),
+            seq: CrsqlSeq(1),
+            site_id: actor_id.to_bytes(),
+            cl: 1,
+        },
+    ];
+    let rows = vec![(
+        ChangeV1 {
+            actor_id,
+            changeset: Changeset::Full {
+                version: CrsqlDbVersion(1),
+                changes,
+                seqs: dbsr!(0, 1),
+                last_seq: CrsqlSeq(1),
+                ts: ta1.agent.clock().new_timestamp().into(),
+            },
+        },
+        ChangeSource::Broadcast,
+        Instant::now(),
+    )];
+
+    process_multiple_changes(
+        ta1.agent.clone(),
+        ta1.bookie.clone(),
+        rows,
+        Duration::from_secs(60),
+    )
+    .await?;
+
+    // parked, not applied nor booked
+    assert_eq!(ta1.agent.quarantine().len(), 1);
+    let booked = ta1
+        .bookie
+        .write::<&str, _>("test", None)
+        .await
+        .ensure(actor_id);
+    assert_eq!(booked.read::<&str, _>("test", None).await.last(), None);
+    let count: i64 =
+        ta1.agent
+            .pool()
+            .read()
+            .await?
+            .query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?;
+    assert_eq!(count, 0);
+
+    let (status_code, _body) = api_v1_db_schema(
+        Extension(ta1.agent.clone()),
+        axum::Json(vec!["CREATE TABLE tests (
+                id INTEGER NOT NULL PRIMARY KEY,
+                text TEXT NOT NULL DEFAULT \"\",
+                extra TEXT NOT NULL DEFAULT \"\"
+            ) WITHOUT ROWID;"
+            .into()]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+
+    let row = timeout(Duration::from_secs(5), async {
+        loop {
+            let row = ta1
+                .agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT text, extra FROM tests WHERE id = 1", [], |row| {
+                    Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+                })
+                .optional()?;
+            if let Some(row) = row {
+                return Ok::<_, eyre::Report>(row);
+            }
+            sleep(Duration::from_millis(50)).await;
+        }
+    })
+    .await??;
+    assert_eq!(row, ("one".to_string(), "uno".to_string()));
+    assert!(ta1.agent.quarantine().is_empty());
+    assert_eq!(
+        booked.read::<&str, _>("test", None).await.last(),
+        Some(CrsqlDbVersion(1))
+    );
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_change_log_roundtrip() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    insert_rows(ta1.agent.clone(), 1, 20).await;
+    // overwritten rows leave seq gaps in older versions
+    insert_rows(ta1.agent.clone(), 3, 6).await;
+    let (status_code, _) = api_v1_transactions(
+        Extension(ta1.agent.clone()),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::Json(vec![Statement::Simple(
+            "DELETE FROM tests3 WHERE id = 10".into(),
+        )]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+
+    let last_version = ta1
+        .bookie
+        .write::<&str, _>("test", None)
+        .await
+        .ensure(ta1.agent.actor_id())
+        .read::<&str, _>("test", None)
+        .await
+        .last();
+    assert!(last_version.is_some());
+
+    let path = ta1.tmpdir.path().join("export.log");
+
+    let res = export_change_log(
+        &ta1.agent,
+        Some(vec![TableName::from("nope")]),
+        &path,
+        false,
+    )
+    .await;
+    assert!(matches!(res, Err(SnapshotError::UnknownTable(_))));
+
+    let exported = export_change_log(&ta1.agent, None, &path, true).await?;
+    assert!(exported.versions > 0);
+
+    let imported =
+        import_change_log(&ta2.agent, &ta2.bookie, &path, ImportOptions::default()).await?;
+    assert_eq!(imported, exported);
+
+    let rows = |agent: Agent| async move {
+        let conn = agent.pool().read().await?;
+        let rows = conn
+            .prepare("SELECT id, text, text2, num, num2 FROM tests3 ORDER BY id")?
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, i64>(3)?,
+                    row.get::<_, i64>(4)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok::<_, eyre::Report>(rows)
+    };
+    let expected = rows(ta1.agent.clone()).await?;
+    assert_eq!(expected.len(), 19);
+    assert_eq!(rows(ta2.agent.clone()).await?, expected);
+
+    // booked like synced versions, nothing left to sync
+    let booked = ta2
+        .bookie
+        .write::<&str, _>("test", None)
+        .await
+        .ensure(ta1.agent.actor_id());
+    let booked = booked.read::<&str, _>("test", None).await;
+    assert_eq!(booked.last(), last_version);
+    assert!(booked.needed().is_empty());
+
+    // a table export keeps the version's last seq, not the last one of the
+    // exported table
+    let (status_code, body) = api_v1_transactions(
+        Extension(ta1.agent.clone()),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::Json(vec![
+            Statement::Simple("INSERT INTO tests (id, text) VALUES (100, 'first')".into()),
+            Statement::Simple(
+                "INSERT INTO tests3 (id, text, text2, num, num2) VALUES (100, 'a', 'b', 1, 2)"
+                    .into(),
+            ),
+        ]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+    let version = CrsqlDbVersion(body.0.version.unwrap());
+
+    let last_seq: CrsqlSeq = ta1.agent.pool().read().await?.query_row(
+        "SELECT MAX(seq) FROM crsql_changes WHERE db_version = ?",
+        [version],
+        |row| row.get(0),
+    )?;
+
+    let table_path = ta1.tmpdir.path().join("tests.log");
+    export_change_log(
+        &ta1.agent,
+        Some(vec![TableName::from("tests")]),
+        &table_path,
+        false,
+    )
+    .await?;
+    let exported = ChangeLogReader::new(std::fs::File::open(&table_path)?)?
+        .map(|change| change.map(|change| change.changeset))
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .find(|changeset| changeset.versions().contains(version))
+        .unwrap();
+    assert!(exported
+        .changes()
+        .iter()
+        .all(|change| change.seq < last_seq));
+    assert_eq!(exported.last_seq(), Some(last_seq));
+    assert_eq!(
+        exported.seqs(),
+        Some(CrsqlSeqRange::new(CrsqlSeq(0), last_seq))
+    );
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_change_observers_after_sync() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    for i in 1..=3i64 {
+        let (status_code, _) = api_v1_transactions(
+            Extension(ta1.agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                vec![i.into(), format!("row {i}").into()],
+            )]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+    }
+
+    let observed = Arc::new(parking_lot::Mutex::new(vec![]));
+    let failing: Arc<dyn ChangeObserver> = Arc::new(|_: &[Change]| panic!("observer failure"));
+    let collecting: Arc<dyn ChangeObserver> = Arc::new({
+        let observed = observed.clone();
+        move |changes: &[Change]| observed.lock().extend_from_slice(changes)
+    });
+
+    let tmpdir = tempfile::tempdir()?;
+    let schema_path = tmpdir.path().join("schema");
+    tokio::fs::create_dir(&schema_path).await?;
+    tokio::fs::write(schema_path.join("tests.sql"), TEST_SCHEMA.as_bytes()).await?;
+    let conf = Config::builder()
+        .api_addr("127.0.0.1:0".parse()?)
+        .gossip_addr("127.0.0.1:0".parse()?)
+        .admin_path(tmpdir.path().join("admin.sock").display().to_string())
+        .db_path(tmpdir.path().join("corrosion.db").display().to_string())
+        .add_schema_path(schema_path.display().to_string())
+        .build()?;
+    let (agent, bookie, transport, _) = start_with_observers(
+        conf,
+        ChangeObservers::new(vec![failing, collecting]),
+        tripwire.clone(),
+    )
+    .await?;
+
+    let members = vec![(ta1.agent.actor_id(), ta1.agent.gossip_addr())];
+    let sync_state = generate_sync(&bookie, agent.actor_id()).await;
+    parallel_sync(&agent, &transport, members, sync_state).await?;
+
+    // observers run after the apply commits, the panicking one included
+    timeout(Duration::from_secs(5), async {
+        while observed.lock().len() < 3 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+
+    let observed = observed.lock().clone();
+    assert!(observed
+        .iter()
+        .all(|change| change.table.as_str() == "tests"
+            && change.site_id == ta1.agent.actor_id().to_bytes()));
+    let mut texts: Vec<_> = observed.iter().map(|change| change.val.clone()).collect();
+    texts.sort_by_key(|val| format!("{val:?}"));
+    assert_eq!(
+        texts,
+        (1..=3)
+            .map(|i| format!("row {i}").as_str().into())
+            .collect::<Vec<_>>()
+    );
+
+    // committed before the observers were called
+    let conn = agent.pool().read().await?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?;
+    assert_eq!(count, 3);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_change_observers_after_buffered_version() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    // a single version of 2 changes
+    let (status_code, _) = api_v1_transactions(
+        Extension(ta1.agent.clone()),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::Json(vec![
+            Statement::WithParams(
+                "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                vec![1i64.into(), "one".into()],
+            ),
+            Statement::WithParams(
+                "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                vec![2i64.into(), "two".into()],
+            ),
+        ]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+
+    let observed = Arc::new(parking_lot::Mutex::new(vec![]));
+    let collecting: Arc<dyn ChangeObserver> = Arc::new({
+        let observed = observed.clone();
+        move |changes: &[Change]| observed.lock().extend_from_slice(changes)
+    });
+
+    let tmpdir = tempfile::tempdir()?;
+    let schema_path = tmpdir.path().join("schema");
+    tokio::fs::create_dir(&schema_path).await?;
+    tokio::fs::write(schema_path.join("tests.sql"), TEST_SCHEMA.as_bytes()).await?;
+    let conf = Config::builder()
+        .api_addr("127.0.0.1:0".parse()?)
+        .gossip_addr("127.0.0.1:0".parse()?)
+        .admin_path(tmpdir.path().join("admin.sock").display().to_string())
+        .db_path(tmpdir.path().join("corrosion.db").display().to_string())
+        .add_schema_path(schema_path.display().to_string())
+        .build()?;
+    let (agent, bookie, _, _) = start_with_observers(
+        conf,
+        ChangeObservers::new(vec![collecting]),
+        tripwire.clone(),
+    )
+    .await?;
+
+    // one seq at a time, buffered until the version is complete
+    let tx_timeout = Duration::from_secs(60);
+    for seqs in [CrsqlSeq(0)..=CrsqlSeq(0), CrsqlSeq(1)..=CrsqlSeq(1)] {
+        let rows = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 1), Some(seqs))]).await?;
+        process_multiple_changes(agent.clone(), bookie.clone(), rows, tx_timeout).await?;
+    }
+
+    timeout(Duration::from_secs(5), async {
+        while observed.lock().len() < 2 {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+
+    let mut texts: Vec<_> = observed
+        .lock()
+        .iter()
+        .map(|change| change.val.clone())
+        .collect();
+    texts.sort_by_key(|val| format!("{val:?}"));
+    assert_eq!(texts, vec!["one".into(), "two".into()]);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failing_chunk_quarantined() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta = launch_test_agent(
+        |conf| {
+            conf.perf(PerfConfig {
+                max_chunk_attempts: 3,
+                ..Default::default()
+            })
+            .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+    let tx_timeout = Duration::from_secs(60);
+
+    // every apply of a row into `tests` fails
+    ta.agent.pool().write_priority().await?.execute_batch(
+        "CREATE TRIGGER fail_apply BEFORE INSERT ON tests BEGIN SELECT RAISE(ABORT, 'forced failure'); END;",
+    )?;
+
+    let actor_id = ActorId(Uuid::new_v4());
+    let change = ChangeV1 {
+        actor_id,
+        changeset: Changeset::Full {
+            version: CrsqlDbVersion(1),
+            changes: vec![Change {
+                table: TableName::from("tests"),
+                pk: pack_columns(&[1i64.into()])?,
+                cid: ColumnName::from("text"),
+                val: "hello".into(),
+                col_version: 1,
+                db_version: CrsqlDbVersion(1),
+                seq: CrsqlSeq(0),
+                site_id: actor_id.to_bytes(),
+                cl: 1,
+            }],
+            seqs: dbsr!(0, 0),
+            last_seq: CrsqlSeq(0),
+            ts: ta.agent.clock().new_timestamp().into(),
+        },
+    };
+    let key = (actor_id, change.versions(), change.seqs());
+
+    for attempt in 1..=3 {
+        assert!(!ta.agent.chunk_retries().is_quarantined(&key));
+        process_multiple_changes(
+            ta.agent.clone(),
+            ta.bookie.clone(),
+            vec![(change.clone(), ChangeSource::Sync, Instant::now())],
+            tx_timeout,
+        )
+        .await?;
+        if attempt < 3 {
+            assert_eq!(ta.agent.chunk_retries().failures(&key), attempt);
+        }
+    }
+    assert!(ta.agent.chunk_retries().is_quarantined(&key));
+
+    let quarantined = {
+        let conn = ta.agent.pool().read().await?;
+        quarantined_chunks(&conn)?
+    };
+    assert_eq!(quarantined.len(), 1);
+    assert_eq!(quarantined[0].key(), key);
+    assert_eq!(quarantined[0].attempts, 3);
+    assert!(quarantined[0].error.contains("forced failure"));
+
+    // skipped from now on, without counting more attempts
+    process_multiple_changes(
+        ta.agent.clone(),
+        ta.bookie.clone(),
+        vec![(change.clone(), ChangeSource::Sync, Instant::now())],
+        tx_timeout,
+    )
+    .await?;
+    assert_eq!(ta.agent.chunk_retries().failures(&key), 0);
+
+    // requeued once the trigger is gone
+    ta.agent
+        .pool()
+        .write_priority()
+        .await?
+        .execute_batch("DROP TRIGGER fail_apply;")?;
+    let requeued = {
+        let conn = ta.agent.pool().write_priority().await?;
+        take_quarantined_chunk(&conn, quarantined[0].id)?
+    };
+    assert_eq!(requeued.as_ref(), Some(&change));
+    ta.agent.chunk_retries().release(&key);
+
+    process_multiple_changes(
+        ta.agent.clone(),
+        ta.bookie.clone(),
+        vec![(change, ChangeSource::Sync, Instant::now())],
+        tx_timeout,
+    )
+    .await?;
+    let conn = ta.agent.pool().read().await?;
+    let text: String =
+        conn.query_row("SELECT text FROM tests WHERE id = 1", [], |row| row.get(0))?;
+    assert_eq!(text, "hello");
+    assert!(quarantined_chunks(&conn)?.is_empty());
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_concurrent_syncs_capped() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta = launch_test_agent(
+        |conf| {
+            conf.perf(PerfConfig {
+                max_inbound_syncs: 1,
+                max_outbound_syncs: 2,
+                ..Default::default()
+            })
+            .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+
+    let mut peers = vec![];
+    for i in 1..=4 {
+        let peer = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        insert_rows(peer.agent.clone(), i * 10, i * 10 + 4).await;
+        peers.push(peer);
+    }
+    let members: Vec<_> = peers
+        .iter()
+        .map(|peer| (peer.agent.actor_id(), peer.agent.gossip_addr()))
+        .collect();
+
+    let limiter = ta.agent.limits().outbound_sync.clone();
+    let peak = Arc::new(AtomicUsize::new(0));
+    let watcher = tokio::spawn({
+        let limiter = limiter.clone();
+        let peak = peak.clone();
+        async move {
+            loop {
+                peak.fetch_max(limiter.active(), Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+    });
+
+    // 3 syncs with 4 members each, 12 sessions for 2 permits
+    let syncs = (0..3).map(|_| async {
+        let sync_state = generate_sync(&ta.bookie, ta.agent.actor_id()).await;
+        parallel_sync(&ta.agent, &ta.transport, members.clone(), sync_state).await
+    });
+    for res in future::join_all(syncs).await {
+        res?;
+    }
+    watcher.abort();
+
+    let peak = peak.load(Ordering::SeqCst);
+    assert!((1..=2).contains(&peak), "peak of {peak} concurrent syncs");
+    assert_eq!(limiter.active(), 0);
+
+    // inbound syncs are rejected while every inbound permit is taken,
+    // whatever outbound syncs are running
+    let held = ta.agent.limits().sync.acquire().await;
+    let _outbound = [limiter.acquire().await, limiter.acquire().await];
+    let res = parallel_sync(
+        &peers[0].agent,
+        &peers[0].transport,
+        vec![(ta.agent.actor_id(), ta.agent.gossip_addr())],
+        generate_sync(&peers[0].bookie, peers[0].agent.actor_id()).await,
+    )
+    .await;
+    assert!(res.is_err());
+    drop(held);
+
+    parallel_sync(
+        &peers[0].agent,
+        &peers[0].transport,
+        vec![(ta.agent.actor_id(), ta.agent.gossip_addr())],
+        generate_sync(&peers[0].bookie, peers[0].agent.actor_id()).await,
+    )
+    .await?;
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_anti_entropy_repairs_dropped_range() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta1_actor_id = ta1.agent.actor_id();
+    let candidates = vec![(ta1_actor_id, ta1.agent.gossip_addr())];
+
+    insert_rows(ta1.agent.clone(), 1, 5).await;
+
+    // drift: 2..=3 booked by ta2 without their changes
+    process_multiple_changes(
+        ta2.agent.clone(),
+        ta2.bookie.clone(),
+        vec![(
+            ChangeV1 {
+                actor_id: ta1_actor_id,
+                changeset: Changeset::Empty {
+                    versions: CrsqlDbVersionRange::new(CrsqlDbVersion(2), CrsqlDbVersion(3)),
+                    ts: None,
+                },
+            },
+            ChangeSource::Sync,
+            Instant::now(),
+        )],
+        Duration::from_secs(60),
+    )
+    .await?;
+
+    // regular syncs can't tell
+    let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+    parallel_sync(&ta2.agent, &ta2.transport, candidates.clone(), sync_state).await?;
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+            if state.heads.get(&ta1_actor_id) == Some(&CrsqlDbVersion(5)) && state.need.is_empty() {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    let count = |agent: Agent| async move {
+        let conn = agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests3", [], |row| row.get(0))?;
+        Ok::<_, eyre::Report>(count)
+    };
+    assert_eq!(count(ta2.agent.clone()).await?, 3);
+
+    let repaired =
+        anti_entropy_pass(&ta2.agent, &ta2.bookie, &ta2.transport, candidates.clone()).await?;
+    assert_eq!(repaired, 1);
+
+    let state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+    assert!(state.need.is_empty());
+    assert_eq!(state.heads.get(&ta1_actor_id), Some(&CrsqlDbVersion(5)));
+    assert_eq!(count(ta2.agent.clone()).await?, 5);
+
+    // nothing to repair anymore
+    assert_eq!(
+        anti_entropy_pass(&ta2.agent, &ta2.bookie, &ta2.transport, candidates.clone()).await?,
+        0
+    );
+
+    // version 6 overwrites version 1's cells on ta1 only: its digests differ
+    // without anything to repair, they're not compared
+    insert_rows(ta1.agent.clone(), 1, 1).await;
+    assert_eq!(
+        anti_entropy_pass(&ta2.agent, &ta2.bookie, &ta2.transport, candidates.clone()).await?,
+        0
+    );
+    let state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+    assert!(state.need.is_empty());
+    assert_eq!(state.heads.get(&ta1_actor_id), Some(&CrsqlDbVersion(5)));
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_actor_id_collision_detected() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+    // two nodes configured with the same name, e.g. a copy-pasted config
+    let ta1 = launch_test_agent(|conf| conf.node_name("twin").build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.node_name("twin").build(), tripwire.clone()).await?;
+    let actor_id = ta1.agent.actor_id();
+    assert_eq!(actor_id, ActorId::from_node_name("twin"));
+    assert_eq!(ta2.agent.actor_id(), actor_id);
+    assert_ne!(ActorId::from_node_name("other"), actor_id);
+
+    let (status_code, body) = api_v1_transactions(
+        Extension(ta1.agent.clone()),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::Json(vec![Statement::WithParams(
+            "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+            vec![1i64.into(), "one".into()],
+        )]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+    let version = CrsqlDbVersion(body.0.version.unwrap());
+
+    // populated by the spawned broadcast
+    let cached = timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(cached) = ta1.agent.recent_changes().get(actor_id, version) {
+                return cached;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+
+    // ta1's own change reaching ta2, which never wrote it
+    ta2.agent
+        .tx_changes()
+        .send((
+            ChangeV1 {
+                actor_id,
+                changeset: Changeset::Full {
+                    version,
+                    changes: cached.changes,
+                    seqs: CrsqlSeqRange::new(CrsqlSeq(0), cached.last_seq),
+                    last_seq: cached.last_seq,
+                    ts: cached.ts,
+                },
+            },
+            ChangeSource::Broadcast,
+        ))
+        .await?;
+
+    timeout(Duration::from_secs(5), async {
+        while !ta2.agent.actor_collision() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+
+    // a version we did write is fine
+    assert!(ta1.agent.check_own_version(version, "test").await);
+    assert!(!ta1.agent.actor_collision());
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_strict_seqs_reject_holes() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let strict = launch_test_agent(
+        |conf| conf.seq_mode(SeqMode::Strict).build(),
+        tripwire.clone(),
+    )
+    .await?;
+    let lenient = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let actor_id = ta1.agent.actor_id();
+    let tx_timeout = Duration::from_secs(60);
+
+    // a single version of seqs 0 to 3, minus seq 1
+    insert_rows(ta1.agent.clone(), 1, 1).await;
+    let mut rows = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 1), None)]).await?;
+    if let Changeset::Full { changes, .. } = &mut rows[0].0.changeset {
+        assert_eq!(changes.len(), 4);
+        changes.retain(|change| change.seq != CrsqlSeq(1));
+    }
+    // synced changes are checked as they're received
+    rows[0].1 = ChangeSource::Broadcast;
+
+    match crate::agent::util::check_seqs(&rows[0].0, &[]) {
+        Err(ChangeError::MissingSeqs {
+            actor_id: missing_actor_id,
+            version,
+            missing,
+        }) => {
+            assert_eq!(missing_actor_id, actor_id);
+            assert_eq!(version, CrsqlDbVersion(1));
+            assert_eq!(missing, vec![CrsqlSeq(1)..=CrsqlSeq(1)]);
+        }
+        res => panic!("expected missing seqs, got {res:?}"),
+    }
+    // unless the sender marked it cleared
+    crate::agent::util::check_seqs(&rows[0].0, &[CrsqlSeq(1)..=CrsqlSeq(1)])?;
+
+    for ta in [&strict, &lenient] {
+        process_multiple_changes(
+            ta.agent.clone(),
+            ta.bookie.clone(),
+            rows.clone(),
+            tx_timeout,
+        )
+        .await?;
+    }
+
+    let applied = |ta: &TestAgent| {
+        let ta = ta.clone();
+        async move {
+            let booked = ta
+                .bookie
+                .write::<&str, _>("test", None)
+                .await
+                .ensure(actor_id);
+            let booked = booked.read::<&str, _>("test", None).await;
+            booked.contains_all(dbvri!(1, 1), Some(dbsr!(0, 3)))
+        }
+    };
+    assert!(!applied(&strict).await);
+    assert!(applied(&lenient).await);
+
+    // the complete version goes through either way
+    let rows = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 1), None)]).await?;
+    crate::agent::util::check_seqs(&rows[0].0, &[])?;
+    process_multiple_changes(
+        strict.agent.clone(),
+        strict.bookie.clone(),
+        rows,
+        tx_timeout,
+    )
+    .await?;
+    assert!(applied(&strict).await);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_pause_resume_replication() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| {
+            conf.perf(PerfConfig {
+                processing_queue_len: 5,
+                ..Default::default()
+            })
+            .build()
+        },
+        tripwire.clone(),
+    )
+    .await?;
+    let actor_id = ta1.agent.actor_id();
+
+    let count = |ta: &TestAgent| {
+        let agent = ta.agent.clone();
+        async move {
+            agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT COUNT(*) FROM tests3", [], |row| {
+                    row.get::<_, i64>(0)
+                })
+                .map_err(eyre::Report::from)
+        }
+    };
+
+    ta2.agent.pause_replication(ReplicationDirection::Inbound);
+    let health = crate::api::public::health::health(&ta2.agent, &ta2.bookie).await;
+    assert!(health.inbound_paused);
+    assert!(!health.outbound_paused);
+
+    // 10 versions reach ta2 while it's paused, only 5 fit in its queue
+    insert_rows(ta1.agent.clone(), 1, 10).await;
+    let rows = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 10), None)]).await?;
+    for (change, src, _) in rows {
+        ta2.agent.tx_changes().send((change, src)).await?;
+    }
+
+    sleep(Duration::from_millis(500)).await;
+    assert_eq!(count(&ta2).await?, 0);
+
+    let dropped = ta2.agent.resume_replication();
+    assert_eq!(
+        dropped
+            .get(&actor_id)
+            .map(|versions| versions.iter().cloned().collect::<Vec<_>>()),
+        Some(vec![dbvri!(1, 5)])
+    );
+    let health = crate::api::public::health::health(&ta2.agent, &ta2.bookie).await;
+    assert!(!health.inbound_paused);
+
+    // queued versions are applied on resume
+    timeout(Duration::from_secs(5), async {
+        while count(&ta2).await? != 5 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        Ok::<_, eyre::Report>(())
+    })
+    .await??;
+
+    // and dropped ones synced
+    let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+    parallel_sync(
+        &ta2.agent,
+        &ta2.transport,
+        vec![(actor_id, ta1.agent.gossip_addr())],
+        sync_state,
+    )
+    .await?;
+    timeout(Duration::from_secs(5), async {
+        while count(&ta2).await? != 10 {
+            sleep(Duration::from_millis(10)).await;
+        }
+        Ok::<_, eyre::Report>(())
+    })
+    .await??;
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_fabricated_timestamps_reported() -> eyre::Result<()> {
+    use corro_types::{broadcast::Timestamp, change::insert_local_changes};
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+    use tokio::task::block_in_place;
+
+    struct Recording(Arc<parking_lot::Mutex<Vec<CrsqlDbVersion>>>);
+
+    impl ChangeObserver for Recording {
+        fn on_applied(&self, _changes: &[Change]) {}
+
+        fn on_fabricated_timestamp(&self, db_version: CrsqlDbVersion) {
+            self.0.lock().push(db_version);
+        }
+    }
+
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+
+    let fabricated = Arc::new(parking_lot::Mutex::new(vec![]));
+    let failing: Arc<dyn ChangeObserver> = Arc::new(|_: &[Change]| panic!("observer failure"));
+
+    let tmpdir = tempfile::tempdir()?;
+    let schema_path = tmpdir.path().join("schema");
+    tokio::fs::create_dir(&schema_path).await?;
+    tokio::fs::write(schema_path.join("tests.sql"), TEST_SCHEMA.as_bytes()).await?;
+    let conf = Config::builder()
+        .api_addr("127.0.0.1:0".parse()?)
+        .gossip_addr("127.0.0.1:0".parse()?)
+        .admin_path(tmpdir.path().join("admin.sock").display().to_string())
+        .db_path(tmpdir.path().join("corrosion.db").display().to_string())
+        .add_schema_path(schema_path.display().to_string())
+        .build()?;
+    let (agent, _, _, _) = start_with_observers(
+        conf,
+        ChangeObservers::new(vec![failing, Arc::new(Recording(fabricated.clone()))]),
+        tripwire.clone(),
+    )
+    .await?;
+
+    let recorder = DebuggingRecorder::new();
+    let snapshotter = recorder.snapshotter();
+
+    let before = Timestamp::from(agent.clock().new_timestamp());
+
+    // a local write whose clock rows have no ts, as left by versions
+    // written before ts were recorded
+    let mut conn = agent.pool().write_priority().await?;
+    let mut book_writer = agent
+        .booked()
+        .write::<&str, _>("test_fabricated_timestamps_reported", None)
+        .await;
+    let info = block_in_place(|| {
+        let tx = conn.transaction()?;
+        tx.execute("INSERT INTO tests (id, text) VALUES (1, 'hello')", [])?;
+        tx.execute("UPDATE tests__crsql_clock SET ts = NULL", [])?;
+        let info = metrics::with_local_recorder(&recorder, || {
+            insert_local_changes(&agent, &tx, &mut book_writer)
+        })?
+        .expect("version was not found");
+        tx.commit()?;
+        Ok::<_, eyre::Report>(info)
+    })?;
+    book_writer.commit_snapshot(info.snap);
+    drop(book_writer);
+    drop(conn);
+    assert!(info.ts > before);
+
+    // called from the observers' thread once the write is done
+    timeout(Duration::from_secs(5), async {
+        while fabricated.lock().is_empty() {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    assert_eq!(*fabricated.lock(), vec![info.db_version]);
+
+    let counted = snapshotter
+        .snapshot()
+        .into_vec()
+        .into_iter()
+        .find(|(key, _, _, _)| key.key().name() == "corro.fabricated.timestamps.total")
+        .map(|(_, _, _, value)| value);
+    assert_eq!(counted, Some(DebugValue::Counter(1)));
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_apply_change_log_resumable() -> eyre::Result<()> {
+    use tokio::task::block_in_place;
+
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+    insert_rows(ta1.agent.clone(), 1, 10).await;
+
+    let path = ta1.tmpdir.path().join("capture.log");
+    let exported = export_change_log(&ta1.agent, None, &path, true).await?;
+    assert_eq!(exported.versions, 10);
+
+    // an interrupted replay only got the first versions in
+    let partial = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 4), None)]).await?;
+    process_multiple_changes(
+        ta2.agent.clone(),
+        ta2.bookie.clone(),
+        partial,
+        Duration::from_secs(5),
+    )
+    .await?;
+
+    let opts = ImportOptions {
+        continue_on_error: true,
+        skip_applied: true,
+    };
+    let summary = import_change_log(&ta2.agent, &ta2.bookie, &path, opts).await?;
+    assert_eq!(summary.versions, 6);
+    assert_eq!(summary.skipped, 4);
+    assert!(summary.rejected.is_empty());
+
+    let count = |agent: Agent| async move {
+        let conn = agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests3", [], |row| row.get(0))?;
+        Ok::<_, eyre::Report>(count)
+    };
+    assert_eq!(count(ta2.agent.clone()).await?, 10);
+
+    let booked = ta2
+        .bookie
+        .write::<&str, _>("test", None)
+        .await
+        .ensure(ta1.agent.actor_id());
+    let booked = booked.read::<&str, _>("test", None).await;
+    assert_eq!(booked.last(), Some(CrsqlDbVersion(10)));
+    assert!(booked.needed().is_empty());
+
+    // replaying again is a no-op
+    let summary = import_change_log(&ta2.agent, &ta2.bookie, &path, opts).await?;
+    assert_eq!(summary.versions, 0);
+    assert_eq!(summary.skipped, 10);
+    assert_eq!(count(ta2.agent.clone()).await?, 10);
+
+    // a changeset failing to apply is rejected, the rest still applies
+    let tampered = ta1.tmpdir.path().join("tampered.log");
+    block_in_place(|| {
+        let reader = ChangeLogReader::new(std::fs::File::open(&path)?)?;
+        let header = reader.header().clone();
+        let mut writer = ChangeLogWriter::new(std::fs::File::create(&tampered)?, &header, false)?;
+        for change in reader {
+            let mut change = change?;
+            if let Changeset::Full {
+                version, changes, ..
+            } = &mut change.changeset
+            {
+                if *version == CrsqlDbVersion(5) {
+                    for change in changes
+                        .iter_mut()
+                        .filter(|change| change.cid.as_str() == "text")
+                    {
+                        // violates the column's NOT NULL
+                        change.val = SqliteValue::Null;
+                    }
+                }
+            }
+            writer.write(&change)?;
+        }
+        writer.finish()?.sync_all()?;
+        Ok::<_, eyre::Report>(())
+    })?;
+
+    let ta3 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let summary = import_change_log(&ta3.agent, &ta3.bookie, &tampered, opts).await?;
+    assert_eq!(summary.versions, 9);
+    assert_eq!(summary.rejected.len(), 1);
+    assert_eq!(summary.rejected[0].actor_id, ta1.agent.actor_id());
+    assert_eq!(
+        summary.rejected[0].versions,
+        CrsqlDbVersion(5)..=CrsqlDbVersion(5)
+    );
+    assert_eq!(count(ta3.agent.clone()).await?, 9);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn test_bulk_delete_converges() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let members = vec![(ta1.agent.actor_id(), ta1.agent.gossip_addr())];
+
+    let exec = |sql: &str| {
+        api_v1_transactions(
+            Extension(ta1.agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::Simple(sql.into())]),
+        )
+    };
+    let count = |agent: Agent| async move {
+        Ok::<i64, eyre::Report>(agent.pool().read().await?.query_row(
+            "SELECT COUNT(*) FROM tests",
+            (),
+            |row| row.get(0),
+        )?)
+    };
+    let (ta2, members) = (&ta2, &members);
+    let sync_until = |expected: i64| async move {
+        timeout(Duration::from_secs(30), async {
+            loop {
+                parallel_sync(
+                    &ta2.agent,
+                    &ta2.transport,
+                    members.clone(),
+                    generate_sync(&ta2.bookie, ta2.agent.actor_id()).await,
+                )
+                .await?;
+                if count(ta2.agent.clone()).await? == expected {
+                    return Ok::<_, eyre::Report>(());
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+        })
+        .await?
+    };
+
+    let (status_code, _) = exec(
+        "INSERT INTO tests (id, text) WITH RECURSIVE cte(id) AS (SELECT 1 UNION ALL SELECT id + 1 FROM cte LIMIT 1000) SELECT id, 'row ' || id FROM cte",
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+    sync_until(1000).await?;
+
+    let (status_code, _) = exec("DELETE FROM tests").await;
+    assert_eq!(status_code, StatusCode::OK);
+    assert_eq!(count(ta1.agent.clone()).await?, 0);
+
+    // the delete is only sentinels, in more than one chunk
+    let sentinels = {
+        let conn = ta1.agent.pool().read().await?;
+        let mut prepped = conn.prepare(
+            r#"SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl
+                FROM crsql_changes WHERE db_version = 2 ORDER BY seq ASC"#,
+        )?;
+        let changes = prepped
+            .query_map([], row_to_change)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        changes
+    };
+    assert_eq!(sentinels.len(), 1000);
+    assert!(sentinels
+        .iter()
+        .all(|change| change.cid.is_crsql_sentinel() && change.cl % 2 == 0));
+    let last_seq = sentinels.last().unwrap().seq;
+    let chunks = ChunkedChanges::new(
+        sentinels.into_iter().map(Ok),
+        CrsqlSeq(0),
+        last_seq,
+        MAX_CHANGES_BYTE_SIZE,
+    )
+    .collect::<rusqlite::Result<Vec<_>>>()?;
+    assert!(chunks.len() > 1, "only {} chunk(s)", chunks.len());
+
+    sync_until(0).await?;
+
+    // every row is left with a tombstone
+    let tombstones: i64 = ta2.agent.pool().read().await?.query_row(
+        "SELECT COUNT(*) FROM crsql_changes WHERE \"table\" = 'tests' AND cid = '-1' AND cl % 2 = 0",
+        (),
+        |row| row.get(0),
+    )?;
+    assert_eq!(tombstones, 1000);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_reject_too_large_values() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let limited =
+        launch_test_agent(|conf| conf.max_value_bytes(64).build(), tripwire.clone()).await?;
+    let actor_id = ta1.agent.actor_id();
+
+    insert_rows(ta1.agent.clone(), 1, 2).await;
+    let mut rows = get_rows(
+        ta1.agent.clone(),
+        vec![(dbvri!(1, 1), None), (dbvri!(2, 2), None)],
+    )
+    .await?;
+    assert_eq!(rows.len(), 2);
+    if let Changeset::Full { changes, .. } = &mut rows[1].0.changeset {
+        let change = changes
+            .iter_mut()
+            .find(|change| change.cid.as_str() == "text")
+            .expect("no text change");
+        change.val = SqliteValue::Text("a".repeat(100).into());
+    }
+
+    match crate::agent::util::check_value_sizes(&rows[1].0, 64) {
+        Err(ChangeError::ValueTooLarge { cid, size, limit }) => {
+            assert_eq!(cid.as_str(), "text");
+            assert_eq!(size, 100);
+            assert_eq!(limit, 64);
+        }
+        res => panic!("expected a value too large, got {res:?}"),
+    }
+    crate::agent::util::check_value_sizes(&rows[0].0, 64)?;
+
+    // only the change with the large value is rejected
+    process_multiple_changes(
+        limited.agent.clone(),
+        limited.bookie.clone(),
+        rows,
+        Duration::from_secs(60),
+    )
+    .await?;
+
+    let booked = limited
+        .bookie
+        .write::<&str, _>("test", None)
+        .await
+        .ensure(actor_id);
+    let booked = booked.read::<&str, _>("test", None).await;
+    assert!(booked.contains_version(&CrsqlDbVersion(1)));
+    // booked as cleared, it's not requested again
+    assert!(booked.contains_version(&CrsqlDbVersion(2)));
+    assert!(booked.needed().is_empty());
+    drop(booked);
+    let conn = limited.agent.pool().read().await?;
+    let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests3", [], |row| row.get(0))?;
+    assert_eq!(count, 1);
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_strict_seqs_sync_cleared() -> eyre::Result<()> {
+    _ = tracing_subscriber::fmt::try_init();
+    let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+    let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+    let ta2 = launch_test_agent(
+        |conf| conf.seq_mode(SeqMode::Strict).build(),
+        tripwire.clone(),
+    )
+    .await?;
+    let actor_id = ta1.agent.actor_id();
+
+    // version 2 overwrites seq 1 of version 1, a hole it doesn't have anymore
+    insert_rows(ta1.agent.clone(), 1, 1).await;
+    let (status_code, _) = api_v1_transactions(
+        Extension(ta1.agent.clone()),
+        axum::extract::Query(TimeoutParams { timeout: None }),
+        axum::Json(vec![Statement::Simple(
+            "UPDATE tests3 SET text2 = 'overwritten' WHERE id = 1".into(),
+        )]),
+    )
+    .await;
+    assert_eq!(status_code, StatusCode::OK);
+
+    let rows = get_rows(ta1.agent.clone(), vec![(dbvri!(1, 1), None)]).await?;
+    assert!(crate::agent::util::check_seqs(&rows[0].0, &[]).is_err());
+
+    let members = vec![(actor_id, ta1.agent.gossip_addr())];
+    let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+    parallel_sync(&ta2.agent, &ta2.transport, members, sync_state).await?;
+
+    timeout(Duration::from_secs(5), async {
+        loop {
+            let booked = ta2
+                .bookie
+                .write::<&str, _>("test", None)
+                .await
+                .ensure(actor_id);
+            if booked
+                .read::<&str, _>("test", None)
+                .await
+                .contains_all(dbvri!(1, 2), None)
+            {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+
+    tripwire_tx.send(()).await.ok();
+    tripwire_worker.await;
+    wait_for_all_pending_handles().await;
+
+    Ok(())
+}
@@ -1,26 +1,51 @@
+use std::{io, net::SocketAddr, sync::Arc, time::Duration};
+
+use bytes::BytesMut;
 use corro_types::{
-    actor::ClusterId,
+    actor::{ActorId, ClusterId},
+    agent::Agent,
+    api::with_max_value_bytes,
     broadcast::{BroadcastV1, ChangeSource, ChangeV1, UniPayload, UniPayloadV1},
     channel::CorroSender,
+    config::PeerRejection,
 };
+use futures::Stream;
 use metrics::counter;
 use speedy::Readable;
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, LengthDelimitedCodec};
-use tracing::{debug, error, trace};
+use tracing::{debug, error, trace, warn};
 use tripwire::Tripwire;
 
+/// How long a stream waits for room in the broadcast ingress before dropping
+/// a change
+const INGRESS_WAIT: Duration = Duration::from_secs(5);
+
 /// Spawn a task that accepts unidirectional broadcast streams, then
 /// spawns another task for each incoming stream to handle.
+///
+/// Changes read from the streams hold a permit of the broadcast ingress
+/// until they're queued for processing. Without permits left, streams stop
+/// being read and QUIC flow control slows down the senders. SWIM messages
+/// come in as datagrams and aren't affected. Frames over the configured
+/// `max_frame_bytes` end the stream, streams from peers the config doesn't
+/// give access to are dropped unread.
 pub fn spawn_unipayload_handler(
+    agent: &Agent,
     tripwire: &Tripwire,
     conn: &quinn::Connection,
-    cluster_id: ClusterId,
     tx_changes: CorroSender<(ChangeV1, ChangeSource)>,
 ) {
     tokio::spawn({
+        let agent = agent.clone();
         let conn = conn.clone();
         let mut tripwire = tripwire.clone();
+        let cluster_id = agent.cluster_id();
+        let ingress = agent.limits().broadcast_ingress.clone();
         async move {
             loop {
                 let rx = tokio::select! {
@@ -39,68 +64,222 @@ pub fn spawn_unipayload_handler(
 
                 counter!("corro.peer.stream.accept.total", "type" => "uni").increment(1);
 
-                trace!(
-                    "accepted a unidirectional stream from {}",
-                    conn.remote_address()
-                );
+                let remote_addr = conn.remote_address();
+                trace!("accepted a unidirectional stream from {remote_addr}");
+
+                if let Err(rejection) = check_access(&agent, remote_addr) {
+                    let reason: &'static str = rejection.into();
+                    counter!("corro.broadcast.rejected", "reason" => reason).increment(1);
+                    debug!(addr = %remote_addr, "dropping broadcast stream: {rejection}");
+                    continue;
+                }
 
+                let max_frame_bytes = agent.config().perf.max_frame_bytes;
+                let max_value_bytes = agent.config().db.max_value_bytes;
                 tokio::spawn({
                     let tx_changes = tx_changes.clone();
+                    let ingress = ingress.clone();
                     async move {
-                        let mut framed = FramedRead::new(
+                        let framed = FramedRead::new(
                             rx,
                             LengthDelimitedCodec::builder()
-                                .max_frame_length(100 * 1_024 * 1_024)
+                                .max_frame_length(max_frame_bytes)
                                 .new_codec(),
                         );
 
-                        let mut changes = vec![];
-                        loop {
-                            match StreamExt::next(&mut framed).await {
-                                Some(Ok(b)) => {
-                                    counter!("corro.peer.stream.bytes.recv.total", "type" => "uni")
-                                        .increment(b.len() as u64);
-                                    match UniPayload::read_from_buffer(&b) {
-                                        Ok(payload) => {
-                                            trace!("parsed a payload: {payload:?}");
-
-                                            match payload {
-                                                UniPayload::V1 {
-                                                    data:
-                                                        UniPayloadV1::Broadcast(BroadcastV1::Change(
-                                                            change,
-                                                        )),
-                                                    cluster_id: payload_cluster_id,
-                                                } => {
-                                                    if cluster_id != payload_cluster_id {
-                                                        continue;
-                                                    }
-                                                    changes.push((change, ChangeSource::Broadcast));
-                                                }
-                                            }
-                                        }
-                                        Err(e) => {
-                                            error!("could not decode UniPayload: {e}");
-                                            continue;
-                                        }
-                                    }
-                                }
-                                Some(Err(e)) => {
-                                    error!("decode error: {e}");
+                        read_broadcasts(framed, cluster_id, max_value_bytes, &tx_changes, &ingress)
+                            .await;
+                    }
+                });
+            }
+        }
+    });
+}
+
+/// Checks the sender of a stream, by its address and the members gossiping
+/// from it, against the configured peer access
+fn check_access(agent: &Agent, addr: SocketAddr) -> Result<(), PeerRejection> {
+    let actor_ids: Vec<ActorId> = agent
+        .members()
+        .read()
+        .states
+        .iter()
+        .filter(|(_, state)| state.addr.ip().to_canonical() == addr.ip().to_canonical())
+        .map(|(actor_id, _)| *actor_id)
+        .collect();
+    agent
+        .config()
+        .gossip
+        .peer_access
+        .check_addr(addr.ip(), &actor_ids)
+}
+
+type IngressChange = ((ChangeV1, ChangeSource), OwnedSemaphorePermit);
+
+async fn read_broadcasts<S>(
+    mut framed: S,
+    cluster_id: ClusterId,
+    max_value_bytes: Option<usize>,
+    tx_changes: &CorroSender<(ChangeV1, ChangeSource)>,
+    ingress: &Arc<Semaphore>,
+) where
+    S: Stream<Item = io::Result<BytesMut>> + Unpin,
+{
+    let mut changes: Vec<IngressChange> = vec![];
+    loop {
+        let change = match StreamExt::next(&mut framed).await {
+            Some(Ok(b)) => {
+                counter!("corro.peer.stream.bytes.recv.total", "type" => "uni")
+                    .increment(b.len() as u64);
+                match with_max_value_bytes(max_value_bytes, || UniPayload::read_from_buffer(&b)) {
+                    Ok(payload) => {
+                        trace!("parsed a payload: {payload:?}");
+
+                        match payload {
+                            UniPayload::V1 {
+                                data: UniPayloadV1::Broadcast(BroadcastV1::Change(change)),
+                                cluster_id: payload_cluster_id,
+                            } => {
+                                if cluster_id != payload_cluster_id {
+                                    continue;
                                 }
-                                None => break,
+                                change
                             }
                         }
+                    }
+                    Err(e) => {
+                        error!("could not decode UniPayload: {e}");
+                        continue;
+                    }
+                }
+            }
+            Some(Err(e)) => {
+                error!("decode error: {e}");
+                continue;
+            }
+            None => break,
+        };
 
-                        for change in changes.into_iter().rev() {
-                            if let Err(e) = tx_changes.send(change).await {
-                                error!("could not send change for processing: {e}");
-                                return;
-                            }
-                        }
+        let permit = match ingress.clone().try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                counter!("corro.broadcast.backpressure").increment(1);
+                // our own buffered changes might be what's filling the
+                // ingress, hand them off before waiting
+                if !send_changes(&mut changes, tx_changes).await {
+                    return;
+                }
+                match timeout(INGRESS_WAIT, ingress.clone().acquire_owned()).await {
+                    Ok(Ok(permit)) => permit,
+                    _ => {
+                        warn!("broadcast ingress is full, dropping change");
+                        counter!("corro.broadcast.dropped").increment(1);
+                        continue;
                     }
-                });
+                }
             }
+        };
+
+        changes.push(((change, ChangeSource::Broadcast), permit));
+    }
+
+    send_changes(&mut changes, tx_changes).await;
+}
+
+/// Queues buffered changes for processing, releasing their ingress permits.
+/// Returns false if the changes channel is closed.
+async fn send_changes(
+    changes: &mut Vec<IngressChange>,
+    tx_changes: &CorroSender<(ChangeV1, ChangeSource)>,
+) -> bool {
+    for (change, _permit) in changes.drain(..).rev() {
+        if let Err(e) = tx_changes.send(change).await {
+            error!("could not send change for processing: {e}");
+            return false;
         }
-    });
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use corro_types::{
+        actor::ActorId,
+        base::{CrsqlDbVersion, CrsqlDbVersionRange},
+        broadcast::Changeset,
+        channel::bounded,
+    };
+    use speedy::Writable;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_broadcast_ingress_stays_bounded() -> eyre::Result<()> {
+        const CAPACITY: usize = 10;
+        const COUNT: usize = 100;
+
+        let cluster_id = ClusterId::default();
+        let frames = (1..=COUNT as u64)
+            .map(|version| {
+                let payload = UniPayload::V1 {
+                    data: UniPayloadV1::Broadcast(BroadcastV1::Change(ChangeV1 {
+                        actor_id: ActorId::default(),
+                        changeset: Changeset::Empty {
+                            versions: CrsqlDbVersionRange::single(CrsqlDbVersion(version)),
+                            ts: None,
+                        },
+                    })),
+                    cluster_id,
+                };
+                Ok(BytesMut::from(&payload.write_to_vec()?[..]))
+            })
+            .collect::<Result<Vec<_>, speedy::Error>>()?;
+
+        let pulled = Arc::new(AtomicUsize::new(0));
+        let framed = futures::StreamExt::inspect(
+            futures::stream::iter(frames.into_iter().map(Ok::<_, io::Error>)),
+            {
+                let pulled = pulled.clone();
+                move |_| {
+                    pulled.fetch_add(1, Ordering::SeqCst);
+                }
+            },
+        );
+
+        let ingress = Arc::new(Semaphore::new(CAPACITY));
+        let (tx_changes, mut rx_changes) = bounded(1, "test_ingress");
+
+        let reader = tokio::spawn({
+            let ingress = ingress.clone();
+            async move { read_broadcasts(framed, cluster_id, None, &tx_changes, &ingress).await }
+        });
+
+        // nothing is processing the changes: the reader stops once the
+        // ingress and the channel are full
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(
+            pulled.load(Ordering::SeqCst) <= CAPACITY + 1,
+            "read {} frames",
+            pulled.load(Ordering::SeqCst)
+        );
+        assert!(!reader.is_finished());
+
+        // processing resumes and every change makes it through
+        let mut received = 0;
+        while received < COUNT {
+            tokio::time::timeout(Duration::from_secs(5), rx_changes.recv())
+                .await?
+                .expect("channel closed early");
+            received += 1;
+            // buffered with a permit, in the channel or waiting for a permit
+            assert!(pulled.load(Ordering::SeqCst) - received <= CAPACITY + 2);
+        }
+        reader.await?;
+        assert_eq!(pulled.load(Ordering::SeqCst), COUNT);
+        assert_eq!(ingress.available_permits(), CAPACITY);
+
+        Ok(())
+    }
 }
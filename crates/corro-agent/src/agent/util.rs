@@ -8,7 +8,8 @@
 use crate::{
     agent::{handlers, CountedExecutor, TO_CLEAR_COUNT},
     api::public::{
-        api_v1_db_schema, api_v1_queries, api_v1_table_stats, api_v1_transactions,
+        api_v1_api_schema, api_v1_db_schema, api_v1_enable_crr, api_v1_queries,
+        api_v1_schema_changes, api_v1_table_digest, api_v1_table_stats, api_v1_transactions,
         pubsub::{api_v1_sub_by_id, api_v1_subs},
         update::SharedUpdateBroadcastCache,
     },
@@ -18,18 +19,29 @@ use crate::{
 use antithesis_sdk::assert_sometimes;
 use corro_types::{
     actor::{Actor, ActorId},
-    agent::{Agent, Bookie, ChangeError, CurrentVersion, KnownDbVersion, PartialVersion},
+    agent::{
+        Agent, Booked, BookedVersions, Bookie, ChangeError, CurrentVersion, KnownDbVersion,
+        PartialVersion,
+    },
     api::TableName,
     base::{CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeq},
     broadcast::{ChangeSource, ChangeV1, Changeset, ChangesetParts, FocaCmd, FocaInput},
+    change::{
+        conflict_winner, insert_change, insert_quarantined_chunk, row_to_change, total_changes,
+        Change, ConflictWinner, SqliteValue, WriteAmplification,
+    },
     channel::CorroReceiver,
-    config::AuthzConfig,
+    config::{AuthzConfig, SeqMode},
     pubsub::SubsManager,
+    sqlite::TxIsolation,
     updates::{match_changes, match_changes_from_db_version},
 };
 
 use super::BcastCache;
-use crate::api::public::update::api_v1_updates;
+use crate::api::public::{
+    health::{api_v1_health, api_v1_health_live},
+    update::api_v1_updates,
+};
 use antithesis_sdk::{assert_always, assert_unreachable};
 use axum::{
     error_handling::HandleErrorLayer,
@@ -41,24 +53,28 @@ use axum::{
 use corro_types::broadcast::Timestamp;
 use foca::Member;
 use futures::FutureExt;
+use governor::{DefaultDirectRateLimiter, Quota, RateLimiter};
 use hyper::{server::conn::AddrIncoming, StatusCode};
 use metrics::{counter, histogram};
+use once_cell::sync::Lazy;
 use rangemap::{RangeInclusiveMap, RangeInclusiveSet};
-use rusqlite::{named_params, params, Connection};
+use rusqlite::{named_params, params, Connection, OptionalExtension};
 use serde_json::json;
 use spawn::spawn_counted;
 use sqlite_pool::{Committable, InterruptibleTransaction};
 use std::{
     cmp,
-    collections::{BTreeMap, HashSet},
+    collections::{hash_map, BTreeMap, HashMap, HashSet},
     convert::Infallible,
     net::SocketAddr,
+    num::NonZeroU32,
     ops::{Deref, RangeInclusive},
     sync::{atomic::AtomicI64, Arc},
     time::{Duration, Instant},
 };
 use tokio::{
     net::TcpListener,
+    sync::broadcast,
     task::{block_in_place, JoinHandle},
 };
 use tower::{limit::ConcurrencyLimitLayer, load_shed::LoadShedLayer};
@@ -173,6 +189,7 @@ pub async fn load_member_states(agent: &Agent) -> Vec<(SocketAddr, Member<Actor>
 
 pub async fn setup_http_api_handler(
     agent: &Agent,
+    bookie: &Bookie,
     tripwire: &Tripwire,
     subs_bcast_cache: BcastCache,
     updates_bcast_cache: SharedUpdateBroadcastCache,
@@ -266,6 +283,49 @@ pub async fn setup_http_api_handler(
                     .layer(ConcurrencyLimitLayer::new(4)),
             ),
         )
+        .route(
+            "/v1/schema/changes",
+            get(api_v1_schema_changes).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(128)),
+            ),
+        )
+        .route(
+            "/v1/tables/:table/crr",
+            post(api_v1_enable_crr).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route(
+            "/v1/tables/:table/digest",
+            get(api_v1_table_digest).route_layer(
+                tower::ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(|_error: BoxError| async {
+                        Ok::<_, Infallible>((
+                            StatusCode::SERVICE_UNAVAILABLE,
+                            "max concurrency limit reached".to_string(),
+                        ))
+                    }))
+                    .layer(LoadShedLayer::new())
+                    .layer(ConcurrencyLimitLayer::new(4)),
+            ),
+        )
+        .route("/v1/api_schema", get(api_v1_api_schema))
         .route(
             "/v1/table_stats",
             post(api_v1_table_stats).route_layer(
@@ -281,16 +341,20 @@ pub async fn setup_http_api_handler(
             ),
         )
         .layer(axum::middleware::from_fn(require_authz))
+        // probes don't need authorization
+        .route("/v1/health", get(api_v1_health))
+        .route("/v1/health/live", get(api_v1_health_live))
         .layer(
             tower::ServiceBuilder::new()
                 .layer(Extension(Arc::new(AtomicI64::new(0))))
                 .layer(Extension(agent.clone()))
+                .layer(Extension(bookie.clone()))
                 .layer(Extension(subs_bcast_cache))
                 .layer(Extension(updates_bcast_cache))
                 .layer(Extension(subs_manager.clone()))
                 .layer(Extension(tripwire.clone())),
         )
-        .layer(DefaultBodyLimit::disable())
+        .layer(DefaultBodyLimit::max(agent.config().perf.max_frame_bytes))
         .layer(TraceLayer::new_for_http());
 
     let mut handles: Vec<JoinHandle<()>> = vec![];
@@ -366,8 +430,20 @@ pub async fn sync_loop(agent: Agent, bookie: Bookie, transport: Transport, mut t
             _ = &mut tripwire => {
                 break;
             }
+            _ = agent.sync_requested() => {
+                debug!("sync requested before the next scheduled one");
+            }
         };
 
+        // synced changes wouldn't be applied, resuming requests a sync
+        if agent.inbound_paused() {
+            debug!("inbound replication is paused, skipping sync");
+            next_sync_at
+                .as_mut()
+                .reset(tokio::time::Instant::now() + sync_backoff.next().unwrap());
+            continue;
+        }
+
         // ignoring here, there is trying and logging going on inside
         match tokio::time::timeout(
             Duration::from_secs(300),
@@ -397,6 +473,135 @@ pub async fn sync_loop(agent: Agent, bookie: Bookie, transport: Transport, mut t
     }
 }
 
+/// Requests a sync when an incomplete version hasn't received any chunk for
+/// `perf.partial_version_timeout` seconds.
+///
+/// Chunks of a version can arrive in any order, they're buffered until every
+/// seq up to the version's `last_seq` is there and only then applied, in a
+/// single transaction. Lost chunks are requested by the next sync, this makes
+/// it happen sooner than the sync backoff would.
+pub async fn request_missing_chunks_loop(agent: Agent, bookie: Bookie, mut tripwire: Tripwire) {
+    let timeout = Duration::from_secs(agent.config().perf.partial_version_timeout as u64);
+    let mut interval = tokio::time::interval(cmp::max(timeout / 2, Duration::from_secs(1)));
+    let mut progress = HashMap::new();
+
+    while let Outcome::Completed(_) = interval.tick().preemptible(&mut tripwire).await {
+        let booked: Vec<(ActorId, Booked)> = bookie
+            .read::<&str, _>("request_missing_chunks", None)
+            .await
+            .iter()
+            .map(|(actor_id, booked)| (*actor_id, booked.clone()))
+            .collect();
+
+        let mut partials = HashMap::new();
+        for (actor_id, booked) in booked {
+            let read = booked
+                .read("request_missing_chunks(booked)", actor_id.as_simple())
+                .await;
+            for (version, partial) in read.partials.iter() {
+                partials.insert((actor_id, *version), partial.seqs.clone());
+            }
+        }
+
+        let stale = stale_partials(&mut progress, partials, Instant::now(), timeout);
+        if !stale.is_empty() {
+            counter!("corro.changes.partial.timeout").increment(stale.len() as u64);
+            warn!(
+                "{} incomplete versions did not receive chunks for {timeout:?}, requesting a sync: {stale:?}",
+                stale.len()
+            );
+            agent.request_sync();
+        }
+    }
+}
+
+type PartialProgress = HashMap<(ActorId, CrsqlDbVersion), (RangeInclusiveSet<CrsqlSeq>, Instant)>;
+
+/// Tracks when each incomplete version last received a chunk, returns those
+/// idle for `timeout` and restarts their clock.
+fn stale_partials(
+    progress: &mut PartialProgress,
+    partials: HashMap<(ActorId, CrsqlDbVersion), RangeInclusiveSet<CrsqlSeq>>,
+    now: Instant,
+    timeout: Duration,
+) -> Vec<(ActorId, CrsqlDbVersion)> {
+    // completed or cleared
+    progress.retain(|key, _| partials.contains_key(key));
+
+    let mut stale = vec![];
+    for (key, seqs) in partials {
+        match progress.entry(key) {
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert((seqs, now));
+            }
+            hash_map::Entry::Occupied(mut entry) => {
+                let (known, since) = entry.get_mut();
+                if *known != seqs {
+                    *known = seqs;
+                    *since = now;
+                } else if now.duration_since(*since) >= timeout {
+                    stale.push(key);
+                    *since = now;
+                }
+            }
+        }
+    }
+    stale.sort();
+    stale
+}
+
+/// Retries quarantined changes whenever the schema changes and reports
+/// those still waiting after `perf.quarantine_timeout` seconds as errors.
+///
+/// Changes are quarantined when they reference a table or column missing
+/// from the local schema, see
+/// [`process_multiple_changes`].
+pub async fn quarantine_loop(agent: Agent, bookie: Bookie, mut tripwire: Tripwire) {
+    let timeout = Duration::from_secs(agent.config().perf.quarantine_timeout as u64);
+    let tx_timeout = Duration::from_secs(agent.config().perf.sql_tx_timeout as u64);
+    let mut schema_changes = agent.subscribe_schema_changes();
+    let mut interval = tokio::time::interval(cmp::max(timeout / 10, Duration::from_secs(1)));
+
+    loop {
+        tokio::select! {
+            biased;
+
+            _ = &mut tripwire => break,
+            res = schema_changes.recv() => {
+                if let Err(broadcast::error::RecvError::Closed) = res {
+                    break;
+                }
+                let ready = agent.quarantine().take_ready(&agent.schema().read());
+                if ready.is_empty() {
+                    continue;
+                }
+                info!("retrying {} quarantined changes after a schema change", ready.len());
+                let changes = ready
+                    .into_iter()
+                    .map(|parked| (parked.change, parked.src, Instant::now()))
+                    .collect();
+                if let Err(e) =
+                    process_multiple_changes(agent.clone(), bookie.clone(), changes, tx_timeout)
+                        .await
+                {
+                    error!("could not apply quarantined changes: {e}");
+                }
+            }
+            _ = interval.tick() => {
+                for parked in agent.quarantine().take_expired(timeout) {
+                    counter!("corro.quarantined.changes.expired").increment(1);
+                    error!(
+                        actor_id = %parked.change.actor_id,
+                        versions = ?parked.change.versions(),
+                        "change quarantined for over {timeout:?}, dropping it: {}",
+                        parked.mismatch
+                    );
+                }
+            }
+        }
+    }
+}
+
 pub async fn apply_fully_buffered_changes_loop(
     agent: Agent,
     bookie: Bookie,
@@ -489,6 +694,56 @@ pub async fn clear_buffered_meta_loop(
     }
 }
 
+/// Whether every version and seq of `change` is already booked, in which
+/// case applying it again would be a no-op. A partially applied version only
+/// counts if all of `change`'s seqs are within its applied seqs.
+pub fn is_already_applied(booked: &BookedVersions, change: &ChangeV1) -> bool {
+    let applied = booked.contains_all(change.versions(), change.seqs());
+    if applied {
+        counter!("corro.changes.skipped", "reason" => "applied").increment(1);
+    }
+    applied
+}
+
+/// Errors with the seqs missing from `change`'s range, minus the ones its
+/// sender marked `cleared`, which `SeqMode::Strict` refuses to book as applied
+pub fn check_seqs(
+    change: &ChangeV1,
+    cleared: &[RangeInclusive<CrsqlSeq>],
+) -> Result<(), ChangeError> {
+    let mut missing: RangeInclusiveSet<CrsqlSeq> =
+        change.changeset.missing_seqs().into_iter().collect();
+    for seqs in cleared {
+        missing.remove(seqs.clone());
+    }
+    if missing.is_empty() {
+        return Ok(());
+    }
+    Err(ChangeError::MissingSeqs {
+        actor_id: change.actor_id,
+        version: change.versions().start(),
+        missing: missing.into_iter().collect(),
+    })
+}
+
+/// Errors with the first text or blob value of `change` over `limit` bytes.
+/// The value was decoded already, the whole change is rejected for it but
+/// nothing else received with it.
+pub fn check_value_sizes(change: &ChangeV1, limit: usize) -> Result<(), ChangeError> {
+    let too_large = change.changes().iter().find_map(|change| {
+        let size = match &change.val {
+            SqliteValue::Text(text) => text.len(),
+            SqliteValue::Blob(blob) => blob.len(),
+            _ => return None,
+        };
+        (size > limit).then(|| (change.cid.clone(), size))
+    });
+    match too_large {
+        Some((cid, size)) => Err(ChangeError::ValueTooLarge { cid, size, limit }),
+        None => Ok(()),
+    }
+}
+
 #[tracing::instrument(skip_all, err)]
 pub fn process_single_version<T: Deref<Target = rusqlite::Connection> + Committable>(
     agent: &Agent,
@@ -604,27 +859,57 @@ pub async fn process_fully_buffered_changes(
 
             info!(%actor_id, %version, "Processing buffered changes to crsql_changes (actor: {actor_id}, version: {version}, last_seq: {last_seq})");
 
-            let rows_present: bool = tx.prepare_cached("SELECT EXISTS (SELECT 1 FROM __corro_buffered_changes WHERE site_id = ? AND db_version = ?)")
-                                    .map_err(|source| ChangeError::Rusqlite{source, actor_id: Some(actor_id), version: Some(version)})?
-                                    .query_row(params![actor_id, version], |row| row.get(0))
-                                    .map_err(|source| ChangeError::Rusqlite{source, actor_id: Some(actor_id), version: Some(version)})?;
+            let map_err = |source| ChangeError::Rusqlite {
+                source,
+                actor_id: Some(actor_id),
+                version: Some(version),
+            };
 
             let start = Instant::now();
+            // time spent applying changes, per table
+            let mut apply_durations: BTreeMap<TableName, Duration> = BTreeMap::new();
+
+            // insert the buffered changes into crsql_changes one by one, like a
+            // complete version's, to time them per table
+            let mut prepped = tx
+                .prepare_cached(
+                    r#"
+                    SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl, ts
+                        FROM __corro_buffered_changes
+                            WHERE site_id = ?
+                            AND db_version = ?
+                            ORDER BY db_version ASC, seq ASC
+                    "#,
+                )
+                .map_err(map_err)?;
+            let mut rows = prepped
+                .query(params![actor_id.as_bytes(), version])
+                .map_err(map_err)?;
+
+            let mut count = 0;
+            while let Some(row) = rows.next().map_err(map_err)? {
+                let change = row_to_change(row).map_err(map_err)?;
+                let ts: Timestamp = row.get(9).map_err(map_err)?;
+
+                let change_start = Instant::now();
+                insert_change(&tx, &change, ts).map_err(map_err)?;
+                match apply_durations.get_mut(&change.table) {
+                    Some(elapsed) => *elapsed += change_start.elapsed(),
+                    None => {
+                        apply_durations.insert(change.table, change_start.elapsed());
+                    }
+                }
+                count += 1;
+            }
+            drop(rows);
+            drop(prepped);
+
+            for (table_name, elapsed) in apply_durations {
+                histogram!("corro.apply.duration.seconds", "table" => table_name.to_string())
+                    .record(elapsed.as_secs_f64());
+            }
 
-            if rows_present {
-                // insert all buffered changes into crsql_changes directly from the buffered changes table
-                let count = tx
-                    .prepare_cached(
-                        r#"
-                        INSERT INTO crsql_changes ("table", pk, cid, val, col_version, db_version, site_id, cl, seq, ts)
-                            SELECT                 "table", pk, cid, val, col_version, db_version, site_id, cl, seq, ts
-                                FROM __corro_buffered_changes
-                                    WHERE site_id = ?
-                                    AND db_version = ?
-                                    ORDER BY db_version ASC, seq ASC
-                                    "#,
-                    ).map_err(|source| ChangeError::Rusqlite{source, actor_id: Some(actor_id), version: Some(version)})?
-                    .execute(params![actor_id.as_bytes(), version]).map_err(|source| ChangeError::Rusqlite{source, actor_id: Some(actor_id), version: Some(version)})?;
+            if count > 0 {
                 info!(%actor_id, %version, "Inserted {count} rows from buffered into crsql_changes in {:?}", start.elapsed());
             } else {
                 info!(%actor_id, %version, "No buffered rows, skipped insertion into crsql_changes");
@@ -654,7 +939,7 @@ pub async fn process_fully_buffered_changes(
             debug!(%actor_id, %version, "rows impacted by buffered changes insertion: {rows_impacted}");
 
             let mut snap = bookedw.snapshot();
-            snap.insert_db(&tx, [version..=version].into())
+            snap.insert_db(&tx, [version.range_to(version)])
                 .map_err(|source| ChangeError::Rusqlite {
                     source,
                     actor_id: Some(actor_id),
@@ -668,12 +953,19 @@ pub async fn process_fully_buffered_changes(
             })?;
 
             bookedw.commit_snapshot(snap);
+            agent
+                .chunk_retries()
+                .booked(actor_id, CrsqlDbVersionRange::single(version));
 
             Ok::<_, ChangeError>(rows_impacted > 0)
         })
     }?;
 
-    if rows_impacted {
+    // observers and the recent changes cache need the whole changes, read
+    // them back only for them
+    let read_back = !agent.change_observers().is_empty() || agent.recent_changes().bytes() > 0;
+
+    if rows_impacted && !read_back {
         let conn = agent.pool().read().await?;
         block_in_place(|| {
             if let Err(e) =
@@ -690,36 +982,112 @@ pub async fn process_fully_buffered_changes(
                 error!(%version, "could not match changes for updates from db version: {e}");
             }
         });
+    } else if rows_impacted {
+        let conn = agent.pool().read().await?;
+        let changes = block_in_place(|| {
+            conn.prepare_cached(
+                r#"
+                SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl
+                    FROM crsql_changes
+                    WHERE db_version = ?
+                    AND site_id = ?
+                    ORDER BY seq ASC
+                "#,
+            )?
+            .query_map(params![version, actor_id], row_to_change)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+        });
+        drop(conn);
+
+        match changes {
+            Ok(changes) => notify_applied_changes(agent, &changes, version),
+            Err(e) => {
+                error!(%actor_id, %version, "could not read back buffered changes to notify about: {e}");
+            }
+        }
     }
 
     Ok(rows_impacted)
 }
 
-#[tracing::instrument(skip(agent, bookie, changes), err)]
 pub async fn process_multiple_changes(
     agent: Agent,
     bookie: Bookie,
     changes: Vec<(ChangeV1, ChangeSource, Instant)>,
     tx_timeout: Duration,
+) -> Result<(), ChangeError> {
+    process_multiple_changes_with_isolation(
+        agent,
+        bookie,
+        changes,
+        tx_timeout,
+        TxIsolation::default(),
+    )
+    .await
+}
+
+/// Same as [`process_multiple_changes`], but applies the changes in a transaction
+/// opened with the given `BEGIN` mode instead of the default `IMMEDIATE`.
+#[tracing::instrument(
+    name = "process_multiple_changes",
+    skip(agent, bookie, changes),
+    fields(
+        applied = tracing::field::Empty,
+        skipped = tracing::field::Empty,
+        duration = tracing::field::Empty
+    ),
+    err
+)]
+pub async fn process_multiple_changes_with_isolation(
+    agent: Agent,
+    bookie: Bookie,
+    changes: Vec<(ChangeV1, ChangeSource, Instant)>,
+    tx_timeout: Duration,
+    isolation: TxIsolation,
 ) -> Result<(), ChangeError> {
     let start = Instant::now();
+    let total = changes.len();
     counter!("corro.agent.changes.processing.started").increment(changes.len() as u64);
     debug!(self_actor_id = %agent.actor_id(), "processing multiple changes, len: {}", changes.iter().map(|(change, _, _)| cmp::max(change.len(), 1)).sum::<usize>());
     trace!(self_actor_id = %agent.actor_id(), "changes: {changes:?}");
 
     const PROCESSING_WARN_THRESHOLD: Duration = Duration::from_secs(5);
 
+    let max_partial_versions = agent.config().perf.max_partial_versions;
+    let strict_seqs = agent.config().db.seq_mode == SeqMode::Strict;
+    let max_value_bytes = agent.config().db.max_value_bytes;
     let mut seen = HashSet::new();
     let mut unknown_changes: BTreeMap<_, Vec<_>> = BTreeMap::new();
-    for (change, src, queued_at) in changes {
+    for (mut change, src, queued_at) in changes {
         histogram!("corro.agent.changes.queued.seconds").record(queued_at.elapsed());
         let versions = change.versions();
         let seqs = change.seqs();
 
         if !seen.insert((change.actor_id, versions, seqs)) {
+            counter!("corro.changes.skipped", "reason" => "duplicate").increment(1);
             continue;
         }
 
+        if agent
+            .chunk_retries()
+            .is_quarantined(&(change.actor_id, versions, seqs))
+        {
+            counter!("corro.changes.skipped", "reason" => "quarantined").increment(1);
+            continue;
+        }
+
+        if let Some(limit) = max_value_bytes {
+            if let Err(e) = check_value_sizes(&change, limit) {
+                counter!("corro.changes.skipped", "reason" => "value_too_large").increment(1);
+                error!(actor_id = %change.actor_id, ?versions, "rejecting change, booking its version as cleared: {e}");
+                // booked as cleared, or syncs would request it forever
+                change.changeset = Changeset::Empty {
+                    versions,
+                    ts: change.changeset.ts(),
+                };
+            }
+        }
+
         let booked_writer = {
             bookie
                 .write(
@@ -729,14 +1097,15 @@ pub async fn process_multiple_changes(
                 .await
                 .ensure(change.actor_id)
         };
-        if booked_writer
-            .read(
-                "process_multiple_changes(contains_all?)",
-                change.actor_id.as_simple(),
-            )
-            .await
-            .contains_all(change.versions(), change.seqs())
-        {
+        if is_already_applied(
+            &booked_writer
+                .read(
+                    "process_multiple_changes(contains_all?)",
+                    change.actor_id.as_simple(),
+                )
+                .await,
+            &change,
+        ) {
             continue;
         }
 
@@ -752,10 +1121,14 @@ pub async fn process_multiple_changes(
 
     let mut conn = agent.pool().write_normal().await?;
 
-    let changesets = block_in_place(|| {
+    // chunks out of attempts, persisted once the transaction is done
+    let mut to_quarantine = vec![];
+
+    let res = block_in_place(|| {
         let start = Instant::now();
+        let rows_before = total_changes(&conn).ok();
         let tx = conn
-            .immediate_transaction()
+            .transaction_with_isolation(isolation)
             .map_err(|source| ChangeError::Rusqlite {
                 source,
                 actor_id: None,
@@ -768,6 +1141,7 @@ pub async fn process_multiple_changes(
         let mut changesets = vec![];
 
         let mut count = 0;
+        let mut applied = 0;
 
         let sub_start = Instant::now();
         for (actor_id, changes) in unknown_changes {
@@ -786,11 +1160,12 @@ pub async fn process_multiple_changes(
 
             let max = booked_write.last();
             let mut seen = RangeInclusiveMap::new();
+            let mut new_partials = HashSet::new();
 
-            for (change, src) in changes {
+            for (mut change, src) in changes {
                 trace!("handling a single changeset: {change:?}");
                 let seqs = change.seqs();
-                if booked_write.contains_all(change.versions(), change.seqs()) {
+                if is_already_applied(&booked_write, &change) {
                     trace!("previously unknown versions are now deemed known, aborting inserts");
                     continue;
                 }
@@ -838,6 +1213,68 @@ pub async fn process_multiple_changes(
                         }
                     }
 
+                    if strict_seqs {
+                        // synced changes were checked against the seqs their
+                        // sender marked cleared as they were received, there's
+                        // no telling those from lost ones in a broadcast
+                        if matches!(src, ChangeSource::Broadcast) {
+                            if let Err(e) = check_seqs(&change, &[]) {
+                                counter!("corro.changes.skipped", "reason" => "missing_seqs")
+                                    .increment(1);
+                                error!("rejecting change: {e}");
+                                continue;
+                            }
+                        }
+                        if let Changeset::Full { changes, .. } = &mut change.changeset {
+                            changes.sort_by_key(|change| change.seq);
+                        }
+                    }
+
+                    // park changes the local schema can't take yet instead of
+                    // failing them, they're retried once the schema changes
+                    let mismatch = {
+                        let schema = agent.schema().read();
+                        change
+                            .changes()
+                            .iter()
+                            .find_map(|c| c.schema_mismatch(&schema))
+                    };
+                    if let Some(mismatch) = mismatch {
+                        debug!(%actor_id, ?versions, "quarantining change: {mismatch}");
+                        if agent.quarantine().push(change, src, mismatch).is_err() {
+                            counter!("corro.changes.skipped", "reason" => "quarantine_full")
+                                .increment(1);
+                            warn!(%actor_id, ?versions, "quarantine is full, dropping change");
+                        }
+                        continue;
+                    }
+
+                    // bound the chunks buffered while waiting for the rest of
+                    // their version, sync will bring the dropped ones back
+                    if !change.is_complete() {
+                        let version = versions.start();
+                        if booked_write.get_partial(&version).is_none()
+                            && !new_partials.contains(&version)
+                        {
+                            if booked_write.partials.len() + new_partials.len()
+                                >= max_partial_versions
+                            {
+                                counter!("corro.changes.skipped", "reason" => "partials_full")
+                                    .increment(1);
+                                debug!(%actor_id, %version, "too many incomplete versions, dropping chunk");
+                                continue;
+                            }
+                            new_partials.insert(version);
+                        }
+                    }
+
+                    let key = (actor_id, versions, seqs);
+                    // only keep a copy when it might have to be quarantined
+                    let retained = agent
+                        .chunk_retries()
+                        .is_last_attempt(&key)
+                        .then(|| change.clone());
+
                     let (known, changeset) = {
                         match process_single_version(&agent, &mut tx, change) {
                             Ok(res) => {
@@ -846,6 +1283,15 @@ pub async fn process_multiple_changes(
                             }
                             Err(e) => {
                                 error!("error processing single version: {e}");
+                                if agent.chunk_retries().record_failure(&key) {
+                                    if let Some(change) = retained {
+                                        to_quarantine.push((
+                                            change,
+                                            agent.chunk_retries().failures(&key),
+                                            e.to_string(),
+                                        ));
+                                    }
+                                }
                                 if e.sqlite_error_code().is_some_and(|code| {
                                     code != rusqlite::ErrorCode::DiskFull
                                         && code != rusqlite::ErrorCode::OperationInterrupted
@@ -877,6 +1323,7 @@ pub async fn process_multiple_changes(
                     known
                 };
 
+                applied += 1;
                 debug!(%actor_id, self_actor_id = %agent.actor_id(), ?versions, "got known to insert: {known:?}");
                 let partial = match known {
                     KnownDbVersion::Partial(partial) => Some(partial),
@@ -927,8 +1374,7 @@ pub async fn process_multiple_changes(
                 &tx,
                 processed
                     .iter()
-                    .map(|(versions, _)| versions.into())
-                    .collect(),
+                    .map(|(versions, _)| RangeInclusive::from(versions)),
             )
             .map_err(|source| ChangeError::Rusqlite {
                 source,
@@ -963,6 +1409,19 @@ pub async fn process_multiple_changes(
             warn!("process_multiple_changes: commiting transaction took too long - {elapsed:?}");
         }
 
+        if let (Some(before), Ok(after)) = (rows_before, total_changes(&conn)) {
+            let amplification = WriteAmplification {
+                changes_applied: changesets
+                    .iter()
+                    .map(|(_, changeset, _, _)| changeset.len() as u64)
+                    .sum(),
+                rows_changed: after.saturating_sub(before),
+            };
+            if let Some(ratio) = amplification.write_amplification() {
+                histogram!("corro.agent.changes.write_amplification").record(ratio);
+            }
+        }
+
         for (_, changeset, _, _) in changesets.iter() {
             if let Some(ts) = changeset.ts() {
                 let dur = (agent.clock().new_timestamp().get_time() - ts.0).to_duration();
@@ -1011,6 +1470,8 @@ pub async fn process_multiple_changes(
                     } else {
                         debug!(%actor_id, %version, "still have {gaps_count} gaps in partially buffered seqs: {:?}", seqs.gaps(&full_seqs_range).collect::<Vec<_>>());
                     }
+                } else {
+                    agent.chunk_retries().booked(actor_id, versions);
                 }
             }
         }
@@ -1026,24 +1487,59 @@ pub async fn process_multiple_changes(
             warn!("process_multiple_changes: commiting snapshots took too long - {elapsed:?}");
         }
 
-        Ok::<_, ChangeError>(changesets)
-    })?;
+        Ok::<_, ChangeError>((changesets, applied))
+    });
+
+    for (change, attempts, error) in to_quarantine {
+        let key = (change.actor_id, change.versions(), change.seqs());
+        match block_in_place(|| insert_quarantined_chunk(&conn, &change, attempts, &error)) {
+            Ok(id) => {
+                agent.chunk_retries().quarantine(key);
+                counter!("corro.chunk.quarantined").increment(1);
+                warn!(actor_id = %change.actor_id, versions = ?key.1, seqs = ?key.2, "quarantined chunk {id} after {attempts} failed attempts: {error}");
+            }
+            Err(e) => {
+                error!(actor_id = %change.actor_id, versions = ?key.1, "could not quarantine chunk: {e}");
+            }
+        }
+    }
+
+    let (changesets, applied) = res?;
+
+    // observers may be slow, don't hold the writer for them
+    drop(conn);
 
     let mut change_chunk_size = 0;
 
     for (_actor_id, changeset, db_version, _src) in changesets {
         change_chunk_size += changeset.changes().len();
-        match_changes(agent.subs_manager(), changeset.changes(), db_version);
-        match_changes(agent.updates_manager(), changeset.changes(), db_version);
+        notify_applied_changes(&agent, changeset.changes(), db_version);
     }
 
     histogram!("corro.agent.changes.processing.time.seconds", "source" => "remote")
         .record(start.elapsed());
     histogram!("corro.agent.changes.processing.chunk_size").record(change_chunk_size as f64);
 
+    let span = tracing::Span::current();
+    span.record("applied", applied);
+    span.record("skipped", total - applied);
+    span.record("duration", start.elapsed().as_secs_f64());
+
     Ok(())
 }
 
+/// Feeds changes applied as `db_version` to subscriptions, updates and
+/// change observers, and drops the recent local versions they overwrote.
+pub fn notify_applied_changes(agent: &Agent, changes: &[Change], db_version: CrsqlDbVersion) {
+    agent.recent_changes().invalidate(changes);
+    match_changes(agent.subs_manager(), changes, db_version);
+    match_changes(agent.updates_manager(), changes, db_version);
+    if !agent.change_observers().is_empty() {
+        // only blocks when the observers are far behind
+        block_in_place(|| agent.change_observers().enqueue(changes.to_vec()));
+    }
+}
+
 #[tracing::instrument(skip(tx), err)]
 pub fn process_empty_version<T: Deref<Target = rusqlite::Connection> + Committable>(
     tx: &InterruptibleTransaction<T>,
@@ -1226,35 +1722,48 @@ pub fn process_complete_version<T: Deref<Target = rusqlite::Connection> + Commit
     let mut last_rows_impacted = 0;
 
     let mut changes_per_table = BTreeMap::new();
+    let log_conflicts = agent.config().log.conflicts;
+    let check_conflict_winners = agent.config().db.check_conflict_winners;
+    // time spent applying changes, per table
+    let mut apply_durations: BTreeMap<TableName, Duration> = BTreeMap::new();
 
     for change in changes {
         trace!("inserting change! {change:?}");
 
-        sp.prepare_cached(
-            r#"
-                INSERT INTO crsql_changes
-                    ("table", pk, cid, val, col_version, db_version, site_id, cl, seq, ts)
-                VALUES
-                    (?,       ?,  ?,   ?,   ?,           ?,          ?,       ?,  ?, ?)
-            "#,
-        )?
-        .execute(params![
-            change.table.as_str(),
-            change.pk,
-            change.cid.as_str(),
-            &change.val,
-            change.col_version,
-            change.db_version,
-            &change.site_id,
-            change.cl,
-            // increment the seq by the start_seq or else we'll have multiple change rows with the same seq
-            change.seq,
-            ts,
-        ])?;
+        let existing = if log_conflicts || check_conflict_winners {
+            ColumnClock::read(sp, &change)?
+        } else {
+            None
+        };
+
+        let start = Instant::now();
+        insert_change(sp, &change, ts)?;
         let rows_impacted: i64 = sp
             .prepare_cached("SELECT crsql_rows_impacted()")?
             .query_row((), |row| row.get(0))?;
 
+        if let Some(existing) = existing {
+            // the incoming change only touches the table when it won
+            let kept = if rows_impacted > last_rows_impacted {
+                ConflictWinner::Incoming
+            } else {
+                ConflictWinner::Existing
+            };
+            if check_conflict_winners {
+                assert_conflict_winner(&change, &existing, kept);
+            }
+            if log_conflicts {
+                log_conflict(&change, existing, kept);
+            }
+        }
+
+        match apply_durations.get_mut(&change.table) {
+            Some(elapsed) => *elapsed += start.elapsed(),
+            None => {
+                apply_durations.insert(change.table.clone(), start.elapsed());
+            }
+        }
+
         if rows_impacted > last_rows_impacted {
             trace!("inserted the change into crsql_changes");
             impactful_changeset.push(change);
@@ -1269,6 +1778,11 @@ pub fn process_complete_version<T: Deref<Target = rusqlite::Connection> + Commit
         last_rows_impacted = rows_impacted;
     }
 
+    for (table_name, elapsed) in apply_durations {
+        histogram!("corro.apply.duration.seconds", "table" => table_name.to_string())
+            .record(elapsed.as_secs_f64());
+    }
+
     let (known_version, new_changeset) = if impactful_changeset.is_empty() {
         (
             KnownDbVersion::Cleared,
@@ -1297,6 +1811,107 @@ pub fn process_complete_version<T: Deref<Target = rusqlite::Connection> + Commit
     Ok::<_, rusqlite::Error>((known_version, new_changeset, changes_per_table))
 }
 
+/// Clock of a single column, as exposed by `crsql_changes`
+#[derive(Debug, Clone, PartialEq)]
+struct ColumnClock {
+    cl: i64,
+    col_version: i64,
+    site_id: ActorId,
+    db_version: CrsqlDbVersion,
+    val: SqliteValue,
+}
+
+impl ColumnClock {
+    fn read(conn: &Connection, change: &Change) -> rusqlite::Result<Option<Self>> {
+        conn.prepare_cached(
+            r#"SELECT cl, col_version, site_id, db_version, val FROM crsql_changes WHERE "table" = ? AND pk = ? AND cid = ?"#,
+        )?
+        .query_row(
+            params![change.table.as_str(), change.pk, change.cid.as_str()],
+            |row| {
+                Ok(Self {
+                    cl: row.get(0)?,
+                    col_version: row.get(1)?,
+                    site_id: row.get(2)?,
+                    db_version: row.get(3)?,
+                    val: row.get(4)?,
+                })
+            },
+        )
+        .optional()
+    }
+
+    /// The change to the same cell this clock was set by
+    fn to_change(&self, change: &Change) -> Change {
+        Change {
+            val: self.val.clone(),
+            col_version: self.col_version,
+            db_version: self.db_version,
+            site_id: self.site_id.to_bytes(),
+            cl: self.cl,
+            ..change.clone()
+        }
+    }
+}
+
+/// Checks that cr-sqlite kept the side of a conflict [`conflict_winner`]
+/// predicts, row deletions aside.
+fn assert_conflict_winner(change: &Change, existing: &ColumnClock, kept: ConflictWinner) {
+    if existing.site_id.as_bytes() == &change.site_id || change.cid.is_crsql_sentinel() {
+        return;
+    }
+
+    let (expected, reason) = conflict_winner(&existing.to_change(change), change);
+    assert_eq!(
+        kept, expected,
+        "cr-sqlite kept the {kept:?} change to {}.{} over the one expected to win by {reason:?}",
+        change.table, change.cid,
+    );
+}
+
+// bounds the conflict log when a lot of conflicting changes arrive at once
+static CONFLICT_LOG_LIMITER: Lazy<DefaultDirectRateLimiter> =
+    Lazy::new(|| RateLimiter::direct(Quota::per_second(NonZeroU32::new(100).unwrap())));
+
+/// Logs which of `change` and the `existing` value of the same column, from
+/// another actor, was `kept` once `change` was inserted, and the first clock
+/// field that differed between them (the order cr-sqlite compares them in).
+fn log_conflict(change: &Change, existing: ColumnClock, kept: ConflictWinner) {
+    if existing.site_id.as_bytes() == &change.site_id {
+        // a newer write from the same actor, not a conflict
+        return;
+    }
+
+    if CONFLICT_LOG_LIMITER.check().is_err() {
+        counter!("corro.conflicts.log.suppressed").increment(1);
+        return;
+    }
+
+    let (_, reason) = conflict_winner(&existing.to_change(change), change);
+    let reason: &'static str = reason.into();
+    let winner: &'static str = kept.into();
+
+    info!(
+        target: "corro::conflicts",
+        table = %change.table,
+        pk = %hex::encode(&change.pk),
+        cid = %change.cid,
+        winner,
+        reason,
+        existing_site_id = %existing.site_id,
+        existing_db_version = %existing.db_version,
+        existing_col_version = existing.col_version,
+        existing_cl = existing.cl,
+        incoming_site_id = %ActorId::from_bytes(change.site_id),
+        incoming_db_version = %change.db_version,
+        incoming_col_version = change.col_version,
+        incoming_cl = change.cl,
+        "conflicting change on {}.{}, {winner} value won by {reason}",
+        change.table,
+        change.cid,
+    );
+}
+
 pub fn check_buffered_meta_to_clear(
     conn: &Connection,
     actor_id: ActorId,
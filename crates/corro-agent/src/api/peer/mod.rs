@@ -1,22 +1,28 @@
 use std::cmp;
 use std::collections::{HashMap, VecDeque};
 use std::net::SocketAddr;
+use std::ops::RangeInclusive;
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
 use antithesis_sdk::assert_sometimes;
 use bytes::{BufMut, BytesMut};
 use corro_types::actor::ClusterId;
-use corro_types::agent::{Agent, SplitPool};
+use corro_types::agent::{Agent, ChangeError, SplitPool};
+use corro_types::api::{with_max_value_bytes, TableName};
 use corro_types::base::{CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeq, CrsqlSeqRange};
 use corro_types::broadcast::{
     BiPayload, BiPayloadV1, ChangeSource, ChangeV1, Changeset, Timestamp,
 };
-use corro_types::change::{row_to_change, Change, ChunkedChanges};
-use corro_types::config::{GossipConfig, TlsClientConfig};
+use corro_types::change::{
+    insert_change, row_to_change, Change, ChunkSizeTuner, ChunkedChanges, RecentChanges,
+    TableWeights,
+};
+use corro_types::config::{ExcludedColumns, GossipConfig, SeqMode, TlsClientConfig};
 use corro_types::sync::{
-    generate_sync, SyncMessage, SyncMessageEncodeError, SyncMessageV1, SyncNeedV1, SyncRejectionV1,
-    SyncRequestV1, SyncStateV1, SyncTraceContextV1,
+    generate_sync, negotiate_sync_encoding, ClearedSeqsV1, SyncEncoding, SyncMessage,
+    SyncMessageEncodeError, SyncMessageV1, SyncNeedV1, SyncRejectionV1, SyncRequestV1, SyncStateV1,
+    SyncTableRequestV1, SyncTraceContextV1, VersionsDigestV1, SUPPORTED_SYNC_ENCODINGS,
 };
 use futures::stream::FuturesUnordered;
 use futures::{Future, Stream, TryFutureExt, TryStreamExt};
@@ -24,21 +30,25 @@ use itertools::Itertools;
 use metrics::counter;
 use quinn::{RecvStream, SendStream, WriteError};
 use rangemap::RangeInclusiveSet;
-use rusqlite::{named_params, Connection};
+use rusqlite::{named_params, params, Connection};
 use speedy::Writable;
 use std::string::String;
 use tokio::io::AsyncWriteExt;
 use tokio::sync::mpsc::{self, unbounded_channel, Sender};
+use tokio::sync::Semaphore;
 use tokio::task::block_in_place;
 use tokio::time::timeout;
 use tokio_stream::wrappers::{ReceiverStream, UnboundedReceiverStream};
 use tokio_stream::StreamExt as TokioStreamExt;
 // use tokio_stream::StreamExt as TokioStreamExt;
-use tokio_util::codec::{Encoder, FramedRead, LengthDelimitedCodec};
-use tracing::{debug, error, info, info_span, trace, warn, Instrument};
+use tokio_util::codec::{Encoder, FramedRead, LengthDelimitedCodec, LengthDelimitedCodecError};
+use tracing::{debug, debug_span, error, info, info_span, trace, warn, Instrument};
 use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-use crate::agent::SyncRecvError;
+use crate::agent::{
+    util::{check_seqs, notify_applied_changes},
+    versions_digests, SyncRecvError, MAX_DIGEST_RANGES,
+};
 use crate::transport::{Transport, TransportError};
 
 use corro_types::{actor::ActorId, agent::Bookie};
@@ -361,23 +371,26 @@ impl rustls::client::ServerCertVerifier for SkipServerVerification {
     }
 }
 
-const MAX_CHANGES_BYTES_PER_MESSAGE: usize = 8 * 1024;
-const MIN_CHANGES_BYTES_PER_MESSAGE: usize = 1024;
-
-const ADAPT_CHUNK_SIZE_THRESHOLD: Duration = Duration::from_millis(500);
-
 #[allow(clippy::too_many_arguments)]
 fn handle_need(
     conn: &mut Connection,
     actor_id: ActorId,
     need: SyncNeedV1,
+    tables: Option<&[TableName]>,
+    excluded: &ExcludedColumns,
     sender: &Sender<SyncMessage>,
+    chunk_reads: &Arc<Semaphore>,
+    recent_changes: Option<&RecentChanges>,
+    tuner: &ChunkSizeTuner,
 ) -> eyre::Result<()> {
-    debug!(%actor_id, "handle known versions! need: {need:?}");
+    debug!(%actor_id, "handle known versions! need: {need:?}, tables: {tables:?}");
 
     let mut empties: RangeInclusiveSet<CrsqlDbVersion> = RangeInclusiveSet::new();
 
     assert_sometimes!(true, "Corrosion handles sync requests from other nodes");
+    // the tables of a table-scoped sync, filtered in the queries below
+    let tables_filter = tables.map(serde_json::to_string).transpose()?;
+
     // this is a read transaction!
     let tx = conn.transaction()?;
 
@@ -419,12 +432,41 @@ fn handle_need(
                 let ts: Timestamp = row.get(2)?;
                 debug!(%actor_id, ?version, %ts, "not empty");
 
+                if let Some(recent_changes) = recent_changes {
+                    if let Some(cached) = recent_changes.get(actor_id, version) {
+                        counter!("corro.sync.cache.hit.total").increment(1);
+                        trace!(%actor_id, %version, "serving version from recent changes");
+                        let changes = cached
+                            .changes
+                            .into_iter()
+                            .map(Ok)
+                            .filter(in_scope(tables, excluded));
+                        send_change_chunks(
+                            sender,
+                            ChunkedChanges::new(
+                                changes,
+                                CrsqlSeq(0),
+                                cached.last_seq,
+                                tuner.size(),
+                            ),
+                            actor_id,
+                            version,
+                            cached.last_seq,
+                            cached.ts,
+                            tuner,
+                        )?;
+                        continue;
+                    }
+                    counter!("corro.sync.cache.miss.total").increment(1);
+                }
+
                 let mut prepped = tx.prepare_cached(
                     r#"
                         SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl, ts
                             FROM crsql_changes
                             WHERE site_id = :actor_id
                               AND db_version = :version
+                              AND (:tables IS NULL OR "table" IN (SELECT value FROM json_each(:tables)))
                             ORDER BY seq ASC
                     "#,
                 )?;
@@ -432,7 +474,8 @@ fn handle_need(
                 let rows = prepped.query_map(
                     named_params! {
                         ":actor_id": actor_id,
-                        ":version": version
+                        ":version": version,
+                        ":tables": tables_filter,
                     },
                     row_to_change,
                 )?;
@@ -441,11 +484,18 @@ fn handle_need(
 
                 send_change_chunks(
                     sender,
-                    ChunkedChanges::new(rows, CrsqlSeq(0), last_seq, MAX_CHANGES_BYTES_PER_MESSAGE),
+                    ChunkedChanges::new(
+                        rows.filter(in_scope(tables, excluded)),
+                        CrsqlSeq(0),
+                        last_seq,
+                        tuner.size(),
+                    )
+                    .with_read_permits(chunk_reads.clone()),
                     actor_id,
                     version,
                     last_seq,
                     ts,
+                    tuner,
                 )?;
             }
 
@@ -501,6 +551,7 @@ fn handle_need(
                                     WHERE site_id = :actor_id
                                         AND db_version = :db_version
                                         AND seq BETWEEN :start_seq AND :end_seq
+                                        AND (:tables IS NULL OR "table" IN (SELECT value FROM json_each(:tables)))
                                     ORDER BY seq ASC
                             "#,
                         )?;
@@ -513,7 +564,8 @@ fn handle_need(
                                 ":actor_id": actor_id,
                                 ":db_version": version,
                                 ":start_seq": start_seq,
-                                ":end_seq": end_seq
+                                ":end_seq": end_seq,
+                                ":tables": tables_filter,
                             },
                             row_to_change,
                         )?;
@@ -521,15 +573,17 @@ fn handle_need(
                         send_change_chunks(
                             sender,
                             ChunkedChanges::new(
-                                rows,
+                                rows.filter(in_scope(tables, excluded)),
                                 start_seq,
                                 end_seq,
-                                MAX_CHANGES_BYTES_PER_MESSAGE,
-                            ),
+                                tuner.size(),
+                            )
+                            .with_read_permits(chunk_reads.clone()),
                             actor_id,
                             version,
                             last_seq,
                             ts,
+                            tuner,
                         )?;
                     }
                 }
@@ -559,6 +613,7 @@ fn handle_need(
                                     WHERE site_id = :actor_id
                                       AND db_version = :version
                                       AND seq BETWEEN :start AND :end
+                                      AND (:tables IS NULL OR "table" IN (SELECT value FROM json_each(:tables)))
                                     ORDER BY seq ASC
                             "#,
                         )?;
@@ -569,6 +624,7 @@ fn handle_need(
                                 ":version": version,
                                 ":start": range_needed.start(),
                                 ":end": range_needed.end(),
+                                ":tables": tables_filter,
                             },
                             row_to_change,
                         )?;
@@ -576,15 +632,17 @@ fn handle_need(
                         send_change_chunks(
                             sender,
                             ChunkedChanges::new(
-                                rows,
+                                rows.filter(in_scope(tables, excluded)),
                                 range_needed.start(),
                                 range_needed.end(),
-                                MAX_CHANGES_BYTES_PER_MESSAGE,
-                            ),
+                                tuner.size(),
+                            )
+                            .with_read_permits(chunk_reads.clone()),
                             actor_id,
                             version,
                             last_seq,
                             ts,
+                            tuner,
                         )?;
                     }
                 }
@@ -675,6 +733,7 @@ fn handle_need(
                                         WHERE site_id = :actor_id
                                             AND db_version = :version
                                             AND seq BETWEEN :start_seq AND :end_seq
+                                            AND (:tables IS NULL OR "table" IN (SELECT value FROM json_each(:tables)))
                                         ORDER BY seq ASC
                                 "#,
                             )?;
@@ -690,7 +749,8 @@ fn handle_need(
                                         ":actor_id": actor_id,
                                         ":version": version,
                                         ":start_seq": start_seq,
-                                        ":end_seq": end_seq
+                                        ":end_seq": end_seq,
+                                        ":tables": tables_filter,
                                     },
                                     row_to_change,
                                 )?;
@@ -698,15 +758,17 @@ fn handle_need(
                                 send_change_chunks(
                                     sender,
                                     ChunkedChanges::new(
-                                        rows,
+                                        rows.filter(in_scope(tables, excluded)),
                                         start_seq,
                                         end_seq,
-                                        MAX_CHANGES_BYTES_PER_MESSAGE,
-                                    ),
+                                        tuner.size(),
+                                    )
+                                    .with_read_permits(chunk_reads.clone()),
                                     actor_id,
                                     version,
                                     last_seq,
                                     ts,
+                                    tuner,
                                 )?;
                             }
                         }
@@ -734,6 +796,31 @@ fn handle_need(
     Ok(())
 }
 
+fn chunk_span(changes: &[Change]) -> tracing::Span {
+    debug_span!(
+        "sync_chunk",
+        changes = changes.len(),
+        bytes = changes
+            .iter()
+            .map(Change::estimated_byte_size)
+            .sum::<usize>()
+    )
+}
+
+/// Keeps only the changes for the requested tables of a table-scoped sync,
+/// minus the excluded columns
+fn in_scope<'a>(
+    tables: Option<&'a [TableName]>,
+    excluded: &'a ExcludedColumns,
+) -> impl FnMut(&rusqlite::Result<Change>) -> bool + 'a {
+    move |res| match (tables, res) {
+        (_, Ok(change)) if excluded.excludes(&change.table, &change.cid) => false,
+        (Some(tables), Ok(change)) => tables.contains(&change.table),
+        _ => true,
+    }
+}
+
+#[tracing::instrument(skip_all, fields(%actor_id, %version), level = "debug")]
 fn send_change_chunks<I: Iterator<Item = rusqlite::Result<Change>>>(
     sender: &Sender<SyncMessage>,
     mut chunked: ChunkedChanges<I>,
@@ -741,8 +828,8 @@ fn send_change_chunks<I: Iterator<Item = rusqlite::Result<Change>>>(
     version: CrsqlDbVersion,
     last_seq: CrsqlSeq,
     ts: Timestamp,
+    tuner: &ChunkSizeTuner,
 ) -> eyre::Result<()> {
-    let mut max_buf_size = chunked.max_buf_size();
     loop {
         if sender.is_closed() {
             eyre::bail!("sync message sender channel is closed");
@@ -750,11 +837,13 @@ fn send_change_chunks<I: Iterator<Item = rusqlite::Result<Change>>>(
         match chunked.next() {
             Some(Ok((changes, seqs))) => {
                 let start = Instant::now();
+                let bytes = changes.iter().map(Change::estimated_byte_size).sum();
 
                 if changes.is_empty() && seqs.start() == CrsqlSeq(0) && seqs.end() == last_seq {
                     warn!(%actor_id, %version, "got an empty changes we should've had");
                     return Ok(());
                 } else {
+                    let _span = chunk_span(&changes).entered();
                     sender.blocking_send(SyncMessage::V1(SyncMessageV1::Changeset(ChangeV1 {
                         actor_id,
                         changeset: Changeset::Full {
@@ -773,15 +862,15 @@ fn send_change_chunks<I: Iterator<Item = rusqlite::Result<Change>>>(
                     eyre::bail!("time out: peer is too slow");
                 }
 
-                if elapsed > ADAPT_CHUNK_SIZE_THRESHOLD {
-                    if max_buf_size <= MIN_CHANGES_BYTES_PER_MESSAGE {
-                        eyre::bail!("time out: peer is too slow even after reducing throughput");
-                    }
-
-                    max_buf_size /= 2;
-                    debug!("adapting max chunk size to {max_buf_size} bytes");
+                let max_buf_size = chunked.max_buf_size();
+                if elapsed > tuner.target() && max_buf_size <= tuner.min() {
+                    eyre::bail!("time out: peer is too slow even after reducing throughput");
+                }
 
-                    chunked.set_max_buf_size(max_buf_size);
+                let next_buf_size = tuner.observe(bytes, elapsed);
+                if next_buf_size != max_buf_size {
+                    debug!("adapting max chunk size to {next_buf_size} bytes");
+                    chunked.set_max_buf_size(next_buf_size);
                 }
             }
             Some(Err(e)) => {
@@ -797,11 +886,17 @@ fn send_change_chunks<I: Iterator<Item = rusqlite::Result<Change>>>(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn process_sync(
     pool: SplitPool,
     bookie: Bookie,
     sender: Sender<SyncMessage>,
-    recv: mpsc::Receiver<SyncRequestV1>,
+    recv: mpsc::Receiver<(Option<Arc<[TableName]>>, SyncRequestV1)>,
+    weights: Arc<TableWeights>,
+    excluded: Arc<ExcludedColumns>,
+    chunk_reads: Arc<Semaphore>,
+    recent_changes: RecentChanges,
+    tuner: ChunkSizeTuner,
 ) -> eyre::Result<()> {
     let chunked_reqs = ReceiverStream::new(recv).chunks_timeout(10, Duration::from_millis(500));
     tokio::pin!(chunked_reqs);
@@ -817,7 +912,7 @@ async fn process_sync(
         );
     loop {
         enum Branch {
-            Reqs(Vec<Vec<(ActorId, Vec<SyncNeedV1>)>>),
+            Reqs(Vec<(Option<Arc<[TableName]>>, SyncRequestV1)>),
         }
 
         let branch = tokio::select! {
@@ -840,13 +935,17 @@ async fn process_sync(
             Branch::Reqs(reqs) => {
                 let agg = reqs
                     .into_iter()
-                    .flatten()
-                    .group_by(|req| req.0)
+                    .flat_map(|(tables, req)| {
+                        req.into_iter()
+                            .map(move |(actor_id, needs)| ((actor_id, tables.clone()), needs))
+                    })
+                    .group_by(|(key, _)| key.clone())
                     .into_iter()
-                    .map(|(actor_id, reqs)| (actor_id, reqs.flat_map(|(_, reqs)| reqs).collect()))
-                    .collect::<Vec<(ActorId, Vec<SyncNeedV1>)>>();
+                    .map(|(key, reqs)| (key, reqs.flat_map(|(_, needs)| needs).collect()))
+                    .collect::<Vec<((ActorId, Option<Arc<[TableName]>>), Vec<SyncNeedV1>)>>();
 
-                for (actor_id, needs) in agg {
+                let mut jobs = vec![];
+                for ((actor_id, tables), needs) in agg {
                     let booked = bookie
                         .read::<&str, _>("process_sync get actor", None)
                         .await
@@ -882,20 +981,57 @@ async fn process_sync(
                             }
                         }
 
-                        let pool = pool.clone();
-                        let sender = sender.clone();
-
-                        let fut = Box::pin(async move {
-                            let mut conn = pool.read().await?;
-
-                            block_in_place(|| handle_need(&mut conn, actor_id, need, &sender))?;
+                        jobs.push((actor_id, tables.clone(), need));
+                    }
+                }
 
-                            Ok(())
-                        });
+                // needs of heavier tables go first, the others still make
+                // progress
+                let jobs = if weights.is_empty() {
+                    jobs
+                } else {
+                    let conn = pool.read().await?;
+                    let weighted = block_in_place(|| {
+                        jobs.into_iter()
+                            .map(|job| {
+                                let weight = need_weight(&conn, &weights, job.0, &job.2)?;
+                                Ok((job, weight))
+                            })
+                            .collect::<rusqlite::Result<Vec<_>>>()
+                    })?;
+                    weights.interleave(weighted).collect()
+                };
 
-                        if job_tx.send(fut).is_err() {
-                            eyre::bail!("could not send into job channel");
-                        }
+                for (actor_id, tables, need) in jobs {
+                    let pool = pool.clone();
+                    let sender = sender.clone();
+                    let chunk_reads = chunk_reads.clone();
+                    let recent_changes = recent_changes.clone();
+                    let excluded = excluded.clone();
+                    let tuner = tuner.clone();
+
+                    let fut = Box::pin(async move {
+                        let mut conn = pool.read().await?;
+
+                        block_in_place(|| {
+                            handle_need(
+                                &mut conn,
+                                actor_id,
+                                need,
+                                tables.as_deref(),
+                                &excluded,
+                                &sender,
+                                &chunk_reads,
+                                Some(&recent_changes),
+                                &tuner,
+                            )
+                        })?;
+
+                        Ok(())
+                    });
+
+                    if job_tx.send(fut).is_err() {
+                        eyre::bail!("could not send into job channel");
                     }
                 }
             }
@@ -912,14 +1048,46 @@ async fn process_sync(
     Ok(())
 }
 
+/// Weight of the versions `need` asks for, from the tables they changed
+fn need_weight(
+    conn: &Connection,
+    weights: &TableWeights,
+    actor_id: ActorId,
+    need: &SyncNeedV1,
+) -> rusqlite::Result<u32> {
+    let versions = match need {
+        SyncNeedV1::Full { versions } => *versions,
+        SyncNeedV1::Partial { version, .. } => CrsqlDbVersionRange::single(*version),
+        SyncNeedV1::Empty { .. } => return Ok(1),
+    };
+    let tables = conn
+        .prepare_cached(
+            r#"SELECT DISTINCT "table" FROM crsql_changes WHERE site_id = ? AND db_version >= ? AND db_version <= ?"#,
+        )?
+        .query_map(
+            params![actor_id, versions.start(), versions.end()],
+            |row| row.get::<_, TableName>(0),
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    Ok(weights.weight(&tables))
+}
+
+/// Encodes `msg` framed with `encoding`, bare when there's none (handshake
+/// messages and older peers)
 fn encode_sync_msg(
     codec: &mut LengthDelimitedCodec,
     encode_buf: &mut BytesMut,
     send_buf: &mut BytesMut,
     msg: SyncMessage,
+    encoding: Option<SyncEncoding>,
 ) -> Result<(), SyncSendError> {
-    msg.write_to_stream(encode_buf.writer())
-        .map_err(SyncMessageEncodeError::from)?;
+    match encoding {
+        Some(encoding) => msg.write_framed(encoding, encode_buf.writer())?,
+        None => msg
+            .write_to_stream(encode_buf.writer())
+            .map_err(SyncMessageEncodeError::from)?,
+    }
 
     let data = encode_buf.split().freeze();
     trace!("encoded sync message, len: {}", data.len());
@@ -959,9 +1127,10 @@ async fn encode_write_sync_msg(
     encode_buf: &mut BytesMut,
     send_buf: &mut BytesMut,
     msg: SyncMessage,
+    encoding: Option<SyncEncoding>,
     write: &mut SendStream,
 ) -> Result<(), SyncSendError> {
-    encode_sync_msg(codec, encode_buf, send_buf, msg)?;
+    encode_sync_msg(codec, encode_buf, send_buf, msg, encoding)?;
 
     write_buf(send_buf, write)
         .await
@@ -980,23 +1149,56 @@ async fn write_buf(send_buf: &mut BytesMut, write: &mut SendStream) -> Result<()
 #[tracing::instrument(skip(read), fields(buf_size = tracing::field::Empty), err)]
 pub async fn read_sync_msg<R: Stream<Item = std::io::Result<BytesMut>> + Unpin>(
     read: &mut R,
+    encoding: Option<SyncEncoding>,
+    max_value_bytes: Option<usize>,
 ) -> Result<Option<SyncMessage>, SyncRecvError> {
     match read.next().await {
         Some(buf_res) => match buf_res {
             Ok(mut buf) => {
                 counter!("corro.sync.chunk.recv.bytes").increment(buf.len() as u64);
                 tracing::Span::current().record("buf_size", buf.len());
-                match SyncMessage::from_buf(&mut buf) {
+                let res = with_max_value_bytes(max_value_bytes, || match encoding {
+                    Some(_) => SyncMessage::from_framed(&buf, SUPPORTED_SYNC_ENCODINGS),
+                    None => SyncMessage::from_buf(&mut buf),
+                });
+                match res {
                     Ok(msg) => Ok(Some(msg)),
                     Err(e) => Err(SyncRecvError::from(e)),
                 }
             }
+            // the codec rejects oversized frames from their length prefix,
+            // before buffering them
+            Err(e)
+                if e.get_ref()
+                    .is_some_and(|inner| inner.is::<LengthDelimitedCodecError>()) =>
+            {
+                Err(SyncRecvError::FrameTooLarge)
+            }
             Err(e) => Err(SyncRecvError::from(e)),
         },
         None => Ok(None),
     }
 }
 
+/// Compares a peer's schema digest with ours, logging and recording a metric
+/// for the differing tables. Never fails the sync: changes for tables with
+/// matching definitions can still be applied.
+fn check_schema_drift(agent: &Agent, actor_id: ActorId, their_sync_state: &SyncStateV1) {
+    // older peers don't send a digest
+    let Some(theirs) = their_sync_state.schema_digest.as_ref() else {
+        return;
+    };
+
+    let ours = agent.schema().read().digest();
+    if ours.hash() == theirs.hash() {
+        return;
+    }
+
+    let tables = ours.mismatched_tables(theirs);
+    warn!(%actor_id, "schema mismatch with peer, differing tables: {tables:?}");
+    counter!("corro.schema.mismatch", "peer" => actor_id.to_string()).increment(1);
+}
+
 #[tracing::instrument(skip_all, err)]
 pub async fn parallel_sync(
     agent: &Agent,
@@ -1004,6 +1206,60 @@ pub async fn parallel_sync(
     members: Vec<(ActorId, SocketAddr)>,
     our_sync_state: SyncStateV1,
 ) -> Result<usize, SyncError> {
+    sync_with_members(agent, transport, members, our_sync_state, None).await
+}
+
+/// Syncs only the changes of `tables`. Their versions are not booked since
+/// changes for the other tables are missing, they're recorded as table-scoped
+/// and a complete sync still needs to fetch them.
+pub async fn parallel_sync_tables(
+    agent: &Agent,
+    transport: &Transport,
+    members: Vec<(ActorId, SocketAddr)>,
+    our_sync_state: SyncStateV1,
+    tables: Vec<TableName>,
+) -> Result<usize, SyncError> {
+    sync_with_members(
+        agent,
+        transport,
+        members,
+        our_sync_state,
+        Some(tables.into()),
+    )
+    .await
+}
+
+async fn sync_with_members(
+    agent: &Agent,
+    transport: &Transport,
+    mut members: Vec<(ActorId, SocketAddr)>,
+    our_sync_state: SyncStateV1,
+    tables: Option<Arc<[TableName]>>,
+) -> Result<usize, SyncError> {
+    // one permit per session, held until every session of this sync is done
+    // since their needs are split between them. waits for the first one, the
+    // members we can't get a permit for are left to the next sync.
+    let mut permits = vec![];
+    if !members.is_empty() {
+        permits.push(agent.limits().outbound_sync.acquire().await);
+        while permits.len() < members.len() {
+            match agent.limits().outbound_sync.try_acquire() {
+                Some(permit) => permits.push(permit),
+                None => break,
+            }
+        }
+        if permits.len() < members.len() {
+            debug!(
+                "syncing with {} of {} members, no more sync permits",
+                permits.len(),
+                members.len()
+            );
+            members.truncate(permits.len());
+        }
+    }
+
+    let max_value_bytes = agent.config().db.max_value_bytes;
+
     trace!(
         self_actor_id = %agent.actor_id(),
         "parallel syncing w/ {}",
@@ -1026,19 +1282,19 @@ pub async fn parallel_sync(
                 *actor_id,
                 *addr,
                 async {
-                    let mut codec = LengthDelimitedCodec::builder().max_frame_length(100 * 1_024 * 1_024).new_codec();
+                    let mut codec = LengthDelimitedCodec::builder().max_frame_length(agent.config().perf.max_frame_bytes).new_codec();
                     let mut send_buf = BytesMut::new();
                     let mut encode_buf = BytesMut::new();
 
                     let actor_id = *actor_id;
                     let (mut tx, rx) = transport.open_bi(*addr).await?;
-                    let mut read = FramedRead::new(rx, LengthDelimitedCodec::builder().max_frame_length(100 * 1_024 * 1_024).new_codec());
+                    let mut read = FramedRead::new(rx, LengthDelimitedCodec::builder().max_frame_length(agent.config().perf.max_frame_bytes).new_codec());
 
                     encode_write_bipayload_msg(
                         &mut codec,
                         &mut encode_buf,
                         &mut send_buf,
-                        BiPayload::V1 {data: BiPayloadV1::SyncStart {actor_id: agent.actor_id(), trace_ctx}, cluster_id: agent.cluster_id()},
+                        BiPayload::V1 {data: BiPayloadV1::SyncStart {actor_id: agent.actor_id(), trace_ctx}, cluster_id: agent.cluster_id(), encodings: SUPPORTED_SYNC_ENCODINGS.to_vec()},
                         &mut tx,
                     ).instrument(info_span!("write_sync_start"))
                     .await?;
@@ -1050,6 +1306,7 @@ pub async fn parallel_sync(
                         &mut encode_buf,
                         &mut send_buf,
                         SyncMessage::V1(SyncMessageV1::Clock(agent.clock().new_timestamp().into())),
+                        None,
                         &mut tx,
                     ).instrument(info_span!("write_sync_clock"))
                     .await?;
@@ -1059,7 +1316,7 @@ pub async fn parallel_sync(
 
                     trace!(%actor_id, self_actor_id = %agent.actor_id(), "flushed sync payloads");
 
-                    let their_sync_state = match timeout(Duration::from_secs(2), read_sync_msg(&mut read)).instrument(info_span!("read_sync_state")).await.map_err(SyncRecvError::from)?? {
+                    let their_sync_state = match timeout(Duration::from_secs(2), read_sync_msg(&mut read, None, max_value_bytes)).instrument(info_span!("read_sync_state")).await.map_err(SyncRecvError::from)?? {
                         Some(SyncMessage::V1(SyncMessageV1::State(state))) => state,
                         Some(SyncMessage::V1(SyncMessageV1::Rejection(rejection))) => {
                             return Err(rejection.into())
@@ -1069,7 +1326,16 @@ pub async fn parallel_sync(
                     };
                     trace!(%actor_id, self_actor_id = %agent.actor_id(), "read state payload: {their_sync_state:?}");
 
-                    match timeout(Duration::from_secs(2), read_sync_msg(&mut read)).instrument(info_span!("read_sync_clock")).await.map_err(SyncRecvError::from)??  {
+                    check_schema_drift(agent, actor_id, &their_sync_state);
+                    if let Some(head) = their_sync_state.heads.get(&agent.actor_id()) {
+                        agent.check_own_version(*head, "sync").await;
+                    }
+                    agent.record_peer_sync_state(their_sync_state.clone());
+
+                    // everything after the state is in the encoding the server picked
+                    let encoding = their_sync_state.encoding;
+
+                    match timeout(Duration::from_secs(2), read_sync_msg(&mut read, encoding, max_value_bytes)).instrument(info_span!("read_sync_clock")).await.map_err(SyncRecvError::from)??  {
                         Some(SyncMessage::V1(SyncMessageV1::Clock(ts))) => {
                             match agent.update_clock_with_timestamp(actor_id, ts) {
                                 Ok(()) => (),
@@ -1085,11 +1351,17 @@ pub async fn parallel_sync(
 
                     counter!("corro.sync.client.member", "id" => actor_id.to_string(), "addr" => addr.to_string()).increment(1);
 
-                    let needs = our_sync_state.compute_available_needs(&their_sync_state);
+                    // older peers don't know table requests, don't send them any
+                    let needs = if tables.is_some() && !their_sync_state.table_requests {
+                        warn!(%actor_id, "peer does not support table-scoped sync, skipping it");
+                        Default::default()
+                    } else {
+                        our_sync_state.compute_available_needs(&their_sync_state)
+                    };
 
                     debug!(%actor_id, self_actor_id = %agent.actor_id(), "computed needs: {:?}, their_sync_state: {:?}", needs, their_sync_state);
 
-                    Ok::<_, SyncError>((needs, tx, read))
+                    Ok::<_, SyncError>((needs, tx, read, encoding))
                 }.await
             )
         }.instrument(info_span!("sync_client_handshake", %actor_id, %addr))
@@ -1104,9 +1376,9 @@ pub async fn parallel_sync(
     let syncers = results
         .into_iter()
         .fold(Ok(vec![]), |agg, (actor_id, addr, res)| match res {
-            Ok((needs, tx, read)) => {
+            Ok((needs, tx, read, encoding)) => {
                 let mut v = agg.unwrap_or_default();
-                v.push((actor_id, addr, needs, tx, read));
+                v.push((actor_id, addr, needs, tx, read, encoding));
                 Ok(v)
             }
             Err(e) => {
@@ -1129,12 +1401,12 @@ pub async fn parallel_sync(
     let (readers, mut servers) = {
         syncers.into_iter().fold(
             (Vec::with_capacity(len), Vec::with_capacity(len)),
-            |(mut readers, mut servers), (actor_id, addr, needs, tx, read)| {
+            |(mut readers, mut servers), (actor_id, addr, needs, tx, read, encoding)| {
                 if needs.is_empty() {
                     trace!(%actor_id, "no needs!");
                     return (readers, servers);
                 }
-                readers.push((actor_id, read));
+                readers.push((actor_id, addr, read, encoding));
 
                 trace!(%actor_id, "needs: {needs:?}");
 
@@ -1172,6 +1444,7 @@ pub async fn parallel_sync(
                     addr,
                     actor_needs,
                     tx,
+                    encoding,
                 ));
 
                 (readers, servers)
@@ -1183,9 +1456,11 @@ pub async fn parallel_sync(
         return Ok(0);
     }
 
+    let req_tables = tables.clone();
+    let max_frame_bytes = agent.config().perf.max_frame_bytes;
     tokio::spawn(async move {
         // reusable buffers and constructs
-        let mut codec = LengthDelimitedCodec::builder().max_frame_length(100 * 1_024 * 1_024).new_codec();
+        let mut codec = LengthDelimitedCodec::builder().max_frame_length(max_frame_bytes).new_codec();
         let mut send_buf = BytesMut::new();
         let mut encode_buf = BytesMut::new();
 
@@ -1202,7 +1477,7 @@ pub async fn parallel_sync(
                 break;
             }
             let mut next_servers = Vec::with_capacity(servers.len());
-            'servers: for (server_actor_id, addr, mut needs, mut tx) in servers {
+            'servers: for (server_actor_id, addr, mut needs, mut tx, encoding) in servers {
                 if needs.is_empty() {
                     continue;
                 }
@@ -1279,11 +1554,21 @@ pub async fn parallel_sync(
 
                     let req_len = actual_needs.len();
 
+                    let req = vec![(actor_id, actual_needs)];
+                    let msg = match req_tables.as_ref() {
+                        Some(tables) => SyncMessageV1::TableRequest(SyncTableRequestV1 {
+                            tables: tables.to_vec(),
+                            needs: req,
+                        }),
+                        None => SyncMessageV1::Request(req),
+                    };
+
                     if let Err(e) = encode_sync_msg(
                         &mut codec,
                         &mut encode_buf,
                         &mut send_buf,
-                        SyncMessage::V1(SyncMessageV1::Request(vec![(actor_id, actual_needs)])),
+                        SyncMessage::V1(msg),
+                        encoding,
                     ) {
                         error!(%server_actor_id, %actor_id, %addr, "could not encode sync request: {e} (elapsed: {:?})", start.elapsed());
                         continue 'servers;
@@ -1310,20 +1595,31 @@ pub async fn parallel_sync(
                     continue;
                 }
 
-                next_servers.push((server_actor_id, addr, needs, tx));
+                next_servers.push((server_actor_id, addr, needs, tx, encoding));
             }
             servers = next_servers;
         }
     }.instrument(info_span!("send_sync_requests")));
 
     // now handle receiving changesets!
-    let counts = FuturesUnordered::from_iter(readers.into_iter().map(|(actor_id, mut read)| {
+    let strict_seqs = agent.config().db.seq_mode == SeqMode::Strict;
+    let counts = FuturesUnordered::from_iter(readers.into_iter().map(|(actor_id, addr, mut read, encoding)| {
         let tx_changes = agent.tx_changes().clone();
+        let tables = tables.clone();
+        let span = info_span!(
+            "sync_session",
+            peer = %addr,
+            %actor_id,
+            tables = ?tables.as_deref(),
+            changes = tracing::field::Empty
+        );
 
         async move {
             let mut count = 0;
+            // seqs the server marked cleared, for the changeset that follows
+            let mut cleared: HashMap<(ActorId, CrsqlDbVersion), Vec<RangeInclusive<CrsqlSeq>>> = HashMap::new();
             loop {
-                match read_sync_msg(&mut read).await {
+                match read_sync_msg(&mut read, encoding, max_value_bytes).await {
                     Ok(None) => {
                         break;
                     }
@@ -1334,7 +1630,6 @@ pub async fn parallel_sync(
                     Ok(Some(msg)) => match msg {
                         SyncMessage::V1(SyncMessageV1::Changeset(change)) => {
                             let changes_len = cmp::max(change.len(), 1);
-                            // tracing::Span::current().record("changes_len", changes_len);
                             count += changes_len;
                             counter!("corro.sync.changes.recv", "actor_id" => actor_id.to_string())
                                 .increment(changes_len as u64);
@@ -1351,15 +1646,45 @@ pub async fn parallel_sync(
                                 continue;
                             }
 
+                            if strict_seqs {
+                                let marked = cleared
+                                    .remove(&(change.actor_id, change.versions().start()))
+                                    .unwrap_or_default();
+                                if let Err(e) = check_seqs(&change, &marked) {
+                                    counter!("corro.changes.skipped", "reason" => "missing_seqs")
+                                        .increment(1);
+                                    error!(%actor_id, "rejecting synced change: {e}");
+                                    continue;
+                                }
+                            }
+
+                            // empty versions are empty for every table and can be booked
+                            let is_full = matches!(change.changeset, Changeset::Full { .. });
+                            if let Some(tables) = tables.as_deref().filter(|_| is_full) {
+                                if let Err(e) = apply_table_scoped_changes(agent, tables, change).await {
+                                    error!(%actor_id, "could not apply table-scoped changes: {e}");
+                                }
+                                continue;
+                            }
+
                             tx_changes
                                 .send((change, ChangeSource::Sync))
                                 .await
                                 .map_err(|_| SyncRecvError::ChangesChannelClosed)?;
                         }
-                        SyncMessage::V1(SyncMessageV1::Request(_)) => {
+                        SyncMessage::V1(SyncMessageV1::Request(_) | SyncMessageV1::TableRequest(_)) => {
                             warn!("received sync request message unexpectedly, ignoring");
                             continue;
                         }
+                        SyncMessage::V1(SyncMessageV1::ClearedSeqs(ClearedSeqsV1 { actor_id, version, seqs })) => {
+                            if strict_seqs {
+                                cleared.entry((actor_id, version)).or_default().extend(seqs);
+                            }
+                        }
+                        SyncMessage::V1(SyncMessageV1::Digests(_)) => {
+                            warn!("received digests message unexpectedly, ignoring");
+                            continue;
+                        }
                         SyncMessage::V1(SyncMessageV1::State(_)) => {
                             warn!("received sync state message unexpectedly, ignoring");
                             continue;
@@ -1376,10 +1701,11 @@ pub async fn parallel_sync(
             }
 
             debug!(%actor_id, %count, "done reading sync messages");
+            tracing::Span::current().record("changes", count);
 
             Ok((actor_id, count))
         }
-        .instrument(info_span!("read_sync_requests_responses", %actor_id))
+        .instrument(span)
     }))
     .collect::<Vec<Result<(ActorId, usize), SyncError>>>()
     .await;
@@ -1401,13 +1727,233 @@ pub async fn parallel_sync(
         .sum::<usize>())
 }
 
-#[tracing::instrument(skip(agent, bookie, their_actor_id, read, write), fields(actor_id = %their_actor_id), err)]
+/// Applies the changes of a table-scoped sync without booking their version,
+/// recording which tables were synced for it instead.
+pub(crate) async fn apply_table_scoped_changes(
+    agent: &Agent,
+    tables: &[TableName],
+    change: ChangeV1,
+) -> Result<usize, ChangeError> {
+    let ChangeV1 {
+        actor_id,
+        changeset:
+            Changeset::Full {
+                version,
+                changes,
+                ts,
+                ..
+            },
+    } = change
+    else {
+        return Ok(0);
+    };
+
+    let mut conn = agent.pool().write_normal().await?;
+
+    if !agent.accepts_writes() {
+        return Err(ChangeError::ShuttingDown);
+    }
+
+    let changes: Vec<Change> = changes
+        .into_iter()
+        .filter(|change| tables.contains(&change.table))
+        .collect();
+    let applied = changes.len();
+
+    let (impactful, db_version) = block_in_place(|| {
+        let tx = conn.transaction()?;
+
+        let mut impactful = vec![];
+        let mut last_rows_impacted = 0;
+        for change in changes {
+            insert_change(&tx, &change, ts)?;
+            let rows_impacted: i64 = tx
+                .prepare_cached("SELECT crsql_rows_impacted()")?
+                .query_row((), |row| row.get(0))?;
+
+            tx.prepare_cached(
+                r#"INSERT OR IGNORE INTO __corro_table_scoped_versions (site_id, db_version, "table") VALUES (?, ?, ?)"#,
+            )?
+            .execute(params![actor_id, version, change.table])?;
+
+            if rows_impacted > last_rows_impacted {
+                impactful.push(change);
+            }
+            last_rows_impacted = rows_impacted;
+        }
+
+        // the local version the changes were applied at
+        let db_version: CrsqlDbVersion =
+            tx.query_row("SELECT crsql_db_version()", [], |row| row.get(0))?;
+
+        tx.commit()?;
+
+        Ok::<_, rusqlite::Error>((impactful, db_version))
+    })
+    .map_err(|source| ChangeError::Rusqlite {
+        source,
+        actor_id: Some(actor_id),
+        version: Some(version),
+    })?;
+    drop(conn);
+
+    if ts > Timestamp::from(agent.clock().new_timestamp()) {
+        if let Err(e) = agent.update_clock_with_timestamp(actor_id, ts) {
+            warn!("could not update clock from actor {actor_id}: {e}");
+        }
+    }
+
+    notify_applied_changes(agent, &impactful, db_version);
+
+    Ok(applied)
+}
+
+/// Asks the peer at `addr` for the digests of `ranges`, for anti-entropy,
+/// along with the sync state they were computed against
+#[tracing::instrument(skip(agent, transport, ranges), err)]
+pub async fn request_digests(
+    agent: &Agent,
+    transport: &Transport,
+    addr: SocketAddr,
+    ranges: Vec<(ActorId, RangeInclusive<CrsqlDbVersion>)>,
+) -> Result<(SyncStateV1, Vec<VersionsDigestV1>), SyncError> {
+    let mut codec = LengthDelimitedCodec::builder()
+        .max_frame_length(agent.config().perf.max_frame_bytes)
+        .new_codec();
+    let mut send_buf = BytesMut::new();
+    let mut encode_buf = BytesMut::new();
+
+    let (mut tx, rx) = transport.open_bi(addr).await?;
+    let mut read = FramedRead::new(
+        rx,
+        LengthDelimitedCodec::builder()
+            .max_frame_length(agent.config().perf.max_frame_bytes)
+            .new_codec(),
+    );
+
+    encode_write_bipayload_msg(
+        &mut codec,
+        &mut encode_buf,
+        &mut send_buf,
+        BiPayload::V1 {
+            data: BiPayloadV1::Digests {
+                actor_id: agent.actor_id(),
+                ranges,
+            },
+            cluster_id: agent.cluster_id(),
+            encodings: vec![],
+        },
+        &mut tx,
+    )
+    .await?;
+    tx.flush().await.map_err(SyncSendError::from)?;
+
+    let res = timeout(Duration::from_secs(10), read_digests(&mut read))
+        .await
+        .map_err(SyncRecvError::from)?;
+    _ = tx.finish().await;
+
+    res
+}
+
+async fn read_digests(
+    read: &mut FramedRead<RecvStream, LengthDelimitedCodec>,
+) -> Result<(SyncStateV1, Vec<VersionsDigestV1>), SyncError> {
+    let state = match read_sync_msg(read, None, None).await? {
+        Some(SyncMessage::V1(SyncMessageV1::State(state))) => state,
+        Some(SyncMessage::V1(SyncMessageV1::Rejection(rejection))) => return Err(rejection.into()),
+        Some(_) => return Err(SyncRecvError::ExpectedDigests.into()),
+        None => return Err(SyncRecvError::UnexpectedEndOfStream.into()),
+    };
+    match read_sync_msg(read, None, None).await? {
+        Some(SyncMessage::V1(SyncMessageV1::Digests(digests))) => Ok((state, digests)),
+        Some(_) => Err(SyncRecvError::ExpectedDigests.into()),
+        None => Err(SyncRecvError::UnexpectedEndOfStream.into()),
+    }
+}
+
+/// Answers a digests request with our sync state and digests, see
+/// [`request_digests`]
+pub async fn serve_digests(
+    agent: &Agent,
+    bookie: &Bookie,
+    their_actor_id: ActorId,
+    their_addr: SocketAddr,
+    cluster_id: ClusterId,
+    mut ranges: Vec<(ActorId, RangeInclusive<CrsqlDbVersion>)>,
+    mut write: SendStream,
+) -> Result<(), SyncError> {
+    let mut codec = LengthDelimitedCodec::builder()
+        .max_frame_length(agent.config().perf.max_frame_bytes)
+        .new_codec();
+    let mut send_buf = BytesMut::new();
+    let mut encode_buf = BytesMut::new();
+
+    let msgs = if cluster_id != agent.cluster_id() {
+        counter!("corro.sync.rejected", "reason" => "different_cluster").increment(1);
+        vec![SyncMessageV1::Rejection(SyncRejectionV1::DifferentCluster)]
+    } else if let Err(rejection) = agent
+        .config()
+        .gossip
+        .peer_access
+        .check(their_actor_id, their_addr.ip())
+    {
+        let reason: &'static str = rejection.into();
+        counter!("corro.sync.rejected", "reason" => reason).increment(1);
+        vec![SyncMessageV1::Rejection(SyncRejectionV1::NotAllowed)]
+    } else {
+        // digests read as much as a sync does, they share its permits
+        match agent.limits().sync.try_acquire() {
+            Some(_permit) => {
+                ranges.truncate(MAX_DIGEST_RANGES);
+                let state = generate_sync(bookie, agent.actor_id()).await;
+                match versions_digests(agent, bookie, ranges).await {
+                    Ok(digests) => {
+                        vec![SyncMessageV1::State(state), SyncMessageV1::Digests(digests)]
+                    }
+                    Err(e) => {
+                        error!(actor_id = %their_actor_id, "could not compute versions digests: {e}");
+                        return Ok(());
+                    }
+                }
+            }
+            None => {
+                counter!("corro.sync.rejected", "reason" => "max_concurrency").increment(1);
+                vec![SyncMessageV1::Rejection(
+                    SyncRejectionV1::MaxConcurrencyReached,
+                )]
+            }
+        }
+    };
+
+    for msg in msgs {
+        encode_write_sync_msg(
+            &mut codec,
+            &mut encode_buf,
+            &mut send_buf,
+            SyncMessage::V1(msg),
+            None,
+            &mut write,
+        )
+        .await?;
+    }
+
+    if let Err(e) = write.finish().await {
+        warn!("could not finish digests stream: {e}");
+    }
+
+    Ok(())
+}
+
+#[tracing::instrument(skip(agent, bookie, their_actor_id, their_addr, read, write), fields(actor_id = %their_actor_id, addr = %their_addr), err)]
 pub async fn serve_sync(
     agent: &Agent,
     bookie: &Bookie,
     their_actor_id: ActorId,
+    their_addr: SocketAddr,
     trace_ctx: SyncTraceContextV1,
     cluster_id: ClusterId,
+    encodings: Vec<SyncEncoding>,
     mut read: FramedRead<RecvStream, LengthDelimitedCodec>,
     mut write: SendStream,
 ) -> Result<usize, SyncError> {
@@ -1417,17 +1963,20 @@ pub async fn serve_sync(
 
     debug!(actor_id = %their_actor_id, self_actor_id = %agent.actor_id(), "received sync request");
     let mut codec = LengthDelimitedCodec::builder()
-        .max_frame_length(100 * 1_024 * 1_024)
+        .max_frame_length(agent.config().perf.max_frame_bytes)
         .new_codec();
     let mut send_buf = BytesMut::new();
     let mut encode_buf = BytesMut::new();
+    let max_value_bytes = agent.config().db.max_value_bytes;
 
     if cluster_id != agent.cluster_id() {
+        counter!("corro.sync.rejected", "reason" => "different_cluster").increment(1);
         encode_write_sync_msg(
             &mut codec,
             &mut encode_buf,
             &mut send_buf,
             SyncMessage::V1(SyncMessageV1::Rejection(SyncRejectionV1::DifferentCluster)),
+            None,
             &mut write,
         )
         .instrument(info_span!("write_rejection_cluster_id"))
@@ -1435,8 +1984,30 @@ pub async fn serve_sync(
         return Ok(0);
     }
 
+    let access = agent
+        .config()
+        .gossip
+        .peer_access
+        .check(their_actor_id, their_addr.ip());
+    if let Err(rejection) = access {
+        let reason: &'static str = rejection.into();
+        counter!("corro.sync.rejected", "reason" => reason).increment(1);
+        warn!(actor_id = %their_actor_id, addr = %their_addr, "rejecting sync: {rejection}");
+        encode_write_sync_msg(
+            &mut codec,
+            &mut encode_buf,
+            &mut send_buf,
+            SyncMessage::V1(SyncMessageV1::Rejection(SyncRejectionV1::NotAllowed)),
+            None,
+            &mut write,
+        )
+        .instrument(info_span!("write_rejection_peer_access"))
+        .await?;
+        return Ok(0);
+    }
+
     // read the clock
-    match read_sync_msg(&mut read)
+    match read_sync_msg(&mut read, None, max_value_bytes)
         .instrument(info_span!("read_peer_clock"))
         .await?
     {
@@ -1460,9 +2031,10 @@ pub async fn serve_sync(
     trace!(actor_id = %their_actor_id, self_actor_id = %agent.actor_id(), "read clock");
 
     let _permit = match agent.limits().sync.try_acquire() {
-        Ok(permit) => permit,
-        Err(_) => {
+        Some(permit) => permit,
+        None => {
             // no permits!
+            counter!("corro.sync.rejected", "reason" => "max_concurrency").increment(1);
             encode_write_sync_msg(
                 &mut codec,
                 &mut encode_buf,
@@ -1470,6 +2042,7 @@ pub async fn serve_sync(
                 SyncMessage::V1(SyncMessageV1::Rejection(
                     SyncRejectionV1::MaxConcurrencyReached,
                 )),
+                None,
                 &mut write,
             )
             .instrument(info_span!("write_sync_rejection"))
@@ -1478,7 +2051,12 @@ pub async fn serve_sync(
         }
     };
 
-    let sync_state = generate_sync(bookie, agent.actor_id()).await;
+    let mut sync_state = generate_sync(bookie, agent.actor_id()).await;
+    sync_state.schema_digest = Some(agent.schema().read().digest());
+    // older clients don't advertise encodings and get bare messages
+    let encoding = negotiate_sync_encoding(SUPPORTED_SYNC_ENCODINGS, &encodings);
+    sync_state.encoding = encoding;
+    sync_state.table_requests = true;
 
     // first, send the current sync state
     encode_write_sync_msg(
@@ -1486,6 +2064,7 @@ pub async fn serve_sync(
         &mut encode_buf,
         &mut send_buf,
         SyncMessage::V1(SyncMessageV1::State(sync_state)),
+        None,
         &mut write,
     )
     .instrument(info_span!("write_sync_state"))
@@ -1499,6 +2078,7 @@ pub async fn serve_sync(
         &mut encode_buf,
         &mut send_buf,
         SyncMessage::V1(SyncMessageV1::Clock(agent.clock().new_timestamp().into())),
+        encoding,
         &mut write,
     )
     .instrument(info_span!("write_sync_clock"))
@@ -1517,9 +2097,21 @@ pub async fn serve_sync(
     let (tx, mut rx) = mpsc::channel::<SyncMessage>(256);
 
     tokio::spawn(
-        process_sync(agent.pool().clone(), bookie.clone(), tx, rx_need)
-            .instrument(info_span!("process_sync"))
-            .inspect_err(|e| error!("could not process sync request: {e}")),
+        process_sync(
+            agent.pool().clone(),
+            bookie.clone(),
+            tx,
+            rx_need,
+            Arc::new(TableWeights::new(
+                agent.config().perf.sync_table_weights.clone(),
+            )),
+            Arc::new(agent.config().db.excluded_columns.clone()),
+            agent.limits().chunk_reads.clone(),
+            agent.recent_changes().clone(),
+            agent.chunk_size_tuner(their_actor_id),
+        )
+        .instrument(info_span!("process_sync"))
+        .inspect_err(|e| error!("could not process sync request: {e}")),
     );
 
     let (send_res, recv_res) = tokio::join!(
@@ -1551,8 +2143,19 @@ pub async fn serve_sync(
                         Some(msg) => {
                             if let SyncMessage::V1(SyncMessageV1::Changeset(change)) = &msg {
                                 count += change.len();
+
+                                // so strict peers can tell the seqs we have no change for from lost ones
+                                let seqs = change.missing_seqs();
+                                if !seqs.is_empty() && encoding.is_some_and(|encoding| encoding >= SyncEncoding::V2) {
+                                    let cleared = SyncMessage::V1(SyncMessageV1::ClearedSeqs(ClearedSeqsV1 {
+                                        actor_id: change.actor_id,
+                                        version: change.versions().start(),
+                                        seqs,
+                                    }));
+                                    encode_sync_msg(&mut codec, &mut encode_buf, &mut send_buf, cleared, encoding)?;
+                                }
                             }
-                            encode_sync_msg(&mut codec, &mut encode_buf, &mut send_buf, msg)?;
+                            encode_sync_msg(&mut codec, &mut encode_buf, &mut send_buf, msg, encoding)?;
 
                             if send_buf.len() >= 16 * 1024 {
                                 write_buf(&mut send_buf, &mut write).await.map_err(SyncSendError::from)?;
@@ -1591,7 +2194,7 @@ pub async fn serve_sync(
             let mut count = 0;
 
             loop {
-                match read_sync_msg(&mut read).await {
+                match read_sync_msg(&mut read, encoding, max_value_bytes).await {
                     Ok(None) => {
                         break;
                     }
@@ -1609,11 +2212,24 @@ pub async fn serve_sync(
                                 })
                                 .sum::<usize>();
                             tx_need
-                                .send(req)
+                                .send((None, req))
+                                .await
+                                .map_err(|_| SyncRecvError::RequestsChannelClosed)?;
+                        }
+                        SyncMessage::V1(SyncMessageV1::TableRequest(SyncTableRequestV1 { tables, needs })) => {
+                            trace!(actor_id = %their_actor_id, self_actor_id = %agent.actor_id(), "read table request: {needs:?}, tables: {tables:?}");
+                            count += needs
+                                .iter()
+                                .map(|(_, needs)| {
+                                    needs.iter().map(|need| need.count()).sum::<usize>()
+                                })
+                                .sum::<usize>();
+                            tx_need
+                                .send((Some(tables.into()), needs))
                                 .await
                                 .map_err(|_| SyncRecvError::RequestsChannelClosed)?;
                         }
-                        SyncMessage::V1(SyncMessageV1::Changeset(_)) => {
+                        SyncMessage::V1(SyncMessageV1::Changeset(_) | SyncMessageV1::Digests(_) | SyncMessageV1::ClearedSeqs(_)) => {
                             warn!(actor_id = %their_actor_id, "received sync changeset message unexpectedly, ignoring");
                             continue;
                         }
@@ -1658,7 +2274,7 @@ mod tests {
     use corro_types::base::{dbsr, dbvr, CrsqlDbVersion};
     use corro_types::{
         api::{ColumnName, TableName},
-        config::{Config, TlsConfig, DEFAULT_GOSSIP_CLIENT_ADDR},
+        config::{Config, PeerAccessConfig, PeerMatcher, TlsConfig, DEFAULT_GOSSIP_CLIENT_ADDR},
         pubsub::pack_columns,
         tls::{generate_ca, generate_client_cert, generate_server_cert},
     };
@@ -1728,6 +2344,176 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_parallel_sync_tables() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        // a single version touching two tables
+        let (status_code, body) = api_v1_transactions(
+            Extension(ta1.agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![
+                "INSERT INTO tests (id, text) VALUES (1, 'one')".into(),
+                "INSERT INTO tests2 (id, text) VALUES (1, 'one')".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        let version = body.0.version.unwrap();
+
+        let actor_id = ta1.agent.actor_id();
+        let members = vec![(actor_id, ta1.agent.gossip_addr())];
+
+        let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+        parallel_sync_tables(
+            &ta2.agent,
+            &ta2.transport,
+            members.clone(),
+            sync_state,
+            vec![TableName::from("tests")],
+        )
+        .await?;
+
+        let count_rows = |table: &'static str| {
+            let agent = ta2.agent.clone();
+            async move {
+                let conn = agent.pool().read().await?;
+                let count: i64 =
+                    conn.query_row(&format!("SELECT COUNT(*) FROM {table}"), (), |row| {
+                        row.get(0)
+                    })?;
+                Ok::<_, eyre::Report>(count)
+            }
+        };
+
+        assert_eq!(count_rows("tests").await?, 1);
+        assert_eq!(count_rows("tests2").await?, 0);
+
+        // the version is incomplete: not booked, still needed
+        let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+        assert!(sync_state
+            .heads
+            .get(&actor_id)
+            .is_none_or(|head| *head < CrsqlDbVersion(version)));
+        {
+            let conn = ta2.agent.pool().read().await?;
+            assert_eq!(
+                corro_types::agent::table_scoped_versions(&conn, actor_id)?,
+                [(CrsqlDbVersion(version), vec![TableName::from("tests")])].into()
+            );
+        }
+
+        // a complete sync fills in the rest
+        parallel_sync(&ta2.agent, &ta2.transport, members, sync_state).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while count_rows("tests2").await? != 1 {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Ok::<_, eyre::Report>(())
+        })
+        .await??;
+
+        // and once booked, the version isn't table-scoped anymore
+        {
+            let conn = ta2.agent.pool().read().await?;
+            assert!(corro_types::agent::table_scoped_versions(&conn, actor_id)?.is_empty());
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sync_peer_access() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta3 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let (status_code, _) = api_v1_transactions(
+            Extension(ta1.agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec!["INSERT INTO tests (id, text) VALUES (1, 'one')".into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let set_access = |allow: Vec<PeerMatcher>, deny: Vec<PeerMatcher>| {
+            let mut config = Config::clone(&ta1.agent.config());
+            config.gossip.peer_access = PeerAccessConfig { allow, deny };
+            ta1.agent.set_config(config);
+        };
+        let members = vec![(ta1.agent.actor_id(), ta1.agent.gossip_addr())];
+        let sync = |ta: &corro_tests::TestAgent| {
+            let ta = ta.clone();
+            let members = members.clone();
+            async move {
+                let sync_state = generate_sync(&ta.bookie, ta.agent.actor_id()).await;
+                parallel_sync(&ta.agent, &ta.transport, members, sync_state).await
+            }
+        };
+        let count_rows = |ta: &corro_tests::TestAgent| {
+            let agent = ta.agent.clone();
+            async move {
+                tokio::time::timeout(Duration::from_secs(5), async {
+                    loop {
+                        let count: i64 = agent.pool().read().await?.query_row(
+                            "SELECT COUNT(*) FROM tests",
+                            (),
+                            |row| row.get(0),
+                        )?;
+                        if count > 0 {
+                            return Ok::<_, eyre::Report>(count);
+                        }
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                })
+                .await?
+            }
+        };
+
+        set_access(vec![], vec![PeerMatcher::Actor(ta2.agent.actor_id())]);
+
+        let res = sync(&ta2).await;
+        assert!(
+            matches!(res, Err(SyncError::Rejection(SyncRejectionV1::NotAllowed))),
+            "{res:?}"
+        );
+        assert_eq!(
+            ta2.agent
+                .pool()
+                .read()
+                .await?
+                .query_row("SELECT COUNT(*) FROM tests", (), |row| row.get::<_, i64>(0))?,
+            0
+        );
+
+        sync(&ta3).await?;
+        assert_eq!(count_rows(&ta3).await?, 1);
+
+        // only an address range nobody's in
+        set_access(vec!["10.0.0.0/8".parse()?], vec![]);
+        let res = sync(&ta3).await;
+        assert!(
+            matches!(res, Err(SyncError::Rejection(SyncRejectionV1::NotAllowed))),
+            "{res:?}"
+        );
+
+        // changes apply to the next sync, no restart needed
+        set_access(vec!["127.0.0.1".parse()?], vec![]);
+        sync(&ta2).await?;
+        assert_eq!(count_rows(&ta2).await?, 1);
+
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread")]
     async fn test_handle_need() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
@@ -1858,7 +2644,12 @@ mod tests {
                     SyncNeedV1::Full {
                         versions: dbvr!(1, 1),
                     },
+                    None,
+                    &ExcludedColumns::default(),
                     &tx,
+                    &agent.limits().chunk_reads,
+                    None,
+                    &agent.chunk_size_tuner(actor_id),
                 )
             })?;
 
@@ -1886,7 +2677,12 @@ mod tests {
                         version: CrsqlDbVersion(2),
                         seqs: vec![dbsr!(0, 0)],
                     },
+                    None,
+                    &ExcludedColumns::default(),
                     &tx,
+                    &agent.limits().chunk_reads,
+                    None,
+                    &agent.chunk_size_tuner(actor_id),
                 )
             })?;
 
@@ -1965,7 +2761,12 @@ mod tests {
                         version: CrsqlDbVersion(1),
                         seqs: vec![dbsr!(0, 0)],
                     },
+                    None,
+                    &ExcludedColumns::default(),
                     &tx,
+                    &agent.limits().chunk_reads,
+                    None,
+                    &agent.chunk_size_tuner(actor_id),
                 )
             })?;
 
@@ -1995,7 +2796,12 @@ mod tests {
                     SyncNeedV1::Full {
                         versions: dbvr!(1, 6),
                     },
+                    None,
+                    &ExcludedColumns::default(),
                     &tx,
+                    &agent.limits().chunk_reads,
+                    None,
+                    &agent.chunk_size_tuner(actor_id),
                 )
             })?;
 
@@ -2161,7 +2967,12 @@ mod tests {
                     SyncNeedV1::Full {
                         versions: dbvr!(1, 1000),
                     },
+                    None,
+                    &ExcludedColumns::default(),
                     &tx,
+                    &agent.limits().chunk_reads,
+                    None,
+                    &agent.chunk_size_tuner(actor_id),
                 )
             })?;
 
@@ -2237,7 +3048,12 @@ mod tests {
                         version: CrsqlDbVersion(5),
                         seqs: vec![dbsr!(4, 7)],
                     },
+                    None,
+                    &ExcludedColumns::default(),
                     &tx,
+                    &agent.limits().chunk_reads,
+                    None,
+                    &agent.chunk_size_tuner(actor_id),
                 )
             })?;
 
@@ -2273,7 +3089,12 @@ mod tests {
                         version: CrsqlDbVersion(5),
                         seqs: vec![dbsr!(2, 2), dbsr!(15, 24)],
                     },
+                    None,
+                    &ExcludedColumns::default(),
                     &tx,
+                    &agent.limits().chunk_reads,
+                    None,
+                    &agent.chunk_size_tuner(actor_id),
                 )
             })?;
 
@@ -2376,6 +3197,7 @@ mod tests {
             plaintext: false,
             max_mtu: None,
             disable_gso: false,
+            peer_access: Default::default(),
         };
 
         let server = gossip_server_endpoint(&gossip_config).await?;
@@ -2434,4 +3256,462 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_mutual_tls_rejects_untrusted_peer() -> eyre::Result<()> {
+        let ca_cert = generate_ca()?;
+        let (server_cert, server_cert_signed) = generate_server_cert(
+            &ca_cert.serialize_pem()?,
+            &ca_cert.serialize_private_key_pem(),
+            "127.0.0.1".parse()?,
+        )?;
+        let (client_cert, client_cert_signed) = generate_client_cert(
+            &ca_cert.serialize_pem()?,
+            &ca_cert.serialize_private_key_pem(),
+        )?;
+
+        // a well-formed client cert, but from a CA the server doesn't know
+        let rogue_ca_cert = generate_ca()?;
+        let (rogue_cert, rogue_cert_signed) = generate_client_cert(
+            &rogue_ca_cert.serialize_pem()?,
+            &rogue_ca_cert.serialize_private_key_pem(),
+        )?;
+
+        let tmpdir = TempDir::new()?;
+        let base_path = Utf8PathBuf::from(tmpdir.path().display().to_string());
+
+        let ca_file = base_path.join("ca.pem");
+        tokio::fs::write(&ca_file, ca_cert.serialize_pem()?).await?;
+
+        let cert_file = base_path.join("cert.pem");
+        let key_file = base_path.join("cert.key");
+        tokio::fs::write(&cert_file, &server_cert_signed).await?;
+        tokio::fs::write(&key_file, server_cert.serialize_private_key_pem()).await?;
+
+        let gossip_config =
+            |client_cert_file: Utf8PathBuf, client_key_file: Utf8PathBuf| GossipConfig {
+                bind_addr: "127.0.0.1:0".parse().unwrap(),
+                client_addr: DEFAULT_GOSSIP_CLIENT_ADDR,
+                external_addr: None,
+                bootstrap: vec![],
+                tls: Some(TlsConfig {
+                    cert_file: cert_file.clone(),
+                    key_file: key_file.clone(),
+                    ca_file: Some(ca_file.clone()),
+                    client: Some(TlsClientConfig {
+                        cert_file: client_cert_file,
+                        key_file: client_key_file,
+                    }),
+                    insecure: false,
+                }),
+                idle_timeout_secs: 30,
+                plaintext: false,
+                max_mtu: None,
+                disable_gso: false,
+                peer_access: Default::default(),
+            };
+
+        let client_cert_file = base_path.join("client-cert.pem");
+        let client_key_file = base_path.join("client-cert.key");
+        tokio::fs::write(&client_cert_file, &client_cert_signed).await?;
+        tokio::fs::write(&client_key_file, client_cert.serialize_private_key_pem()).await?;
+        let trusted_config = gossip_config(client_cert_file, client_key_file);
+
+        let rogue_cert_file = base_path.join("rogue-cert.pem");
+        let rogue_key_file = base_path.join("rogue-cert.key");
+        tokio::fs::write(&rogue_cert_file, &rogue_cert_signed).await?;
+        tokio::fs::write(&rogue_key_file, rogue_cert.serialize_private_key_pem()).await?;
+        let rogue_config = gossip_config(rogue_cert_file, rogue_key_file);
+
+        let server = gossip_server_endpoint(&trusted_config).await?;
+        let addr = server.local_addr()?;
+
+        // the trusted peer completes the handshake and can send data
+        let client = gossip_client_endpoint(&trusted_config).await?;
+        let (client_conn, server_conn) = tokio::try_join!(
+            async { Ok::<_, eyre::Report>(client.connect(addr, &addr.ip().to_string())?.await?) },
+            async {
+                let connecting = server
+                    .accept()
+                    .await
+                    .ok_or_else(|| eyre::eyre!("None accept!"))?;
+                Ok(connecting.await?)
+            }
+        )?;
+
+        let mut send = client_conn.open_uni().await?;
+        send.write_all(b"hello").await?;
+        send.finish().await?;
+        let mut recv = server_conn.accept_uni().await?;
+        assert_eq!(recv.read_to_end(64).await?, b"hello");
+
+        // the untrusted peer is turned away before any stream is accepted
+        let rogue = gossip_client_endpoint(&rogue_config).await?;
+        let (client_res, server_res) = tokio::join!(
+            async {
+                let conn = rogue.connect(addr, &addr.ip().to_string())?.await?;
+                // the client may consider the handshake done before the
+                // server has checked its certificate, the close follows
+                Err::<(), _>(eyre::Report::from(conn.closed().await))
+            },
+            async {
+                let connecting = server
+                    .accept()
+                    .await
+                    .ok_or_else(|| eyre::eyre!("None accept!"))?;
+                Ok::<_, eyre::Report>(connecting.await?)
+            }
+        );
+        assert!(client_res.is_err());
+        assert!(server_res.is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_read_sync_msg_rejects_oversized_frame() {
+        // only a length prefix announcing a 1GiB frame, no body
+        let header = (1_024u32 * 1_024 * 1_024).to_be_bytes();
+        let mut read = FramedRead::new(
+            &header[..],
+            LengthDelimitedCodec::builder()
+                .max_frame_length(1_024)
+                .new_codec(),
+        );
+
+        let res = read_sync_msg(&mut read, None, None).await;
+        assert!(
+            matches!(res, Err(SyncRecvError::FrameTooLarge)),
+            "unexpected result: {res:?}"
+        );
+        // rejected from the header alone, the frame was never buffered
+        assert!(read.read_buffer().capacity() < 1_024 * 1_024);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_need_recent_changes_cache() -> eyre::Result<()> {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let agent = ta1.agent.clone();
+        let actor_id = agent.actor_id();
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                vec![1i64.into(), "one".into()],
+            )]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        let version = CrsqlDbVersion(body.0.version.unwrap());
+
+        // populated by the spawned broadcast
+        timeout(Duration::from_secs(5), async {
+            while agent.recent_changes().get(actor_id, version).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        assert!(agent.recent_changes().bytes() > 0);
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        let mut conn = agent.pool().read().await?;
+        let (tx, mut rx) = mpsc::channel(16);
+        let mut request = |recent_changes: Option<&RecentChanges>| {
+            block_in_place(|| {
+                metrics::with_local_recorder(&recorder, || {
+                    handle_need(
+                        &mut conn,
+                        actor_id,
+                        SyncNeedV1::Full {
+                            versions: CrsqlDbVersionRange::single(version),
+                        },
+                        None,
+                        &ExcludedColumns::default(),
+                        &tx,
+                        &agent.limits().chunk_reads,
+                        recent_changes,
+                        &agent.chunk_size_tuner(actor_id),
+                    )
+                })
+            })
+        };
+
+        request(None)?;
+        request(Some(agent.recent_changes()))?;
+        request(Some(agent.recent_changes()))?;
+
+        // served from the cache, same as from the db
+        let from_db = rx.recv().await.unwrap();
+        assert_eq!(rx.recv().await.unwrap(), from_db);
+        assert_eq!(rx.recv().await.unwrap(), from_db);
+
+        let count = |name: &str| {
+            snapshotter
+                .snapshot()
+                .into_vec()
+                .into_iter()
+                .find(|(key, _, _, _)| key.key().name() == name)
+                .map(|(_, _, _, value)| match value {
+                    DebugValue::Counter(count) => count,
+                    _ => panic!("expected a counter"),
+                })
+                .unwrap_or(0)
+        };
+        assert_eq!(count("corro.sync.cache.hit.total"), 2);
+        assert_eq!(count("corro.sync.cache.miss.total"), 0);
+
+        // not one of our versions
+        assert!(agent
+            .recent_changes()
+            .get(ActorId(uuid::Uuid::new_v4()), version)
+            .is_none());
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_handle_need_excluded_columns() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(
+            |conf| conf.exclude_column("tests3", "text2").build(),
+            tripwire.clone(),
+        )
+        .await?;
+        let agent = ta1.agent.clone();
+        let actor_id = agent.actor_id();
+
+        let (status_code, body) = api_v1_transactions(
+            Extension(agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                "INSERT INTO tests3 (id,text,text2,num,num2) VALUES (?,?,?,?,?)".into(),
+                vec![
+                    1i64.into(),
+                    "one".into(),
+                    "internal".into(),
+                    2i64.into(),
+                    3i64.into(),
+                ],
+            )]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        let version = CrsqlDbVersion(body.0.version.unwrap());
+
+        timeout(Duration::from_secs(5), async {
+            while agent.recent_changes().get(actor_id, version).is_none() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        let mut conn = agent.pool().read().await?;
+        let (tx, mut rx) = mpsc::channel(16);
+        let excluded = agent.config().db.excluded_columns.clone();
+
+        // from the db and from the recent changes cache
+        for recent_changes in [None, Some(agent.recent_changes())] {
+            block_in_place(|| {
+                handle_need(
+                    &mut conn,
+                    actor_id,
+                    SyncNeedV1::Full {
+                        versions: CrsqlDbVersionRange::single(version),
+                    },
+                    None,
+                    &excluded,
+                    &tx,
+                    &agent.limits().chunk_reads,
+                    recent_changes,
+                    &agent.chunk_size_tuner(actor_id),
+                )
+            })?;
+
+            let Some(SyncMessage::V1(SyncMessageV1::Changeset(ChangeV1 {
+                changeset:
+                    Changeset::Full {
+                        changes,
+                        seqs,
+                        last_seq,
+                        ..
+                    },
+                ..
+            }))) = rx.recv().await
+            else {
+                panic!("expected a full changeset");
+            };
+
+            let mut cids: Vec<&str> = changes.iter().map(|change| change.cid.as_str()).collect();
+            cids.sort();
+            assert_eq!(cids, vec!["num", "num2", "text"]);
+            // the excluded column's seq is still covered
+            assert_eq!(seqs, CrsqlSeqRange::new(CrsqlSeq(0), last_seq));
+        }
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    type SpanFields = HashMap<&'static str, String>;
+
+    /// Records the fields of every span, by span name
+    #[derive(Clone, Default)]
+    struct SpanCapture(Arc<parking_lot::Mutex<Vec<(tracing::span::Id, &'static str, SpanFields)>>>);
+
+    impl SpanCapture {
+        fn fields(&self, name: &str) -> Vec<SpanFields> {
+            self.0
+                .lock()
+                .iter()
+                .filter(|(_, span_name, _)| *span_name == name)
+                .map(|(_, _, fields)| fields.clone())
+                .collect()
+        }
+    }
+
+    struct FieldVisitor<'a>(&'a mut SpanFields);
+
+    impl tracing::field::Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name(), format!("{value:?}"));
+        }
+    }
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanCapture {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let mut fields = SpanFields::new();
+            attrs.record(&mut FieldVisitor(&mut fields));
+            self.0
+                .lock()
+                .push((id.clone(), attrs.metadata().name(), fields));
+        }
+
+        fn on_record(
+            &self,
+            id: &tracing::span::Id,
+            values: &tracing::span::Record<'_>,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            // ids are reused once closed, the latest span holds it
+            if let Some((_, _, fields)) = self
+                .0
+                .lock()
+                .iter_mut()
+                .rev()
+                .find(|(span_id, _, _)| span_id == id)
+            {
+                values.record(&mut FieldVisitor(fields));
+            }
+        }
+    }
+
+    #[test]
+    fn test_sync_spans() -> eyre::Result<()> {
+        use tracing_subscriber::layer::SubscriberExt;
+
+        let capture = SpanCapture::default();
+        let dispatch = tracing::Dispatch::new(tracing_subscriber::registry().with(capture.clone()));
+
+        // every runtime thread records to the capture, the sync is served
+        // and applied from spawned tasks
+        let rt = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .on_thread_start({
+                let dispatch = dispatch.clone();
+                move || std::mem::forget(tracing::dispatcher::set_default(&dispatch))
+            })
+            .build()?;
+        let _guard = tracing::dispatcher::set_default(&dispatch);
+
+        rt.block_on(async {
+            let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+            let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+            let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+            for i in 1..=3i64 {
+                let (status_code, _) = api_v1_transactions(
+                    Extension(ta1.agent.clone()),
+                    axum::extract::Query(TimeoutParams { timeout: None }),
+                    axum::Json(vec![Statement::WithParams(
+                        "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                        vec![i.into(), format!("row {i}").into()],
+                    )]),
+                )
+                .await;
+                assert_eq!(status_code, StatusCode::OK);
+            }
+
+            let members = vec![(ta1.agent.actor_id(), ta1.agent.gossip_addr())];
+            let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+            let count = parallel_sync(&ta2.agent, &ta2.transport, members, sync_state).await?;
+            assert_eq!(count, 3);
+
+            // applies record their fields once done
+            let applied = || {
+                capture
+                    .fields("process_multiple_changes")
+                    .iter()
+                    .filter_map(|fields| {
+                        let applied = fields.get("applied")?.parse::<usize>().ok()?;
+                        assert!(fields.contains_key("skipped"));
+                        assert!(fields.contains_key("duration"));
+                        Some(applied)
+                    })
+                    .sum::<usize>()
+            };
+            timeout(Duration::from_secs(5), async {
+                while applied() < count {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await?;
+
+            let peer = ta1.agent.gossip_addr().to_string();
+            let session = capture
+                .fields("sync_session")
+                .into_iter()
+                .find(|fields| fields.get("peer") == Some(&peer))
+                .expect("no sync session span");
+            assert_eq!(session["actor_id"], ta1.agent.actor_id().to_string());
+            assert_eq!(session["tables"], "None");
+            assert_eq!(session["changes"], count.to_string());
+
+            let chunks = capture.fields("sync_chunk");
+            let mut sent = 0;
+            for chunk in chunks {
+                sent += chunk["changes"].parse::<usize>()?;
+                assert!(chunk["bytes"].parse::<usize>()? > 0);
+            }
+            assert_eq!(sent, count);
+
+            tripwire_tx.send(()).await.ok();
+            tripwire_worker.await;
+            spawn::wait_for_all_pending_handles().await;
+
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,307 @@
+//! Liveness and readiness probes
+//!
+//! `/v1/health/live` succeeds as long as the runtime is responsive, without
+//! taking any lock. `/v1/health` only once we're caught up enough to serve
+//! reads: bookkeeping loaded, few enough versions missing compared to what
+//! our peers have and enough gossip members, see
+//! [`HealthConfig`](corro_types::config::HealthConfig). Both are served
+//! without authorization, for orchestrators' probes.
+
+use std::{cmp, time::Duration};
+
+use axum::Extension;
+use corro_types::{
+    actor::ActorId,
+    agent::{Agent, Bookie},
+    api::{HealthResponse, LivenessResponse, UnappliedVersion},
+    base::CrsqlDbVersion,
+    sync::generate_sync,
+};
+use hyper::StatusCode;
+
+/// How long a new task can wait to be scheduled before we're not live
+const LIVENESS_TIMEOUT: Duration = Duration::from_secs(1);
+
+async fn live() -> bool {
+    tokio::time::timeout(LIVENESS_TIMEOUT, tokio::spawn(async {}))
+        .await
+        .is_ok()
+}
+
+pub async fn health(agent: &Agent, bookie: &Bookie) -> HealthResponse {
+    let live = live().await;
+
+    let members = agent
+        .members()
+        .read()
+        .states
+        .iter()
+        .filter(|(id, state)| **id != agent.actor_id() && state.cluster_id == agent.cluster_id())
+        .count();
+
+    let state = generate_sync(bookie, agent.actor_id()).await;
+
+    // latest versions our peers had when we last synced with them, the ones
+    // past our heads are missing too
+    let mut heads = state.heads.clone();
+    for peer_state in agent.peer_sync_states().values() {
+        for (actor_id, head) in peer_state.heads.iter() {
+            if *actor_id == agent.actor_id() {
+                continue;
+            }
+            let known = heads.entry(*actor_id).or_default();
+            *known = cmp::max(*known, *head);
+        }
+    }
+    let behind_peers: Vec<_> = heads
+        .iter()
+        .filter_map(|(actor_id, head)| {
+            let ours = state.heads.get(actor_id).copied().unwrap_or_default();
+            (*head > ours).then(|| (*actor_id, CrsqlDbVersion(ours.0 + 1)..=*head))
+        })
+        .collect();
+
+    let mut gaps = 0;
+    let mut largest_gap = 0;
+    for versions in state
+        .need
+        .values()
+        .flatten()
+        .chain(behind_peers.iter().map(|(_, versions)| versions))
+    {
+        let len = versions.end().0 - versions.start().0 + 1;
+        gaps += len;
+        largest_gap = largest_gap.max(len);
+    }
+    gaps += state
+        .partial_need
+        .values()
+        .map(|partials| partials.len() as u64)
+        .sum::<u64>();
+
+    let behind = |(actor_id, version): &(ActorId, CrsqlDbVersion)| {
+        heads
+            .get(actor_id)
+            .map_or(0, |head| head.0.saturating_sub(version.0))
+    };
+    let oldest_unapplied = state
+        .need
+        .iter()
+        .flat_map(|(actor_id, ranges)| {
+            ranges
+                .iter()
+                .map(move |versions| (*actor_id, *versions.start()))
+        })
+        .chain(state.partial_need.iter().flat_map(|(actor_id, partials)| {
+            partials.keys().map(move |version| (*actor_id, *version))
+        }))
+        .chain(
+            behind_peers
+                .iter()
+                .map(|(actor_id, versions)| (*actor_id, *versions.start())),
+        )
+        .max_by_key(behind)
+        .map(|unapplied| UnappliedVersion {
+            actor_id: unapplied.0.to_string(),
+            version: unapplied.1 .0,
+            behind: behind(&unapplied),
+        });
+
+    let config = &agent.config().api.health;
+    let ready = live
+        && agent.bookkeeping_loaded()
+        && gaps <= config.max_gaps
+        && members >= config.min_members;
+
+    HealthResponse {
+        live,
+        ready,
+        members,
+        gaps,
+        largest_gap,
+        oldest_unapplied,
+        inbound_paused: agent.inbound_paused(),
+        outbound_paused: agent.outbound_paused(),
+    }
+}
+
+/// Readiness probe, 503 until the node is caught up
+pub async fn api_v1_health(
+    Extension(agent): Extension<Agent>,
+    Extension(bookie): Extension<Bookie>,
+) -> (StatusCode, axum::Json<HealthResponse>) {
+    let health = health(&agent, &bookie).await;
+    let status = if health.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, axum::Json(health))
+}
+
+/// Liveness probe, 503 when the runtime is too busy to schedule tasks
+pub async fn api_v1_health_live() -> (StatusCode, axum::Json<LivenessResponse>) {
+    let live = live().await;
+    let status = if live {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, axum::Json(LivenessResponse { live }))
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_tests::launch_test_agent;
+    use corro_types::{agent::ReplicationDirection, api::Statement, config::HealthConfig};
+    use spawn::wait_for_all_pending_handles;
+    use tokio::time::{sleep, timeout};
+    use tripwire::Tripwire;
+
+    use super::*;
+    use crate::api::{
+        peer::parallel_sync,
+        public::{api_v1_transactions, TimeoutParams},
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_health_readiness_follows_gaps() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(
+            |conf| {
+                conf.health(HealthConfig {
+                    max_gaps: 0,
+                    min_members: 0,
+                })
+                .build()
+            },
+            tripwire.clone(),
+        )
+        .await?;
+        let ta1_actor_id = ta1.agent.actor_id();
+        let members = vec![(ta1_actor_id, ta1.agent.gossip_addr())];
+
+        for i in 1..=3i64 {
+            let (status_code, _) = api_v1_transactions(
+                Extension(ta1.agent.clone()),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::Json(vec![Statement::WithParams(
+                    "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                    vec![i.into(), format!("row {i}").into()],
+                )]),
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+        }
+
+        let probe = || api_v1_health(Extension(ta2.agent.clone()), Extension(ta2.bookie.clone()));
+        let wait_ready = || async {
+            timeout(Duration::from_secs(5), async {
+                loop {
+                    let (status, health) = probe().await;
+                    if status == StatusCode::OK {
+                        return health.0;
+                    }
+                    sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+        };
+
+        let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+        parallel_sync(&ta2.agent, &ta2.transport, members.clone(), sync_state).await?;
+        timeout(Duration::from_secs(5), async {
+            while generate_sync(&ta2.bookie, ta2.agent.actor_id())
+                .await
+                .heads
+                .get(&ta1_actor_id)
+                != Some(&CrsqlDbVersion(3))
+            {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+        let health = wait_ready().await?;
+        assert!(health.live);
+        assert_eq!(health.gaps, 0);
+
+        // lose track of versions 1 and 2
+        {
+            let booked = ta2
+                .bookie
+                .write::<&str, _>("test", None)
+                .await
+                .ensure(ta1_actor_id);
+            let mut booked_write = booked.write::<&str, _>("test", None).await;
+            let mut snap = booked_write.snapshot();
+            snap.insert_gaps([CrsqlDbVersion(1)..=CrsqlDbVersion(2)]);
+            booked_write.commit_snapshot(snap);
+        }
+
+        let (status, health) = probe().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(health.live);
+        assert!(!health.ready);
+        assert_eq!(health.gaps, 2);
+        assert_eq!(health.largest_gap, 2);
+        assert_eq!(
+            health.oldest_unapplied,
+            Some(UnappliedVersion {
+                actor_id: ta1_actor_id.to_string(),
+                version: 1,
+                behind: 2,
+            })
+        );
+
+        // still live
+        let (status, live) = api_v1_health_live().await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(live.live);
+
+        let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+        parallel_sync(&ta2.agent, &ta2.transport, members.clone(), sync_state).await?;
+        let health = wait_ready().await?;
+        assert_eq!(health.gaps, 0);
+        assert_eq!(health.oldest_unapplied, None);
+
+        // learn about a new version from a peer without applying it
+        ta2.agent.pause_replication(ReplicationDirection::Inbound);
+        let (status_code, _) = api_v1_transactions(
+            Extension(ta1.agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                "INSERT INTO tests (id,text) VALUES (?,?)".into(),
+                vec![4i64.into(), "row 4".into()],
+            )]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let sync_state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+        parallel_sync(&ta2.agent, &ta2.transport, members, sync_state).await?;
+
+        let (status, health) = probe().await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(health.gaps, 1);
+        assert_eq!(
+            health.oldest_unapplied,
+            Some(UnappliedVersion {
+                actor_id: ta1_actor_id.to_string(),
+                version: 4,
+                behind: 0,
+            })
+        );
+
+        ta2.agent.resume_replication();
+        let health = wait_ready().await?;
+        assert_eq!(health.gaps, 0);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
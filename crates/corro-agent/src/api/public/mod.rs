@@ -1,4 +1,5 @@
 use std::{
+    borrow::Cow,
     collections::BTreeSet,
     net::SocketAddr,
     ops::Deref,
@@ -8,28 +9,37 @@ use std::{
 use antithesis_sdk::assert_sometimes;
 use axum::{extract::ConnectInfo, response::IntoResponse, Extension};
 use bytes::{BufMut, BytesMut};
-use compact_str::ToCompactString;
+use compact_str::{CompactString, ToCompactString};
 use corro_types::{
     agent::{Agent, ChangeError},
     api::{
-        ColumnName, ExecResponse, ExecResult, QueryEvent, Statement, TableStatRequest,
-        TableStatResponse,
+        ColumnMeta, ColumnName, ExecResponse, ExecResult, QueryEvent, SchemaChange,
+        SchemaChangeKind, Statement, TableName, TableStatRequest, TableStatResponse,
     },
     base::CrsqlDbVersion,
     broadcast::Timestamp,
     change::{insert_local_changes, InsertChangesInfo, SqliteValue},
-    schema::{apply_schema, parse_sql},
+    pubsub::{unpack_columns, UnpackError},
+    schema::{
+        apply_schema, parse_sql, table_digest, ApplySchemaError, ConstrainedSchemaError,
+        SchemaError,
+    },
     sqlite::SqlitePoolError,
 };
 use hyper::StatusCode;
 use metrics::{counter, histogram};
-use rusqlite::{params_from_iter, ToSql, Transaction};
+use rusqlite::{params_from_iter, Connection, ToSql, Transaction};
 use serde::Deserialize;
 use spawn::spawn_counted;
+use sqlite3_parser::{
+    ast::{Cmd, Stmt},
+    lexer::sql::Parser,
+};
 use sqlite_pool::{Committable, InterruptibleTransaction};
 
 use tokio::{
     sync::{
+        broadcast,
         mpsc::{self, channel},
         oneshot,
     },
@@ -37,9 +47,11 @@ use tokio::{
 };
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, trace, warn};
+use tripwire::Tripwire;
 
 use corro_types::broadcast::broadcast_changes;
 
+pub mod health;
 pub mod pubsub;
 
 pub mod update;
@@ -50,6 +62,209 @@ pub struct TimeoutParams {
     pub timeout: Option<u64>,
 }
 
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct QueryParams {
+    #[serde(default)]
+    pub timeout: Option<u64>,
+    #[serde(default)]
+    pub mode: QueryMode,
+    /// Only the rows of `table` changed after this cursor, see
+    /// [`ChangesSince`]
+    #[serde(default)]
+    pub since: Option<u64>,
+    #[serde(default)]
+    pub table: Option<TableName>,
+    /// Send a [`QueryEvent::Metadata`] line with the columns' types first
+    #[serde(default)]
+    pub metadata: bool,
+}
+
+/// What `/v1/queries` streams back for a statement
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum QueryMode {
+    /// The statement's rows
+    #[default]
+    Rows,
+    /// The statement's `EXPLAIN QUERY PLAN`, one row per step
+    Explain,
+    /// A single `count` row with the number of rows the statement returns
+    CountOnly,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryModeError {
+    #[error(transparent)]
+    Parse(#[from] sqlite3_parser::lexer::sql::Error),
+    #[error("only a single SELECT statement can be explained, counted or filtered by version")]
+    NotSelect,
+    #[error("`since` needs the `table` the rows come from")]
+    SinceWithoutTable,
+    #[error("`since` only applies to the rows mode")]
+    SinceNotRows,
+    #[error("unknown table '{0}'")]
+    UnknownTable(TableName),
+}
+
+impl QueryMode {
+    /// The SQL to run for `query` in this mode, bound to the same parameters
+    pub fn sql(self, query: &str) -> Result<Cow<'_, str>, QueryModeError> {
+        Ok(match self {
+            QueryMode::Rows => Cow::Borrowed(query),
+            QueryMode::Explain => format!("EXPLAIN QUERY PLAN {}", single_select(query)?).into(),
+            QueryMode::CountOnly => {
+                format!("SELECT COUNT(*) AS count FROM ({})", single_select(query)?).into()
+            }
+        })
+    }
+}
+
+/// Rows of a table changed after a cursor, for clients materializing it
+/// incrementally without subscribing. Rows are matched on the table's primary
+/// key, which the query has to return by name. Deleted rows come back as
+/// their primary key, and the query ends with the cursor to ask from next.
+///
+/// Cursors are positions in `__corro_apply_log`, where versions are logged in
+/// the order they're booked here. Versions of different actors don't compare
+/// and arrive out of order, only this order is local and monotonic.
+#[derive(Clone, Debug)]
+pub struct ChangesSince {
+    table: TableName,
+    pk: Vec<String>,
+    since: u64,
+}
+
+impl ChangesSince {
+    pub fn from_params(
+        agent: &Agent,
+        params: &QueryParams,
+    ) -> Result<Option<Self>, QueryModeError> {
+        let Some(since) = params.since else {
+            return Ok(None);
+        };
+        let table = params
+            .table
+            .clone()
+            .ok_or(QueryModeError::SinceWithoutTable)?;
+        if params.mode != QueryMode::Rows {
+            return Err(QueryModeError::SinceNotRows);
+        }
+        let pk = match agent.schema().read().tables.get(table.as_str()) {
+            Some(schema) => schema.pk.iter().cloned().collect(),
+            None => return Err(QueryModeError::UnknownTable(table)),
+        };
+
+        Ok(Some(Self { table, pk, since }))
+    }
+
+    /// Last booked position of the apply log, the next `since`. Never before
+    /// the requested one, e.g. when it comes from another node.
+    fn cursor(&self, conn: &Connection) -> rusqlite::Result<u64> {
+        let current: u64 = conn.query_row(
+            "SELECT COALESCE(MAX(seq), 0) FROM __corro_apply_log",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(current.max(self.since))
+    }
+
+    /// Changes booked after `cursor` are left to the next query, rows changed
+    /// since then are sent with their current values again. The table and
+    /// cursors are bound by name after the query's own parameters, see
+    /// [`ChangesSince::params`].
+    fn sql(&self, select: &str) -> String {
+        let pk = self
+            .pk
+            .iter()
+            .map(|col| format!("\"{}\"", col.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(",");
+        // on its own line, the query could end with a comment
+        format!(
+            r#"SELECT * FROM ({select}
+) WHERE crsql_pack_columns({pk}) IN (SELECT c.pk FROM __corro_apply_log l JOIN crsql_changes c ON c.site_id = l.site_id AND c.db_version >= l.start_version AND c.db_version <= l.end_version WHERE l.seq > :corro_since AND l.seq <= :corro_since_cursor AND c."table" = :corro_since_table)"#
+        )
+    }
+
+    /// Values of the parameters [`ChangesSince::sql`] adds, numbered after the
+    /// query's own in the order they appear
+    fn params<'a>(&'a self, cursor: &'a u64) -> [(&'static str, &'a dyn ToSql); 3] {
+        [
+            (":corro_since", &self.since),
+            (":corro_since_cursor", cursor),
+            (":corro_since_table", &self.table),
+        ]
+    }
+
+    /// Primary keys of the rows deleted up to `cursor`
+    fn deleted(&self, conn: &Connection, cursor: u64) -> Result<Vec<Vec<SqliteValue>>, QueryError> {
+        let mut prepped = conn.prepare_cached(
+            r#"SELECT c.pk FROM __corro_apply_log l JOIN crsql_changes c ON c.site_id = l.site_id AND c.db_version >= l.start_version AND c.db_version <= l.end_version WHERE l.seq > ? AND l.seq <= ? AND c."table" = ? AND c.cid = '-1' AND c.cl % 2 = 0"#,
+        )?;
+        let pks = prepped
+            .query_map(
+                rusqlite::params![self.since, cursor, self.table.as_str()],
+                |row| row.get::<_, Vec<u8>>(0),
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        pks.iter()
+            .map(|pk| {
+                Ok(unpack_columns(pk)?
+                    .into_iter()
+                    .map(|value| value.to_owned())
+                    .collect())
+            })
+            .collect()
+    }
+}
+
+/// `query` without its trailing `;` and comments, if it's a single `SELECT`
+fn single_select(query: &str) -> Result<&str, QueryModeError> {
+    let mut parser = Parser::new(query.as_bytes());
+    if !matches!(parser.next()?, Some(Cmd::Stmt(Stmt::Select(_)))) || parser.next()?.is_some() {
+        return Err(QueryModeError::NotSelect);
+    }
+    Ok(&query[..statement_end(query)])
+}
+
+/// Offset just past the last token of `sql` that isn't a `;`, skipping over
+/// whitespace, comments and quoted strings or identifiers
+fn statement_end(sql: &str) -> usize {
+    let bytes = sql.as_bytes();
+    let skip_to = |from: usize, pat: &[u8]| {
+        bytes[from..]
+            .windows(pat.len())
+            .position(|w| w == pat)
+            .map_or(bytes.len(), |pos| from + pos + pat.len())
+    };
+
+    let mut end = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'-' if bytes.get(i + 1) == Some(&b'-') => i = skip_to(i + 2, b"\n"),
+            b'/' if bytes.get(i + 1) == Some(&b'*') => i = skip_to(i + 2, b"*/"),
+            b';' => i += 1,
+            b if b.is_ascii_whitespace() => i += 1,
+            quote @ (b'\'' | b'"' | b'`' | b'[') => {
+                let close = if quote == b'[' { b']' } else { quote };
+                // a doubled quote is an escape, scanned as two strings
+                i = skip_to(i + 1, &[close]);
+                end = i;
+            }
+            _ => {
+                i += 1;
+                end = i;
+            }
+        }
+    }
+    end
+}
+
+/// Runs `f` in a write transaction and broadcasts the changes it made. Not
+/// subject to the [`WriteLimiter`](corro_types::agent::WriteLimiter), public
+/// API handlers check it before calling this.
 pub async fn make_broadcastable_changes<F, T>(
     agent: &Agent,
     timeout: Option<u64>,
@@ -62,6 +277,12 @@ where
     let mut conn = agent.pool().write_priority().await?;
     trace!("got conn");
 
+    // checked with the write conn held: a shutdown waits on it before
+    // flushing, so no write can slip in after
+    if !agent.accepts_writes() {
+        return Err(ChangeError::ShuttingDown);
+    }
+
     let actor_id = agent.actor_id();
     // maybe we should do this earlier, but there can only ever be 1 write conn at a time,
     // so it probably doesn't matter too much, except for reads of internal state
@@ -196,6 +417,24 @@ pub async fn api_v1_transactions(
     }
 
     assert_sometimes!(true, "Corrosion receives transactions through HTTP API");
+
+    // refused right away rather than queued behind the write connection
+    let bytes = statements.iter().map(Statement::estimated_byte_size).sum();
+    if let Err(e) = agent.write_limiter().check(statements.len(), bytes) {
+        debug!("throttled transaction: {e}");
+        return (
+            StatusCode::TOO_MANY_REQUESTS,
+            axum::Json(ExecResponse {
+                results: vec![ExecResult::Error {
+                    error: ChangeError::from(e).to_string(),
+                }],
+                time: 0.0,
+                version: None,
+                actor_id: Some(actor_id),
+            }),
+        );
+    }
+
     let res = make_broadcastable_changes(&agent, params.timeout, move |tx| {
         let mut total_rows_affected = 0;
 
@@ -230,8 +469,13 @@ pub async fn api_v1_transactions(
         Ok(res) => res,
         Err(e) => {
             error!("could not execute statement(s): {e}");
+            let status = match e {
+                ChangeError::ShuttingDown => StatusCode::SERVICE_UNAVAILABLE,
+                ChangeError::ValueTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                _ => StatusCode::INTERNAL_SERVER_ERROR,
+            };
             return (
-                StatusCode::INTERNAL_SERVER_ERROR,
+                status,
                 axum::Json(ExecResponse {
                     results: vec![ExecResult::Error {
                         error: e.to_string(),
@@ -261,6 +505,8 @@ pub enum QueryError {
     Pool(#[from] SqlitePoolError),
     #[error("sqlite error: {0}")]
     Rusqlite(#[from] rusqlite::Error),
+    #[error(transparent)]
+    Unpack(#[from] UnpackError),
 }
 
 async fn build_query_rows_response(
@@ -268,8 +514,29 @@ async fn build_query_rows_response(
     client_addr: SocketAddr,
     data_tx: mpsc::Sender<QueryEvent>,
     stmt: Statement,
-    timeout: Option<u64>,
+    params: QueryParams,
 ) -> Result<(), (StatusCode, ExecResult)> {
+    let sql = ChangesSince::from_params(agent, &params).and_then(|since| {
+        let sql = match since {
+            Some(_) => single_select(stmt.query()).map(Cow::Borrowed)?,
+            None => params.mode.sql(stmt.query())?,
+        };
+        Ok((sql.into_owned(), since))
+    });
+    let (sql, since) = match sql {
+        Ok(sql) => sql,
+        Err(e) => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                ExecResult::Error {
+                    error: e.to_string(),
+                },
+            ))
+        }
+    };
+    let timeout = params.timeout;
+    let with_metadata = params.metadata;
+
     let (res_tx, res_rx) = oneshot::channel();
 
     let pool = agent.pool().clone();
@@ -288,9 +555,29 @@ async fn build_query_rows_response(
             }
         };
 
-        trace!(%client_addr, "Preparing statement {}", stmt.query());
+        let since = match since {
+            Some(since) => match block_in_place(|| since.cursor(&conn)) {
+                Ok(cursor) => Some((since, cursor)),
+                Err(e) => {
+                    _ = res_tx.send(Err((
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        ExecResult::Error {
+                            error: e.to_string(),
+                        },
+                    )));
+                    return;
+                }
+            },
+            None => None,
+        };
+        let sql = match &since {
+            Some((since, _)) => since.sql(&sql),
+            None => sql,
+        };
 
-        let prepped_res = block_in_place(|| conn.prepare(stmt.query()));
+        trace!(%client_addr, "Preparing statement {sql}");
+
+        let prepped_res = block_in_place(|| conn.prepare_cached_query(&sql));
 
         let mut prepped = match prepped_res {
             Ok(prepped) => prepped,
@@ -344,34 +631,43 @@ async fn build_query_rows_response(
             let col_count = prepped.column_count();
             trace!("inside block in place, col count: {col_count}");
 
-            if let Err(e) = data_tx.blocking_send(QueryEvent::Columns(
-                prepped
-                    .columns()
-                    .into_iter()
-                    .map(|col| ColumnName(col.name().to_compact_string()))
-                    .collect(),
-            )) {
-                error!("could not send back columns: {e}");
-                return;
-            }
+            let columns: Vec<(ColumnName, Option<CompactString>)> = prepped
+                .columns()
+                .into_iter()
+                .map(|col| {
+                    (
+                        ColumnName(col.name().to_compact_string()),
+                        col.decl_type().map(CompactString::from),
+                    )
+                })
+                .collect();
 
             let start = Instant::now();
 
             trace!(%client_addr, "Executing statement {}", stmt.query());
             let elapsed = start.elapsed();
 
+            let since_params = match &since {
+                Some((since, cursor)) => since.params(cursor).to_vec(),
+                None => vec![],
+            };
             let query = match &stmt {
                 Statement::Simple(_)
                 | Statement::Verbose {
                     params: None,
                     named_params: None,
                     ..
-                } => prepped.query(()),
+                } => prepped.query(since_params.as_slice()),
                 Statement::WithParams(_, params)
                 | Statement::Verbose {
                     params: Some(params),
                     ..
-                } => prepped.query(params_from_iter(params)),
+                } => prepped.query(params_from_iter(
+                    params
+                        .iter()
+                        .map(|v| v as &dyn ToSql)
+                        .chain(since_params.iter().map(|(_, v)| *v)),
+                )),
                 Statement::WithNamedParams(_, params)
                 | Statement::Verbose {
                     named_params: Some(params),
@@ -380,6 +676,7 @@ async fn build_query_rows_response(
                     params
                         .iter()
                         .map(|(k, v)| (k.as_str(), v as &dyn ToSql))
+                        .chain(since_params.iter().copied())
                         .collect::<Vec<(&str, &dyn ToSql)>>()
                         .as_slice(),
                 ),
@@ -409,37 +706,86 @@ async fn build_query_rows_response(
                 return;
             }
 
+            let row_cells = |row: &rusqlite::Row| {
+                trace!("got a row: {row:?}");
+                (0..col_count)
+                    .map(|i| row.get::<_, SqliteValue>(i))
+                    .collect::<rusqlite::Result<Vec<_>>>()
+            };
+
+            // expressions have no declared type, the first row gives them one
+            let mut next = rows
+                .next()
+                .and_then(|row| row.map(row_cells).transpose())
+                .transpose();
+
+            if with_metadata {
+                let first = match &next {
+                    Some(Ok(cells)) => Some(cells),
+                    _ => None,
+                };
+                let metadata = QueryEvent::Metadata {
+                    columns: columns
+                        .iter()
+                        .enumerate()
+                        .map(|(i, (name, decl_type))| ColumnMeta {
+                            name: name.clone(),
+                            sql_type: decl_type.clone().or_else(|| {
+                                first
+                                    .and_then(|cells| cells[i].column_type().sqlite_name())
+                                    .map(CompactString::from)
+                            }),
+                        })
+                        .collect(),
+                };
+                if let Err(e) = data_tx.blocking_send(metadata) {
+                    error!("could not send back columns metadata: {e}");
+                    return;
+                }
+            }
+
+            if let Err(e) = data_tx.blocking_send(QueryEvent::Columns(
+                columns.into_iter().map(|(name, _)| name).collect(),
+            )) {
+                error!("could not send back columns: {e}");
+                return;
+            }
+
             let mut rowid = 1;
 
             trace!("about to loop through rows!");
 
-            loop {
-                match rows.next() {
-                    Ok(Some(row)) => {
-                        trace!("got a row: {row:?}");
-                        match (0..col_count)
-                            .map(|i| row.get::<_, SqliteValue>(i))
-                            .collect::<rusqlite::Result<Vec<_>>>()
+            while let Some(res) = next {
+                match res {
+                    Ok(cells) => {
+                        if let Err(e) = data_tx.blocking_send(QueryEvent::Row(rowid.into(), cells))
                         {
-                            Ok(cells) => {
-                                if let Err(e) =
-                                    data_tx.blocking_send(QueryEvent::Row(rowid.into(), cells))
-                                {
-                                    error!("could not send back row: {e}");
-                                    return;
-                                }
-                                rowid += 1;
-                            }
-                            Err(e) => {
-                                _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
+                            error!("could not send back row: {e}");
+                            return;
+                        }
+                        rowid += 1;
+                    }
+                    Err(e) => {
+                        _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
+                        return;
+                    }
+                }
+                next = rows
+                    .next()
+                    .and_then(|row| row.map(row_cells).transpose())
+                    .transpose();
+            }
+
+            if let Some((since, cursor)) = &since {
+                match since.deleted(&conn, *cursor) {
+                    Ok(pks) => {
+                        for pk in pks {
+                            if let Err(e) = data_tx.blocking_send(QueryEvent::Deleted(pk)) {
+                                error!("could not send back deleted row: {e}");
                                 return;
                             }
                         }
                     }
-                    Ok(None) => {
-                        // done!
-                        break;
-                    }
                     Err(e) => {
                         _ = data_tx.blocking_send(QueryEvent::Error(e.to_compact_string()));
                         return;
@@ -450,6 +796,7 @@ async fn build_query_rows_response(
             _ = data_tx.blocking_send(QueryEvent::EndOfQuery {
                 time: elapsed.as_secs_f64(),
                 change_id: None,
+                cursor: since.as_ref().map(|(_, cursor)| *cursor),
             });
         });
     });
@@ -468,7 +815,7 @@ async fn build_query_rows_response(
 pub async fn api_v1_queries(
     Extension(agent): Extension<Agent>,
     ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
-    axum::extract::Query(params): axum::extract::Query<TimeoutParams>,
+    axum::extract::Query(params): axum::extract::Query<QueryParams>,
     axum::extract::Json(stmt): axum::extract::Json<Statement>,
 ) -> impl IntoResponse {
     let (mut tx, body) = hyper::Body::channel();
@@ -511,7 +858,7 @@ pub async fn api_v1_queries(
     trace!("building query rows response...");
     assert_sometimes!(true, "Corrosion accepts queries");
 
-    match build_query_rows_response(&agent, client_addr, data_tx, stmt, params.timeout).await {
+    match build_query_rows_response(&agent, client_addr, data_tx, stmt, params).await {
         Ok(_) => {
             histogram!("corro.api.queries.processing.time.seconds", "result" => "success")
                 .record(start.elapsed());
@@ -546,6 +893,10 @@ pub(crate) async fn execute_schema(agent: &Agent, statements: Vec<String>) -> ey
     let mut conn = agent.pool().write_priority().await?;
     info!("got write connection to update schema");
 
+    if !agent.accepts_writes() {
+        return Err(ChangeError::ShuttingDown.into());
+    }
+
     // hold onto this lock so nothing else makes changes
     let mut schema_write = agent.schema().write();
 
@@ -587,119 +938,423 @@ pub(crate) async fn execute_schema(agent: &Agent, statements: Vec<String>) -> ey
 
     apply_res?;
 
+    let schema_changes: Vec<SchemaChange> = partial_schema
+        .tables
+        .keys()
+        .filter_map(|name| {
+            let table = new_schema.tables.get(name)?;
+            let kind = match schema_write.tables.get(name) {
+                None => SchemaChangeKind::Created,
+                Some(prev) if prev.columns != table.columns => SchemaChangeKind::Altered,
+                Some(_) => return None,
+            };
+            Some(SchemaChange {
+                table: name.as_str().into(),
+                kind,
+                columns: table
+                    .columns
+                    .keys()
+                    .map(|col| col.as_str().into())
+                    .collect(),
+            })
+        })
+        .collect();
+
     *schema_write = new_schema;
+    drop(schema_write);
+
+    for change in schema_changes {
+        info!(table = %change.table, kind = ?change.kind, "schema changed");
+        agent.notify_schema_change(change);
+    }
 
     Ok(())
 }
 
-pub async fn api_v1_db_schema(
-    Extension(agent): Extension<Agent>,
-    axum::extract::Json(statements): axum::extract::Json<Vec<String>>,
-) -> (StatusCode, axum::Json<ExecResponse>) {
-    let actor_id = agent.actor_id().to_string();
-    if statements.is_empty() {
-        return (
-            StatusCode::BAD_REQUEST,
-            axum::Json(ExecResponse {
-                results: vec![ExecResult::Error {
-                    error: "at least 1 statement is required".into(),
-                }],
-                time: 0.0,
-                version: None,
-                actor_id: Some(actor_id),
-            }),
-        );
-    }
-
-    let start = Instant::now();
+#[derive(Debug, thiserror::Error)]
+pub enum EnableCrrError {
+    #[error("table '{0}' does not exist")]
+    TableNotFound(String),
+    #[error("table '{0}' has no primary key, it can't be replicated")]
+    MissingPrimaryKey(String),
+    #[error("table '{tbl_name}' has unsupported types or constraints: {source}")]
+    Unsupported {
+        tbl_name: String,
+        source: ConstrainedSchemaError,
+    },
+    #[error(transparent)]
+    Schema(#[from] Box<SchemaError>),
+    #[error(transparent)]
+    ApplySchema(#[from] ApplySchemaError),
+    #[error(transparent)]
+    Change(#[from] ChangeError),
+    #[error(transparent)]
+    Rusqlite(#[from] rusqlite::Error),
+}
 
-    assert_sometimes!(true, "Corrosion applies schema");
-    if let Err(e) = execute_schema(&agent, statements).await {
-        error!("could not merge schemas: {e}");
-        return (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            axum::Json(ExecResponse {
-                results: vec![ExecResult::Error {
-                    error: e.to_string(),
-                }],
-                time: 0.0,
-                version: None,
-                actor_id: Some(actor_id),
-            }),
-        );
+impl EnableCrrError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            EnableCrrError::TableNotFound(_) => StatusCode::NOT_FOUND,
+            EnableCrrError::MissingPrimaryKey(_) | EnableCrrError::Unsupported { .. } => {
+                StatusCode::BAD_REQUEST
+            }
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
     }
-
-    (
-        StatusCode::OK,
-        axum::Json(ExecResponse {
-            results: vec![],
-            time: start.elapsed().as_secs_f64(),
-            version: None,
-            actor_id: Some(actor_id),
-        }),
-    )
 }
 
-/// Query the table status of the current node
+/// Brings an existing, plain SQLite table into replication: the table is
+/// turned into a CRR, its existing rows are backfilled as local changes and
+/// booked like any other local transaction so peers converge on them.
 ///
-/// Currently this endpoint only supports querying the row count for a
-/// selection of provided tables.  Table names are checked for
-/// existence before querying
-pub async fn api_v1_table_stats(
-    Extension(agent): Extension<Agent>,
-    axum::extract::Json(ts_req): axum::extract::Json<TableStatRequest>,
-) -> (StatusCode, axum::Json<TableStatResponse>) {
-    async fn count_table_lengths(
-        agent: &Agent,
-        ts_req: TableStatRequest,
-    ) -> eyre::Result<(i64, Vec<String>)> {
-        debug!("Querying row count for {} tables", ts_req.tables.len());
-        let conn = agent.pool().read().await?;
+/// Enabling a table that is already part of the schema is a no-op.
+pub async fn enable_crr(
+    agent: &Agent,
+    tbl_name: &str,
+) -> Result<Option<CrsqlDbVersion>, EnableCrrError> {
+    if agent.schema().read().tables.contains_key(tbl_name) {
+        debug!("table '{tbl_name}' is already a CRR");
+        return Ok(None);
+    }
 
-        block_in_place(move || -> eyre::Result<(i64, Vec<String>)> {
-            let valid_tables: BTreeSet<String> = conn
-                .prepare_cached("select name from sqlite_schema where type = 'table'")?
-                .query_map([], |row| row.get(0))?
-                .filter_map(|name| name.ok())
-                .collect();
+    let mut conn = agent
+        .pool()
+        .write_priority()
+        .await
+        .map_err(ChangeError::from)?;
+    let mut book_writer = agent
+        .booked()
+        .write::<&str, _>("enable_crr(booked writer)", None)
+        .await;
 
-            let mut invalid_tables = vec![];
-            let mut total_count = 0;
-            for table in ts_req.tables.into_iter() {
-                if !valid_tables.contains(&table) {
-                    error!("Table name {} doesn't exist!", &table);
-                    invalid_tables.push(table);
-                    continue;
-                }
+    let actor_id = agent.actor_id();
+    let ts = Timestamp::from(agent.clock().new_timestamp());
 
-                let count: i64 = conn
-                    .prepare_cached(&format!("SELECT COUNT(*) FROM {}", &table))?
-                    .query_row((), |row| row.get(0))?;
+    // hold onto this lock so nothing else makes schema changes
+    let mut schema_write = agent.schema().write();
 
-                total_count += count;
-            }
-            Ok((total_count, invalid_tables))
-        })
+    // checked again while holding the lock, could've been enabled concurrently
+    if schema_write.tables.contains_key(tbl_name) {
+        return Ok(None);
     }
 
-    match count_table_lengths(&agent, ts_req).await {
-        Ok((count, invalid_tables)) => (
-            StatusCode::OK,
-            axum::Json(TableStatResponse {
-                total_row_count: count,
-                invalid_tables,
-            }),
-        ),
-        Err(_) => (
-            StatusCode::INTERNAL_SERVER_ERROR,
-            axum::Json(TableStatResponse {
-                total_row_count: 0,
-                // Since we don't know what error occured or if any
-                // tables were valid, we just return an empty list
-                invalid_tables: vec![],
-            }),
-        ),
-    }
+    let (new_schema, insert_info) = block_in_place(|| {
+        let tx = conn.immediate_transaction()?;
+
+        let sql: Vec<String> = tx
+            .prepare("SELECT sql FROM sqlite_schema WHERE tbl_name = ? AND type IN ('table', 'index') AND name IS NOT NULL AND sql IS NOT NULL")?
+            .query_map([tbl_name], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let table = parse_sql(&sql.join(";"))?
+            .tables
+            .shift_remove(tbl_name)
+            .ok_or_else(|| EnableCrrError::TableNotFound(tbl_name.to_owned()))?;
+
+        if table.pk.is_empty() {
+            return Err(EnableCrrError::MissingPrimaryKey(tbl_name.to_owned()));
+        }
+
+        let mut new_schema = schema_write.clone();
+        new_schema.tables.insert(tbl_name.to_owned(), table);
+        new_schema
+            .constrain()
+            .map_err(|source| EnableCrrError::Unsupported {
+                tbl_name: tbl_name.to_owned(),
+                source,
+            })?;
+
+        tx.prepare_cached("SELECT crsql_set_ts(?)")?
+            .query_row([&ts], |row| row.get::<_, String>(0))?;
+
+        // creating the table fails, so the existing definition is imported and
+        // `crsql_as_crr` backfills clock entries for the existing rows
+        apply_schema(&tx, &schema_write, &mut new_schema)?;
+
+        tx.execute("INSERT INTO __corro_schema SELECT tbl_name, type, name, sql, 'api' AS source FROM sqlite_schema WHERE tbl_name = ? AND type IN ('table', 'index') AND name IS NOT NULL AND sql IS NOT NULL", [tbl_name])?;
+
+        let insert_info = insert_local_changes(agent, &tx, &mut book_writer)?;
+
+        tx.commit().map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: insert_info.as_ref().map(|info| info.db_version),
+        })?;
+
+        // drain the pool of RO connections because they might not get the new tables in cr-sqlite!
+        agent.pool().drain_read();
+
+        Ok::<_, EnableCrrError>((new_schema, insert_info))
+    })?;
+
+    let columns = new_schema
+        .tables
+        .get(tbl_name)
+        .map(|table| {
+            table
+                .columns
+                .keys()
+                .map(|col| col.as_str().into())
+                .collect()
+        })
+        .unwrap_or_default();
+
+    *schema_write = new_schema;
+    drop(schema_write);
+
+    info!("enabled replication for table '{tbl_name}'");
+    agent.notify_schema_change(SchemaChange {
+        table: tbl_name.into(),
+        kind: SchemaChangeKind::Created,
+        columns,
+    });
+
+    let Some(InsertChangesInfo {
+        db_version,
+        last_seq,
+        ts,
+        snap,
+    }) = insert_info
+    else {
+        // empty table, nothing to backfill
+        return Ok(None);
+    };
+
+    book_writer.commit_snapshot(snap);
+    drop(book_writer);
+
+    let agent = agent.clone();
+    spawn_counted(async move { broadcast_changes(agent, db_version, last_seq, ts).await });
+
+    Ok(Some(db_version))
+}
+
+/// Enables replication for an existing table, see [`enable_crr`]
+pub async fn api_v1_enable_crr(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(table): axum::extract::Path<String>,
+) -> (StatusCode, axum::Json<ExecResponse>) {
+    let actor_id = agent.actor_id().to_string();
+    let start = Instant::now();
+
+    match enable_crr(&agent, &table).await {
+        Ok(version) => (
+            StatusCode::OK,
+            axum::Json(ExecResponse {
+                results: vec![],
+                time: start.elapsed().as_secs_f64(),
+                version: version.map(Into::into),
+                actor_id: Some(actor_id),
+            }),
+        ),
+        Err(e) => {
+            error!("could not enable replication for table '{table}': {e}");
+            (
+                e.status_code(),
+                axum::Json(ExecResponse {
+                    results: vec![ExecResult::Error {
+                        error: e.to_string(),
+                    }],
+                    time: 0.0,
+                    version: None,
+                    actor_id: Some(actor_id),
+                }),
+            )
+        }
+    }
+}
+
+/// Order-independent content hash of a table, to compare with a peer's, see
+/// [`table_digest`]
+pub async fn api_v1_table_digest(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Path(table): axum::extract::Path<String>,
+) -> axum::response::Response {
+    let Some(schema_table) = agent.schema().read().tables.get(&table).cloned() else {
+        return (StatusCode::NOT_FOUND, format!("unknown table '{table}'")).into_response();
+    };
+
+    let res = match agent.pool().read().await {
+        Ok(conn) => {
+            block_in_place(|| table_digest(&conn, &schema_table)).map_err(|e| e.to_string())
+        }
+        Err(e) => Err(e.to_string()),
+    };
+
+    match res {
+        Ok(digest) => (StatusCode::OK, axum::Json(digest)).into_response(),
+        Err(e) => {
+            error!("could not compute digest of table '{table}': {e}");
+            (StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
+        }
+    }
+}
+
+/// Streams a newline-delimited JSON [`SchemaChange`] for every table created
+/// or altered through schema application, until the client disconnects
+pub async fn api_v1_schema_changes(
+    Extension(agent): Extension<Agent>,
+    Extension(tripwire): Extension<Tripwire>,
+) -> impl IntoResponse {
+    let mut rx = agent.subscribe_schema_changes();
+    let (mut tx, body) = hyper::Body::channel();
+
+    spawn_counted(async move {
+        let mut tripwire = tripwire;
+        loop {
+            let change = tokio::select! {
+                res = rx.recv() => match res {
+                    Ok(change) => change,
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        warn!("schema changes listener lagged, skipped {skipped} notifications");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = &mut tripwire => break,
+            };
+
+            let mut line = match serde_json::to_vec(&change) {
+                Ok(line) => line,
+                Err(e) => {
+                    error!("could not serialize schema change: {e}");
+                    continue;
+                }
+            };
+            line.push(b'\n');
+
+            if let Err(e) = tx.send_data(line.into()).await {
+                debug!("schema changes listener is gone: {e}");
+                break;
+            }
+        }
+    });
+
+    hyper::Response::builder()
+        .status(StatusCode::OK)
+        .body(body)
+        .expect("could not build schema changes response body")
+}
+
+pub async fn api_v1_db_schema(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(statements): axum::extract::Json<Vec<String>>,
+) -> (StatusCode, axum::Json<ExecResponse>) {
+    let actor_id = agent.actor_id().to_string();
+    if statements.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            axum::Json(ExecResponse {
+                results: vec![ExecResult::Error {
+                    error: "at least 1 statement is required".into(),
+                }],
+                time: 0.0,
+                version: None,
+                actor_id: Some(actor_id),
+            }),
+        );
+    }
+
+    let start = Instant::now();
+
+    assert_sometimes!(true, "Corrosion applies schema");
+    if let Err(e) = execute_schema(&agent, statements).await {
+        error!("could not merge schemas: {e}");
+        let status_code = match e.downcast_ref::<ChangeError>() {
+            Some(ChangeError::ShuttingDown) => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        return (
+            status_code,
+            axum::Json(ExecResponse {
+                results: vec![ExecResult::Error {
+                    error: e.to_string(),
+                }],
+                time: 0.0,
+                version: None,
+                actor_id: Some(actor_id),
+            }),
+        );
+    }
+
+    (
+        StatusCode::OK,
+        axum::Json(ExecResponse {
+            results: vec![],
+            time: start.elapsed().as_secs_f64(),
+            version: None,
+            actor_id: Some(actor_id),
+        }),
+    )
+}
+
+/// Serves the JSON Schema of the HTTP API's request and response types, for
+/// generating clients
+pub async fn api_v1_api_schema() -> axum::Json<serde_json::Value> {
+    axum::Json(corro_types::api::schema::api_schema())
+}
+
+/// Query the table status of the current node
+///
+/// Currently this endpoint only supports querying the row count for a
+/// selection of provided tables.  Table names are checked for
+/// existence before querying
+pub async fn api_v1_table_stats(
+    Extension(agent): Extension<Agent>,
+    axum::extract::Json(ts_req): axum::extract::Json<TableStatRequest>,
+) -> (StatusCode, axum::Json<TableStatResponse>) {
+    async fn count_table_lengths(
+        agent: &Agent,
+        ts_req: TableStatRequest,
+    ) -> eyre::Result<(i64, Vec<String>)> {
+        debug!("Querying row count for {} tables", ts_req.tables.len());
+        let conn = agent.pool().read().await?;
+
+        block_in_place(move || -> eyre::Result<(i64, Vec<String>)> {
+            let valid_tables: BTreeSet<String> = conn
+                .prepare_cached("select name from sqlite_schema where type = 'table'")?
+                .query_map([], |row| row.get(0))?
+                .filter_map(|name| name.ok())
+                .collect();
+
+            let mut invalid_tables = vec![];
+            let mut total_count = 0;
+            for table in ts_req.tables.into_iter() {
+                if !valid_tables.contains(&table) {
+                    error!("Table name {} doesn't exist!", &table);
+                    invalid_tables.push(table);
+                    continue;
+                }
+
+                let count: i64 = conn
+                    .prepare_cached(&format!("SELECT COUNT(*) FROM {}", &table))?
+                    .query_row((), |row| row.get(0))?;
+
+                total_count += count;
+            }
+            Ok((total_count, invalid_tables))
+        })
+    }
+
+    match count_table_lengths(&agent, ts_req).await {
+        Ok((count, invalid_tables)) => (
+            StatusCode::OK,
+            axum::Json(TableStatResponse {
+                total_row_count: count,
+                invalid_tables,
+            }),
+        ),
+        Err(_) => (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            axum::Json(TableStatResponse {
+                total_row_count: 0,
+                // Since we don't know what error occured or if any
+                // tables were valid, we just return an empty list
+                invalid_tables: vec![],
+            }),
+        ),
+    }
 }
 
 #[cfg(test)]
@@ -862,7 +1517,7 @@ mod tests {
         let res = api_v1_queries(
             Extension(agent.clone()),
             ConnectInfo("127.0.0.1:1234".parse().unwrap()),
-            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::extract::Query(QueryParams::default()),
             axum::Json(Statement::Simple("select * from tests".into())),
         )
         .await
@@ -923,8 +1578,9 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_api_db_schema() -> eyre::Result<()> {
+    async fn test_api_db_query_metadata() -> eyre::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
+
         let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
 
         let dir = tempfile::tempdir()?;
@@ -941,62 +1597,590 @@ mod tests {
 
         let (status_code, _body) = api_v1_db_schema(
             Extension(agent.clone()),
-            axum::Json(vec![
-                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
-            ]),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
         )
         .await;
-
         assert_eq!(status_code, StatusCode::OK);
 
-        // scope the schema reader in here
-        {
-            let schema = agent.schema().read();
-            let tests = schema
-                .tables
-                .get("tests")
-                .expect("no tests table in schema");
-
-            let id_col = tests.columns.get("id").unwrap();
-            assert_eq!(id_col.name, "id");
-            assert_eq!(id_col.sql_type(), (SqliteType::Integer, Some("BIGINT")));
-            assert!(!id_col.nullable);
-            assert!(id_col.primary_key);
-
-            let foo_col = tests.columns.get("foo").unwrap();
-            assert_eq!(foo_col.name, "foo");
-            assert_eq!(foo_col.sql_type(), (SqliteType::Text, Some("TEXT")));
-            assert!(foo_col.nullable);
-            assert!(!foo_col.primary_key);
-        }
-
-        let (status_code, _body) = api_v1_db_schema(
+        let (status_code, _body) = api_v1_transactions(
             Extension(agent.clone()),
-            axum::Json(vec![
-                "CREATE TABLE tests2 (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
-                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
-            ]),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                "insert into tests (id, text) values (?,?)".into(),
+                vec![1i64.into(), "service-name".into()],
+            )]),
         )
         .await;
-
         assert_eq!(status_code, StatusCode::OK);
 
-        {
-            let schema = agent.schema().read();
-            let tests = schema
-                .tables
-                .get("tests")
-                .expect("no tests table in schema");
+        let query_events = |query: &'static str, metadata: bool| {
+            let agent = agent.clone();
+            async move {
+                let res = api_v1_queries(
+                    Extension(agent),
+                    ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+                    axum::extract::Query(QueryParams {
+                        metadata,
+                        ..Default::default()
+                    }),
+                    axum::Json(Statement::Simple(query.into())),
+                )
+                .await
+                .into_response();
+                assert_eq!(res.status(), StatusCode::OK);
+
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                let events = body
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(serde_json::from_slice::<QueryEvent>)
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok::<_, eyre::Report>(events)
+            }
+        };
 
-            let id_col = tests.columns.get("id").unwrap();
-            assert_eq!(id_col.name, "id");
-            assert_eq!(id_col.sql_type(), (SqliteType::Integer, Some("BIGINT")));
-            assert!(!id_col.nullable);
-            assert!(id_col.primary_key);
+        // only sent when asked for
+        let events = query_events("SELECT id FROM tests", false).await?;
+        assert_eq!(events[0], QueryEvent::Columns(vec!["id".into()]));
 
-            let foo_col = tests.columns.get("foo").unwrap();
-            assert_eq!(foo_col.name, "foo");
-            assert_eq!(foo_col.sql_type(), (SqliteType::Text, Some("TEXT")));
+        // a declared column and an expression
+        let events = query_events("SELECT id, text || '!' AS shout FROM tests", true).await?;
+        assert_eq!(
+            events[0],
+            QueryEvent::Metadata {
+                columns: vec![
+                    ColumnMeta {
+                        name: "id".into(),
+                        sql_type: Some("INTEGER".into()),
+                    },
+                    ColumnMeta {
+                        name: "shout".into(),
+                        sql_type: Some("TEXT".into()),
+                    },
+                ],
+            }
+        );
+        assert_eq!(
+            events[1],
+            QueryEvent::Columns(vec!["id".into(), "shout".into()])
+        );
+        assert_eq!(
+            events[2],
+            QueryEvent::Row(RowId(1), vec![1i64.into(), "service-name!".into()])
+        );
+
+        // no row to type the expression with
+        let events = query_events("SELECT text, id * 1.5 FROM tests WHERE id = 2", true).await?;
+        assert_eq!(
+            events[0],
+            QueryEvent::Metadata {
+                columns: vec![
+                    ColumnMeta {
+                        name: "text".into(),
+                        sql_type: Some("TEXT".into()),
+                    },
+                    ColumnMeta {
+                        name: "id * 1.5".into(),
+                        sql_type: None,
+                    },
+                ],
+            }
+        );
+        assert!(matches!(events[2], QueryEvent::EndOfQuery { .. }));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query_modes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let mut statements = vec![];
+        for i in 1..=5i64 {
+            statements.push(Statement::WithParams(
+                "insert into tests (id, text) values (?,?)".into(),
+                vec![i.into(), format!("service-{i}").into()],
+            ));
+        }
+        for i in 1..=3i64 {
+            statements.push(Statement::WithParams(
+                "insert into tests2 (id, text) values (?,?)".into(),
+                vec![i.into(), format!("other-{i}").into()],
+            ));
+        }
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(statements),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let query = |mode: QueryMode, stmt: Statement| {
+            let agent = agent.clone();
+            async move {
+                let res = api_v1_queries(
+                    Extension(agent),
+                    ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+                    axum::extract::Query(QueryParams {
+                        mode,
+                        ..Default::default()
+                    }),
+                    axum::Json(stmt),
+                )
+                .await
+                .into_response();
+                let status = res.status();
+
+                let body = hyper::body::to_bytes(res.into_body()).await?;
+                if status != StatusCode::OK {
+                    return Ok::<_, eyre::Report>((status, vec![]));
+                }
+                let rows = body
+                    .split(|b| *b == b'\n')
+                    .filter(|line| !line.is_empty())
+                    .map(serde_json::from_slice::<QueryEvent>)
+                    .filter_map(|event| match event {
+                        Ok(QueryEvent::Row(_, cells)) => Some(Ok(cells)),
+                        Ok(_) => None,
+                        Err(e) => Some(Err(e)),
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                Ok((status, rows))
+            }
+        };
+
+        let join = || {
+            Statement::WithParams(
+                "SELECT tests.id, tests2.text FROM tests JOIN tests2 ON tests2.id = tests.id WHERE tests.id > ?;"
+                    .into(),
+                vec![1i64.into()],
+            )
+        };
+
+        let (status, rows) = query(QueryMode::Rows, join()).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rows.len(), 2);
+
+        // the plan mentions both tables of the join
+        let (status, plan) = query(QueryMode::Explain, join()).await?;
+        assert_eq!(status, StatusCode::OK);
+        let details: Vec<String> = plan
+            .iter()
+            .map(|cells| cells[3].as_text().unwrap_or_default().to_string())
+            .collect();
+        assert!(details.iter().any(|detail| detail.contains("tests2")));
+        assert!(details
+            .iter()
+            .any(|detail| detail.contains("tests") && !detail.contains("tests2")));
+
+        let (status, count) = query(QueryMode::CountOnly, join()).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(count, vec![vec![SqliteValue::Integer(rows.len() as i64)]]);
+
+        let (status, count) = query(
+            QueryMode::CountOnly,
+            Statement::Simple("SELECT * FROM tests -- all of them".into()),
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(count, vec![vec![SqliteValue::Integer(5)]]);
+
+        for sql in [
+            "SELECT * FROM tests; -- all of them",
+            "SELECT * FROM tests /* all */ ;\n-- of them\n",
+            "SELECT * FROM tests WHERE text != '--;'; ",
+        ] {
+            let (status, count) =
+                query(QueryMode::CountOnly, Statement::Simple(sql.into())).await?;
+            assert_eq!(status, StatusCode::OK, "{sql}");
+            assert_eq!(count, vec![vec![SqliteValue::Integer(5)]], "{sql}");
+
+            let (status, _) = query(QueryMode::Explain, Statement::Simple(sql.into())).await?;
+            assert_eq!(status, StatusCode::OK, "{sql}");
+        }
+
+        // only SELECTs
+        for mode in [QueryMode::Explain, QueryMode::CountOnly] {
+            for sql in [
+                "DELETE FROM tests",
+                "PRAGMA table_info(tests)",
+                "SELECT 1; SELECT 2",
+            ] {
+                let (status, _) = query(mode, Statement::Simple(sql.into())).await?;
+                assert_eq!(status, StatusCode::BAD_REQUEST, "{mode:?}: {sql}");
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Runs `stmt` with `params`, returning the status, rows sorted by their
+    /// first column, deleted primary keys and cursor it got back
+    async fn query_since(
+        agent: Agent,
+        params: QueryParams,
+        stmt: Statement,
+    ) -> eyre::Result<(
+        StatusCode,
+        Vec<Vec<SqliteValue>>,
+        Vec<Vec<SqliteValue>>,
+        Option<u64>,
+    )> {
+        let res = api_v1_queries(
+            Extension(agent),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            axum::extract::Query(params),
+            axum::Json(stmt),
+        )
+        .await
+        .into_response();
+        let status = res.status();
+
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let (mut rows, mut deleted, mut cursor) = (vec![], vec![], None);
+        if status == StatusCode::OK {
+            for line in body.split(|b| *b == b'\n').filter(|line| !line.is_empty()) {
+                match serde_json::from_slice::<QueryEvent>(line)? {
+                    QueryEvent::Row(_, cells) => rows.push(cells),
+                    QueryEvent::Deleted(pk) => deleted.push(pk),
+                    QueryEvent::EndOfQuery { cursor: eoq, .. } => cursor = eoq,
+                    QueryEvent::Error(e) => eyre::bail!("{e}"),
+                    _ => {}
+                }
+            }
+        }
+        rows.sort_by_key(|cells| cells[0].as_integer().copied());
+        Ok((status, rows, deleted, cursor))
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_query_since() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let write = |statements: Vec<Statement>| {
+            let agent = agent.clone();
+            async move {
+                let (status_code, body) = api_v1_transactions(
+                    Extension(agent),
+                    axum::extract::Query(TimeoutParams { timeout: None }),
+                    axum::Json(statements),
+                )
+                .await;
+                assert_eq!(status_code, StatusCode::OK);
+                body.0.version.expect("no version written")
+            }
+        };
+
+        let query_stmt = |params: QueryParams, stmt: Statement| {
+            let agent = agent.clone();
+            async move { query_since(agent, params, stmt).await }
+        };
+        let query = |params: QueryParams| {
+            query_stmt(
+                params,
+                Statement::Simple("SELECT id, text FROM tests".into()),
+            )
+        };
+        let since = |cursor: u64| QueryParams {
+            since: Some(cursor),
+            table: Some("tests".into()),
+            ..Default::default()
+        };
+
+        write(
+            (1..=3i64)
+                .map(|i| {
+                    Statement::WithParams(
+                        "INSERT INTO tests (id, text) VALUES (?,?)".into(),
+                        vec![i.into(), format!("first-{i}").into()],
+                    )
+                })
+                .collect(),
+        )
+        .await;
+
+        // everything so far
+        let (status, rows, deleted, c1) = query(since(0)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rows.len(), 3);
+        assert!(deleted.is_empty());
+        let c1 = c1.expect("no cursor");
+
+        write(vec![
+            Statement::WithParams(
+                "INSERT INTO tests (id, text) VALUES (?,?)".into(),
+                vec![4i64.into(), "second-4".into()],
+            ),
+            Statement::WithParams(
+                "UPDATE tests SET text = ? WHERE id = ?".into(),
+                vec!["second-1".into(), 1i64.into()],
+            ),
+            Statement::Simple("DELETE FROM tests WHERE id = 2".into()),
+        ])
+        .await;
+
+        // only the second batch
+        let (status, rows, deleted, c2) = query(since(c1)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            rows,
+            vec![
+                vec![1i64.into(), "second-1".into()],
+                vec![4i64.into(), "second-4".into()],
+            ]
+        );
+        assert_eq!(deleted, vec![vec![SqliteValue::Integer(2)]]);
+        let c2 = c2.expect("no cursor");
+        assert!(c2 > c1);
+
+        // the query's own parameters come first
+        let (status, rows, deleted, cursor) = query_stmt(
+            since(c1),
+            Statement::WithParams(
+                "SELECT id, text FROM tests WHERE id > ?".into(),
+                vec![1i64.into()],
+            ),
+        )
+        .await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rows, vec![vec![4i64.into(), "second-4".into()]]);
+        assert_eq!(deleted, vec![vec![SqliteValue::Integer(2)]]);
+        assert_eq!(cursor, Some(c2));
+
+        // caught up
+        let (status, rows, deleted, cursor) = query(since(c2)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert!(rows.is_empty());
+        assert!(deleted.is_empty());
+        assert_eq!(cursor, Some(c2));
+
+        // plain queries have no cursor
+        let (status, rows, _, cursor) = query(QueryParams::default()).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rows.len(), 3);
+        assert_eq!(cursor, None);
+
+        for params in [
+            QueryParams {
+                since: Some(c1),
+                ..Default::default()
+            },
+            QueryParams {
+                mode: QueryMode::CountOnly,
+                ..since(c1)
+            },
+            QueryParams {
+                table: Some("nope".into()),
+                ..since(c1)
+            },
+        ] {
+            let (status, _, _, _) = query(params.clone()).await?;
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{params:?}");
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_api_db_query_since_remote_versions() -> eyre::Result<()> {
+        use corro_tests::launch_test_agent;
+        use corro_types::sync::generate_sync;
+
+        use crate::api::peer::parallel_sync;
+
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let write = |agent: Agent, id: i64, text: &'static str| async move {
+            let (status_code, _) = api_v1_transactions(
+                Extension(agent),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::Json(vec![Statement::WithParams(
+                    "INSERT INTO tests (id, text) VALUES (?,?)".into(),
+                    vec![id.into(), text.into()],
+                )]),
+            )
+            .await;
+            assert_eq!(status_code, StatusCode::OK);
+        };
+        let query = |params: QueryParams| {
+            query_since(
+                ta2.agent.clone(),
+                params,
+                Statement::Simple("SELECT id, text FROM tests".into()),
+            )
+        };
+        let since = |cursor: u64| QueryParams {
+            since: Some(cursor),
+            table: Some("tests".into()),
+            ..Default::default()
+        };
+
+        // ta1's versions lag ta2's
+        write(ta1.agent.clone(), 10, "remote").await;
+        for id in 1..=3 {
+            write(ta2.agent.clone(), id, "local").await;
+        }
+
+        let (status, rows, _, cursor) = query(since(0)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rows.len(), 3);
+        let cursor = cursor.expect("no cursor");
+
+        let ta1_actor_id = ta1.agent.actor_id();
+        let state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+        parallel_sync(
+            &ta2.agent,
+            &ta2.transport,
+            vec![(ta1_actor_id, ta1.agent.gossip_addr())],
+            state,
+        )
+        .await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let state = generate_sync(&ta2.bookie, ta2.agent.actor_id()).await;
+                if state.heads.get(&ta1_actor_id) == Some(&CrsqlDbVersion(1)) {
+                    break;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await?;
+
+        // version 1 of ta1 was booked after ta2's version 3
+        let (status, rows, deleted, next) = query(since(cursor)).await?;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(rows, vec![vec![10i64.into(), "remote".into()]]);
+        assert!(deleted.is_empty());
+        assert!(next.expect("no cursor") > cursor);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_db_schema() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+            ]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        // scope the schema reader in here
+        {
+            let schema = agent.schema().read();
+            let tests = schema
+                .tables
+                .get("tests")
+                .expect("no tests table in schema");
+
+            let id_col = tests.columns.get("id").unwrap();
+            assert_eq!(id_col.name, "id");
+            assert_eq!(id_col.sql_type(), (SqliteType::Integer, Some("BIGINT")));
+            assert!(!id_col.nullable);
+            assert!(id_col.primary_key);
+
+            let foo_col = tests.columns.get("foo").unwrap();
+            assert_eq!(foo_col.name, "foo");
+            assert_eq!(foo_col.sql_type(), (SqliteType::Text, Some("TEXT")));
+            assert!(foo_col.nullable);
+            assert!(!foo_col.primary_key);
+        }
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![
+                "CREATE TABLE tests2 (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+            ]),
+        )
+        .await;
+
+        assert_eq!(status_code, StatusCode::OK);
+
+        {
+            let schema = agent.schema().read();
+            let tests = schema
+                .tables
+                .get("tests")
+                .expect("no tests table in schema");
+
+            let id_col = tests.columns.get("id").unwrap();
+            assert_eq!(id_col.name, "id");
+            assert_eq!(id_col.sql_type(), (SqliteType::Integer, Some("BIGINT")));
+            assert!(!id_col.nullable);
+            assert!(id_col.primary_key);
+
+            let foo_col = tests.columns.get("foo").unwrap();
+            assert_eq!(foo_col.name, "foo");
+            assert_eq!(foo_col.sql_type(), (SqliteType::Text, Some("TEXT")));
             assert!(foo_col.nullable);
             assert!(!foo_col.primary_key);
 
@@ -1115,4 +2299,321 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_schema_changes() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let res = api_v1_schema_changes(Extension(agent.clone()), Extension(tripwire.clone()))
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut body = res.into_body();
+        let mut lines = LinesCodec::new();
+        let mut buf = BytesMut::new();
+
+        // re-applying the same schema is not a change
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // equivalent of ALTER TABLE tests ADD COLUMN bar
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![
+                "CREATE TABLE tests (id BIGINT NOT NULL PRIMARY KEY, foo TEXT, bar INTEGER);"
+                    .into(),
+                "CREATE TABLE tests2 (id BIGINT NOT NULL PRIMARY KEY);".into(),
+            ]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let mut changes = vec![];
+        while changes.len() < 2 {
+            if let Some(line) = lines.decode(&mut buf)? {
+                changes.push(serde_json::from_str::<SchemaChange>(&line)?);
+                continue;
+            }
+            let data = tokio::time::timeout(Duration::from_secs(5), body.data())
+                .await?
+                .unwrap()?;
+            buf.extend_from_slice(&data);
+        }
+
+        assert_eq!(
+            changes,
+            vec![
+                SchemaChange {
+                    table: "tests".into(),
+                    kind: SchemaChangeKind::Altered,
+                    columns: vec!["id".into(), "foo".into(), "bar".into()],
+                },
+                SchemaChange {
+                    table: "tests2".into(),
+                    kind: SchemaChangeKind::Created,
+                    columns: vec!["id".into()],
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_enable_crr_existing_table() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, mut agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let rx_bcast = &mut agent_options.rx_bcast;
+
+        {
+            let conn = agent.pool().write_priority().await?;
+            conn.execute_batch(
+                "CREATE TABLE plain (id INTEGER NOT NULL PRIMARY KEY, text TEXT);
+                INSERT INTO plain (id, text) VALUES (1, 'existing');
+                CREATE TABLE no_pk (text TEXT);",
+            )?;
+        }
+
+        assert!(matches!(
+            enable_crr(&agent, "no_pk").await,
+            Err(EnableCrrError::MissingPrimaryKey(_))
+        ));
+        assert!(matches!(
+            enable_crr(&agent, "nope").await,
+            Err(EnableCrrError::TableNotFound(_))
+        ));
+
+        // existing rows are backfilled
+        let (status_code, body) = api_v1_enable_crr(
+            Extension(agent.clone()),
+            axum::extract::Path("plain".to_string()),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+        assert_eq!(body.0.version, Some(1));
+        assert!(agent.schema().read().tables.contains_key("plain"));
+
+        let msg = rx_bcast
+            .recv()
+            .await
+            .expect("not msg received on bcast channel");
+        assert!(matches!(
+            msg,
+            BroadcastInput::AddBroadcast(BroadcastV1::Change(ChangeV1 {
+                changeset: Changeset::Full {
+                    version: CrsqlDbVersion(1),
+                    ..
+                },
+                ..
+            }))
+        ));
+
+        // idempotent
+        assert_eq!(enable_crr(&agent, "plain").await?, None);
+
+        let (status_code, _body) = api_v1_transactions(
+            Extension(agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                "INSERT INTO plain (id, text) VALUES (?, ?)".into(),
+                vec![2i64.into(), "new".into()],
+            )]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let msg = rx_bcast
+            .recv()
+            .await
+            .expect("not msg received on bcast channel");
+        let BroadcastInput::AddBroadcast(BroadcastV1::Change(ChangeV1 {
+            changeset: Changeset::Full {
+                version, changes, ..
+            },
+            ..
+        })) = msg
+        else {
+            panic!("unexpected broadcast: {msg:?}");
+        };
+        assert_eq!(version, CrsqlDbVersion(2));
+        assert!(changes
+            .iter()
+            .any(|change| change.table.as_str() == "plain" && change.val == "new".into()));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_transactions_throttled() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .write_ops_per_sec(5)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |i: i64| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::Json(vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![i.into(), format!("burst {i}").into()],
+                )]),
+            )
+        };
+
+        let mut statuses = vec![];
+        for i in 0..10 {
+            let (status_code, _body) = insert(i).await;
+            statuses.push(status_code);
+        }
+
+        // the bucket holds a second's worth of writes
+        assert!(statuses[..5].iter().all(|status| *status == StatusCode::OK));
+        assert_eq!(statuses[9], StatusCode::TOO_MANY_REQUESTS);
+
+        let (status_code, body) = insert(100).await;
+        assert_eq!(status_code, StatusCode::TOO_MANY_REQUESTS);
+        assert!(matches!(
+            &body.0.results[..],
+            [ExecResult::Error { error }] if error.starts_with("too many local writes")
+        ));
+
+        // admin writes bypass the limiter
+        let (_, version, _) = make_broadcastable_changes(&agent, None, |tx| {
+            tx.execute("INSERT INTO tests (id, text) VALUES (200, 'admin')", [])
+                .map_err(|source| ChangeError::Rusqlite {
+                    source,
+                    actor_id: None,
+                    version: None,
+                })
+        })
+        .await?;
+        assert!(version.is_some());
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let (status_code, _body) = insert(101).await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_api_v1_transactions_value_too_large() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = tempfile::tempdir()?;
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .max_value_bytes(1024 * 1024)
+                .build()?,
+            tripwire,
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let insert = |i: i64, len: usize| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::Json(vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![i.into(), "a".repeat(len).into()],
+                )]),
+            )
+        };
+
+        let (status_code, body) = insert(1, 2 * 1024 * 1024).await;
+        assert_eq!(status_code, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(matches!(
+            &body.0.results[..],
+            [ExecResult::Error { error }] if error == "value of column text is 2097152 bytes, over the 1048576 bytes limit"
+        ));
+
+        let (status_code, _body) = insert(2, 1024 * 1024).await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // the rejected write was rolled back
+        let conn = agent.pool().read().await?;
+        let ids: Vec<i64> = conn
+            .prepare("SELECT id FROM tests")?
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        assert_eq!(ids, vec![2]);
+
+        Ok(())
+    }
 }
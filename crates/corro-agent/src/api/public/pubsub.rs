@@ -1,6 +1,6 @@
-use std::{collections::HashMap, io::Write, sync::Arc, time::Duration};
+use std::{collections::HashMap, io::Write, net::SocketAddr, sync::Arc, time::Duration};
 
-use axum::{http::StatusCode, response::IntoResponse, Extension};
+use axum::{extract::ConnectInfo, http::StatusCode, response::IntoResponse, Extension};
 use bytes::{BufMut, Bytes, BytesMut};
 use compact_str::{format_compact, ToCompactString};
 use corro_types::updates::Handle;
@@ -41,7 +41,15 @@ pub async fn api_v1_sub_by_id(
     axum::extract::Path(id): axum::extract::Path<Uuid>,
     axum::extract::Query(params): axum::extract::Query<SubParams>,
 ) -> impl IntoResponse {
-    sub_by_id(agent.subs_manager(), id, params, &bcast_cache, tripwire).await
+    sub_by_id(
+        agent.subs_manager(),
+        id,
+        params,
+        &bcast_cache,
+        agent.config().api.subscription_heartbeat(),
+        tripwire,
+    )
+    .await
 }
 
 async fn sub_by_id(
@@ -49,6 +57,7 @@ async fn sub_by_id(
     id: Uuid,
     params: SubParams,
     bcast_cache: &SharedMatcherBroadcastCache,
+    heartbeat: Option<Duration>,
     tripwire: Tripwire,
 ) -> hyper::Response<hyper::Body> {
     let matcher_rx = bcast_cache.read().await.get(&id).and_then(|tx| {
@@ -101,7 +110,9 @@ async fn sub_by_id(
 
     let (tx, body) = hyper::Body::channel();
 
-    tokio::spawn(forward_bytes_to_body_sender(id, evt_rx, tx, tripwire));
+    tokio::spawn(forward_bytes_to_body_sender(
+        id, evt_rx, tx, heartbeat, tripwire,
+    ));
 
     hyper::Response::builder()
         .status(StatusCode::OK)
@@ -294,6 +305,9 @@ impl MatcherUpsertError {
             MatcherUpsertError::Pool(_)
             | MatcherUpsertError::CouldNotExpand
             | MatcherUpsertError::MissingBroadcaster => StatusCode::INTERNAL_SERVER_ERROR,
+            MatcherUpsertError::Matcher(MatcherError::TooManySubscriptions { .. }) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
             MatcherUpsertError::Sqlite(_)
             | MatcherUpsertError::NormalizeStatement(_)
             | MatcherUpsertError::Matcher(_)
@@ -442,8 +456,14 @@ pub async fn catch_up_sub(
                     _ = cancel.cancelled() => {
                         break;
                     },
-                    Ok(res) = sub_rx.recv() => res,
-                    else => break
+                    res = sub_rx.recv() => match res {
+                        Ok(res) => res,
+                        // a skipped change would leave a gap
+                        Err(RecvError::Lagged(skipped)) => {
+                            return Err(eyre::eyre!("catching up too slowly, skipped {skipped} events"));
+                        }
+                        Err(RecvError::Closed) => break,
+                    },
                 };
 
                 if let QueryEventMeta::Change(change_id) = meta {
@@ -479,6 +499,15 @@ pub async fn catch_up_sub(
 
         match res {
             Ok(change_id) => change_id,
+            Err(CatchUpError::Matcher(MatcherError::ChangesCompacted { min_change_id })) => {
+                info!(sub_id = %matcher.id(), "requested change id was compacted (min: {min_change_id}), client must resync");
+                if let Ok(evt) =
+                    make_query_event_bytes(&mut buf, &QueryEvent::Resync { min_change_id })
+                {
+                    _ = evt_tx.send(evt).await;
+                }
+                return;
+            }
             Err(e) => {
                 if !matches!(e, CatchUpError::Send(_)) {
                     _ = evt_tx
@@ -669,6 +698,7 @@ pub async fn api_v1_subs(
     Extension(agent): Extension<Agent>,
     Extension(bcast_cache): Extension<SharedMatcherBroadcastCache>,
     Extension(tripwire): Extension<Tripwire>,
+    ConnectInfo(client_addr): ConnectInfo<SocketAddr>,
     axum::extract::Query(params): axum::extract::Query<SubParams>,
     axum::extract::Json(stmt): axum::extract::Json<Statement>,
 ) -> impl IntoResponse {
@@ -679,10 +709,18 @@ pub async fn api_v1_subs(
 
     info!("Received subscription request for query: {stmt}");
 
-    let mut bcast_write = bcast_cache.write().await;
-
     let subs = agent.subs_manager();
 
+    let conn_guard =
+        match subs.acquire_conn_slot(client_addr, agent.config().api.max_subscriptions_per_conn) {
+            Ok(guard) => guard,
+            Err(e) => {
+                return hyper::Response::<hyper::Body>::from(MatcherUpsertError::from(e));
+            }
+        };
+
+    let mut bcast_write = bcast_cache.write().await;
+
     let upsert_res = subs.get_or_insert(
         &stmt,
         &agent.config().db.subscriptions_path(),
@@ -699,12 +737,15 @@ pub async fn api_v1_subs(
     let (tx, body) = hyper::Body::channel();
     let (forward_tx, forward_rx) = mpsc::channel(10240);
 
-    tokio::spawn(forward_bytes_to_body_sender(
-        handle.id(),
-        forward_rx,
-        tx,
-        tripwire,
-    ));
+    tokio::spawn({
+        let sub_id = handle.id();
+        let heartbeat = agent.config().api.subscription_heartbeat();
+        async move {
+            // release the connection's subscription slot once the stream is done
+            let _conn_guard = conn_guard;
+            forward_bytes_to_body_sender(sub_id, forward_rx, tx, heartbeat, tripwire).await
+        }
+    });
 
     let query_hash = handle.hash().to_owned();
     let matcher_id = match upsert_sub(
@@ -812,6 +853,7 @@ async fn forward_bytes_to_body_sender(
     sub_id: Uuid,
     mut rx: mpsc::Receiver<(Bytes, QueryEventMeta)>,
     mut tx: hyper::body::Sender,
+    heartbeat: Option<Duration>,
     mut tripwire: Tripwire,
 ) {
     let mut buf = BytesMut::new();
@@ -819,6 +861,12 @@ async fn forward_bytes_to_body_sender(
     let send_deadline = tokio::time::sleep(Duration::from_millis(10));
     tokio::pin!(send_deadline);
 
+    let mut heartbeat_interval = heartbeat.map(|period| {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    });
+
     let mut last_change_id = ChangeId(0);
 
     loop {
@@ -850,6 +898,20 @@ async fn forward_bytes_to_body_sender(
                     continue;
                 }
             },
+            _ = async { heartbeat_interval.as_mut().unwrap().tick().await }, if heartbeat_interval.is_some() => {
+                // serializes the heartbeat after anything still buffered, sending it all right away
+                let to_send = match make_query_event_bytes(&mut buf, &QueryEvent::Heartbeat { change_id: last_change_id }) {
+                    Ok((bytes, _meta)) => bytes,
+                    Err(e) => {
+                        error!(%sub_id, "could not serialize heartbeat: {e}");
+                        continue;
+                    }
+                };
+                if let Err(e) = tx.send_data(to_send).await {
+                    warn!(%sub_id, "could not send subscription heartbeat: {e}");
+                    return;
+                }
+            },
             _ = &mut tripwire => {
                 break;
             }
@@ -881,13 +943,14 @@ async fn forward_bytes_to_body_sender(
 
 #[cfg(test)]
 mod tests {
+    use camino::Utf8Path;
     use corro_types::actor::ActorId;
     use corro_types::api::NotifyEvent;
     use corro_types::api::{ColumnName, TableName};
     use corro_types::base::{dbsr, CrsqlDbVersion, CrsqlSeq};
     use corro_types::broadcast::{ChangeSource, ChangeV1, Changeset};
     use corro_types::change::Change;
-    use corro_types::pubsub::pack_columns;
+    use corro_types::pubsub::{pack_columns, Matcher};
     use corro_types::{
         api::{ChangeId, RowId},
         config::Config,
@@ -911,7 +974,7 @@ mod tests {
     };
     use corro_tests::launch_test_agent;
     use corro_tests::tempdir::TempDir;
-    use corro_types::api::SqliteValue::Integer;
+    use corro_types::api::SqliteValue::{self, Integer};
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_api_v1_subs() -> eyre::Result<()> {
@@ -967,6 +1030,7 @@ mod tests {
                 Extension(agent.clone()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                ConnectInfo("127.0.0.1:1234".parse().unwrap()),
                 axum::extract::Query(SubParams::default()),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
@@ -1094,6 +1158,7 @@ mod tests {
                 Extension(agent.clone()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                ConnectInfo("127.0.0.1:1234".parse().unwrap()),
                 axum::extract::Query(SubParams {
                     from: Some(1.into()),
                     ..Default::default()
@@ -1195,6 +1260,7 @@ mod tests {
                 Extension(agent.clone()),
                 Extension(bcast_cache.clone()),
                 Extension(tripwire.clone()),
+                ConnectInfo("127.0.0.1:1234".parse().unwrap()),
                 axum::extract::Query(SubParams::default()),
                 axum::Json(Statement::Simple("select * from tests".into())),
             )
@@ -1311,6 +1377,7 @@ mod tests {
             Extension(agent.clone()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
             axum::extract::Query(SubParams {
                 from: Some(1.into()),
                 ..Default::default()
@@ -1359,6 +1426,7 @@ mod tests {
             Extension(agent.clone()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
             axum::extract::Query(SubParams {
                 from: Some(0.into()),
                 ..Default::default()
@@ -1439,6 +1507,7 @@ mod tests {
             Extension(agent.clone()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
             axum::extract::Query(SubParams {
                 skip_rows: true,
                 ..Default::default()
@@ -1490,6 +1559,7 @@ mod tests {
             Extension(agent.clone()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
             axum::extract::Query(SubParams {
                 skip_rows: true,
                 from: Some(ChangeId(3)),
@@ -1603,6 +1673,7 @@ mod tests {
             Extension(ta1.agent.clone()),
             Extension(bcast_cache.clone()),
             Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
             axum::extract::Query(SubParams::default()),
             axum::Json(Statement::Simple("select * from buftests".into())),
         )
@@ -1762,6 +1833,662 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_resume_from_cursor() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = TempDir::new(tempfile::tempdir()?);
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let insert = |id: &str, text: &str| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::Json(vec![Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![id.into(), text.into()],
+                )]),
+            )
+        };
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            axum::extract::Query(SubParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let sub_id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .unwrap()
+            .to_str()?
+            .parse()?;
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Columns(_)
+        ));
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        let (status_code, _) = insert("service-id", "service-name").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // record the cursor and disconnect
+        let cursor = match rows.recv::<QueryEvent>().await.unwrap().unwrap() {
+            QueryEvent::Change(ChangeType::Insert, _, _, change_id) => change_id,
+            evt => panic!("unexpected event: {evt:?}"),
+        };
+        assert_eq!(cursor, ChangeId(1));
+        drop(rows);
+
+        let (status_code, _) = insert("service-id-2", "service-name-2").await;
+        assert_eq!(status_code, StatusCode::OK);
+        let (status_code, _) = insert("service-id-3", "service-name-3").await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        // give the matcher some time to process the changes
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Path(sub_id),
+            axum::extract::Query(SubParams {
+                from: Some(cursor),
+                ..Default::default()
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        // only the changes after the cursor are replayed
+        assert_eq!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(2),
+                vec!["service-id-2".into(), "service-name-2".into()],
+                ChangeId(2)
+            )
+        );
+        assert_eq!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(3),
+                vec!["service-id-3".into(), "service-name-3".into()],
+                ChangeId(3)
+            )
+        );
+        drop(rows);
+
+        // simulate the subscription purging its older changes
+        let matcher = agent.subs_manager().get(&sub_id).unwrap();
+        {
+            let conn = rusqlite::Connection::open(Matcher::sub_db_path(
+                Utf8Path::new(matcher.subs_path()),
+                sub_id,
+            ))?;
+            conn.execute("DELETE FROM changes WHERE id < 3", [])?;
+        }
+
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Path(sub_id),
+            axum::extract::Query(SubParams {
+                from: Some(cursor),
+                ..Default::default()
+            }),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert_eq!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Resync {
+                min_change_id: ChangeId(3)
+            }
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_predicate_enter_leave() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = TempDir::new(tempfile::tempdir()?);
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        // predicates referencing unknown columns are rejected upfront
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            axum::extract::Query(SubParams::default()),
+            axum::Json(Statement::Simple(
+                "select * from tests where tests.nope = 'wanted'".into(),
+            )),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let exec = |stmt: &str, params: Vec<SqliteValue>| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::Json(vec![Statement::WithParams(stmt.into(), params)]),
+            )
+        };
+
+        let (status_code, _) = exec(
+            "insert into tests (id, text) values (?,?)",
+            vec!["service-id".into(), "unwanted".into()],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            axum::extract::Query(SubParams::default()),
+            axum::Json(Statement::Simple(
+                "select * from tests where text = 'wanted'".into(),
+            )),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Columns(_)
+        ));
+        // the non-matching row is not part of the initial result set
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        // non-matching -> matching: the row enters the result set
+        let (status_code, _) = exec(
+            "update tests set text = ? where id = ?",
+            vec!["wanted".into(), "service-id".into()],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Change(
+                ChangeType::Insert,
+                RowId(1),
+                vec!["service-id".into(), "wanted".into()],
+                ChangeId(1)
+            )
+        );
+
+        // matching -> non-matching: the row leaves the result set
+        let (status_code, _) = exec(
+            "update tests set text = ? where id = ?",
+            vec!["unwanted-again".into(), "service-id".into()],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert_eq!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Change(
+                ChangeType::Delete,
+                RowId(1),
+                vec!["service-id".into(), "wanted".into()],
+                ChangeId(2)
+            )
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_max_per_conn() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = TempDir::new(tempfile::tempdir()?);
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .max_subscriptions_per_conn(2)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+        let client_addr: SocketAddr = "127.0.0.1:1234".parse()?;
+
+        let subscribe = |sql: &str, addr: SocketAddr| {
+            api_v1_subs(
+                Extension(agent.clone()),
+                Extension(bcast_cache.clone()),
+                Extension(tripwire.clone()),
+                ConnectInfo(addr),
+                axum::extract::Query(SubParams::default()),
+                axum::Json(Statement::Simple(sql.into())),
+            )
+        };
+
+        // keep the responses around so the subscriptions stay active
+        let mut responses = vec![];
+        for sql in ["select * from tests", "select * from tests2"] {
+            let res = subscribe(sql, client_addr).await.into_response();
+            assert_eq!(res.status(), StatusCode::OK);
+            responses.push(res);
+        }
+        assert_eq!(agent.subs_manager().conn_subs_count(&client_addr), 2);
+
+        let res = subscribe("select * from tests3", client_addr)
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        // other connections have their own budget
+        let res = subscribe("select * from tests3", "127.0.0.1:4321".parse()?)
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // dropping a subscription frees up a slot
+        drop(responses.pop());
+        let start = Instant::now();
+        while agent.subs_manager().conn_subs_count(&client_addr) > 1 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let res = subscribe("select * from tests3", client_addr)
+            .await
+            .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_v1_subs_heartbeats() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = TempDir::new(tempfile::tempdir()?);
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .subscription_heartbeat_secs(1)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            axum::extract::Query(SubParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Columns(_)
+        ));
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::EndOfQuery { .. }
+        ));
+
+        // idle subscription, heartbeats keep coming on schedule
+        let evt = timeout(Duration::from_secs(2), rows.recv::<QueryEvent>())
+            .await?
+            .unwrap()?;
+        assert_eq!(
+            evt,
+            QueryEvent::Heartbeat {
+                change_id: ChangeId(0)
+            }
+        );
+        let first = Instant::now();
+
+        let evt = timeout(Duration::from_secs(2), rows.recv::<QueryEvent>())
+            .await?
+            .unwrap()?;
+        assert_eq!(
+            evt,
+            QueryEvent::Heartbeat {
+                change_id: ChangeId(0)
+            }
+        );
+        let elapsed = first.elapsed();
+        assert!(
+            elapsed >= Duration::from_millis(500) && elapsed <= Duration::from_millis(1500),
+            "unexpected heartbeat interval: {elapsed:?}"
+        );
+
+        let (status_code, _) = api_v1_transactions(
+            Extension(agent.clone()),
+            axum::extract::Query(TimeoutParams { timeout: None }),
+            axum::Json(vec![Statement::WithParams(
+                "insert into tests (id, text) values (?,?)".into(),
+                vec!["service-id".into(), "service-name".into()],
+            )]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        assert!(matches!(
+            rows.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Change(ChangeType::Insert, _, _, ChangeId(1))
+        ));
+
+        // the heartbeat carries the updated cursor
+        let evt = timeout(Duration::from_secs(2), rows.recv::<QueryEvent>())
+            .await?
+            .unwrap()?;
+        assert_eq!(
+            evt,
+            QueryEvent::Heartbeat {
+                change_id: ChangeId(1)
+            }
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_api_v1_subs_snapshot_then_tail() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let (tripwire, _tripwire_worker, _tripwire_tx) = Tripwire::new_simple();
+
+        let dir = TempDir::new(tempfile::tempdir()?);
+
+        let (agent, _agent_options) = setup(
+            Config::builder()
+                .db_path(dir.path().join("corrosion.db").display().to_string())
+                .gossip_addr("127.0.0.1:0".parse()?)
+                .api_addr("127.0.0.1:0".parse()?)
+                .build()?,
+            tripwire.clone(),
+        )
+        .await?;
+
+        let (status_code, _body) = api_v1_db_schema(
+            Extension(agent.clone()),
+            axum::Json(vec![corro_tests::TEST_SCHEMA.into()]),
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let bcast_cache: SharedMatcherBroadcastCache = Default::default();
+
+        let exec = |sql: &str, params: Vec<SqliteValue>| {
+            api_v1_transactions(
+                Extension(agent.clone()),
+                axum::extract::Query(TimeoutParams { timeout: None }),
+                axum::Json(vec![Statement::WithParams(sql.into(), params)]),
+            )
+        };
+
+        let (status_code, _) = exec(
+            "insert into tests (id, text) values (?,?)",
+            vec!["service-id".into(), "v0".into()],
+        )
+        .await;
+        assert_eq!(status_code, StatusCode::OK);
+
+        let res = api_v1_subs(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            ConnectInfo("127.0.0.1:1234".parse().unwrap()),
+            axum::extract::Query(SubParams::default()),
+            axum::Json(Statement::Simple("select * from tests".into())),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+        let sub_id: Uuid = res
+            .headers()
+            .get("corro-query-id")
+            .unwrap()
+            .to_str()?
+            .parse()?;
+        let mut first = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+        assert!(matches!(
+            first.recv::<QueryEvent>().await.unwrap().unwrap(),
+            QueryEvent::Columns(_)
+        ));
+
+        const UPDATES: usize = 50;
+
+        // mutate the row while the snapshot subscription starts
+        let writer = tokio::spawn({
+            let agent = agent.clone();
+            async move {
+                for i in 1..=UPDATES {
+                    let (status_code, _) = api_v1_transactions(
+                        Extension(agent.clone()),
+                        axum::extract::Query(TimeoutParams { timeout: None }),
+                        axum::Json(vec![Statement::WithParams(
+                            "update tests set text = ? where id = ?".into(),
+                            vec![format!("v{i}").into(), "service-id".into()],
+                        )]),
+                    )
+                    .await;
+                    assert_eq!(status_code, StatusCode::OK);
+                }
+            }
+        });
+
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let res = api_v1_sub_by_id(
+            Extension(agent.clone()),
+            Extension(bcast_cache.clone()),
+            Extension(tripwire.clone()),
+            axum::extract::Path(sub_id),
+            axum::extract::Query(SubParams::default()),
+        )
+        .await
+        .into_response();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let mut rows = RowsIter {
+            body: res.into_body(),
+            codec: LinesCodec::new(),
+            buf: BytesMut::new(),
+            done: false,
+        };
+
+        writer.await?;
+
+        let mut state: HashMap<RowId, Vec<SqliteValue>> = HashMap::new();
+        let snapshot_change_id = loop {
+            match timeout(Duration::from_secs(5), rows.recv::<QueryEvent>())
+                .await?
+                .unwrap()?
+            {
+                QueryEvent::Columns(_) => {}
+                QueryEvent::Row(rowid, cells) => {
+                    assert!(state.insert(rowid, cells).is_none());
+                }
+                QueryEvent::EndOfQuery { change_id, .. } => break change_id.unwrap(),
+                evt => panic!("unexpected event before the end of the snapshot: {evt:?}"),
+            }
+        };
+
+        // every update makes a change, the insert happened before subscribing
+        let last_change_id = ChangeId(UPDATES as u64);
+        let mut expected = snapshot_change_id + 1;
+        while expected <= last_change_id {
+            match timeout(Duration::from_secs(5), rows.recv::<QueryEvent>())
+                .await?
+                .unwrap()?
+            {
+                QueryEvent::Change(ChangeType::Update, rowid, cells, change_id) => {
+                    assert_eq!(change_id, expected, "gap or duplicate");
+                    state.insert(rowid, cells);
+                    expected += 1;
+                }
+                evt => panic!("unexpected event: {evt:?}"),
+            }
+        }
+
+        // nothing more, not even a duplicate
+        assert!(
+            timeout(Duration::from_millis(500), rows.recv::<QueryEvent>())
+                .await
+                .is_err()
+        );
+
+        assert_eq!(
+            state.into_values().collect::<Vec<_>>(),
+            vec![vec![
+                SqliteValue::from("service-id"),
+                SqliteValue::from(format!("v{UPDATES}"))
+            ]]
+        );
+
+        drop(first);
+
+        Ok(())
+    }
+
     struct RowsIter {
         body: axum::body::BoxBody,
         codec: LinesCodec,
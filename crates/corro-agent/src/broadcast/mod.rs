@@ -520,6 +520,12 @@ async fn handle_broadcasts(
             Branch::Broadcast(input) => {
                 trace!("handling Branch::Broadcast");
 
+                // peers get these changes by syncing with us instead
+                if agent.outbound_paused() {
+                    counter!("corro.broadcast.dropped", "reason" => "paused").increment(1);
+                    continue;
+                }
+
                 let (bcast, is_local) = match input {
                     BroadcastInput::Rebroadcast(bcast) => (bcast, false),
                     BroadcastInput::AddBroadcast(bcast) => (bcast, true),
@@ -1047,8 +1053,9 @@ mod tests {
     use crate::agent::spawn_unipayload_handler;
     use corro_tests::launch_test_agent;
     use corro_types::{
-        base::{dbsr, CrsqlDbVersion, CrsqlSeq},
+        base::{dbsr, CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeq},
         broadcast::{BroadcastV1, ChangeV1, Changeset},
+        config::{Config, PeerMatcher},
     };
     use uuid::Uuid;
 
@@ -1180,7 +1187,7 @@ mod tests {
             let conn = conn.await.unwrap();
 
             let (tx_changes, mut rx_changes) = bounded(100, "changes");
-            spawn_unipayload_handler(&tripwire, &conn, ta1.agent.cluster_id(), tx_changes);
+            spawn_unipayload_handler(&ta1.agent, &tripwire, &conn, tx_changes);
 
             // we should receive five items starting from the biggest version
             for i in (0..5).rev() {
@@ -1200,4 +1207,76 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_broadcast_from_denied_peer_dropped() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let (tx_rtt, _) = mpsc::channel(100);
+        let transport = Transport::new(&ta1.config.gossip, tx_rtt).await?;
+
+        let server_config = quinn_plaintext::server_config();
+        let endpoint = quinn::Endpoint::server(server_config, "127.0.0.1:0".parse().unwrap())?;
+        let addr = endpoint.local_addr()?;
+
+        let set_deny = |deny: Vec<PeerMatcher>| {
+            let mut config = Config::clone(&ta1.agent.config());
+            config.gossip.peer_access.deny = deny;
+            ta1.agent.set_config(config);
+        };
+        // a bare address, as written in the config
+        let localhost: PeerMatcher = serde_json::from_value(serde_json::json!("127.0.0.1"))?;
+        set_deny(vec![localhost]);
+
+        let payload = |version| -> eyre::Result<Bytes> {
+            let payload = UniPayload::V1 {
+                data: UniPayloadV1::Broadcast(BroadcastV1::Change(ChangeV1 {
+                    actor_id: ActorId(Uuid::new_v4()),
+                    changeset: Changeset::Empty {
+                        versions: CrsqlDbVersionRange::single(CrsqlDbVersion(version)),
+                        ts: None,
+                    },
+                })),
+                cluster_id: ta1.agent.cluster_id(),
+            };
+            let mut buf = BytesMut::new();
+            LengthDelimitedCodec::new().encode(Bytes::from(payload.write_to_vec()?), &mut buf)?;
+            Ok(buf.freeze())
+        };
+
+        let send = tokio::spawn({
+            let transport = transport.clone();
+            let payload = payload(1)?;
+            async move { transport.send_uni(addr, payload).await }
+        });
+        let conn = endpoint.accept().await.unwrap().await?;
+        let (tx_changes, mut rx_changes) = bounded(100, "changes");
+        spawn_unipayload_handler(&ta1.agent, &tripwire, &conn, tx_changes);
+        send.await??;
+
+        assert!(
+            tokio::time::timeout(Duration::from_millis(500), rx_changes.recv())
+                .await
+                .is_err(),
+            "change from a denied peer was queued"
+        );
+
+        set_deny(vec![]);
+        transport.send_uni(addr, payload(2)?).await?;
+        let (change, _) = tokio::time::timeout(Duration::from_secs(5), rx_changes.recv())
+            .await?
+            .unwrap();
+        assert_eq!(
+            change.versions(),
+            CrsqlDbVersionRange::single(CrsqlDbVersion(2))
+        );
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        spawn::wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
 }
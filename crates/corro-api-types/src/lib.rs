@@ -1,6 +1,8 @@
 use std::{
     borrow::Borrow,
-    collections::HashMap,
+    cell::Cell,
+    cmp::Ordering,
+    collections::{BTreeMap, HashMap},
     fmt::{self, Write},
     hash::Hash,
     ops::{AddAssign, Deref},
@@ -17,6 +19,8 @@ use smallvec::{SmallVec, ToSmallVec};
 use speedy::{Context, Readable, Reader, Writable, Writer};
 use sqlite::ChangeType;
 
+pub mod row;
+pub mod schema;
 pub mod sqlite;
 
 pub type QueryEvent = TypedQueryEvent<Vec<SqliteValue>>;
@@ -24,25 +28,47 @@ pub type QueryEvent = TypedQueryEvent<Vec<SqliteValue>>;
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "snake_case")]
 pub enum TypedQueryEvent<T> {
+    /// First line of a query's response, before its columns
+    Metadata {
+        columns: Vec<ColumnMeta>,
+    },
     Columns(Vec<ColumnName>),
     Row(RowId, T),
+    /// Primary key of a row deleted since the `since` cursor of a query
+    Deleted(T),
     #[serde(rename = "eoq")]
     EndOfQuery {
         time: f64,
         #[serde(skip_serializing_if = "Option::is_none")]
         change_id: Option<ChangeId>,
+        /// For `since` queries, the cursor to pass as `since` next time
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<u64>,
     },
     Change(ChangeType, RowId, T, ChangeId),
+    /// The requested change id is older than the oldest change still kept by
+    /// the subscription, the client needs to re-subscribe from scratch.
+    Resync {
+        min_change_id: ChangeId,
+    },
+    /// Periodic keepalive carrying the last change id sent on the stream
+    Heartbeat {
+        change_id: ChangeId,
+    },
     Error(CompactString),
 }
 
 impl<T> TypedQueryEvent<T> {
     pub fn meta(&self) -> QueryEventMeta {
         match self {
+            TypedQueryEvent::Metadata { .. } => QueryEventMeta::Metadata,
             TypedQueryEvent::Columns(_) => QueryEventMeta::Columns,
             TypedQueryEvent::Row(rowid, _) => QueryEventMeta::Row(*rowid),
+            TypedQueryEvent::Deleted(_) => QueryEventMeta::Deleted,
             TypedQueryEvent::EndOfQuery { change_id, .. } => QueryEventMeta::EndOfQuery(*change_id),
             TypedQueryEvent::Change(_, _, _, id) => QueryEventMeta::Change(*id),
+            TypedQueryEvent::Resync { .. } => QueryEventMeta::Resync,
+            TypedQueryEvent::Heartbeat { .. } => QueryEventMeta::Heartbeat,
             TypedQueryEvent::Error(_) => QueryEventMeta::Error,
         }
     }
@@ -50,14 +76,29 @@ impl<T> TypedQueryEvent<T> {
 
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum QueryEventMeta {
+    Metadata,
     Columns,
     Row(RowId),
+    Deleted,
     EndOfQuery(Option<ChangeId>),
     Change(ChangeId),
+    Resync,
+    Heartbeat,
     Error,
     Notify,
 }
 
+/// An output column of a query
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ColumnMeta {
+    pub name: ColumnName,
+    /// The declared type of the column the output comes from, or the type of
+    /// the first row's value for expressions. `None` when the query returned
+    /// no rows or a NULL.
+    #[serde(rename = "type")]
+    pub sql_type: Option<CompactString>,
+}
+
 pub type NotifyEvent = TypedNotifyEvent<Vec<SqliteValue>>;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -67,6 +108,21 @@ pub enum TypedNotifyEvent<T> {
     Error(CompactString),
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SchemaChangeKind {
+    Created,
+    Altered,
+}
+
+/// Emitted when a table of the replicated schema is created or altered
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct SchemaChange {
+    pub table: TableName,
+    pub kind: SchemaChangeKind,
+    pub columns: Vec<ColumnName>,
+}
+
 /// RowId newtype to differentiate from ChangeId
 #[derive(
     Debug,
@@ -207,6 +263,32 @@ impl Statement {
             | Statement::WithNamedParams(query, _) => query,
         }
     }
+
+    /// Rough size of the query and its parameters, for rate limiting
+    pub fn estimated_byte_size(&self) -> usize {
+        let params = match self {
+            Statement::Simple(_)
+            | Statement::Verbose {
+                params: None,
+                named_params: None,
+                ..
+            } => 0,
+            Statement::WithParams(_, params)
+            | Statement::Verbose {
+                params: Some(params),
+                ..
+            } => params.iter().map(SqliteParam::estimated_byte_size).sum(),
+            Statement::WithNamedParams(_, params)
+            | Statement::Verbose {
+                named_params: Some(params),
+                ..
+            } => params
+                .iter()
+                .map(|(name, param)| name.len() + param.estimated_byte_size())
+                .sum(),
+        };
+        self.query().len() + params
+    }
 }
 
 impl From<&str> for Statement {
@@ -241,6 +323,73 @@ pub struct TableStatResponse {
     pub invalid_tables: Vec<String>,
 }
 
+/// Liveness and readiness of a node, served by `/v1/health`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HealthResponse {
+    /// The runtime picked up a new task in time
+    pub live: bool,
+    /// Bookkeeping is loaded, and missing versions and gossip members are
+    /// within the configured thresholds
+    pub ready: bool,
+    /// Gossip members, besides this node
+    pub members: usize,
+    /// Versions missing or partially applied, across actors
+    pub gaps: u64,
+    /// Longest run of missing versions of a single actor
+    pub largest_gap: u64,
+    /// Missing version furthest behind its actor's latest known version
+    pub oldest_unapplied: Option<UnappliedVersion>,
+    /// Changes from peers are queued but not applied
+    #[serde(default)]
+    pub inbound_paused: bool,
+    /// Changes aren't broadcast to peers
+    #[serde(default)]
+    pub outbound_paused: bool,
+}
+
+/// Body of `/v1/health/live`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LivenessResponse {
+    /// The runtime picked up a new task in time
+    pub live: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UnappliedVersion {
+    pub actor_id: String,
+    pub version: u64,
+    /// Versions known for the actor after this one
+    pub behind: u64,
+}
+
+/// Order-independent content hash of a table's rows
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TableDigest {
+    pub table: String,
+    /// XOR of every row's hash
+    pub hash: u64,
+    /// Row hashes, by hash of the row's primary key
+    pub rows: BTreeMap<u64, u64>,
+}
+
+impl TableDigest {
+    /// Number of primary keys present on a single side, or with different
+    /// rows on each side
+    pub fn differing_pks(&self, other: &TableDigest) -> usize {
+        let changed_or_removed = self
+            .rows
+            .iter()
+            .filter(|(pk, row)| other.rows.get(pk) != Some(row))
+            .count();
+        let added = other
+            .rows
+            .keys()
+            .filter(|pk| !self.rows.contains_key(pk))
+            .count();
+        changed_or_removed + added
+    }
+}
+
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub struct SqliteValueRef<'a>(pub ValueRef<'a>);
 
@@ -327,6 +476,17 @@ impl ColumnType {
             _ => return None,
         })
     }
+
+    /// Inverse of [`ColumnType::from_sqlite_name`], NULL has no type name
+    pub fn sqlite_name(&self) -> Option<&'static str> {
+        Some(match self {
+            Self::Integer => "INTEGER",
+            Self::Float => "REAL",
+            Self::Text => "TEXT",
+            Self::Blob => "BLOB",
+            Self::Null => return None,
+        })
+    }
 }
 
 impl FromSql for ColumnType {
@@ -360,6 +520,18 @@ pub enum SqliteParam {
     Json(Box<RawValue>),
 }
 
+impl SqliteParam {
+    pub fn estimated_byte_size(&self) -> usize {
+        1 + match self {
+            SqliteParam::Null | SqliteParam::Bool(_) => 1,
+            SqliteParam::Integer(_) | SqliteParam::Real(_) => 8,
+            SqliteParam::Text(t) => 4 + t.len(),
+            SqliteParam::Blob(v) => 4 + v.len(),
+            SqliteParam::Json(v) => 4 + v.get().len(),
+        }
+    }
+}
+
 impl From<&str> for SqliteParam {
     fn from(value: &str) -> Self {
         Self::Text(value.into())
@@ -416,6 +588,8 @@ impl<'a> From<ValueRef<'a>> for SqliteValueRef<'a> {
     }
 }
 
+/// A value as stored by SQLite. Ordered the way SQLite sorts values, see the
+/// [`PartialOrd`] impl.
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Default, Clone, Serialize, Deserialize, PartialEq, Hash)]
 #[serde(untagged)]
@@ -460,7 +634,86 @@ fn integer_decode(val: f64) -> (u64, i16, i8) {
     (mantissa, exponent, sign)
 }
 
+/// SQLite's sort order: NULL, then integers and reals compared by numeric
+/// value, then text compared byte-wise (the `BINARY` collation), then blobs
+/// compared byte-wise. Text is never compared as a number, `9 < '10'`.
+///
+/// Consistent with `PartialEq`: an integer sorts before a real of the same
+/// value (`1 < 1.0`) and NaNs aren't comparable. [`SqliteValue::sqlite_cmp`]
+/// leaves the former equal and orders the latter.
+impl PartialOrd for SqliteValue {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (SqliteValue::Integer(_), SqliteValue::Real(r))
+            | (SqliteValue::Real(r), SqliteValue::Integer(_))
+                if r.0.is_nan() =>
+            {
+                None
+            }
+            (SqliteValue::Integer(_), SqliteValue::Real(_)) => {
+                Some(self.sqlite_cmp(other).then(Ordering::Less))
+            }
+            (SqliteValue::Real(_), SqliteValue::Integer(_)) => {
+                Some(self.sqlite_cmp(other).then(Ordering::Greater))
+            }
+            (SqliteValue::Real(a), SqliteValue::Real(b)) => a.0.partial_cmp(&b.0),
+            _ => Some(self.sqlite_cmp(other)),
+        }
+    }
+}
+
+/// Compares an integer and a real exactly, without rounding the integer
+fn cmp_integer_real(i: i64, r: f64) -> Ordering {
+    // 2^63, the first real past i64::MAX
+    const I64_END: f64 = 9223372036854775808.0;
+
+    if r.is_nan() {
+        // same place as with f64::total_cmp
+        return if r.is_sign_negative() {
+            Ordering::Greater
+        } else {
+            Ordering::Less
+        };
+    }
+    if r >= I64_END {
+        return Ordering::Less;
+    }
+    if r < -I64_END {
+        return Ordering::Greater;
+    }
+
+    let trunc = r.trunc();
+    i.cmp(&(trunc as i64))
+        .then_with(|| 0f64.partial_cmp(&(r - trunc)).unwrap_or(Ordering::Equal))
+}
+
 impl SqliteValue {
+    /// Compares the way SQLite does, see the [`PartialOrd`] impl. Unlike it,
+    /// an integer and a real of the same value are equal (`1 = 1.0`), and
+    /// NaNs fall back to [`f64::total_cmp`] so any values can be sorted.
+    pub fn sqlite_cmp(&self, other: &Self) -> Ordering {
+        fn rank(value: &SqliteValue) -> u8 {
+            match value {
+                SqliteValue::Null => 0,
+                SqliteValue::Integer(_) | SqliteValue::Real(_) => 1,
+                SqliteValue::Text(_) => 2,
+                SqliteValue::Blob(_) => 3,
+            }
+        }
+
+        match (self, other) {
+            (SqliteValue::Integer(a), SqliteValue::Integer(b)) => a.cmp(b),
+            (SqliteValue::Integer(a), SqliteValue::Real(b)) => cmp_integer_real(*a, b.0),
+            (SqliteValue::Real(a), SqliteValue::Integer(b)) => cmp_integer_real(*b, a.0).reverse(),
+            (SqliteValue::Real(a), SqliteValue::Real(b)) => {
+                a.0.partial_cmp(&b.0).unwrap_or_else(|| a.0.total_cmp(&b.0))
+            }
+            (SqliteValue::Text(a), SqliteValue::Text(b)) => a.as_bytes().cmp(b.as_bytes()),
+            (SqliteValue::Blob(a), SqliteValue::Blob(b)) => a.as_slice().cmp(b.as_slice()),
+            _ => rank(self).cmp(&rank(other)),
+        }
+    }
+
     pub fn column_type(&self) -> ColumnType {
         match self {
             SqliteValue::Null => ColumnType::Null,
@@ -612,6 +865,44 @@ impl fmt::Display for SqliteValue {
     }
 }
 
+thread_local! {
+    static MAX_VALUE_BYTES: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Runs `decode` with text and blob values over `limit` bytes failing to
+/// decode from their length, before they're allocated. The limit only holds
+/// for the readers `decode` runs, `None` lifts it.
+pub fn with_max_value_bytes<T>(limit: Option<usize>, decode: impl FnOnce() -> T) -> T {
+    struct Restore(Option<usize>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            MAX_VALUE_BYTES.with(|max| max.set(self.0));
+        }
+    }
+
+    let _restore = Restore(MAX_VALUE_BYTES.with(|max| max.replace(limit)));
+    decode()
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("value of {size} bytes is over the {limit} bytes limit")]
+pub struct ValueTooLarge {
+    pub size: usize,
+    pub limit: usize,
+}
+
+/// Reads a text or blob length, checked against the limit set by
+/// [`with_max_value_bytes`]
+fn read_value_len<'a, C: Context, R: Reader<'a, C>>(reader: &mut R) -> Result<usize, C::Error> {
+    let size = reader.read_u32()? as usize;
+    match MAX_VALUE_BYTES.with(Cell::get) {
+        Some(limit) if size > limit => {
+            Err(speedy::Error::custom(ValueTooLarge { size, limit }).into())
+        }
+        _ => Ok(size),
+    }
+}
+
 impl<'a, C> Readable<'a, C> for SqliteValue
 where
     C: Context,
@@ -623,13 +914,16 @@ where
             1 => SqliteValue::Integer(i64::read_from(reader)?),
             2 => SqliteValue::Real(Real(f64::read_from(reader)?)),
             3 => {
-                let len = reader.read_u32()? as usize;
+                let len = read_value_len(reader)?;
 
                 SqliteValue::Text(unsafe {
                     CompactString::from_utf8_unchecked(reader.read_vec(len)?)
                 })
             }
-            4 => SqliteValue::Blob(Readable::read_from(reader)?),
+            4 => {
+                let len = read_value_len(reader)?;
+                SqliteValue::Blob(SmallVec::from_vec(reader.read_vec(len)?))
+            }
             _ => return Err(speedy::Error::custom("unknown SqliteValue variant").into()),
         })
     }
@@ -866,6 +1160,30 @@ impl ToSql for ColumnName {
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_value_size_limit() {
+        let value = SqliteValue::Text("a".repeat(100).into());
+        let encoded = value.write_to_vec().unwrap();
+        // a text claiming 4GiB that never comes
+        let mut truncated = vec![3u8];
+        truncated.extend_from_slice(&u32::MAX.to_le_bytes());
+
+        with_max_value_bytes(Some(64), || {
+            let err = SqliteValue::read_from_buffer(&encoded).unwrap_err();
+            assert!(err.to_string().contains("100 bytes"), "{err}");
+            // rejected from its length, not for the missing bytes
+            let err = SqliteValue::read_from_buffer(&truncated).unwrap_err();
+            assert!(err.to_string().contains("over the 64 bytes limit"), "{err}");
+            let blob = SqliteValue::Blob(vec![0u8; 65].into());
+            assert!(SqliteValue::read_from_buffer(&blob.write_to_vec().unwrap()).is_err());
+        });
+
+        // only for the decodes it ran
+        assert_eq!(SqliteValue::read_from_buffer(&encoded).unwrap(), value);
+        let err = SqliteValue::read_from_buffer(&truncated).unwrap_err();
+        assert!(!err.to_string().contains("limit"), "{err}");
+    }
+
     #[test]
     fn test_statement_serialization() {
         let s = serde_json::to_string(&vec![Statement::WithParams(
@@ -896,4 +1214,55 @@ mod tests {
         let stmts: Vec<Statement> = serde_json::from_str(json).unwrap();
         println!("stmts: {stmts:?}");
     }
+
+    #[test]
+    fn test_sqlite_value_ordering() {
+        let int = SqliteValue::Integer;
+        let real = |r: f64| SqliteValue::Real(Real(r));
+        let text = |t: &str| SqliteValue::Text(t.into());
+        let blob = |b: &[u8]| SqliteValue::Blob(b.into());
+
+        // cross-type: NULL < numbers < text < blob
+        let mut values = vec![blob(b"a"), text("a"), real(1.5), SqliteValue::Null, int(1)];
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(
+            values,
+            vec![SqliteValue::Null, int(1), real(1.5), text("a"), blob(b"a")]
+        );
+
+        // text is never numeric
+        assert!(int(9) < text("10"));
+        assert!(int(i64::MAX) < text("0"));
+        assert!(text("10") < text("9"));
+        assert!(text("Z") < text("a"));
+        assert!(text("a") < text("ab"));
+        assert!(text("zzz") < blob(b""));
+
+        // numbers compare by value, across integers and reals
+        assert!(int(9) < int(10));
+        assert!(int(-10) < real(-9.5));
+        assert!(real(9.5) < int(10));
+        assert!(int(2) > real(1.999));
+        assert!(int(-1) < real(-0.5));
+        assert!(real(f64::NEG_INFINITY) < int(i64::MIN));
+        assert!(int(i64::MAX) < real(9223372036854775808.0));
+        // i64::MAX isn't representable as a real, no rounding to 2^63
+        assert!(int(i64::MAX - 1) < int(i64::MAX));
+        assert!(int(i64::MAX) > real(9223372036854774784.0));
+
+        // equal for sqlite, still ordered to be consistent with PartialEq
+        assert_eq!(int(1).sqlite_cmp(&real(1.0)), Ordering::Equal);
+        assert_ne!(int(1), real(1.0));
+        assert!(int(1) < real(1.0));
+
+        // reals compare numerically
+        assert_eq!(real(-0.0), real(0.0));
+        assert_eq!(real(-0.0).partial_cmp(&real(0.0)), Some(Ordering::Equal));
+        assert_ne!(real(f64::NAN), real(f64::NAN));
+        assert_eq!(real(f64::NAN).partial_cmp(&real(f64::NAN)), None);
+        assert_eq!(int(1).partial_cmp(&real(f64::NAN)), None);
+        // but can still be sorted
+        assert_eq!(real(f64::NAN).sqlite_cmp(&real(f64::NAN)), Ordering::Equal);
+        assert_eq!(real(1.0).sqlite_cmp(&real(f64::NAN)), Ordering::Less);
+    }
 }
@@ -0,0 +1,151 @@
+//! Mapping query rows to Rust types, see the `FromCorroRow` derive macro.
+
+use compact_str::CompactString;
+
+use crate::{ColumnName, ColumnType, SqliteValue};
+
+/// A type that can be built from a query row, usually derived with
+/// `#[derive(FromCorroRow)]`.
+pub trait FromCorroRow: Sized {
+    fn from_row(columns: &[ColumnName], row: &[SqliteValue]) -> Result<Self, RowError>;
+}
+
+/// A type a single column's value can be converted to.
+pub trait FromSqliteValue: Sized {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError>;
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum RowError {
+    #[error("missing column '{0}'")]
+    MissingColumn(String),
+    #[error("row has no value for column '{0}'")]
+    MissingValue(String),
+    #[error("column '{column}': {source}")]
+    Value { column: String, source: ValueError },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ValueError {
+    #[error("expected {expected}, got {got:?}")]
+    WrongType {
+        expected: &'static str,
+        got: ColumnType,
+    },
+    #[error("integer {0} is out of range")]
+    OutOfRange(i64),
+}
+
+/// Looks up `column` in the row and converts its value, used by the derive.
+pub fn get_column<T: FromSqliteValue>(
+    columns: &[ColumnName],
+    row: &[SqliteValue],
+    column: &str,
+) -> Result<T, RowError> {
+    let idx = columns
+        .iter()
+        .position(|name| name.as_str() == column)
+        .ok_or_else(|| RowError::MissingColumn(column.to_owned()))?;
+    let value = row
+        .get(idx)
+        .ok_or_else(|| RowError::MissingValue(column.to_owned()))?;
+
+    T::from_sqlite_value(value).map_err(|source| RowError::Value {
+        column: column.to_owned(),
+        source,
+    })
+}
+
+fn wrong_type(expected: &'static str, value: &SqliteValue) -> ValueError {
+    ValueError::WrongType {
+        expected,
+        got: value.column_type(),
+    }
+}
+
+impl FromSqliteValue for SqliteValue {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+        Ok(value.clone())
+    }
+}
+
+impl<T: FromSqliteValue> FromSqliteValue for Option<T> {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+        match value {
+            SqliteValue::Null => Ok(None),
+            value => T::from_sqlite_value(value).map(Some),
+        }
+    }
+}
+
+impl FromSqliteValue for i64 {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+        value
+            .as_integer()
+            .copied()
+            .ok_or_else(|| wrong_type("integer", value))
+    }
+}
+
+macro_rules! from_sqlite_integer {
+    ($($ty:ty),*) => {
+        $(
+            impl FromSqliteValue for $ty {
+                fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+                    let i = i64::from_sqlite_value(value)?;
+                    <$ty>::try_from(i).map_err(|_| ValueError::OutOfRange(i))
+                }
+            }
+        )*
+    };
+}
+
+from_sqlite_integer!(i8, i16, i32, u8, u16, u32, u64, usize);
+
+impl FromSqliteValue for bool {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+        match value {
+            SqliteValue::Integer(0) => Ok(false),
+            SqliteValue::Integer(1) => Ok(true),
+            SqliteValue::Integer(i) => Err(ValueError::OutOfRange(*i)),
+            value => Err(wrong_type("boolean integer", value)),
+        }
+    }
+}
+
+impl FromSqliteValue for f64 {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+        match value {
+            SqliteValue::Real(f) => Ok(f.0),
+            SqliteValue::Integer(i) => Ok(*i as f64),
+            value => Err(wrong_type("real", value)),
+        }
+    }
+}
+
+impl FromSqliteValue for String {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+        value
+            .as_text()
+            .map(str::to_owned)
+            .ok_or_else(|| wrong_type("text", value))
+    }
+}
+
+impl FromSqliteValue for CompactString {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+        value
+            .as_text()
+            .map(CompactString::from)
+            .ok_or_else(|| wrong_type("text", value))
+    }
+}
+
+impl FromSqliteValue for Vec<u8> {
+    fn from_sqlite_value(value: &SqliteValue) -> Result<Self, ValueError> {
+        match value {
+            SqliteValue::Blob(b) => Ok(b.to_vec()),
+            value => Err(wrong_type("blob", value)),
+        }
+    }
+}
@@ -0,0 +1,476 @@
+use serde_json::{json, Map, Value};
+
+/// JSON Schema (draft 2020-12) of the HTTP API request and response types.
+///
+/// `x-endpoints` maps each endpoint to its request and response schemas,
+/// streaming endpoints respond with newline-delimited JSON where every line
+/// matches the `line` schema.
+pub fn api_schema() -> Value {
+    let mut defs = Map::new();
+    defs.insert(
+        "Blob".into(),
+        json!({
+            "description": "BLOBs are encoded as an array of their byte values",
+            "type": "array",
+            "items": { "type": "integer", "minimum": 0, "maximum": 255 }
+        }),
+    );
+    defs.insert(
+        "SqliteValue".into(),
+        json!({
+            "description": "A SQLite value, untagged: NULL, INTEGER, REAL, TEXT or BLOB",
+            "anyOf": [
+                { "type": "null" },
+                { "type": "integer" },
+                { "type": "number" },
+                { "type": "string" },
+                { "$ref": "#/$defs/Blob" }
+            ]
+        }),
+    );
+    defs.insert(
+        "SqliteParam".into(),
+        json!({
+            "description": "A statement parameter, booleans are bound as 0 or 1 and any other JSON value is bound as its JSON text",
+            "anyOf": [
+                { "type": "null" },
+                { "type": "boolean" },
+                { "type": "integer" },
+                { "type": "number" },
+                { "type": "string" },
+                { "$ref": "#/$defs/Blob" },
+                {}
+            ]
+        }),
+    );
+    defs.insert(
+        "Statement".into(),
+        json!({
+            "anyOf": [
+                { "type": "string" },
+                {
+                    "type": "array",
+                    "prefixItems": [
+                        { "type": "string" },
+                        { "type": "array", "items": { "$ref": "#/$defs/SqliteParam" } }
+                    ],
+                    "minItems": 2,
+                    "maxItems": 2
+                },
+                {
+                    "type": "array",
+                    "prefixItems": [
+                        { "type": "string" },
+                        {
+                            "type": "object",
+                            "additionalProperties": { "$ref": "#/$defs/SqliteParam" }
+                        }
+                    ],
+                    "minItems": 2,
+                    "maxItems": 2
+                },
+                {
+                    "type": "object",
+                    "properties": {
+                        "query": { "type": "string" },
+                        "params": {
+                            "anyOf": [
+                                { "type": "null" },
+                                { "type": "array", "items": { "$ref": "#/$defs/SqliteParam" } }
+                            ]
+                        },
+                        "named_params": {
+                            "anyOf": [
+                                { "type": "null" },
+                                {
+                                    "type": "object",
+                                    "additionalProperties": { "$ref": "#/$defs/SqliteParam" }
+                                }
+                            ]
+                        }
+                    },
+                    "required": ["query"]
+                }
+            ]
+        }),
+    );
+    defs.insert(
+        "ExecResult".into(),
+        json!({
+            "anyOf": [
+                {
+                    "type": "object",
+                    "properties": {
+                        "rows_affected": { "type": "integer", "minimum": 0 },
+                        "time": { "type": "number" }
+                    },
+                    "required": ["rows_affected", "time"]
+                },
+                {
+                    "type": "object",
+                    "properties": { "error": { "type": "string" } },
+                    "required": ["error"]
+                }
+            ]
+        }),
+    );
+    defs.insert(
+        "ExecResponse".into(),
+        json!({
+            "type": "object",
+            "properties": {
+                "results": { "type": "array", "items": { "$ref": "#/$defs/ExecResult" } },
+                "time": { "type": "number" },
+                "version": { "anyOf": [{ "type": "null" }, { "type": "integer", "minimum": 0 }] },
+                "actor_id": { "anyOf": [{ "type": "null" }, { "type": "string" }] }
+            },
+            "required": ["results", "time"]
+        }),
+    );
+    defs.insert("RowId".into(), json!({ "type": "integer", "minimum": 0 }));
+    defs.insert(
+        "ChangeId".into(),
+        json!({ "type": "integer", "minimum": 0 }),
+    );
+    defs.insert(
+        "ChangeType".into(),
+        json!({ "enum": ["insert", "update", "delete"] }),
+    );
+    defs.insert(
+        "Row".into(),
+        json!({ "type": "array", "items": { "$ref": "#/$defs/SqliteValue" } }),
+    );
+    defs.insert(
+        "ColumnMeta".into(),
+        json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string" },
+                "type": {
+                    "description": "Declared type of the column, or the type of the first row's value for expressions",
+                    "anyOf": [{ "type": "null" }, { "type": "string" }]
+                }
+            },
+            "required": ["name", "type"]
+        }),
+    );
+    defs.insert(
+        "QueryEvent".into(),
+        json!({
+            "description": "One line of a query or subscription stream",
+            "oneOf": [
+                tagged("metadata", json!({
+                    "type": "object",
+                    "properties": {
+                        "columns": { "type": "array", "items": { "$ref": "#/$defs/ColumnMeta" } }
+                    },
+                    "required": ["columns"]
+                })),
+                tagged("columns", json!({ "type": "array", "items": { "type": "string" } })),
+                tagged("row", json!({
+                    "type": "array",
+                    "prefixItems": [{ "$ref": "#/$defs/RowId" }, { "$ref": "#/$defs/Row" }],
+                    "minItems": 2,
+                    "maxItems": 2
+                })),
+                tagged("deleted", json!({ "$ref": "#/$defs/Row" })),
+                tagged("eoq", json!({
+                    "type": "object",
+                    "properties": {
+                        "time": { "type": "number" },
+                        "change_id": { "$ref": "#/$defs/ChangeId" },
+                        "cursor": { "type": "integer", "minimum": 0 }
+                    },
+                    "required": ["time"]
+                })),
+                tagged("change", json!({
+                    "type": "array",
+                    "prefixItems": [
+                        { "$ref": "#/$defs/ChangeType" },
+                        { "$ref": "#/$defs/RowId" },
+                        { "$ref": "#/$defs/Row" },
+                        { "$ref": "#/$defs/ChangeId" }
+                    ],
+                    "minItems": 4,
+                    "maxItems": 4
+                })),
+                tagged("resync", json!({
+                    "type": "object",
+                    "properties": { "min_change_id": { "$ref": "#/$defs/ChangeId" } },
+                    "required": ["min_change_id"]
+                })),
+                tagged("heartbeat", json!({
+                    "type": "object",
+                    "properties": { "change_id": { "$ref": "#/$defs/ChangeId" } },
+                    "required": ["change_id"]
+                })),
+                tagged("error", json!({ "type": "string" }))
+            ]
+        }),
+    );
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "Corrosion HTTP API",
+        "$defs": defs,
+        "x-endpoints": {
+            "POST /v1/transactions": {
+                "request": { "type": "array", "items": { "$ref": "#/$defs/Statement" } },
+                "response": { "$ref": "#/$defs/ExecResponse" }
+            },
+            "POST /v1/queries": {
+                "request": { "$ref": "#/$defs/Statement" },
+                "response": {
+                    "content_type": "application/x-ndjson",
+                    "line": { "$ref": "#/$defs/QueryEvent" }
+                }
+            },
+            "POST /v1/subscriptions": {
+                "request": { "$ref": "#/$defs/Statement" },
+                "response": {
+                    "content_type": "application/x-ndjson",
+                    "line": { "$ref": "#/$defs/QueryEvent" }
+                }
+            }
+        }
+    })
+}
+
+// externally tagged enum variant: an object with a single `tag` key
+fn tagged(tag: &str, schema: Value) -> Value {
+    json!({
+        "type": "object",
+        "properties": { tag: schema },
+        "required": [tag],
+        "additionalProperties": false
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::{
+        sqlite::ChangeType, ChangeId, ColumnMeta, ExecResponse, ExecResult, QueryEvent, Real,
+        RowId, SqliteValue, Statement,
+    };
+
+    // minimal validator for the subset of JSON Schema used by `api_schema`
+    fn validate(root: &Value, schema: &Value, value: &Value) -> bool {
+        if let Some(path) = schema.get("$ref").and_then(Value::as_str) {
+            let name = path.trim_start_matches("#/$defs/");
+            return validate(root, &root["$defs"][name], value);
+        }
+        if let Some(schemas) = schema.get("anyOf").and_then(Value::as_array) {
+            if !schemas.iter().any(|s| validate(root, s, value)) {
+                return false;
+            }
+        }
+        if let Some(schemas) = schema.get("oneOf").and_then(Value::as_array) {
+            if schemas.iter().filter(|s| validate(root, s, value)).count() != 1 {
+                return false;
+            }
+        }
+        if let Some(variants) = schema.get("enum").and_then(Value::as_array) {
+            if !variants.contains(value) {
+                return false;
+            }
+        }
+        if let Some(ty) = schema.get("type").and_then(Value::as_str) {
+            let ok = match ty {
+                "null" => value.is_null(),
+                "boolean" => value.is_boolean(),
+                "integer" => value.is_i64() || value.is_u64(),
+                "number" => value.is_number(),
+                "string" => value.is_string(),
+                "array" => value.is_array(),
+                "object" => value.is_object(),
+                _ => false,
+            };
+            if !ok {
+                return false;
+            }
+        }
+        if let Some(n) = value.as_f64() {
+            if schema
+                .get("minimum")
+                .and_then(Value::as_f64)
+                .is_some_and(|min| n < min)
+                || schema
+                    .get("maximum")
+                    .and_then(Value::as_f64)
+                    .is_some_and(|max| n > max)
+            {
+                return false;
+            }
+        }
+        if let Some(items) = value.as_array() {
+            let len = items.len() as u64;
+            if schema
+                .get("minItems")
+                .and_then(Value::as_u64)
+                .is_some_and(|min| len < min)
+                || schema
+                    .get("maxItems")
+                    .and_then(Value::as_u64)
+                    .is_some_and(|max| len > max)
+            {
+                return false;
+            }
+            let prefix = schema.get("prefixItems").and_then(Value::as_array);
+            for (i, item) in items.iter().enumerate() {
+                let item_schema = match prefix.and_then(|prefix| prefix.get(i)) {
+                    Some(s) => Some(s),
+                    None => schema.get("items"),
+                };
+                if item_schema.is_some_and(|s| !validate(root, s, item)) {
+                    return false;
+                }
+            }
+        }
+        if let Some(fields) = value.as_object() {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            if let Some(required) = schema.get("required").and_then(Value::as_array) {
+                if required
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .any(|key| !fields.contains_key(key))
+                {
+                    return false;
+                }
+            }
+            for (key, field) in fields {
+                let field_schema = match properties.and_then(|p| p.get(key)) {
+                    Some(s) => s,
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => return false,
+                        Some(s) => s,
+                        None => continue,
+                    },
+                };
+                if !validate(root, field_schema, field) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    #[test]
+    fn test_api_schema_matches_types() {
+        let root = api_schema();
+        let endpoints = &root["x-endpoints"];
+        let transactions = &endpoints["POST /v1/transactions"];
+        let queries = &endpoints["POST /v1/queries"];
+
+        // sample requests, as integrators would write them
+        let request: Value = serde_json::from_str(
+            r#"[
+                "INSERT INTO foo (id) VALUES (1)",
+                ["INSERT INTO foo (id, data) VALUES (?, ?)", [2, [1, 2, 255]]],
+                ["UPDATE foo SET text = :text", {"text": "hello"}],
+                {"query": "DELETE FROM foo WHERE id = ?", "params": [true]}
+            ]"#,
+        )
+        .unwrap();
+        serde_json::from_value::<Vec<Statement>>(request.clone()).unwrap();
+        assert!(validate(&root, &transactions["request"], &request));
+
+        // serialized types must match the schema
+        let statements = vec![
+            Statement::Simple("SELECT 1".into()),
+            Statement::WithParams("SELECT ?".into(), vec![1i64.into(), vec![0u8, 1].into()]),
+            Statement::WithNamedParams(
+                "SELECT :a".into(),
+                HashMap::from([("a".to_owned(), "b".into())]),
+            ),
+            Statement::Verbose {
+                query: "SELECT ?".into(),
+                params: Some(vec!["a".into()]),
+                named_params: None,
+            },
+        ];
+        let value = serde_json::to_value(&statements).unwrap();
+        assert!(validate(&root, &transactions["request"], &value));
+
+        let response = ExecResponse {
+            results: vec![
+                ExecResult::Execute {
+                    rows_affected: 1,
+                    time: 0.1,
+                },
+                ExecResult::Error {
+                    error: "oops".into(),
+                },
+            ],
+            time: 0.2,
+            version: Some(3),
+            actor_id: None,
+        };
+        let value = serde_json::to_value(&response).unwrap();
+        assert!(validate(&root, &transactions["response"], &value));
+
+        let line = &queries["response"]["line"];
+        let row = vec![
+            SqliteValue::Null,
+            SqliteValue::Integer(1),
+            SqliteValue::Real(Real(1.5)),
+            SqliteValue::Text("text".into()),
+            SqliteValue::Blob(vec![0u8, 255].into()),
+        ];
+        let events = vec![
+            QueryEvent::Metadata {
+                columns: vec![
+                    ColumnMeta {
+                        name: "id".into(),
+                        sql_type: Some("INTEGER".into()),
+                    },
+                    ColumnMeta {
+                        name: "count(*) + 1".into(),
+                        sql_type: None,
+                    },
+                ],
+            },
+            QueryEvent::Columns(vec!["id".into()]),
+            QueryEvent::Row(RowId(1), row.clone()),
+            QueryEvent::Deleted(vec![1i64.into()]),
+            QueryEvent::EndOfQuery {
+                time: 0.1,
+                change_id: Some(ChangeId(1)),
+                cursor: None,
+            },
+            QueryEvent::EndOfQuery {
+                time: 0.1,
+                change_id: None,
+                cursor: Some(3),
+            },
+            QueryEvent::Change(ChangeType::Update, RowId(1), row, ChangeId(2)),
+            QueryEvent::Resync {
+                min_change_id: ChangeId(3),
+            },
+            QueryEvent::Heartbeat {
+                change_id: ChangeId(4),
+            },
+            QueryEvent::Error("oops".into()),
+        ];
+        for event in events {
+            let value = serde_json::to_value(&event).unwrap();
+            assert!(validate(&root, line, &value), "{value} doesn't match");
+        }
+
+        // and drift gets caught
+        assert!(!validate(&root, line, &json!({"row": [1, "a"]})));
+        assert!(!validate(&root, line, &json!({"rows": [1, []]})));
+        assert!(!validate(
+            &root,
+            &transactions["request"],
+            &json!([["SELECT 1", [], "extra"]])
+        ));
+        assert!(!validate(
+            &root,
+            &root["$defs"]["SqliteValue"],
+            &json!([256])
+        ));
+    }
+}
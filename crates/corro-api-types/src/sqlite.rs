@@ -11,8 +11,12 @@ use speedy::{Readable, Writable};
 #[serde(rename_all = "snake_case")]
 #[repr(u8)]
 pub enum ChangeType {
+    /// A row entered the subscription's result set, either because it was
+    /// inserted or because an update made it match the query
     Insert = 0,
     Update = 1,
+    /// A row left the subscription's result set, either because it was
+    /// deleted or because an update made it stop matching the query
     Delete = 2,
 }
 
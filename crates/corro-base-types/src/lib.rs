@@ -46,6 +46,32 @@ macro_rules! dbsri {
 pub struct CrsqlDbVersion(pub u64);
 
 impl CrsqlDbVersion {
+    /// The version right after this one.
+    ///
+    /// # Panics
+    ///
+    /// If this is `u64::MAX`, in release builds too.
+    #[inline]
+    pub fn next(self) -> Self {
+        self.checked_add(1).expect("CrsqlDbVersion overflow")
+    }
+
+    #[inline]
+    pub fn checked_add(self, rhs: u64) -> Option<Self> {
+        self.0.checked_add(rhs).map(Self)
+    }
+
+    #[inline]
+    pub fn saturating_sub(self, rhs: u64) -> Self {
+        Self(self.0.saturating_sub(rhs))
+    }
+
+    /// `self..=end`, empty if `end` comes before `self`
+    #[inline]
+    pub fn range_to(self, end: Self) -> RangeInclusive<Self> {
+        self..=end
+    }
+
     #[inline]
     pub fn chunked_iter(
         range: CrsqlDbVersionRange,
@@ -249,6 +275,13 @@ macro_rules! range {
                 }
             }
 
+            /// Like [`Self::new`], but `None` if `start > end` instead of an
+            /// empty range
+            #[inline]
+            pub fn checked_new(start: $inner, end: $inner) -> Option<Self> {
+                (start <= end).then(|| Self::new(start, end))
+            }
+
             /// Creates an empty range
             #[inline]
             pub fn empty() -> Self {
@@ -307,6 +340,62 @@ macro_rules! range {
             pub fn is_empty(&self) -> bool {
                 self.len() == 0
             }
+
+            /// Iterates over every value of the range without consuming it,
+            /// yields nothing for inverted ranges
+            #[inline]
+            pub fn iter(&self) -> $iter {
+                $iter {
+                    next: self.start,
+                    end: self.end_int(),
+                    done: self.is_empty(),
+                }
+            }
+
+            /// Whether `value` is within the range, bounds included
+            #[inline]
+            pub fn contains(&self, value: $inner) -> bool {
+                !self.is_empty() && self.start <= value.0 && value.0 <= self.end_int()
+            }
+
+            /// Whether both ranges share at least one value. Empty ranges
+            /// never overlap anything.
+            #[inline]
+            pub fn overlaps(&self, other: &Self) -> bool {
+                !self.is_empty()
+                    && !other.is_empty()
+                    && self.start <= other.end_int()
+                    && other.start <= self.end_int()
+            }
+
+            /// Whether the ranges don't overlap but one starts right after the
+            /// other ends, eg. `1..=3` and `4..=6`. Empty ranges are never
+            /// adjacent.
+            #[inline]
+            pub fn is_adjacent(&self, other: &Self) -> bool {
+                fn touches(left: u64, right: u64) -> bool {
+                    left.checked_add(1) == Some(right)
+                }
+
+                !self.is_empty()
+                    && !other.is_empty()
+                    && (touches(self.end_int(), other.start)
+                        || touches(other.end_int(), self.start))
+            }
+
+            /// The smallest range covering both, if they overlap or are
+            /// adjacent so that no value between them is left out.
+            #[inline]
+            pub fn merge(&self, other: &Self) -> Option<Self> {
+                if !self.overlaps(other) && !self.is_adjacent(other) {
+                    return None;
+                }
+
+                Some(Self::new(
+                    $inner(self.start.min(other.start)),
+                    $inner(self.end_int().max(other.end_int())),
+                ))
+            }
         }
 
         impl From<RangeInclusive<$inner>> for $name {
@@ -370,6 +459,56 @@ macro_rules! range {
             }
         }
 
+        impl<'r> IntoIterator for &'r $name {
+            type Item = $inner;
+            type IntoIter = $iter;
+
+            #[inline]
+            fn into_iter(self) -> Self::IntoIter {
+                self.iter()
+            }
+        }
+
+        /// Iterator returned by the range's `iter()`
+        #[derive(Clone, Debug)]
+        pub struct $iter {
+            next: u64,
+            end: u64,
+            done: bool,
+        }
+
+        impl Iterator for $iter {
+            type Item = $inner;
+
+            #[inline]
+            fn next(&mut self) -> Option<Self::Item> {
+                if self.done {
+                    return None;
+                }
+
+                let next = self.next;
+                if next == self.end {
+                    self.done = true;
+                } else {
+                    self.next += 1;
+                }
+
+                Some($inner(next))
+            }
+
+            #[inline]
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                let len = if self.done {
+                    0
+                } else {
+                    (self.end - self.next) as usize + 1
+                };
+                (len, Some(len))
+            }
+        }
+
+        impl ExactSizeIterator for $iter {}
+
         impl<'a, C: speedy::Context> Readable<'a, C> for $name {
             #[inline]
             fn read_from<R: speedy::Reader<'a, C>>(reader: &mut R) -> Result<Self, C::Error> {
@@ -499,6 +638,138 @@ mod test {
         assert_eq!(max.into_iter().count(), 2);
     }
 
+    #[test]
+    fn seq_range_iter() {
+        let single = CrsqlSeqRange::from(CrsqlSeq(7)..=CrsqlSeq(7));
+        assert_eq!(single, CrsqlSeqRange::single(CrsqlSeq(7)));
+        assert_eq!(single.len(), 1);
+        assert!(!single.is_empty());
+        assert_eq!(single.iter().collect::<Vec<_>>(), vec![CrsqlSeq(7)]);
+
+        // seq 0 is a common single-element range
+        let zero = CrsqlSeqRange::from(CrsqlSeq(0)..=CrsqlSeq(0));
+        assert_eq!(zero.len(), 1);
+        assert_eq!(zero.iter().collect::<Vec<_>>(), vec![CrsqlSeq(0)]);
+
+        let multi = CrsqlSeqRange::from(CrsqlSeq(0)..=CrsqlSeq(4));
+        assert_eq!(multi.len(), 5);
+        assert_eq!(multi.iter().len(), 5);
+        assert_eq!(
+            multi.iter().collect::<Vec<_>>(),
+            (0..=4).map(CrsqlSeq).collect::<Vec<_>>()
+        );
+        // iterating by reference doesn't consume the range
+        assert_eq!((&multi).into_iter().count(), 5);
+        assert_eq!(multi.len(), 5);
+
+        let max = CrsqlSeqRange::from(CrsqlSeq(u64::MAX - 1)..=CrsqlSeq(u64::MAX));
+        assert_eq!(
+            max.iter().collect::<Vec<_>>(),
+            vec![CrsqlSeq(u64::MAX - 1), CrsqlSeq(u64::MAX)]
+        );
+
+        // inverted
+        assert_eq!(CrsqlSeqRange::checked_new(CrsqlSeq(5), CrsqlSeq(4)), None);
+        assert_eq!(
+            CrsqlSeqRange::checked_new(CrsqlSeq(4), CrsqlSeq(5)),
+            Some(dbsr!(4, 5))
+        );
+        let inverted = CrsqlSeqRange::new(CrsqlSeq(5), CrsqlSeq(4));
+        assert!(inverted.is_empty());
+        assert_eq!(inverted.iter().count(), 0);
+    }
+
+    #[test]
+    fn seq_range_contains() {
+        let r = dbsr!(3, 6);
+        assert!(!r.contains(CrsqlSeq(2)));
+        assert!(r.contains(CrsqlSeq(3)));
+        assert!(r.contains(CrsqlSeq(5)));
+        assert!(r.contains(CrsqlSeq(6)));
+        assert!(!r.contains(CrsqlSeq(7)));
+
+        // single element
+        let single = CrsqlSeqRange::single(CrsqlSeq(4));
+        assert!(single.contains(CrsqlSeq(4)));
+        assert!(!single.contains(CrsqlSeq(3)));
+        assert!(!single.contains(CrsqlSeq(5)));
+        assert!(dbsr!(0, 0).contains(CrsqlSeq(0)));
+
+        // inverted ranges are empty. `empty()` can't be told apart from
+        // `0..=0` and contains 0.
+        assert!(!dbsr!(5, 4).contains(CrsqlSeq(4)));
+        assert!(!dbsr!(5, 4).contains(CrsqlSeq(5)));
+
+        let max = CrsqlSeqRange::new(CrsqlSeq(u64::MAX - 1), CrsqlSeq(u64::MAX));
+        assert!(max.contains(CrsqlSeq(u64::MAX)));
+    }
+
+    #[test]
+    fn seq_range_overlaps_and_adjacency() {
+        #[track_caller]
+        fn check(
+            a: CrsqlSeqRange,
+            b: CrsqlSeqRange,
+            overlaps: bool,
+            adjacent: bool,
+            merged: Option<CrsqlSeqRange>,
+        ) {
+            // all of these are symmetric
+            for (a, b) in [(a, b), (b, a)] {
+                assert_eq!(a.overlaps(&b), overlaps, "overlaps({a:?}, {b:?})");
+                assert_eq!(a.is_adjacent(&b), adjacent, "is_adjacent({a:?}, {b:?})");
+                assert_eq!(a.merge(&b), merged, "merge({a:?}, {b:?})");
+            }
+        }
+
+        // identical
+        check(dbsr!(1, 5), dbsr!(1, 5), true, false, Some(dbsr!(1, 5)));
+        // sharing a single bound
+        check(dbsr!(1, 5), dbsr!(5, 9), true, false, Some(dbsr!(1, 9)));
+        // partial overlap
+        check(dbsr!(1, 5), dbsr!(3, 9), true, false, Some(dbsr!(1, 9)));
+        // nested, including on either bound
+        check(dbsr!(1, 9), dbsr!(3, 5), true, false, Some(dbsr!(1, 9)));
+        check(dbsr!(1, 9), dbsr!(1, 5), true, false, Some(dbsr!(1, 9)));
+        check(dbsr!(1, 9), dbsr!(5, 9), true, false, Some(dbsr!(1, 9)));
+        // touching
+        check(dbsr!(1, 4), dbsr!(5, 9), false, true, Some(dbsr!(1, 9)));
+        check(dbsr!(0, 0), dbsr!(1, 3), false, true, Some(dbsr!(0, 3)));
+        // disjoint, a single value apart
+        check(dbsr!(1, 3), dbsr!(5, 9), false, false, None);
+        check(dbsr!(1, 3), dbsr!(100, 200), false, false, None);
+
+        // single elements
+        let single = |n| CrsqlSeqRange::single(CrsqlSeq(n));
+        check(single(4), single(4), true, false, Some(single(4)));
+        check(single(4), single(5), false, true, Some(dbsr!(4, 5)));
+        check(single(4), single(6), false, false, None);
+        check(single(4), dbsr!(1, 9), true, false, Some(dbsr!(1, 9)));
+        check(single(1), dbsr!(1, 9), true, false, Some(dbsr!(1, 9)));
+        check(single(9), dbsr!(1, 9), true, false, Some(dbsr!(1, 9)));
+        check(single(10), dbsr!(1, 9), false, true, Some(dbsr!(1, 10)));
+        check(single(0), dbsr!(1, 9), false, true, Some(dbsr!(0, 9)));
+
+        // empty (inverted) ranges never overlap, touch or merge
+        check(dbsr!(5, 4), dbsr!(1, 9), false, false, None);
+        check(dbsr!(5, 4), dbsr!(5, 9), false, false, None);
+        check(dbsr!(5, 4), dbsr!(3, 2), false, false, None);
+
+        // no overflow at the upper bound
+        let max = CrsqlSeqRange::new(CrsqlSeq(u64::MAX - 1), CrsqlSeq(u64::MAX));
+        check(max, dbsr!(0, 1), false, false, None);
+        check(
+            max,
+            CrsqlSeqRange::single(CrsqlSeq(u64::MAX - 2)),
+            false,
+            true,
+            Some(CrsqlSeqRange::new(
+                CrsqlSeq(u64::MAX - 2),
+                CrsqlSeq(u64::MAX),
+            )),
+        );
+    }
+
     #[test]
     fn serialization() {
         #[track_caller]
@@ -519,4 +790,55 @@ mod test {
             CrsqlDbVersion(0),
         ));
     }
+
+    #[test]
+    fn db_version_arithmetic() {
+        let v = CrsqlDbVersion(5);
+        assert_eq!(v.next(), CrsqlDbVersion(6));
+        assert_eq!(v.checked_add(3), Some(CrsqlDbVersion(8)));
+        assert_eq!(v.saturating_sub(2), CrsqlDbVersion(3));
+
+        // boundaries
+        let max = CrsqlDbVersion(u64::MAX);
+        assert_eq!(max.checked_add(0), Some(max));
+        assert_eq!(max.checked_add(1), None);
+        assert_eq!(CrsqlDbVersion(u64::MAX - 1).next(), max);
+        assert_eq!(CrsqlDbVersion(0).saturating_sub(1), CrsqlDbVersion(0));
+        assert_eq!(
+            CrsqlDbVersion(1).saturating_sub(u64::MAX),
+            CrsqlDbVersion(0)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "CrsqlDbVersion overflow")]
+    fn db_version_next_overflow() {
+        CrsqlDbVersion(u64::MAX).next();
+    }
+
+    #[test]
+    fn db_version_range_to() {
+        let single = CrsqlDbVersion(7).range_to(CrsqlDbVersion(7));
+        assert!(!single.is_empty());
+        assert_eq!(CrsqlDbVersionRange::from(&single).len(), 1);
+        assert_eq!(
+            CrsqlDbVersionRange::from(&single),
+            CrsqlDbVersionRange::single(CrsqlDbVersion(7))
+        );
+
+        let empty = CrsqlDbVersion(7).range_to(CrsqlDbVersion(6));
+        assert!(empty.is_empty());
+        assert!(CrsqlDbVersionRange::from(&empty).is_empty());
+
+        let multi = CrsqlDbVersion(1).range_to(CrsqlDbVersion(4));
+        assert_eq!(multi, dbvri!(1, 4));
+        assert_eq!(CrsqlDbVersionRange::from(multi).len(), 4);
+
+        // a range up to the previous version of the first one is empty
+        let v = CrsqlDbVersion(1);
+        assert!(v.range_to(v.saturating_sub(1)).is_empty());
+
+        let full = CrsqlDbVersion(0).range_to(CrsqlDbVersion(u64::MAX));
+        assert_eq!(*full.end(), CrsqlDbVersion(u64::MAX));
+    }
 }
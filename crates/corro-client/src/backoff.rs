@@ -0,0 +1,173 @@
+use std::{fmt, sync::Arc, time::Duration};
+
+/// Called with the attempt number (starting at 1) and the delay before it.
+pub type RetryCallback = Arc<dyn Fn(u32, Duration) + Send + Sync>;
+
+/// Exponential backoff between `base` and `max`, growing by `factor` with
+/// every retry and jittered by up to `jitter` of the delay either way.
+#[derive(Clone)]
+pub struct BackoffConfig {
+    pub base: Duration,
+    pub max: Duration,
+    pub factor: u32,
+    /// Between 0 and 1, exclusive
+    pub jitter: f32,
+    /// Give up after this many consecutive retries, `None` retries forever.
+    pub max_attempts: Option<u32>,
+    pub on_retry: Option<RetryCallback>,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(30),
+            factor: 2,
+            jitter: 0.3,
+            max_attempts: Some(10),
+            on_retry: None,
+        }
+    }
+}
+
+impl fmt::Debug for BackoffConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackoffConfig")
+            .field("base", &self.base)
+            .field("max", &self.max)
+            .field("factor", &self.factor)
+            .field("jitter", &self.jitter)
+            .field("max_attempts", &self.max_attempts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl BackoffConfig {
+    pub fn on_retry(mut self, callback: impl Fn(u32, Duration) + Send + Sync + 'static) -> Self {
+        self.on_retry = Some(Arc::new(callback));
+        self
+    }
+
+    fn iter(&self) -> ::backoff::Iter {
+        ::backoff::Backoff::new(self.max_attempts.unwrap_or(0))
+            .timeout_range(self.base, self.max)
+            .jitter(self.jitter)
+            .factor(self.factor)
+            .iter()
+    }
+}
+
+/// Backoff state for a retry loop, [`Backoff::reset`] it after a success.
+#[derive(Debug)]
+pub struct Backoff {
+    config: BackoffConfig,
+    iter: ::backoff::Iter,
+}
+
+impl Backoff {
+    /// Panics if the config's jitter isn't between 0 and 1.
+    pub fn new(config: BackoffConfig) -> Self {
+        let iter = config.iter();
+        Self { config, iter }
+    }
+
+    pub fn config(&self) -> &BackoffConfig {
+        &self.config
+    }
+
+    /// Number of retries since the last reset.
+    pub fn attempt(&self) -> u32 {
+        self.iter.retry_count()
+    }
+
+    pub fn reset(&mut self) {
+        self.iter = self.config.iter();
+    }
+
+    /// Returns the delay to wait before the next retry, or `None` once
+    /// `max_attempts` retries have been made.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        // no retries at all, the backoff crate takes 0 as retrying forever
+        if self.config.max_attempts == Some(0) {
+            return None;
+        }
+
+        let delay = self.iter.next()?;
+
+        if let Some(on_retry) = self.config.on_retry.as_ref() {
+            on_retry(self.attempt(), delay);
+        }
+
+        Some(delay)
+    }
+
+    /// Sleeps for the next delay, returns false without sleeping if out of
+    /// attempts.
+    pub async fn wait(&mut self) -> bool {
+        match self.next_delay() {
+            Some(delay) => {
+                tokio::time::sleep(delay).await;
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::time::Instant;
+
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_backoff_grows_with_jitter() {
+        let attempts = Arc::new(Mutex::new(vec![]));
+        let config = BackoffConfig {
+            base: Duration::from_millis(100),
+            max: Duration::from_secs(2),
+            factor: 2,
+            jitter: 0.3,
+            max_attempts: Some(8),
+            on_retry: None,
+        }
+        .on_retry({
+            let attempts = attempts.clone();
+            move |attempt, _| attempts.lock().unwrap().push(attempt)
+        });
+        let mut backoff = Backoff::new(config);
+
+        let mut delays = vec![];
+        let mut last = Instant::now();
+        while backoff.wait().await {
+            delays.push(last.elapsed());
+            last = Instant::now();
+        }
+        assert_eq!(delays.len(), 8);
+        assert_eq!(*attempts.lock().unwrap(), (1..=8).collect::<Vec<_>>());
+
+        for delay in &delays {
+            assert!(*delay >= Duration::from_millis(100), "{delay:?}");
+            assert!(*delay <= Duration::from_secs(2), "{delay:?}");
+        }
+        // grows up to the max, whatever the jitter
+        assert!(delays[0] < Duration::from_millis(200));
+        assert_eq!(delays[7], Duration::from_secs(2));
+
+        // a success resets the sequence
+        backoff.reset();
+        assert_eq!(backoff.attempt(), 0);
+        let delay = backoff.next_delay().unwrap();
+        assert!(delay < Duration::from_millis(200));
+        assert_eq!(backoff.attempt(), 1);
+
+        // no retries
+        let mut backoff = Backoff::new(BackoffConfig {
+            max_attempts: Some(0),
+            ..Default::default()
+        });
+        assert_eq!(backoff.next_delay(), None);
+    }
+}
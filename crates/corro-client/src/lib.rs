@@ -1,6 +1,17 @@
+pub mod backoff;
 pub mod sub;
 
-use corro_api_types::{ChangeId, ExecResponse, ExecResult, SqliteValue, Statement};
+use crate::backoff::{Backoff, BackoffConfig};
+use corro_api_types::{
+    row::{FromCorroRow, RowError},
+    ChangeId, ExecResponse, ExecResult, SqliteParam, SqliteValue, Statement, TableDigest,
+    TypedQueryEvent,
+};
+pub use corro_derive::FromCorroRow;
+// lets `FromCorroRow` derives find the api types through this crate
+#[doc(hidden)]
+pub use corro_api_types as __api_types;
+use futures::TryStreamExt;
 use hickory_resolver::{
     error::{ResolveError, ResolveErrorKind},
     name_server::TokioConnectionProvider,
@@ -16,7 +27,7 @@ use std::{
     sync::Arc,
     time::{self, Duration, Instant},
 };
-use sub::{QueryStream, SubscriptionStream, UpdatesStream};
+use sub::{QueryError, QueryStream, SubscriptionStream, UpdatesStream};
 use tokio::{
     sync::{RwLock, RwLockReadGuard},
     time::timeout,
@@ -32,6 +43,7 @@ const DNS_RESOLVE_TIMEOUT: Duration = Duration::from_secs(3);
 pub struct CorrosionApiClient {
     api_addr: SocketAddr,
     api_client: hyper::Client<HttpConnector, Body>,
+    backoff: BackoffConfig,
 }
 
 impl CorrosionApiClient {
@@ -45,9 +57,16 @@ impl CorrosionApiClient {
                 .http2_keep_alive_interval(Some(HTTP2_KEEP_ALIVE_INTERVAL))
                 .http2_keep_alive_timeout(HTTP2_KEEP_ALIVE_INTERVAL / 2)
                 .build(connector),
+            backoff: BackoffConfig::default(),
         }
     }
 
+    /// Sets the backoff used by subscription streams when reconnecting.
+    pub fn with_backoff(mut self, backoff: BackoffConfig) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
     pub async fn query_typed<T: DeserializeOwned + Unpin>(
         &self,
         statement: &Statement,
@@ -100,6 +119,19 @@ impl CorrosionApiClient {
         self.query_typed(statement, timeout).await
     }
 
+    /// Runs a query and maps each row to `T` by column name, see the
+    /// [`FromCorroRow`](derive@FromCorroRow) derive.
+    pub async fn query_as<T: FromCorroRow>(
+        &self,
+        sql: &str,
+        params: Vec<SqliteParam>,
+    ) -> Result<Vec<T>, Error> {
+        let stream = self
+            .query(&Statement::WithParams(sql.into(), params), None)
+            .await?;
+        collect_rows(stream).await
+    }
+
     pub async fn subscribe_typed<T: DeserializeOwned + Unpin>(
         &self,
         statement: &Statement,
@@ -152,6 +184,7 @@ impl CorrosionApiClient {
             self.api_addr,
             res.into_body(),
             from,
+            Backoff::new(self.backoff.clone()),
         ))
     }
 
@@ -209,6 +242,7 @@ impl CorrosionApiClient {
             self.api_addr,
             res.into_body(),
             from,
+            Backoff::new(self.backoff.clone()),
         ))
     }
 
@@ -336,6 +370,25 @@ impl CorrosionApiClient {
         Ok(serde_json::from_slice(&bytes)?)
     }
 
+    /// Order-independent content hash of a table, to compare nodes
+    pub async fn table_digest(&self, table: &str) -> Result<TableDigest, Error> {
+        let req = hyper::Request::builder()
+            .method(hyper::Method::GET)
+            .uri(format!("http://{}/v1/tables/{table}/digest", self.api_addr))
+            .header(hyper::header::ACCEPT, "application/json")
+            .body(Body::empty())?;
+
+        let res = self.api_client.request(req).await?;
+
+        if !res.status().is_success() {
+            return Err(Error::UnexpectedStatusCode(res.status()));
+        }
+
+        let bytes = hyper::body::to_bytes(res.into_body()).await?;
+
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
     pub async fn schema_from_paths<P: AsRef<Path>>(
         &self,
         schema_paths: &[P],
@@ -395,6 +448,9 @@ impl Deref for CorrosionClient {
 #[derive(Clone)]
 pub struct CorrosionPooledClient {
     inner: Arc<RwLock<PooledClientInner>>,
+    // Backoff between failed attempts, if configured. Shared by every clone
+    // and only locked briefly, never across an await.
+    backoff: Arc<std::sync::Mutex<Option<Backoff>>>,
 }
 
 struct PooledClientInner {
@@ -410,6 +466,10 @@ struct PooledClientInner {
     first_fail_at: Option<Instant>,
     // Current client generation, incremented after each client change
     generation: u64,
+    // Earliest time the next attempt can be made after a failure
+    retry_at: Option<Instant>,
+    // Set when the backoff ran out of attempts, reported to the next caller
+    exhausted: bool,
 }
 
 impl CorrosionPooledClient {
@@ -427,7 +487,32 @@ impl CorrosionPooledClient {
                 had_success: false,
                 first_fail_at: None,
                 generation: 0,
+                retry_at: None,
+                exhausted: false,
             })),
+            backoff: Arc::new(std::sync::Mutex::new(None)),
+        }
+    }
+
+    /// Waits with exponential backoff and jitter between failed attempts
+    /// instead of retrying right away, this also applies to the subscription
+    /// streams of the servers picked from then on. Clones share the backoff.
+    pub fn with_backoff(self, backoff: BackoffConfig) -> Self {
+        *self.backoff.lock().unwrap() = Some(Backoff::new(backoff));
+        self
+    }
+
+    fn backoff_config(&self) -> Option<BackoffConfig> {
+        self.backoff
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(|backoff| backoff.config().clone())
+    }
+
+    fn reset_backoff(&self) {
+        if let Some(backoff) = self.backoff.lock().unwrap().as_mut() {
+            backoff.reset();
         }
     }
 
@@ -454,6 +539,18 @@ impl CorrosionPooledClient {
         response
     }
 
+    /// Pooled version of [`CorrosionApiClient::query_as`]
+    pub async fn query_as<T: FromCorroRow>(
+        &self,
+        sql: &str,
+        params: Vec<SqliteParam>,
+    ) -> Result<Vec<T>, Error> {
+        let stream = self
+            .query_typed(&Statement::WithParams(sql.into(), params), None)
+            .await?;
+        collect_rows(stream).await
+    }
+
     pub async fn subscribe_typed<T: DeserializeOwned + Unpin>(
         &self,
         statement: &Statement,
@@ -503,6 +600,22 @@ impl CorrosionPooledClient {
     }
 
     async fn get_client(&self) -> Result<(RwLockReadGuard<'_, CorrosionApiClient>, u64), Error> {
+        let retry_at = {
+            let mut inner = self.inner.write().await;
+            if inner.exhausted {
+                inner.exhausted = false;
+                self.reset_backoff();
+                return Err(Error::MaxRetryAttempts);
+            }
+            inner.retry_at
+        };
+
+        // waited out without the lock, other callers still get to report
+        // their results meanwhile
+        if let Some(retry_at) = retry_at {
+            tokio::time::sleep_until(retry_at.into()).await;
+        }
+
         let mut inner = self.inner.write().await;
         let generation = inner.generation;
 
@@ -512,7 +625,11 @@ impl CorrosionPooledClient {
                 "next Corrosion server to attempt: {}, generation: {}",
                 addr, generation
             );
-            inner.client = Some(CorrosionApiClient::new(addr))
+            let mut client = CorrosionApiClient::new(addr);
+            if let Some(backoff) = self.backoff_config() {
+                client = client.with_backoff(backoff);
+            }
+            inner.client = Some(client)
         }
 
         Ok((
@@ -534,6 +651,8 @@ impl CorrosionPooledClient {
         inner.had_success = true;
         // And reset the time of the first fail.
         inner.first_fail_at = None;
+        inner.retry_at = None;
+        self.reset_backoff();
     }
 
     async fn handle_error(&self, generation: u64) {
@@ -544,6 +663,18 @@ impl CorrosionPooledClient {
             return;
         }
 
+        let next_delay = self
+            .backoff
+            .lock()
+            .unwrap()
+            .as_mut()
+            .map(|backoff| backoff.next_delay());
+        match next_delay {
+            Some(Some(delay)) => inner.retry_at = Some(Instant::now() + delay),
+            Some(None) => inner.exhausted = true,
+            None => {}
+        }
+
         match inner.first_fail_at {
             // First fail after success
             None if inner.had_success => {
@@ -657,6 +788,23 @@ impl AddrPicker {
     }
 }
 
+async fn collect_rows<T: FromCorroRow>(
+    mut stream: QueryStream<Vec<SqliteValue>>,
+) -> Result<Vec<T>, Error> {
+    let mut columns = vec![];
+    let mut rows = vec![];
+    while let Some(event) = stream.try_next().await? {
+        match event {
+            TypedQueryEvent::Columns(names) => columns = names,
+            TypedQueryEvent::Row(_, row) => rows.push(T::from_row(&columns, &row)?),
+            TypedQueryEvent::EndOfQuery { .. } => break,
+            TypedQueryEvent::Error(e) => return Err(Error::ResponseError(e.into())),
+            _ => {}
+        }
+    }
+    Ok(rows)
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
@@ -681,11 +829,18 @@ pub enum Error {
 
     #[error("could not retrieve subscription id from headers")]
     ExpectedQueryId,
+    #[error("max retry attempts exceeded")]
+    MaxRetryAttempts,
+
+    #[error(transparent)]
+    Query(#[from] QueryError),
+    #[error(transparent)]
+    Row(#[from] RowError),
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::{CorrosionPooledClient, Error};
+    use crate::{backoff::BackoffConfig, CorrosionPooledClient, Error};
     use corro_api_types::SqliteValue;
     use hickory_resolver::AsyncResolver;
     use hyper::{header::HeaderValue, service::service_fn, Body, Request, Response};
@@ -782,6 +937,29 @@ mod tests {
         (servers, addrs)
     }
 
+    #[tokio::test]
+    async fn test_pooled_backoff_shared_with_clones() {
+        let resolver = AsyncResolver::tokio_from_system_conf().unwrap();
+        let client = CorrosionPooledClient::new(
+            vec!["127.0.0.1:1".into()],
+            Duration::from_nanos(1),
+            resolver,
+        );
+        let cloned = client.clone();
+        assert!(cloned.backoff_config().is_none());
+
+        let _client = client.with_backoff(BackoffConfig {
+            max_attempts: Some(3),
+            ..Default::default()
+        });
+        assert_eq!(
+            cloned
+                .backoff_config()
+                .and_then(|config| config.max_attempts),
+            Some(3)
+        );
+    }
+
     #[tokio::test]
     async fn test_single_address() {
         let statement = "".into();
@@ -965,4 +1143,52 @@ mod tests {
             .unwrap();
         assert_eq!(sub.id(), pool1_servers[0].id);
     }
+
+    #[tokio::test]
+    async fn test_query_as() {
+        #[derive(Debug, PartialEq, crate::FromCorroRow)]
+        struct Item {
+            id: i64,
+            #[corro(rename = "name")]
+            label: Option<String>,
+        }
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let body = concat!(
+                "{\"columns\":[\"id\",\"name\"]}\n",
+                "{\"row\":[1,[1,\"one\"]]}\n",
+                "{\"row\":[2,[2,null]]}\n",
+                "{\"eoq\":{\"time\":0.0}}\n",
+            );
+            _ = hyper::server::conn::Http::new()
+                .serve_connection(
+                    stream,
+                    service_fn(move |_: Request<Body>| async move {
+                        Ok::<_, Infallible>(Response::new(Body::from(body)))
+                    }),
+                )
+                .await;
+        });
+
+        let client = crate::CorrosionApiClient::new(addr);
+        let items: Vec<Item> = client
+            .query_as("SELECT id, name FROM items", vec![])
+            .await
+            .unwrap();
+
+        assert_eq!(
+            items,
+            vec![
+                Item {
+                    id: 1,
+                    label: Some("one".into())
+                },
+                Item { id: 2, label: None },
+            ]
+        );
+    }
 }
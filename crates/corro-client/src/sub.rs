@@ -4,7 +4,6 @@ use std::{
     net::SocketAddr,
     pin::Pin,
     task::{Context, Poll},
-    time::Duration,
 };
 
 use bytes::{Buf, Bytes, BytesMut};
@@ -21,6 +20,8 @@ use tokio_util::{
 use tracing::error;
 use uuid::Uuid;
 
+use crate::backoff::Backoff;
+
 pin_project! {
     pub struct IoBodyStream {
         #[pin]
@@ -63,7 +64,7 @@ pub struct SubscriptionStream<T> {
     last_change_id: Option<ChangeId>,
     stream: Option<FramedBody>,
     backoff: Option<Pin<Box<Sleep>>>,
-    backoff_count: u32,
+    retries: Backoff,
     response: Option<hyper::client::ResponseFuture>,
     _deser: std::marker::PhantomData<T>,
 }
@@ -84,6 +85,8 @@ pub enum SubscriptionError {
     UnfinishedQuery,
     #[error("max retry attempts exceeded")]
     MaxRetryAttempts,
+    #[error("subscription must be resynced from scratch, min change id: {min_change_id}")]
+    MustResync { min_change_id: ChangeId },
 }
 
 impl<T> SubscriptionStream<T>
@@ -97,6 +100,7 @@ where
         api_addr: SocketAddr,
         body: hyper::Body,
         change_id: Option<ChangeId>,
+        retries: Backoff,
     ) -> Self {
         Self {
             id,
@@ -110,7 +114,7 @@ where
                 LinesBytesCodec::default(),
             )),
             backoff: None,
-            backoff_count: 0,
+            retries,
             response: None,
             _deser: Default::default(),
         }
@@ -160,6 +164,18 @@ where
                         }
                     }
 
+                    if let TypedQueryEvent::Heartbeat { change_id } = &evt {
+                        if let Err(e) = self.handle_heartbeat(*change_id) {
+                            return Poll::Ready(Some(Err(e)));
+                        }
+                    }
+
+                    if let TypedQueryEvent::Resync { min_change_id } = &evt {
+                        return Poll::Ready(Some(Err(SubscriptionError::MustResync {
+                            min_change_id: *min_change_id,
+                        })));
+                    }
+
                     Poll::Ready(Some(Ok(evt)))
                 }
                 Err(deser_err) => {
@@ -211,6 +227,26 @@ where
         Ok(())
     }
 
+    // heartbeats carry the last change id sent, use it as our resume point
+    // unless it reveals we missed changes
+    fn handle_heartbeat(&mut self, change_id: ChangeId) -> Result<(), SubscriptionError> {
+        if !self.observed_eoq {
+            return Ok(());
+        }
+
+        match self.last_change_id {
+            Some(id) if change_id > id => Err(SubscriptionError::MissedChange {
+                expected: id + 1,
+                got: change_id,
+            }),
+            Some(_) => Ok(()),
+            None => {
+                self.last_change_id = Some(change_id);
+                Ok(())
+            }
+        }
+    }
+
     fn poll_request(
         mut self: Pin<&mut Self>,
         cx: &mut Context<'_>,
@@ -279,7 +315,7 @@ where
         let io_err = match ready!(self.as_mut().poll_stream(cx)) {
             Some(Err(SubscriptionError::Io(io_err))) => io_err,
             other => {
-                self.backoff_count = 0;
+                self.retries.reset();
                 return Poll::Ready(other);
             }
         };
@@ -287,13 +323,16 @@ where
         // reset the stream
         self.stream = None;
 
-        if self.backoff_count >= 10 {
+        let Some(delay) = self.retries.next_delay() else {
             return Poll::Ready(Some(Err(SubscriptionError::MaxRetryAttempts)));
-        }
+        };
 
-        error!("encountered a stream IO error: {io_err}, retrying in a bit");
+        error!(
+            attempt = self.retries.attempt(),
+            "encountered a stream IO error: {io_err}, retrying in {delay:?}"
+        );
 
-        let mut backoff = Box::pin(sleep(Duration::from_secs(1)));
+        let mut backoff = Box::pin(sleep(delay));
 
         // register w/ waker
         _ = backoff.as_mut().poll(cx);
@@ -301,8 +340,6 @@ where
         // this can't return Ready, right?
         self.backoff = Some(backoff);
 
-        self.backoff_count += 1;
-
         Poll::Pending
     }
 }
@@ -0,0 +1,104 @@
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use proc_macro_crate::{crate_name, FoundCrate};
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Ident, LitStr};
+
+/// Derives `corro_api_types::row::FromCorroRow`, mapping query columns to
+/// fields by name. Use `#[corro(rename = "column")]` when they differ. Works
+/// with either `corro-api-types` or only `corro-client` as a dependency.
+#[proc_macro_derive(FromCorroRow, attributes(corro))]
+pub fn derive_from_corro_row(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    expand(input)
+        .unwrap_or_else(syn::Error::into_compile_error)
+        .into()
+}
+
+/// Path to `corro_api_types` as seen from the crate using the derive, either
+/// directly or through `corro_client`'s re-export.
+fn api_types() -> syn::Result<proc_macro2::TokenStream> {
+    match crate_name("corro-api-types") {
+        Ok(FoundCrate::Itself) => return Ok(quote!(crate)),
+        Ok(FoundCrate::Name(name)) => {
+            let name = Ident::new(&name, Span::call_site());
+            return Ok(quote!(::#name));
+        }
+        Err(_) => {}
+    }
+
+    match crate_name("corro-client") {
+        Ok(FoundCrate::Itself) => Ok(quote!(crate::__api_types)),
+        Ok(FoundCrate::Name(name)) => {
+            let name = Ident::new(&name, Span::call_site());
+            Ok(quote!(::#name::__api_types))
+        }
+        Err(e) => Err(syn::Error::new(
+            Span::call_site(),
+            format!("FromCorroRow needs corro-api-types or corro-client as a dependency: {e}"),
+        )),
+    }
+}
+
+fn expand(input: DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let name = &input.ident;
+    let api_types = api_types()?;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    name,
+                    "FromCorroRow can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(syn::Error::new_spanned(
+                name,
+                "FromCorroRow can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut assignments = Vec::with_capacity(fields.len());
+    for field in fields {
+        // named fields always have an ident
+        let ident = field.ident.as_ref().unwrap();
+
+        let mut column = ident.to_string();
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|attr| attr.path().is_ident("corro"))
+        {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    column = meta.value()?.parse::<LitStr>()?.value();
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported corro attribute"))
+                }
+            })?;
+        }
+
+        assignments.push(quote! {
+            #ident: #api_types::row::get_column(columns, row, #column)?
+        });
+    }
+
+    Ok(quote! {
+        impl #impl_generics #api_types::row::FromCorroRow for #name #ty_generics #where_clause {
+            fn from_row(
+                columns: &[#api_types::ColumnName],
+                row: &[#api_types::SqliteValue],
+            ) -> ::std::result::Result<Self, #api_types::row::RowError> {
+                ::std::result::Result::Ok(Self {
+                    #(#assignments,)*
+                })
+            }
+        }
+    })
+}
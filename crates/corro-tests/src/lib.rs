@@ -1,6 +1,6 @@
 use std::sync::Arc;
 
-use corro_agent::agent::start_with_config;
+use corro_agent::{agent::start_with_config, transport::Transport};
 use corro_types::{
     agent::{Agent, Bookie},
     config::{Config, ConfigBuilder, ConfigBuilderError},
@@ -56,6 +56,7 @@ pub const TEST_SCHEMA: &str = r#"
 pub struct TestAgent {
     pub agent: Agent,
     pub bookie: Bookie,
+    pub transport: Transport,
     pub tmpdir: Arc<TempDir>,
     pub config: Config,
 }
@@ -72,16 +73,18 @@ pub async fn launch_test_agent<F: FnOnce(ConfigBuilder) -> Result<Config, Config
         .gossip_addr("127.0.0.1:0".parse()?)
         .admin_path(tmpdir.path().join("admin.sock").display().to_string())
         .db_path(tmpdir.path().join("corrosion.db").display().to_string())
-        .add_schema_path(schema_path.display().to_string()))?;
+        .add_schema_path(schema_path.display().to_string())
+        .check_conflict_winners(true))?;
 
     tokio::fs::create_dir(&schema_path).await?;
     tokio::fs::write(schema_path.join("tests.sql"), TEST_SCHEMA.as_bytes()).await?;
 
-    let (agent, bookie, _, _) = start_with_config(conf.clone(), tripwire).await?;
+    let (agent, bookie, transport, _) = start_with_config(conf.clone(), tripwire).await?;
 
     Ok(TestAgent {
         agent,
         bookie,
+        transport,
         tmpdir: Arc::new(tmpdir),
         config: conf,
     })
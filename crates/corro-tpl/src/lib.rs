@@ -10,7 +10,7 @@ use std::sync::Arc;
 use compact_str::ToCompactString;
 use corro_client::sub::SubscriptionStream;
 use corro_client::CorrosionApiClient;
-use corro_types::api::{ColumnName, QueryEvent, RowId, SqliteParam, Statement};
+use corro_types::api::{ColumnName, QueryEvent, Real, RowId, SqliteParam, Statement};
 use corro_types::change::SqliteValue;
 use futures::StreamExt;
 use indexmap::IndexMap;
@@ -21,6 +21,7 @@ use rhai_tpl::TemplateWriter;
 use rhai_tpl::Writer;
 use serde::ser::{SerializeSeq, Serializer};
 use serde_json::ser::Formatter;
+use sha2::{Digest, Sha256};
 use tokio::sync::OnceCell;
 use tokio::sync::{mpsc, RwLock as TokioRwLock};
 use tokio_util::sync::CancellationToken;
@@ -73,6 +74,96 @@ impl QueryResponse {
     fn to_csv(&mut self) -> SqlToCsv {
         SqlToCsv { res: self.clone() }
     }
+
+    fn sha256(&mut self) -> Result<String, Box<EvalAltResult>> {
+        Ok(sha256_hex(&self.clone().stable_bytes()?))
+    }
+
+    fn crc32(&mut self) -> Result<String, Box<EvalAltResult>> {
+        Ok(crc32_hex(&self.clone().stable_bytes()?))
+    }
+
+    /// Encodes the column names and rows, rows sorted by their encoding so
+    /// the result doesn't depend on the order they were returned in.
+    fn stable_bytes(self) -> Result<Vec<u8>, Box<EvalAltResult>> {
+        let mut rows = self.into_iter();
+
+        let mut encoded_rows = vec![];
+        for row in rows.by_ref() {
+            let row = row?;
+            let mut buf = vec![];
+            for value in row.cells.iter() {
+                encode_value(&mut buf, value);
+            }
+            encoded_rows.push(buf);
+        }
+        encoded_rows.sort_unstable();
+
+        let mut buf = vec![];
+        if let Some(columns) = rows.columns.as_ref() {
+            for col in columns.keys() {
+                encode_bytes(&mut buf, col.as_bytes());
+            }
+        }
+        for row in encoded_rows {
+            encode_bytes(&mut buf, &row);
+        }
+
+        Ok(buf)
+    }
+}
+
+fn encode_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u64).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_value(buf: &mut Vec<u8>, value: &SqliteValue) {
+    match value {
+        SqliteValue::Null => buf.push(0),
+        SqliteValue::Integer(i) => {
+            buf.push(1);
+            buf.extend_from_slice(&i.to_be_bytes());
+        }
+        SqliteValue::Real(f) => {
+            buf.push(2);
+            buf.extend_from_slice(&f.0.to_bits().to_be_bytes());
+        }
+        SqliteValue::Text(t) => {
+            buf.push(3);
+            encode_bytes(buf, t.as_bytes());
+        }
+        SqliteValue::Blob(b) => {
+            buf.push(4);
+            encode_bytes(buf, b.as_slice());
+        }
+    }
+}
+
+fn json_path(json: &str, path: &str) -> SqliteValueWrap {
+    SqliteValueWrap(match sqlite_functions::json_path_str(json, path) {
+        None | Some(serde_json::Value::Null) => SqliteValue::Null,
+        Some(serde_json::Value::Bool(b)) => SqliteValue::Integer(b as i64),
+        Some(serde_json::Value::Number(n)) => match n.as_i64() {
+            Some(i) => SqliteValue::Integer(i),
+            None => n
+                .as_f64()
+                .map(|f| SqliteValue::Real(Real(f)))
+                .unwrap_or(SqliteValue::Null),
+        },
+        Some(serde_json::Value::String(s)) => SqliteValue::Text(s.into()),
+        Some(v) => SqliteValue::Text(v.to_string().into()),
+    })
+}
+
+/// Hex encoded SHA-256 of `data`, also used to fingerprint rendered output.
+pub fn sha256_hex(data: &[u8]) -> String {
+    hex::encode(Sha256::digest(data))
+}
+
+/// Hex encoded CRC32 of `data`.
+pub fn crc32_hex(data: &[u8]) -> String {
+    format!("{:08x}", crc32fast::hash(data))
 }
 
 #[derive(Clone)]
@@ -201,6 +292,27 @@ impl SqliteValueWrap {
     fn is_null(&mut self) -> bool {
         matches!(&self.0, SqliteValue::Null)
     }
+
+    /// Value at `path` in this value's JSON text, null if it isn't JSON
+    /// text or nothing is found.
+    fn json_path(&mut self, path: &str) -> SqliteValueWrap {
+        match &self.0 {
+            SqliteValue::Text(t) => json_path(t, path),
+            _ => SqliteValueWrap(SqliteValue::Null),
+        }
+    }
+
+    fn sha256(&mut self) -> String {
+        let mut buf = vec![];
+        encode_value(&mut buf, &self.0);
+        sha256_hex(&buf)
+    }
+
+    fn crc32(&mut self) -> String {
+        let mut buf = vec![];
+        encode_value(&mut buf, &self.0);
+        crc32_hex(&buf)
+    }
 }
 
 impl fmt::Display for SqliteValueWrap {
@@ -260,6 +372,7 @@ impl QueryResponseIter {
             };
             match res {
                 Some(Ok(evt)) => match evt {
+                    QueryEvent::Metadata { .. } | QueryEvent::Deleted(_) => {}
                     QueryEvent::Columns(cols) => {
                         self.columns = Some(Arc::new(
                             cols.into_iter()
@@ -306,6 +419,13 @@ impl QueryResponseIter {
                             }
                         }
                     }
+                    QueryEvent::Heartbeat { .. } => {}
+                    QueryEvent::Resync { min_change_id } => {
+                        self.done = true;
+                        return Some(Err(Box::new(EvalAltResult::from(format!(
+                            "subscription must be resynced, min change id: {min_change_id}"
+                        )))));
+                    }
                     QueryEvent::Error(e) => {
                         self.done = true;
                         return Some(Err(Box::new(EvalAltResult::from(e))));
@@ -412,32 +532,40 @@ async fn wait_for_rows(
     tx: mpsc::Sender<TemplateCommand>,
     cancel: CancellationToken,
 ) {
-    let row_recv = tokio::select! {
-        row_recv = rows.next() => row_recv,
-        _ = cancel.cancelled() => {
-            debug!("template cancellation trigger, returning from tokio task");
-            return
-        },
-    };
+    loop {
+        let row_recv = tokio::select! {
+            row_recv = rows.next() => row_recv,
+            _ = cancel.cancelled() => {
+                debug!("template cancellation trigger, returning from tokio task");
+                return
+            },
+        };
 
-    match row_recv {
-        Some(Ok(QueryEvent::Change(_, _, cells, _))) => {
-            trace!("got an updated row! {cells:?}");
+        match row_recv {
+            Some(Ok(QueryEvent::Change(_, _, cells, _))) => {
+                trace!("got an updated row! {cells:?}");
 
-            if let Err(_e) = tx.send(TemplateCommand::Render).await {
-                debug!("could not send back re-render command, channel must be closed!");
+                if let Err(_e) = tx.send(TemplateCommand::Render).await {
+                    debug!("could not send back re-render command, channel must be closed!");
+                }
+            }
+            Some(Ok(QueryEvent::Heartbeat { .. })) => {
+                // keep waiting for an actual change
+                continue;
+            }
+            Some(Ok(evt)) => {
+                warn!("unexpected event receive: {evt:?}")
+            }
+            Some(Err(e)) => {
+                // TODO: need to re-render possibly...
+                warn!("error from upstream, returning... {e}");
+            }
+            None => {
+                debug!("sql stream is done");
             }
         }
-        Some(Ok(evt)) => {
-            warn!("unexpected event receive: {evt:?}")
-        }
-        Some(Err(e)) => {
-            // TODO: need to re-render possibly...
-            warn!("error from upstream, returning... {e}");
-        }
-        None => {
-            debug!("sql stream is done");
-        }
+
+        return;
     }
 }
 
@@ -487,6 +615,8 @@ impl Engine {
         engine.register_fn("to_json", QueryResponse::to_json);
         engine.register_fn("to_json", QueryResponse::to_json_w_options);
         engine.register_fn("to_csv", QueryResponse::to_csv);
+        engine.register_fn("sha256", QueryResponse::sha256);
+        engine.register_fn("crc32", QueryResponse::crc32);
 
         engine.register_type_with_name::<Row>("Row");
         engine.register_indexer_get(Row::get_cell_value);
@@ -500,6 +630,13 @@ impl Engine {
         engine.register_fn("to_json", SqliteValueWrap::to_json);
         engine.register_fn("to_string", SqliteValueWrap::to_string);
         engine.register_fn("is_null", SqliteValueWrap::is_null);
+        engine.register_fn("json_path", SqliteValueWrap::json_path);
+        engine.register_fn("json_path", json_path);
+        engine.register_fn("sha256", SqliteValueWrap::sha256);
+        engine.register_fn("crc32", SqliteValueWrap::crc32);
+
+        engine.register_fn("sha256", |s: &str| sha256_hex(s.as_bytes()));
+        engine.register_fn("crc32", |s: &str| crc32_hex(s.as_bytes()));
 
         fn sql(
             cx: NativeCallContext,
@@ -817,4 +954,97 @@ mod tests {
 
         println!("output: {output}");
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_hash_stable() {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, _trip_worker, _trip_sender) = Tripwire::new_simple();
+
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone())
+            .await
+            .unwrap();
+
+        let client = corro_client::CorrosionApiClient::new(ta.agent.api_addr());
+
+        client
+            .schema(&[Statement::Simple(corro_tests::TEST_SCHEMA.into())])
+            .await
+            .unwrap();
+
+        client
+            .execute(
+                &[
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec![1i64.into(), "service-name".into()],
+                    ),
+                    Statement::WithParams(
+                        "insert into tests (id, text) values (?,?)".into(),
+                        vec![2i64.into(), "service-name-2".into()],
+                    ),
+                ],
+                None,
+            )
+            .await
+            .unwrap();
+
+        let tmpdir = tempfile::tempdir().unwrap();
+        let mut engine = Engine::new::<std::fs::File>(client.clone());
+        let (tx, _rx) = mpsc::channel(10);
+        let cancel = CancellationToken::new();
+
+        let input = r#"<%= sql("select id, text from tests order by id asc").sha256() %>
+<%= sql("select id, text from tests order by id desc").sha256() %>
+<%= sql("select id, text from tests order by id asc").crc32() %>
+<%= sql("select id, text from tests order by id desc").crc32() %>"#;
+
+        let mut render = |name: &str| {
+            let filepath = tmpdir.path().join(name);
+            let f = std::fs::File::create(&filepath).unwrap();
+            block_in_place(|| {
+                let mut tpl = engine.compile_mut(input).unwrap();
+                let state = TemplateState {
+                    cmd_tx: tx.clone(),
+                    cancel: cancel.clone(),
+                };
+                tpl.evaluator_mut()
+                    .set_default_tag(Dynamic::from(state.clone()));
+                tpl.render(f, state).unwrap();
+            });
+            std::fs::read_to_string(&filepath).unwrap()
+        };
+
+        let first = render("first");
+        let second = render("second");
+        cancel.cancel();
+
+        assert_eq!(first, second);
+        assert_eq!(sha256_hex(first.as_bytes()), sha256_hex(second.as_bytes()));
+
+        let lines: Vec<&str> = first.lines().collect();
+        assert_eq!(lines.len(), 4);
+        // row order doesn't change the fingerprint
+        assert_eq!(lines[0], lines[1]);
+        assert_eq!(lines[0].len(), 64);
+        assert_eq!(lines[2], lines[3]);
+        assert_eq!(lines[2].len(), 8);
+    }
+
+    #[test]
+    fn test_json_path() {
+        let json = r#"{"meta": {"name": "app", "ports": [80, 443]}}"#;
+        assert_eq!(
+            json_path(json, "$.meta.name").0,
+            SqliteValue::Text("app".into())
+        );
+        assert_eq!(
+            json_path(json, "$.meta.ports[1]").0,
+            SqliteValue::Integer(443)
+        );
+        assert!(json_path(json, "$.meta.nope").is_null());
+        assert!(json_path("not json", "$.meta").is_null());
+        assert!(SqliteValueWrap(SqliteValue::Integer(1))
+            .json_path("$.meta")
+            .is_null());
+    }
 }
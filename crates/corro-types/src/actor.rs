@@ -37,6 +37,20 @@ impl ActorId {
     pub fn from_bytes(bytes: [u8; 16]) -> Self {
         Self(Uuid::from_bytes(bytes))
     }
+
+    /// Stable actor id for a node name, so nodes created from the same image
+    /// don't share the random one generated with their database
+    pub fn from_node_name(name: &str) -> Self {
+        let mut bytes = [0u8; 16];
+        bytes[..8]
+            .copy_from_slice(&seahash::hash_seeded(name.as_bytes(), 1, 2, 3, 4).to_be_bytes());
+        bytes[8..]
+            .copy_from_slice(&seahash::hash_seeded(name.as_bytes(), 5, 6, 7, 8).to_be_bytes());
+        // a custom (v8) RFC 4122 uuid
+        bytes[6] = (bytes[6] & 0x0f) | 0x80;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        Self::from_bytes(bytes)
+    }
 }
 
 impl TryFrom<ActorId> for uhlc::ID {
@@ -7,8 +7,9 @@ use std::{
     net::SocketAddr,
     ops::{Deref, DerefMut, RangeInclusive},
     path::{Path, PathBuf},
+    str::FromStr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
     time::{Duration, Instant},
@@ -19,15 +20,15 @@ use arc_swap::ArcSwap;
 use camino::Utf8PathBuf;
 use compact_str::{CompactString, ToCompactString};
 use indexmap::IndexMap;
-use metrics::{gauge, histogram};
-use parking_lot::RwLock;
+use metrics::{counter, gauge, histogram};
+use parking_lot::{Mutex, RwLock};
 use rangemap::RangeInclusiveSet;
 use rusqlite::{named_params, Connection, OpenFlags, OptionalExtension, Transaction};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::{
     runtime::Handle,
-    sync::{oneshot, Semaphore},
+    sync::{broadcast, oneshot, Notify, Semaphore},
 };
 use tokio::{
     sync::{
@@ -43,16 +44,20 @@ use tripwire::Tripwire;
 
 use crate::{
     actor::{Actor, ActorId, ClusterId},
+    api::{ColumnName, SchemaChange, TableName},
     base::{CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeq, CrsqlSeqRange},
     broadcast::{BroadcastInput, ChangeSource, ChangeV1, FocaInput, Timestamp},
+    change::{ChunkRetries, ChunkSizeTuner, Quarantine, RecentChanges},
     channel::{bounded, CorroSender},
-    config::Config,
+    config::{Config, DEFAULT_READ_POOL_SIZE},
+    observer::ChangeObservers,
     pubsub::SubsManager,
     schema::Schema,
     sqlite::{
         rusqlite_to_crsqlite, rusqlite_to_crsqlite_write, setup_conn, CrConn, Migration,
         SqlitePool, SqlitePoolError,
     },
+    sync::SyncStateV1,
     updates::UpdatesManager,
 };
 
@@ -88,9 +93,55 @@ pub struct AgentConfig {
 
     pub updates_manager: UpdatesManager,
 
+    pub change_observers: ChangeObservers,
+
     pub tripwire: Tripwire,
 }
 
+/// Replication directions [`Agent::pause_replication`] can pause
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReplicationDirection {
+    /// Applying changes received from peers
+    Inbound,
+    /// Broadcasting changes to peers
+    Outbound,
+    Both,
+}
+
+impl ReplicationDirection {
+    pub fn inbound(&self) -> bool {
+        matches!(
+            self,
+            ReplicationDirection::Inbound | ReplicationDirection::Both
+        )
+    }
+
+    pub fn outbound(&self) -> bool {
+        matches!(
+            self,
+            ReplicationDirection::Outbound | ReplicationDirection::Both
+        )
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid direction '{0}', expected inbound, outbound or both")]
+pub struct InvalidReplicationDirection(String);
+
+impl FromStr for ReplicationDirection {
+    type Err = InvalidReplicationDirection;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inbound" => Ok(ReplicationDirection::Inbound),
+            "outbound" => Ok(ReplicationDirection::Outbound),
+            "both" => Ok(ReplicationDirection::Both),
+            _ => Err(InvalidReplicationDirection(s.to_owned())),
+        }
+    }
+}
+
 pub struct AgentInner {
     actor_id: ActorId,
     pool: SplitPool,
@@ -110,17 +161,247 @@ pub struct AgentInner {
     schema: RwLock<Schema>,
     cluster_id: ArcSwap<ClusterId>,
     limits: Limits,
+    recent_changes: RecentChanges,
+    quarantine: Quarantine,
+    chunk_retries: ChunkRetries,
     subs_manager: SubsManager,
     updates_manager: UpdatesManager,
+    change_observers: ChangeObservers,
+    schema_changes: broadcast::Sender<SchemaChange>,
+    peer_sync_states: RwLock<HashMap<ActorId, SyncStateV1>>,
+    chunk_size_tuners: Mutex<HashMap<ActorId, ChunkSizeTuner>>,
+    accepting_writes: AtomicBool,
+    bookkeeping_loaded: AtomicBool,
+    actor_collision: AtomicBool,
+    own_version_checked: AtomicU64,
+    inbound_paused: AtomicBool,
+    outbound_paused: AtomicBool,
+    dropped_while_paused: Mutex<HashMap<ActorId, RangeInclusiveSet<CrsqlDbVersion>>>,
+    write_limiter: WriteLimiter,
+    sync_requested: Notify,
 }
 
 #[derive(Debug, Clone)]
 pub struct Limits {
-    pub sync: Arc<Semaphore>,
+    /// sync sessions served to peers
+    pub sync: SyncLimiter,
+    /// sync sessions started with peers
+    pub outbound_sync: SyncLimiter,
+    /// shared by every [`ChunkedChanges`](crate::change::ChunkedChanges) reading from the db
+    pub chunk_reads: Arc<Semaphore>,
+    /// one permit per broadcast change read from a peer and not yet queued
+    /// for processing
+    pub broadcast_ingress: Arc<Semaphore>,
+}
+
+pub const MAX_CONCURRENT_CHUNK_READS: usize = 8;
+
+/// Permits for sync sessions in one direction, so a burst of syncs can't
+/// have every session scanning `crsql_changes` at once.
+///
+/// Only sessions take permits: SWIM probes and broadcasts never wait on them.
+#[derive(Debug, Clone)]
+pub struct SyncLimiter {
+    sema: Arc<Semaphore>,
+    max: usize,
+    direction: &'static str,
+    active: Arc<AtomicUsize>,
+    queued: Arc<AtomicUsize>,
+}
+
+impl SyncLimiter {
+    pub fn new(max: usize, direction: &'static str) -> Self {
+        let max = cmp::max(max, 1);
+        Self {
+            sema: Arc::new(Semaphore::new(max)),
+            max,
+            direction,
+            active: Default::default(),
+            queued: Default::default(),
+        }
+    }
+
+    pub fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Sessions currently holding a permit
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Acquire)
+    }
+
+    /// For inbound syncs, `None` if every permit is taken: the peer is
+    /// rejected and backs off.
+    pub fn try_acquire(&self) -> Option<SyncPermit> {
+        let permit = self.sema.clone().try_acquire_owned().ok()?;
+        Some(self.permit(permit))
+    }
+
+    /// For outbound syncs, waits for a session to end if every permit is
+    /// taken.
+    pub async fn acquire(&self) -> SyncPermit {
+        if let Some(permit) = self.try_acquire() {
+            return permit;
+        }
+
+        let queued = self.queued.fetch_add(1, Ordering::AcqRel) + 1;
+        gauge!("corro.syncs.queued", "direction" => self.direction).set(queued as f64);
+        let permit = self.sema.clone().acquire_owned().await;
+        let queued = self.queued.fetch_sub(1, Ordering::AcqRel) - 1;
+        gauge!("corro.syncs.queued", "direction" => self.direction).set(queued as f64);
+
+        // the semaphore is never closed
+        self.permit(permit.expect("sync semaphore closed"))
+    }
+
+    fn permit(&self, permit: OwnedSemaphorePermit) -> SyncPermit {
+        let active = self.active.fetch_add(1, Ordering::AcqRel) + 1;
+        gauge!("corro.active.syncs", "direction" => self.direction).set(active as f64);
+        SyncPermit {
+            _permit: permit,
+            direction: self.direction,
+            active: self.active.clone(),
+        }
+    }
+}
+
+/// Held for the length of a sync session
+#[derive(Debug)]
+pub struct SyncPermit {
+    _permit: OwnedSemaphorePermit,
+    direction: &'static str,
+    active: Arc<AtomicUsize>,
+}
+
+impl Drop for SyncPermit {
+    fn drop(&mut self) {
+        let active = self.active.fetch_sub(1, Ordering::AcqRel) - 1;
+        gauge!("corro.active.syncs", "direction" => self.direction).set(active as f64);
+    }
+}
+
+/// Token buckets for local writes made through the public API, refilled
+/// every second up to the configured `write_ops_per_sec` and
+/// `write_bytes_per_sec`. Admin writes don't go through it.
+pub struct WriteLimiter {
+    buckets: Mutex<WriteBuckets>,
+}
+
+struct WriteBuckets {
+    ops: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+struct TokenBucket {
+    per_sec: f64,
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(per_sec: u32) -> Self {
+        Self {
+            per_sec: per_sec as f64,
+            tokens: per_sec as f64,
+            refilled_at: Instant::now(),
+        }
+    }
+
+    /// Refills the bucket and returns how many of `n` tokens it takes, a
+    /// second's worth at most, or how long until they'd be there
+    fn refill(&mut self, now: Instant, n: usize) -> Result<f64, Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * self.per_sec).min(self.per_sec);
+        self.refilled_at = now;
+
+        let n = (n as f64).min(self.per_sec);
+        if self.tokens >= n {
+            Ok(n)
+        } else {
+            Err(Duration::from_secs_f64((n - self.tokens) / self.per_sec))
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("too many local writes, retry in {retry_after:?}")]
+pub struct WriteThrottled {
+    pub retry_after: Duration,
+}
+
+impl WriteLimiter {
+    pub fn new(ops_per_sec: Option<u32>, bytes_per_sec: Option<u32>) -> Self {
+        Self {
+            buckets: Mutex::new(WriteBuckets {
+                ops: ops_per_sec.filter(|n| *n > 0).map(TokenBucket::new),
+                bytes: bytes_per_sec.filter(|n| *n > 0).map(TokenBucket::new),
+            }),
+        }
+    }
+
+    /// Takes `ops` statements of `bytes` from the buckets if both have room,
+    /// or tells how long until they would. Writes larger than a second's
+    /// worth only need a full bucket, so they aren't refused forever.
+    pub fn check(&self, ops: usize, bytes: usize) -> Result<(), WriteThrottled> {
+        let mut buckets = self.buckets.lock();
+        let WriteBuckets {
+            ops: ops_bucket,
+            bytes: bytes_bucket,
+        } = &mut *buckets;
+        let now = Instant::now();
+
+        let ops_taken = ops_bucket
+            .as_mut()
+            .map(|bucket| bucket.refill(now, ops))
+            .transpose();
+        let bytes_taken = bytes_bucket
+            .as_mut()
+            .map(|bucket| bucket.refill(now, bytes))
+            .transpose();
+
+        let retry_after = match (ops_taken, bytes_taken) {
+            (Ok(ops_taken), Ok(bytes_taken)) => {
+                if let (Some(bucket), Some(n)) = (ops_bucket, ops_taken) {
+                    bucket.tokens -= n;
+                }
+                if let (Some(bucket), Some(n)) = (bytes_bucket, bytes_taken) {
+                    bucket.tokens -= n;
+                }
+                return Ok(());
+            }
+            (Err(ops_wait), Err(bytes_wait)) => {
+                counter!("corro.write.throttled", "limit" => "ops").increment(1);
+                counter!("corro.write.throttled", "limit" => "bytes").increment(1);
+                cmp::max(ops_wait, bytes_wait)
+            }
+            (Err(wait), Ok(_)) => {
+                counter!("corro.write.throttled", "limit" => "ops").increment(1);
+                wait
+            }
+            (Ok(_), Err(wait)) => {
+                counter!("corro.write.throttled", "limit" => "bytes").increment(1);
+                wait
+            }
+        };
+
+        Err(WriteThrottled { retry_after })
+    }
 }
 
+const SCHEMA_CHANGES_CHANNEL_CAP: usize = 128;
+
 impl Agent {
     pub fn new(config: AgentConfig) -> Self {
+        let broadcast_ingress_len = config.config.load().perf.broadcast_ingress_len;
+        let recent_changes_cache_bytes = config.config.load().perf.recent_changes_cache_bytes;
+        let max_quarantined_changes = config.config.load().perf.max_quarantined_changes;
+        let max_chunk_attempts = config.config.load().perf.max_chunk_attempts;
+        let max_inbound_syncs = config.config.load().perf.max_inbound_syncs;
+        let max_outbound_syncs = config.config.load().perf.max_outbound_syncs;
+        let write_limiter = {
+            let config = config.config.load();
+            WriteLimiter::new(config.api.write_ops_per_sec, config.api.write_bytes_per_sec)
+        };
         Self(Arc::new(AgentInner {
             actor_id: config.actor_id,
             pool: config.pool,
@@ -140,10 +421,29 @@ impl Agent {
             schema: config.schema,
             cluster_id: ArcSwap::from_pointee(config.cluster_id),
             limits: Limits {
-                sync: Arc::new(Semaphore::new(3)),
+                sync: SyncLimiter::new(max_inbound_syncs, "inbound"),
+                outbound_sync: SyncLimiter::new(max_outbound_syncs, "outbound"),
+                chunk_reads: Arc::new(Semaphore::new(MAX_CONCURRENT_CHUNK_READS)),
+                broadcast_ingress: Arc::new(Semaphore::new(broadcast_ingress_len)),
             },
+            recent_changes: RecentChanges::new(config.actor_id, recent_changes_cache_bytes),
+            quarantine: Quarantine::new(max_quarantined_changes),
+            chunk_retries: ChunkRetries::new(max_chunk_attempts),
             subs_manager: config.subs_manager,
             updates_manager: config.updates_manager,
+            change_observers: config.change_observers,
+            schema_changes: broadcast::channel(SCHEMA_CHANGES_CHANNEL_CAP).0,
+            peer_sync_states: Default::default(),
+            chunk_size_tuners: Default::default(),
+            accepting_writes: AtomicBool::new(true),
+            bookkeeping_loaded: AtomicBool::new(false),
+            actor_collision: AtomicBool::new(false),
+            own_version_checked: AtomicU64::new(0),
+            inbound_paused: AtomicBool::new(false),
+            outbound_paused: AtomicBool::new(false),
+            dropped_while_paused: Default::default(),
+            write_limiter,
+            sync_requested: Notify::new(),
         }))
     }
 
@@ -241,6 +541,184 @@ impl Agent {
         &self.0.limits
     }
 
+    /// Local changes recently emitted for broadcast
+    pub fn recent_changes(&self) -> &RecentChanges {
+        &self.0.recent_changes
+    }
+
+    pub fn quarantine(&self) -> &Quarantine {
+        &self.0.quarantine
+    }
+
+    pub fn chunk_retries(&self) -> &ChunkRetries {
+        &self.0.chunk_retries
+    }
+
+    pub fn subscribe_schema_changes(&self) -> broadcast::Receiver<SchemaChange> {
+        self.0.schema_changes.subscribe()
+    }
+
+    pub fn notify_schema_change(&self, change: SchemaChange) {
+        crate::sqlite::invalidate_statement_caches();
+        // an error only means nobody is listening
+        _ = self.0.schema_changes.send(change);
+    }
+
+    /// Remembers the latest sync state a peer sent us, used to figure out
+    /// which versions every peer has applied.
+    pub fn record_peer_sync_state(&self, state: SyncStateV1) {
+        self.0
+            .peer_sync_states
+            .write()
+            .insert(state.actor_id, state);
+    }
+
+    pub fn peer_sync_states(&self) -> HashMap<ActorId, SyncStateV1> {
+        self.0.peer_sync_states.read().clone()
+    }
+
+    /// Size of the chunks synced to `peer`, kept across its sync sessions
+    pub fn chunk_size_tuner(&self, peer: ActorId) -> ChunkSizeTuner {
+        self.0
+            .chunk_size_tuners
+            .lock()
+            .entry(peer)
+            .or_insert_with(|| {
+                let perf = &self.config().perf;
+                ChunkSizeTuner::new(
+                    peer,
+                    perf.sync_chunk_min_bytes,
+                    perf.sync_chunk_max_bytes,
+                    Duration::from_millis(perf.sync_chunk_target_ms),
+                )
+            })
+            .clone()
+    }
+
+    /// Local writes are refused from now on, those already holding the
+    /// write connection still complete.
+    pub fn stop_writes(&self) {
+        self.0.accepting_writes.store(false, Ordering::SeqCst);
+    }
+
+    pub fn accepts_writes(&self) -> bool {
+        self.0.accepting_writes.load(Ordering::SeqCst)
+    }
+
+    /// Every actor's bookkeeping was read from the db on startup, gaps are
+    /// only known from then on.
+    pub fn set_bookkeeping_loaded(&self) {
+        self.0.bookkeeping_loaded.store(true, Ordering::SeqCst);
+    }
+
+    pub fn bookkeeping_loaded(&self) -> bool {
+        self.0.bookkeeping_loaded.load(Ordering::SeqCst)
+    }
+
+    /// Stops applying changes from peers (they're still queued, up to
+    /// `perf.processing_queue_len`) and/or broadcasting changes, until
+    /// [`Agent::resume_replication`].
+    pub fn pause_replication(&self, direction: ReplicationDirection) {
+        if direction.inbound() {
+            self.0.inbound_paused.store(true, Ordering::SeqCst);
+        }
+        if direction.outbound() {
+            self.0.outbound_paused.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Resumes replication in both directions, requesting a sync when
+    /// queued changes were dropped while paused. Returns the dropped
+    /// versions, which the sync brings back.
+    pub fn resume_replication(&self) -> HashMap<ActorId, RangeInclusiveSet<CrsqlDbVersion>> {
+        let dropped = std::mem::take(&mut *self.0.dropped_while_paused.lock());
+        self.0.inbound_paused.store(false, Ordering::SeqCst);
+        self.0.outbound_paused.store(false, Ordering::SeqCst);
+        if !dropped.is_empty() {
+            self.request_sync();
+        }
+        dropped
+    }
+
+    pub fn inbound_paused(&self) -> bool {
+        self.0.inbound_paused.load(Ordering::SeqCst)
+    }
+
+    pub fn outbound_paused(&self) -> bool {
+        self.0.outbound_paused.load(Ordering::SeqCst)
+    }
+
+    /// Remembers versions dropped from the full changes queue while inbound
+    /// replication is paused, to be synced on resume
+    pub fn track_dropped_while_paused(&self, actor_id: ActorId, versions: CrsqlDbVersionRange) {
+        self.0
+            .dropped_while_paused
+            .lock()
+            .entry(actor_id)
+            .or_default()
+            .insert(versions.into());
+    }
+
+    /// Another node was seen using our actor id, see
+    /// [`Agent::check_own_version`].
+    pub fn actor_collision(&self) -> bool {
+        self.0.actor_collision.load(Ordering::SeqCst)
+    }
+
+    /// Checks a version of our own actor id seen coming from a peer: one we
+    /// never wrote means another node shares our actor id (e.g. cloned from
+    /// the same image), and replication would silently mix both nodes'
+    /// changes. Returns `false` and logs loudly when that's the case.
+    pub async fn check_own_version(&self, version: CrsqlDbVersion, source: &'static str) -> bool {
+        let last = self
+            .booked()
+            .read::<&str, _>("check_own_version", None)
+            .await
+            .last();
+        if let Some(last) = last {
+            self.0
+                .own_version_checked
+                .fetch_max(last.0, Ordering::SeqCst);
+            if version <= last {
+                return true;
+            }
+        }
+
+        counter!("corro.actor.collision", "source" => source).increment(1);
+        if !self.0.actor_collision.swap(true, Ordering::SeqCst) {
+            error!(
+                actor_id = %self.actor_id(),
+                "received version {version} of our own actor id from {source}, but our last version is {last:?}: another node shares our actor id! Give it a distinct `db.node_name` or a fresh database"
+            );
+        }
+        false
+    }
+
+    /// Whether a version of our own actor id is known to be ours without
+    /// locking, as of the last [`Agent::check_own_version`]
+    pub fn own_version_checked(&self, version: CrsqlDbVersion) -> bool {
+        version.0 <= self.0.own_version_checked.load(Ordering::SeqCst)
+    }
+
+    /// Limits local writes from the public API, see [`WriteLimiter`]
+    pub fn write_limiter(&self) -> &WriteLimiter {
+        &self.0.write_limiter
+    }
+
+    /// Wakes the sync loop up before its next scheduled sync
+    pub fn request_sync(&self) {
+        self.0.sync_requested.notify_one();
+    }
+
+    /// Resolves once [`Agent::request_sync`] was called
+    pub async fn sync_requested(&self) {
+        self.0.sync_requested.notified().await
+    }
+
+    pub fn change_observers(&self) -> &ChangeObservers {
+        &self.0.change_observers
+    }
+
     pub fn subs_manager(&self) -> &SubsManager {
         &self.0.subs_manager
     }
@@ -274,6 +752,9 @@ pub fn migrate(clock: Arc<uhlc::HLC>, conn: &mut Connection) -> rusqlite::Result
     let migrations: Vec<Box<dyn Migration>> = vec![
         Box::new(init_migration as fn(&Transaction) -> rusqlite::Result<()>),
         Box::new(crsqlite_v0_17_migration(clock)),
+        Box::new(table_scoped_versions_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(quarantined_chunks_migration as fn(&Transaction) -> rusqlite::Result<()>),
+        Box::new(apply_log_migration as fn(&Transaction) -> rusqlite::Result<()>),
     ];
 
     crate::sqlite::migrate(conn, migrations)
@@ -364,6 +845,82 @@ fn init_migration(tx: &Transaction) -> rusqlite::Result<()> {
     Ok(())
 }
 
+/// Versions of `actor_id` only applied for some tables by a table-scoped
+/// sync, with those tables. They're incomplete until booked by a full sync.
+pub fn table_scoped_versions(
+    conn: &Connection,
+    actor_id: ActorId,
+) -> rusqlite::Result<BTreeMap<CrsqlDbVersion, Vec<TableName>>> {
+    let mut versions: BTreeMap<CrsqlDbVersion, Vec<TableName>> = BTreeMap::new();
+
+    let mut prepped = conn.prepare_cached(
+        r#"SELECT db_version, "table" FROM __corro_table_scoped_versions WHERE site_id = ? ORDER BY db_version, "table""#,
+    )?;
+    let rows = prepped.query_map([actor_id], |row| Ok((row.get(0)?, row.get(1)?)))?;
+    for row in rows {
+        let (version, table) = row?;
+        versions.entry(version).or_default().push(table);
+    }
+
+    Ok(versions)
+}
+
+// versions partially applied through a table-scoped sync, they're never
+// booked so they're still needed from a complete sync. Rows are removed
+// once the version gets booked.
+fn table_scoped_versions_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+            CREATE TABLE __corro_table_scoped_versions (
+                site_id BLOB NOT NULL,
+                db_version INTEGER NOT NULL,
+                "table" TEXT NOT NULL,
+
+                PRIMARY KEY (site_id, db_version, "table")
+            ) WITHOUT ROWID;
+        "#,
+    )
+}
+
+// versions in the order they were booked locally, whichever actor they come
+// from. seeded with what's already booked, in one go.
+fn apply_log_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+            CREATE TABLE __corro_apply_log (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                site_id BLOB NOT NULL,
+                start_version INTEGER NOT NULL,
+                end_version INTEGER NOT NULL
+            );
+
+            INSERT INTO __corro_apply_log (site_id, start_version, end_version)
+                SELECT site_id, 1, db_version FROM crsql_db_versions;
+        "#,
+    )
+}
+
+// chunks that failed to apply too many times, skipped until requeued
+fn quarantined_chunks_migration(tx: &Transaction) -> rusqlite::Result<()> {
+    tx.execute_batch(
+        r#"
+            CREATE TABLE __corro_quarantined_chunks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                site_id BLOB NOT NULL,
+                start_version INTEGER NOT NULL,
+                end_version INTEGER NOT NULL,
+                start_seq INTEGER,
+                end_seq INTEGER,
+                attempts INTEGER NOT NULL,
+                error TEXT NOT NULL,
+                -- speedy-encoded changeset
+                change BLOB NOT NULL,
+                quarantined_at INTEGER NOT NULL
+            );
+        "#,
+    )
+}
+
 // since crsqlite 0.17, ts is now stored as TEXT in clock tables
 // also sets the new 'merge-equal-values' config to true.
 fn crsqlite_v0_17_migration(
@@ -458,8 +1015,28 @@ pub enum ChangeError {
         actor_id: Option<ActorId>,
         version: Option<CrsqlDbVersion>,
     },
+    #[error("cr-sqlite could not give the next db version, the extension passed the startup probe (probe_crsqlite) so it may have been unloaded: {0}")]
+    NextDbVersion(#[source] rusqlite::Error),
     #[error("non-contiguous empties range delete")]
     NonContiguousDelete,
+    #[error("missing seqs {missing:?} of version {version} (actor_id: {actor_id})")]
+    MissingSeqs {
+        actor_id: ActorId,
+        version: CrsqlDbVersion,
+        missing: Vec<RangeInclusive<CrsqlSeq>>,
+    },
+    #[error("agent is shutting down, not accepting writes")]
+    ShuttingDown,
+    #[error("value of column {cid} is {size} bytes, over the {limit} bytes limit")]
+    ValueTooLarge {
+        cid: ColumnName,
+        size: usize,
+        limit: usize,
+    },
+    #[error(transparent)]
+    Throttled(#[from] WriteThrottled),
+    #[error("seq range {range:?} is over the {limit} merkle leaves limit")]
+    MerkleRangeTooLarge { range: CrsqlSeqRange, limit: usize },
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -476,6 +1053,16 @@ impl SplitPool {
     pub async fn create<P: AsRef<Path>>(
         path: P,
         write_sema: Arc<Semaphore>,
+    ) -> Result<Self, SplitPoolCreateError> {
+        Self::create_with_read_size(path, write_sema, DEFAULT_READ_POOL_SIZE).await
+    }
+
+    /// Like [`SplitPool::create`], with up to `read_size` read-only
+    /// connections.
+    pub async fn create_with_read_size<P: AsRef<Path>>(
+        path: P,
+        write_sema: Arc<Semaphore>,
+        read_size: usize,
     ) -> Result<Self, SplitPoolCreateError> {
         let rw_pool = sqlite_pool::Config::new(path.as_ref())
             .max_size(1)
@@ -485,7 +1072,7 @@ impl SplitPool {
 
         let ro_pool = sqlite_pool::Config::new(path.as_ref())
             .read_only()
-            .max_size(20)
+            .max_size(read_size)
             .create_pool_transform(rusqlite_to_crsqlite)?;
         debug!("built RO pool");
 
@@ -532,6 +1119,11 @@ impl SplitPool {
         gauge!("corro.sqlite.pool.read.connections").set(read_state.size as f64);
         gauge!("corro.sqlite.pool.read.connections.available").set(read_state.available as f64);
         gauge!("corro.sqlite.pool.read.connections.waiting").set(read_state.waiting as f64);
+        // share of the read connections in use, waiters queue up past 1.0
+        gauge!("corro.sqlite.pool.read.saturation").set(
+            (read_state.size - read_state.available + read_state.waiting) as f64
+                / read_state.max_size.max(1) as f64,
+        );
 
         let write_state = self.0.write.status();
         gauge!("corro.sqlite.pool.write.connections").set(write_state.size as f64);
@@ -545,7 +1137,10 @@ impl SplitPool {
     // get a read-only connection
     #[tracing::instrument(skip(self), level = "debug")]
     pub async fn read(&self) -> Result<sqlite_pool::Connection<CrConn>, SqlitePoolError> {
-        self.0.read.get().await
+        let start = Instant::now();
+        let conn = self.0.read.get().await;
+        histogram!("corro.sqlite.pool.read.wait.seconds").record(start.elapsed().as_secs_f64());
+        conn
     }
 
     #[tracing::instrument(skip(self), level = "debug")]
@@ -1063,6 +1658,41 @@ impl Drop for LockTracker {
     }
 }
 
+/// Cleans up persisted seq ranges before doing gap math on them: inverted
+/// ranges are dropped, single-element ones normalized and overlapping or
+/// adjacent ones merged. Returns the sorted ranges and how many got fixed.
+pub fn repair_seq_ranges(mut ranges: Vec<CrsqlSeqRange>) -> (Vec<CrsqlSeqRange>, usize) {
+    let mut repaired = 0;
+
+    ranges.retain(|range| {
+        let inverted = range.end() < range.start();
+        if inverted {
+            repaired += 1;
+        }
+        !inverted
+    });
+    ranges.sort_by_key(|range| (range.start(), range.end()));
+
+    let mut merged: Vec<CrsqlSeqRange> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        let range = if range.start() == range.end() {
+            CrsqlSeqRange::single(range.start())
+        } else {
+            range
+        };
+
+        match merged.last_mut() {
+            Some(last) if range.start().0 <= last.end().0.saturating_add(1) => {
+                *last = CrsqlSeqRange::new(last.start(), cmp::max(last.end(), range.end()));
+                repaired += 1;
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    (merged, repaired)
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct PartialVersion {
     // range of sequences recorded
@@ -1110,16 +1740,39 @@ impl VersionsSnapshot {
         &self.needed
     }
 
-    pub fn insert_gaps(&mut self, db_versions: RangeInclusiveSet<CrsqlDbVersion>) {
-        self.needed.extend(db_versions);
+    pub fn insert_gaps(
+        &mut self,
+        db_versions: impl IntoIterator<Item = RangeInclusive<CrsqlDbVersion>>,
+    ) {
+        self.needed.extend(non_empty(db_versions));
     }
 
+    /// Books `db_versions`, which can be a set of ranges or ranges built
+    /// with [`CrsqlDbVersion::range_to`]. Empty ranges are ignored.
     pub fn insert_db(
         &mut self,         // only because we want 1 mt a time here
         conn: &Connection, // usually a `Transaction`
-        db_versions: RangeInclusiveSet<CrsqlDbVersion>,
+        db_versions: impl IntoIterator<Item = RangeInclusive<CrsqlDbVersion>>,
     ) -> rusqlite::Result<()> {
+        let db_versions = non_empty(db_versions);
         trace!("wants to insert into db {db_versions:?}");
+
+        // booked versions are complete, table-scoped syncs no longer matter.
+        // logged in booking order for `since` queries.
+        for range in db_versions.iter() {
+            conn.prepare_cached("DELETE FROM __corro_table_scoped_versions WHERE site_id = :actor_id AND db_version >= :start AND db_version <= :end")?
+                .execute(named_params! {
+                    ":actor_id": self.actor_id,
+                    ":start": range.start(),
+                    ":end": range.end()
+                })?;
+            conn.prepare_cached("INSERT INTO __corro_apply_log (site_id, start_version, end_version) VALUES (:actor_id, :start, :end)")?
+                .execute(named_params! {
+                    ":actor_id": self.actor_id,
+                    ":start": range.start(),
+                    ":end": range.end()
+                })?;
+        }
         let mut changes = self.compute_gaps_change(db_versions);
 
         trace!(actor_id = %self.actor_id, "delete: {:?}", changes.remove_ranges);
@@ -1176,6 +1829,46 @@ impl VersionsSnapshot {
         Ok(())
     }
 
+    /// Needs `versions` again, booked up to now. For versions booked without
+    /// their changes being applied, so they're synced again.
+    pub fn insert_gaps_db(
+        &mut self,
+        conn: &Connection,
+        versions: RangeInclusive<CrsqlDbVersion>,
+    ) -> rusqlite::Result<()> {
+        let mut needed = self.needed.clone();
+        needed.insert(versions);
+
+        // gaps merged with the new one are replaced
+        for range in self.needed.iter() {
+            if needed.iter().any(|other| other == range) {
+                continue;
+            }
+            conn.prepare_cached("DELETE FROM __corro_bookkeeping_gaps WHERE actor_id = :actor_id AND start = :start AND end = :end")?
+                .execute(named_params! {
+                    ":actor_id": self.actor_id,
+                    ":start": range.start(),
+                    ":end": range.end()
+                })?;
+        }
+        for range in needed.iter() {
+            if self.needed.iter().any(|other| other == range) {
+                continue;
+            }
+            conn.prepare_cached(
+                "INSERT INTO __corro_bookkeeping_gaps VALUES (:actor_id, :start, :end)",
+            )?
+            .execute(named_params! {
+                ":actor_id": self.actor_id,
+                ":start": range.start(),
+                ":end": range.end()
+            })?;
+        }
+
+        self.needed = needed;
+        Ok(())
+    }
+
     fn compute_gaps_change(&self, versions: RangeInclusiveSet<CrsqlDbVersion>) -> GapsChanges {
         trace!("needed: {:?}", self.needed);
 
@@ -1201,7 +1894,7 @@ impl VersionsSnapshot {
             }
 
             // check if there's a previous range with an end version = start version - 1
-            if let Some(range) = self.needed.get(&CrsqlDbVersion(versions.start().0 - 1)) {
+            if let Some(range) = self.needed.get(&versions.start().saturating_sub(1)) {
                 trace!(actor_id = %self.actor_id, "got a start - 1: {range:?}");
                 // insert the collapsible range
                 changes.insert_set.insert(range.clone());
@@ -1210,7 +1903,11 @@ impl VersionsSnapshot {
             }
 
             // check if there's a next range with an start version = end version + 1
-            if let Some(range) = self.needed.get(&CrsqlDbVersion(versions.end().0 + 1)) {
+            if let Some(range) = versions
+                .end()
+                .checked_add(1)
+                .and_then(|next| self.needed.get(&next))
+            {
                 trace!(actor_id = %self.actor_id, "got a end + 1: {range:?}");
                 // insert the collapsible range
                 changes.insert_set.insert(range.clone());
@@ -1223,9 +1920,9 @@ impl VersionsSnapshot {
             let current_max = self.max.unwrap_or_default();
 
             // check if there's a gap created between our current max and the start version we just inserted
-            let gap_start = current_max + 1;
-            if gap_start < *versions.start() {
-                let range = gap_start..=*versions.start();
+            let gap_start = current_max.checked_add(1);
+            if let Some(gap_start) = gap_start.filter(|start| start < versions.start()) {
+                let range = gap_start.range_to(*versions.start());
                 trace!("inserting gap between max + 1 and start: {range:?}");
                 changes.insert_set.insert(range.clone());
                 for range in self.needed.overlapping(&range) {
@@ -1244,6 +1941,16 @@ impl VersionsSnapshot {
     }
 }
 
+// rangemap panics on empty ranges
+fn non_empty(
+    db_versions: impl IntoIterator<Item = RangeInclusive<CrsqlDbVersion>>,
+) -> RangeInclusiveSet<CrsqlDbVersion> {
+    db_versions
+        .into_iter()
+        .filter(|range| !range.is_empty())
+        .collect()
+}
+
 // this struct must be drained!
 impl Drop for VersionsSnapshot {
     fn drop(&mut self) {
@@ -1440,6 +2147,11 @@ impl BookedVersions {
     pub fn needed(&self) -> &RangeInclusiveSet<CrsqlDbVersion> {
         &self.needed
     }
+
+    /// Missing version ranges, in ascending order
+    pub fn gaps(&self) -> impl Iterator<Item = RangeInclusive<CrsqlDbVersion>> + '_ {
+        self.needed.iter().cloned()
+    }
 }
 
 #[derive(Debug)]
@@ -1609,6 +2321,49 @@ mod tests {
     use crate::base::{dbvr, dbvri};
     use rangemap::range_inclusive_set;
 
+    #[test]
+    fn test_write_limiter_takes_from_both_or_neither() {
+        let limiter = WriteLimiter::new(Some(10), Some(100));
+        let tokens = |limiter: &WriteLimiter| {
+            let buckets = limiter.buckets.lock();
+            (
+                buckets.ops.as_ref().unwrap().tokens,
+                buckets.bytes.as_ref().unwrap().tokens,
+            )
+        };
+
+        // more than a second's worth only needs a full bucket
+        limiter.check(1, 1000).unwrap();
+        let (ops, bytes) = tokens(&limiter);
+        assert!((8.9..9.1).contains(&ops), "ops: {ops}");
+        assert!(bytes < 1.0, "bytes: {bytes}");
+
+        // refused for the bytes, the ops bucket is left alone
+        let throttled = limiter.check(1, 50).unwrap_err();
+        assert!(throttled.retry_after > Duration::from_millis(400));
+        let (ops, _) = tokens(&limiter);
+        assert!((8.9..9.1).contains(&ops), "ops: {ops}");
+
+        let limiter = WriteLimiter::new(Some(2), None);
+        limiter.check(usize::MAX, 0).unwrap();
+        assert!(limiter.check(1, usize::MAX).is_err());
+    }
+
+    #[test]
+    fn test_compute_gaps_change_up_to_max_version() {
+        let bv = BookedVersions::new(ActorId::default());
+        let snap = bv.snapshot();
+
+        let changes = snap.compute_gaps_change(range_inclusive_set![
+            CrsqlDbVersion(u64::MAX - 1)..=CrsqlDbVersion(u64::MAX)
+        ]);
+        assert_eq!(changes.max, Some(CrsqlDbVersion(u64::MAX)));
+        assert_eq!(
+            changes.insert_set,
+            range_inclusive_set![CrsqlDbVersion(1)..=CrsqlDbVersion(u64::MAX - 2)]
+        );
+    }
+
     #[test]
     fn test_booked_insert_db() -> rusqlite::Result<()> {
         _ = tracing_subscriber::fmt::try_init();
@@ -1786,6 +2541,56 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_booked_insert_gaps_db() -> rusqlite::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+
+        let mut conn = CrConn::init(Connection::open_in_memory()?)?;
+        setup_conn(&conn)?;
+        let clock = Arc::new(uhlc::HLC::default());
+        migrate(clock, &mut conn)?;
+
+        let actor_id = ActorId::default();
+        let mut bv = BookedVersions::new(actor_id);
+        let mut all = RangeInclusiveSet::new();
+        insert_everywhere(
+            &conn,
+            &mut bv,
+            &mut all,
+            range_inclusive_set![dbvri!(1, 3), dbvri!(6, 20)],
+        )?;
+        expect_gaps(&conn, &bv, &all, vec![dbvr!(4, 5)])?;
+
+        // merged with the adjacent gap
+        let mut snap = bv.snapshot();
+        snap.insert_gaps_db(&conn, dbvri!(2, 3))?;
+        bv.commit_snapshot(snap);
+
+        let mut snap = bv.snapshot();
+        snap.insert_gaps_db(&conn, dbvri!(10, 10))?;
+        bv.commit_snapshot(snap);
+
+        let gaps: Vec<(CrsqlDbVersion, CrsqlDbVersion)> = conn
+            .prepare_cached("SELECT start, end FROM __corro_bookkeeping_gaps ORDER BY start")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            gaps,
+            vec![
+                (CrsqlDbVersion(2), CrsqlDbVersion(5)),
+                (CrsqlDbVersion(10), CrsqlDbVersion(10))
+            ]
+        );
+        assert!(!bv.contains(CrsqlDbVersion(3), None));
+        assert!(bv.contains(CrsqlDbVersion(9), None));
+        assert_eq!(bv.last(), Some(CrsqlDbVersion(20)));
+
+        let reloaded = BookedVersions::from_conn(&conn, actor_id)?;
+        assert_eq!(reloaded.needed(), bv.needed());
+
+        Ok(())
+    }
+
     fn insert_everywhere(
         conn: &Connection,
         bv: &mut BookedVersions,
@@ -1838,4 +2643,38 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn test_repair_seq_ranges() {
+        use crate::base::dbsr;
+
+        let (ranges, repaired) = repair_seq_ranges(vec![
+            dbsr!(10, 12),
+            // inverted
+            dbsr!(8, 3),
+            dbsr!(0, 2),
+            // adjacent to 0..=2
+            dbsr!(3, 5),
+            dbsr!(20, 20),
+            // overlapping 10..=12
+            dbsr!(11, 14),
+            CrsqlSeqRange::new(CrsqlSeq(7), CrsqlSeq(7)),
+        ]);
+
+        assert_eq!(
+            ranges,
+            vec![
+                dbsr!(0, 5),
+                CrsqlSeqRange::single(CrsqlSeq(7)),
+                dbsr!(10, 14),
+                CrsqlSeqRange::single(CrsqlSeq(20)),
+            ]
+        );
+        assert_eq!(repaired, 3);
+
+        // nothing to fix
+        let (ranges, repaired) = repair_seq_ranges(vec![dbsr!(0, 3), dbsr!(5, 9)]);
+        assert_eq!(ranges, vec![dbsr!(0, 3), dbsr!(5, 9)]);
+        assert_eq!(repaired, 0);
+    }
 }
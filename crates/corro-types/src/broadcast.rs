@@ -1,4 +1,10 @@
-use std::{cmp, fmt, io, num::NonZeroU32, num::ParseIntError, ops::Deref, time::Duration};
+use std::{
+    cmp, fmt, io,
+    num::NonZeroU32,
+    num::ParseIntError,
+    ops::{Deref, RangeInclusive},
+    time::Duration,
+};
 
 use antithesis_sdk::assert_sometimes;
 use bytes::{Bytes, BytesMut};
@@ -7,6 +13,7 @@ use corro_base_types::{CrsqlDbVersionRange, CrsqlSeqRange};
 use foca::{Identity, Member, Notification, Runtime, Timer};
 use itertools::Itertools;
 use metrics::counter;
+use rangemap::RangeInclusiveSet;
 use rusqlite::{
     types::{FromSql, FromSqlError},
     ToSql,
@@ -28,7 +35,7 @@ use crate::{
     change::{row_to_change, Change, ChunkedChanges, MAX_CHANGES_BYTE_SIZE},
     channel::CorroSender,
     sqlite::SqlitePoolError,
-    sync::SyncTraceContextV1,
+    sync::{SyncEncoding, SyncTraceContextV1},
     updates::match_changes,
 };
 
@@ -52,6 +59,9 @@ pub enum BiPayload {
         data: BiPayloadV1,
         #[speedy(default_on_eof)]
         cluster_id: ClusterId,
+        /// Sync encodings the client supports, empty for older peers
+        #[speedy(default_on_eof)]
+        encodings: Vec<SyncEncoding>,
     },
 }
 
@@ -62,6 +72,12 @@ pub enum BiPayloadV1 {
         #[speedy(default_on_eof)]
         trace_ctx: SyncTraceContextV1,
     },
+    /// Asks for the digests of versions ranges, answered with a
+    /// [`SyncMessageV1::Digests`](crate::sync::SyncMessageV1::Digests)
+    Digests {
+        actor_id: ActorId,
+        ranges: Vec<(ActorId, RangeInclusive<CrsqlDbVersion>)>,
+    },
 }
 
 #[derive(Debug)]
@@ -186,6 +202,14 @@ impl Changeset {
         }
     }
 
+    /// Estimated bytes of the changes, empties are free.
+    pub fn estimated_byte_size(&self) -> usize {
+        self.changes()
+            .iter()
+            .map(|change| change.estimated_byte_size())
+            .sum()
+    }
+
     pub fn max_db_version(&self) -> Option<CrsqlDbVersion> {
         self.changes().iter().map(|c| c.db_version).max()
     }
@@ -207,6 +231,29 @@ impl Changeset {
         }
     }
 
+    /// Seqs within this changeset's range, up to `last_seq`, that none of
+    /// its changes have
+    pub fn missing_seqs(&self) -> Vec<RangeInclusive<CrsqlSeq>> {
+        let Changeset::Full {
+            changes,
+            seqs,
+            last_seq,
+            ..
+        } = self
+        else {
+            return vec![];
+        };
+        let end = cmp::min(seqs.end(), *last_seq);
+        if seqs.start() > end {
+            return vec![];
+        }
+        let present: RangeInclusiveSet<CrsqlSeq> = changes
+            .iter()
+            .map(|change| change.seq..=change.seq)
+            .collect();
+        present.gaps(&(seqs.start()..=end)).collect()
+    }
+
     pub fn is_complete(&self) -> bool {
         match self {
             Changeset::Empty { .. } => true,
@@ -510,6 +557,7 @@ pub async fn broadcast_changes(
     ts: Timestamp,
 ) -> Result<(), BroadcastError> {
     let actor_id = agent.actor_id();
+    let excluded = agent.config().db.excluded_columns.clone();
     let conn = agent.pool().read().await?;
     trace!("got conn for broadcast");
 
@@ -525,7 +573,8 @@ pub async fn broadcast_changes(
             "#,
         )?;
         let rows = prepped.query_map([db_version], row_to_change)?;
-        let chunked = ChunkedChanges::new(rows, CrsqlSeq(0), last_seq, MAX_CHANGES_BYTE_SIZE);
+        let chunked = ChunkedChanges::new(rows, CrsqlSeq(0), last_seq, MAX_CHANGES_BYTE_SIZE)
+            .with_read_permits(agent.limits().chunk_reads.clone());
         for changes_seqs in chunked {
             match changes_seqs {
                 Ok((changes, seqs)) => {
@@ -535,10 +584,24 @@ pub async fn broadcast_changes(
 
                     trace!("broadcasting changes: {changes:?} for seq: {seqs:?}");
 
+                    agent
+                        .recent_changes()
+                        .insert(db_version, seqs, &changes, last_seq, ts);
+
                     debug!("match_changes db_version: {db_version}");
                     match_changes(agent.subs_manager(), &changes, db_version);
                     match_changes(agent.updates_manager(), &changes, db_version);
 
+                    // excluded columns are still seen by local subscriptions
+                    let changes = if excluded.is_empty() {
+                        changes
+                    } else {
+                        changes
+                            .into_iter()
+                            .filter(|change| !excluded.excludes(&change.table, &change.cid))
+                            .collect()
+                    };
+
                     let tx_bcast = agent.tx_bcast().clone();
                     assert_sometimes!(true, "Corrosion broadcasts changes");
                     tokio::spawn(async move {
@@ -1,19 +1,42 @@
-use std::iter::Peekable;
+use std::{
+    cmp,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::Write,
+    iter::Peekable,
+    ops::RangeInclusive,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use antithesis_sdk::assert_always;
 pub use corro_api_types::SqliteValue;
-use corro_api_types::{ColumnName, TableName};
-use corro_base_types::{CrsqlDbVersion, CrsqlSeqRange};
-use rusqlite::{Connection, Row};
+use corro_api_types::{ColumnName, SqliteValueRef, TableName};
+use corro_base_types::{CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeqRange};
+use metrics::{counter, gauge, histogram};
+use parking_lot::Mutex;
+use rangemap::RangeInclusiveSet;
+use rusqlite::{Connection, OptionalExtension, Row};
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 use speedy::{Readable, Writable};
+use tokio::{
+    sync::{Notify, Semaphore},
+    time::Instant,
+};
 use tracing::{debug, trace, warn};
+use uhlc::NTP64;
 
 use crate::{
+    actor::ActorId,
     agent::{Agent, BookedVersions, ChangeError, VersionsSnapshot},
     base::CrsqlSeq,
-    broadcast::Timestamp,
+    broadcast::{ChangeSource, ChangeV1, Timestamp},
+    config::RetentionConfig,
+    pubsub::{unpack_columns, UnpackError},
+    schema::{Schema, Table},
 };
 
 #[derive(Debug, Default, Clone, Serialize, Deserialize, Readable, Writable, PartialEq)]
@@ -47,6 +70,219 @@ impl Change {
         // site_version
         8
     }
+
+    // hash of the full change content, identical changes share the same hash
+    pub fn content_hash(&self) -> u64 {
+        let bytes = self
+            .write_to_vec()
+            .expect("encoding a change in memory should not fail");
+        seahash::hash(&bytes)
+    }
+
+    /// Unpacks the cr-sqlite encoded primary key, values are in the order of
+    /// `table.pk`.
+    pub fn decode_pk(&self, table: &Table) -> Result<Vec<SqliteValue>, DecodePkError> {
+        if self.table.as_str() != table.name {
+            return Err(DecodePkError::TableMismatch {
+                change: self.table.clone(),
+                table: table.name.clone(),
+            });
+        }
+        if self.pk.is_empty() {
+            return Err(DecodePkError::Unpack(UnpackError::Misuse));
+        }
+
+        let values = unpack_columns(&self.pk)?;
+        if values.len() != table.pk.len() {
+            return Err(DecodePkError::ColumnCount {
+                table: table.name.clone(),
+                expected: table.pk.len(),
+                got: values.len(),
+            });
+        }
+
+        Ok(values.iter().map(SqliteValueRef::to_owned).collect())
+    }
+
+    /// Like [`Change::decode_pk`], looking the change's table up in `schema`
+    pub fn decode_pk_in(&self, schema: &Schema) -> Result<Vec<SqliteValue>, DecodePkError> {
+        let table = schema
+            .tables
+            .get(self.table.as_str())
+            .ok_or_else(|| DecodePkError::UnknownTable(self.table.clone()))?;
+        self.decode_pk(table)
+    }
+
+    /// Returns the table or column of the change missing from `schema`, if
+    /// any. Row deletions (the sentinel column) only need the table.
+    pub fn schema_mismatch(&self, schema: &Schema) -> Option<SchemaMismatch> {
+        let Some(table) = schema.tables.get(self.table.as_str()) else {
+            return Some(SchemaMismatch::UnknownTable(self.table.clone()));
+        };
+        if self.cid.is_crsql_sentinel() || table.columns.contains_key(self.cid.as_str()) {
+            return None;
+        }
+        Some(SchemaMismatch::UnknownColumn {
+            table: self.table.clone(),
+            column: self.cid.clone(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SchemaMismatch {
+    #[error("unknown table '{0}'")]
+    UnknownTable(TableName),
+    #[error("unknown column '{column}' in table '{table}'")]
+    UnknownColumn {
+        table: TableName,
+        column: ColumnName,
+    },
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DecodePkError {
+    #[error("unknown table '{0}'")]
+    UnknownTable(TableName),
+    #[error("change is for table '{change}', not '{table}'")]
+    TableMismatch { change: TableName, table: String },
+    #[error("pk has {got} columns, table '{table}' has {expected}")]
+    ColumnCount {
+        table: String,
+        expected: usize,
+        got: usize,
+    },
+    #[error("could not unpack pk: {0}")]
+    Unpack(#[from] UnpackError),
+}
+
+/// Returns true if the two batches touch disjoint cells, meaning they can be
+/// applied in any order and produce the same result.
+pub fn batches_commute(a: &[Change], b: &[Change]) -> bool {
+    let (small, large) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+
+    let cells: HashSet<(&TableName, &[u8], &ColumnName)> = small
+        .iter()
+        .map(|change| (&change.table, change.pk.as_slice(), &change.cid))
+        .collect();
+
+    !large
+        .iter()
+        .any(|change| cells.contains(&(&change.table, change.pk.as_slice(), &change.cid)))
+}
+
+/// Renders changes as a chronological timeline, grouping consecutive changes
+/// sharing the same timestamp. `Change` does not carry its HLC timestamp, so
+/// the `db_version` (the site's logical clock) is used as the timestamp.
+/// Input is expected to be sorted.
+pub fn render_timeline(changes: impl Iterator<Item = Change>) -> String {
+    let mut out = String::new();
+    let mut current = None;
+
+    for change in changes {
+        if current != Some(change.db_version) {
+            current = Some(change.db_version);
+            _ = writeln!(out, "[{}]", change.db_version);
+        }
+        _ = writeln!(
+            out,
+            "  {} {}: {}={} ({})",
+            change.table,
+            hex::encode(&change.pk),
+            change.cid.as_str(),
+            change.val,
+            ActorId::from_bytes(change.site_id)
+        );
+    }
+
+    out
+}
+
+/// Returns the changes in `a` that have no identical counterpart in `b`.
+pub fn change_set_difference(a: &[Change], b: &[Change]) -> Vec<Change> {
+    let hashes: HashSet<u64> = b.iter().map(Change::content_hash).collect();
+
+    a.iter()
+        .filter(|change| !hashes.contains(&change.content_hash()))
+        .cloned()
+        .collect()
+}
+
+/// Returns the db_version ranges, in ascending order, that precede the
+/// batch's versions but haven't been booked yet, versions within the batch
+/// itself are not required.
+pub fn required_predecessors(
+    batch: &[Change],
+    booked: &BookedVersions,
+) -> Vec<RangeInclusive<CrsqlDbVersion>> {
+    let Some(max) = batch.iter().map(|change| change.db_version).max() else {
+        return vec![];
+    };
+    let below = CrsqlDbVersion(1).range_to(max.saturating_sub(1));
+    if below.is_empty() {
+        return vec![];
+    }
+
+    let mut missing: RangeInclusiveSet<CrsqlDbVersion> = booked
+        .needed()
+        .overlapping(&below)
+        .map(|range| {
+            cmp::max(*range.start(), *below.start())..=cmp::min(*range.end(), *below.end())
+        })
+        .collect();
+
+    // everything past the last booked version is unknown
+    let last = booked.last().unwrap_or_default();
+    if last < *below.end() {
+        missing.insert(last.next().range_to(*below.end()));
+    }
+
+    for change in batch {
+        missing.remove(change.db_version.range_to(change.db_version));
+    }
+
+    missing.into_iter().collect()
+}
+
+/// Splits changes into recent ones, at or after `boundary`, and historical
+/// ones before it. Order is preserved within each tier.
+pub fn tier_changes(
+    changes: impl Iterator<Item = Change>,
+    boundary: CrsqlDbVersion,
+) -> (Vec<Change>, Vec<Change>) {
+    changes.partition(|change| change.db_version >= boundary)
+}
+
+/// Upper bound for [`estimate_sort_memory`], sorting more than this should
+/// spill to disk.
+pub const MAX_SORT_MEMORY_BYTES: usize = 64 * 1024 * 1024;
+
+/// Suggests an in-memory buffer size to sort `change_count` changes weighing
+/// `avg_change_bytes` each, capped at [`MAX_SORT_MEMORY_BYTES`].
+pub fn estimate_sort_memory(change_count: u64, avg_change_bytes: usize) -> usize {
+    // every buffered change also occupies a `Change` slot on top of its payload
+    let per_change = avg_change_bytes.saturating_add(std::mem::size_of::<Change>());
+
+    usize::try_from(change_count)
+        .unwrap_or(usize::MAX)
+        .saturating_mul(per_change)
+        .min(MAX_SORT_MEMORY_BYTES)
+}
+
+/// Averages the estimated byte size of (at most) the first `sample_size`
+/// changes, to feed [`estimate_sort_memory`] without scanning a whole stream.
+pub fn sample_avg_change_bytes<'a>(
+    changes: impl IntoIterator<Item = &'a Change>,
+    sample_size: usize,
+) -> usize {
+    let (count, total) = changes
+        .into_iter()
+        .take(sample_size)
+        .fold((0usize, 0usize), |(count, total), change| {
+            (count + 1, total + change.estimated_byte_size())
+        });
+
+    total.checked_div(count).unwrap_or(0)
 }
 
 pub fn row_to_change(row: &Row) -> Result<Change, rusqlite::Error> {
@@ -63,6 +299,290 @@ pub fn row_to_change(row: &Row) -> Result<Change, rusqlite::Error> {
     })
 }
 
+/// Changes of the row with the cr-sqlite encoded primary key `pk`, by
+/// db_version and seq. cr-sqlite only keeps the latest change of each column
+/// and the row's sentinel, which remains once the row is deleted.
+pub fn row_changes(conn: &Connection, table: &str, pk: &[u8]) -> rusqlite::Result<Vec<Change>> {
+    conn.prepare_cached(
+        r#"SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl FROM crsql_changes
+            WHERE "table" = ? AND pk = ?
+            ORDER BY db_version, seq"#,
+    )?
+    .query_map((table, pk), row_to_change)?
+    .collect()
+}
+
+// smallest possible encoding of an `InternedChange`: empty pk, a null value,
+// the indexes and the fixed-size fields
+const MIN_ENCODED_CHANGE_SIZE: usize = 4 + 4 + 4 + 1 + 8 + 8 + 8 + 4 + 8;
+
+/// Encodes changes with the layout of a speedy-encoded [`InternedChanges`],
+/// without cloning them.
+pub fn encode_changes(changes: &[Change]) -> Result<Vec<u8>, speedy::Error> {
+    InterningWriter::new(changes).write_to_vec()
+}
+
+/// Decodes changes written by [`encode_changes`] in a single pass, resolving
+/// their interned names and site_ids as they're read. A truncated or
+/// corrupted buffer returns an error.
+pub fn decode_changes(buf: &[u8]) -> Result<Vec<Change>, speedy::Error> {
+    let (tables, mut offset) = Vec::<TableName>::read_with_length_from_buffer(buf);
+    let tables = tables?;
+    let (columns, len) = Vec::<ColumnName>::read_with_length_from_buffer(&buf[offset..]);
+    let columns = columns?;
+    offset += len;
+    let (site_ids, len) = Vec::<[u8; 16]>::read_with_length_from_buffer(&buf[offset..]);
+    let site_ids = site_ids?;
+    offset += len;
+    let (count, len) = u32::read_with_length_from_buffer(&buf[offset..]);
+    let count = count? as usize;
+    offset += len;
+
+    // don't trust the count for the allocation, it can't exceed what fits
+    let mut changes = Vec::with_capacity(cmp::min(
+        count,
+        buf.len().saturating_sub(offset) / MIN_ENCODED_CHANGE_SIZE,
+    ));
+    for _ in 0..count {
+        let (change, len) = InternedChange::read_with_length_from_buffer(&buf[offset..]);
+        let change = change?
+            .resolve(&tables, &columns, &site_ids)
+            .map_err(speedy::Error::custom)?;
+        changes.push(change);
+        offset += len;
+    }
+
+    Ok(changes)
+}
+
+/// A change whose table, column and site_id are indexes into the
+/// dictionaries of its [`InternedChanges`].
+#[derive(Debug, Clone, PartialEq, Readable, Writable)]
+pub struct InternedChange {
+    pub table: u32,
+    pub pk: Vec<u8>,
+    pub cid: u32,
+    pub val: SqliteValue,
+    pub col_version: i64,
+    pub db_version: CrsqlDbVersion,
+    pub seq: CrsqlSeq,
+    pub site_idx: u32,
+    pub cl: i64,
+}
+
+impl InternedChange {
+    fn resolve(
+        self,
+        tables: &[TableName],
+        columns: &[ColumnName],
+        site_ids: &[[u8; 16]],
+    ) -> Result<Change, InternedIndexError> {
+        Ok(Change {
+            table: lookup("table", tables, self.table)?.clone(),
+            pk: self.pk,
+            cid: lookup("column", columns, self.cid)?.clone(),
+            val: self.val,
+            col_version: self.col_version,
+            db_version: self.db_version,
+            seq: self.seq,
+            site_id: *lookup("site_id", site_ids, self.site_idx)?,
+            cl: self.cl,
+        })
+    }
+}
+
+fn lookup<'a, T>(
+    kind: &'static str,
+    interned: &'a [T],
+    index: u32,
+) -> Result<&'a T, InternedIndexError> {
+    interned.get(index as usize).ok_or(InternedIndexError {
+        kind,
+        index,
+        len: interned.len(),
+    })
+}
+
+/// Wire encoding for a chunk of changes storing each distinct table name,
+/// column name and site_id once. A chunk of a single table from a single
+/// actor otherwise repeats the same names and 16 bytes for every change.
+/// Chunks are decoded back to flat changes, interning doesn't change how
+/// much memory they take once received.
+#[derive(Debug, Clone, Default, PartialEq, Readable, Writable)]
+pub struct InternedChanges {
+    pub tables: Vec<TableName>,
+    pub columns: Vec<ColumnName>,
+    pub site_ids: Vec<[u8; 16]>,
+    pub changes: Vec<InternedChange>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{kind} index {index} out of bounds ({len} interned)")]
+pub struct InternedIndexError {
+    pub kind: &'static str,
+    pub index: u32,
+    pub len: usize,
+}
+
+/// Assigns each distinct value the index of its first occurrence
+struct Interner<T> {
+    values: Vec<T>,
+    indexes: HashMap<T, u32>,
+}
+
+impl<T> Default for Interner<T> {
+    fn default() -> Self {
+        Self {
+            values: vec![],
+            indexes: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Clone + Eq + std::hash::Hash> Interner<T> {
+    fn intern(&mut self, value: T) -> u32 {
+        *self.indexes.entry(value).or_insert_with_key(|value| {
+            self.values.push(value.clone());
+            (self.values.len() - 1) as u32
+        })
+    }
+}
+
+impl InternedChanges {
+    pub fn from_changes(changes: impl IntoIterator<Item = Change>) -> Self {
+        let mut tables = Interner::default();
+        let mut columns = Interner::default();
+        let mut site_ids = Interner::default();
+
+        let changes = changes
+            .into_iter()
+            .map(|change| InternedChange {
+                table: tables.intern(change.table),
+                pk: change.pk,
+                cid: columns.intern(change.cid),
+                val: change.val,
+                col_version: change.col_version,
+                db_version: change.db_version,
+                seq: change.seq,
+                site_idx: site_ids.intern(change.site_id),
+                cl: change.cl,
+            })
+            .collect();
+
+        Self {
+            tables: tables.values,
+            columns: columns.values,
+            site_ids: site_ids.values,
+            changes,
+        }
+    }
+
+    /// Reconstitutes the changes with their full names and site_ids.
+    pub fn into_changes(self) -> Result<Vec<Change>, InternedIndexError> {
+        let Self {
+            tables,
+            columns,
+            site_ids,
+            changes,
+        } = self;
+        changes
+            .into_iter()
+            .map(|change| change.resolve(&tables, &columns, &site_ids))
+            .collect()
+    }
+}
+
+impl From<Vec<Change>> for InternedChanges {
+    fn from(changes: Vec<Change>) -> Self {
+        Self::from_changes(changes)
+    }
+}
+
+impl TryFrom<InternedChanges> for Vec<Change> {
+    type Error = InternedIndexError;
+
+    fn try_from(interned: InternedChanges) -> Result<Self, Self::Error> {
+        interned.into_changes()
+    }
+}
+
+/// Borrowed changes written as an [`InternedChanges`]
+struct InterningWriter<'a> {
+    tables: Vec<&'a TableName>,
+    columns: Vec<&'a ColumnName>,
+    site_ids: Vec<[u8; 16]>,
+    // table, column and site_id indexes of each change
+    indexes: Vec<(u32, u32, u32)>,
+    changes: &'a [Change],
+}
+
+impl<'a> InterningWriter<'a> {
+    fn new(changes: &'a [Change]) -> Self {
+        let mut tables = Interner::default();
+        let mut columns = Interner::default();
+        let mut site_ids = Interner::default();
+
+        let indexes = changes
+            .iter()
+            .map(|change| {
+                (
+                    tables.intern(&change.table),
+                    columns.intern(&change.cid),
+                    site_ids.intern(change.site_id),
+                )
+            })
+            .collect();
+
+        Self {
+            tables: tables.values,
+            columns: columns.values,
+            site_ids: site_ids.values,
+            indexes,
+            changes,
+        }
+    }
+}
+
+fn write_len<C: speedy::Context, W: ?Sized + speedy::Writer<C>>(
+    writer: &mut W,
+    len: usize,
+) -> Result<(), C::Error> {
+    let len = u32::try_from(len)
+        .map_err(|_| speedy::Error::custom("too many interned values to encode"))?;
+    writer.write_u32(len)
+}
+
+impl<C> Writable<C> for InterningWriter<'_>
+where
+    C: speedy::Context,
+{
+    fn write_to<T: ?Sized + speedy::Writer<C>>(&self, writer: &mut T) -> Result<(), C::Error> {
+        write_len(writer, self.tables.len())?;
+        for table in &self.tables {
+            table.write_to(writer)?;
+        }
+        write_len(writer, self.columns.len())?;
+        for column in &self.columns {
+            column.write_to(writer)?;
+        }
+        self.site_ids.write_to(writer)?;
+
+        write_len(writer, self.changes.len())?;
+        for (change, &(table, cid, site_idx)) in self.changes.iter().zip(&self.indexes) {
+            table.write_to(writer)?;
+            change.pk.write_to(writer)?;
+            cid.write_to(writer)?;
+            change.val.write_to(writer)?;
+            change.col_version.write_to(writer)?;
+            change.db_version.write_to(writer)?;
+            change.seq.write_to(writer)?;
+            site_idx.write_to(writer)?;
+            change.cl.write_to(writer)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct ChunkedChanges<I: Iterator> {
     iter: Peekable<I>,
     changes: Vec<Change>,
@@ -72,6 +592,8 @@ pub struct ChunkedChanges<I: Iterator> {
     max_buf_size: usize,
     buffered_size: usize,
     done: bool,
+    read_permits: Option<Arc<Semaphore>>,
+    chunks: u64,
 }
 
 impl<I> ChunkedChanges<I>
@@ -88,9 +610,20 @@ where
             max_buf_size,
             buffered_size: 0,
             done: false,
+            read_permits: None,
+            chunks: 0,
         }
     }
 
+    /// Caps concurrent reads across every chunker sharing the same semaphore:
+    /// a permit is held while reading each chunk from the underlying iterator.
+    /// Waiting for it blocks the thread, iterate from `block_in_place` or a
+    /// blocking thread.
+    pub fn with_read_permits(mut self, permits: Arc<Semaphore>) -> Self {
+        self.read_permits = Some(permits);
+        self
+    }
+
     pub fn max_buf_size(&self) -> usize {
         self.max_buf_size
     }
@@ -98,6 +631,15 @@ where
     pub fn set_max_buf_size(&mut self, size: usize) {
         self.max_buf_size = size;
     }
+
+    /// Records the size of an emitted chunk. Chunks per version can be
+    /// derived from the histograms' count over `corro.chunk.versions.total`.
+    /// Without an installed recorder these go to the no-op recorder.
+    fn record_chunk(&mut self) {
+        self.chunks += 1;
+        histogram!("corro.chunk.bytes").record(self.buffered_size as f64);
+        histogram!("corro.chunk.changes").record(self.changes.len() as f64);
+    }
 }
 
 impl<I> Iterator for ChunkedChanges<I>
@@ -122,15 +664,48 @@ where
         // reset the buffered size
         self.buffered_size = 0;
 
+        // held until this chunk is returned, a closed semaphore does not throttle
+        let _permit = self
+            .read_permits
+            .as_ref()
+            .and_then(|permits| futures::executor::block_on(permits.clone().acquire_owned()).ok());
+
         loop {
             trace!("chunking through the rows iterator");
+
+            // a change that doesn't fit in a chunk by itself goes in its own
+            // chunk, instead of growing the one being buffered even further
+            if !self.changes.is_empty() {
+                if let Some(Ok(change)) = self.iter.peek() {
+                    if change.estimated_byte_size() >= self.max_buf_size {
+                        let start_seq = self.last_start_seq;
+                        self.last_start_seq = self.last_pushed_seq + 1;
+
+                        self.record_chunk();
+                        return Some(Ok((
+                            self.changes.drain(..).collect(),
+                            CrsqlSeqRange::new(start_seq, self.last_pushed_seq),
+                        )));
+                    }
+                }
+            }
+
             match self.iter.next() {
                 Some(Ok(change)) => {
                     trace!("got change: {change:?}");
 
                     self.last_pushed_seq = change.seq;
 
-                    self.buffered_size += change.estimated_byte_size();
+                    let size = change.estimated_byte_size();
+                    if size > self.max_buf_size {
+                        counter!("corro.chunk.oversized.total").increment(1);
+                        debug!(
+                            seq = %change.seq,
+                            "change of ~{size} bytes exceeds the {} bytes chunk size, sending it alone",
+                            self.max_buf_size
+                        );
+                    }
+                    self.buffered_size += size;
 
                     self.changes.push(change);
 
@@ -151,6 +726,7 @@ where
                         // prepare for next round! we're not done...
                         self.last_start_seq = self.last_pushed_seq + 1;
 
+                        self.record_chunk();
                         return Some(Ok((
                             self.changes.drain(..).collect(),
                             CrsqlSeqRange::new(start_seq, self.last_pushed_seq),
@@ -169,6 +745,10 @@ where
 
         self.done = true;
 
+        self.record_chunk();
+        counter!("corro.chunk.versions.total").increment(1);
+        trace!(chunks = self.chunks, "done chunking version");
+
         // return buffered changes
         Some(Ok((
             self.changes.clone(), // no need to drain here like before
@@ -177,224 +757,3253 @@ where
     }
 }
 
-pub const MAX_CHANGES_BYTE_SIZE: usize = 8 * 1024;
-
-pub struct InsertChangesInfo {
-    pub db_version: CrsqlDbVersion,
-    pub last_seq: CrsqlSeq,
-    pub ts: Timestamp,
-    pub snap: VersionsSnapshot,
+/// Tunes the size of the chunks synced to a peer, AIMD-style: a chunk the
+/// peer took longer than `target` to accept halves the size, a full chunk
+/// accepted in time grows it by `min`. Clones share the size.
+#[derive(Debug, Clone)]
+pub struct ChunkSizeTuner {
+    peer: ActorId,
+    size: Arc<AtomicUsize>,
+    min: usize,
+    max: usize,
+    target: Duration,
 }
 
-pub fn insert_local_changes(
-    agent: &Agent,
-    tx: &Connection,
-    book_writer: &mut tokio::sync::RwLockWriteGuard<'_, BookedVersions>,
-) -> Result<Option<InsertChangesInfo>, ChangeError> {
-    let actor_id = agent.actor_id();
+impl ChunkSizeTuner {
+    /// Starts at [`MAX_CHANGES_BYTE_SIZE`], within `min..=max`
+    pub fn new(peer: ActorId, min: usize, max: usize, target: Duration) -> Self {
+        let min = min.max(1);
+        let max = max.max(min);
+        let tuner = Self {
+            peer,
+            size: Arc::new(AtomicUsize::new(MAX_CHANGES_BYTE_SIZE.clamp(min, max))),
+            min,
+            max,
+            target,
+        };
+        tuner.record(tuner.size());
+        tuner
+    }
 
-    let db_version: CrsqlDbVersion = tx
-        .prepare_cached("SELECT crsql_peek_next_db_version()")
-        .map_err(|source| ChangeError::Rusqlite {
-            source,
-            actor_id: Some(actor_id),
-            version: None,
-        })?
-        .query_row((), |row| row.get(0))
-        .map_err(|source| ChangeError::Rusqlite {
-            source,
-            actor_id: Some(actor_id),
-            version: None,
-        })?;
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
 
-    let version_info: (Option<CrsqlSeq>, Option<Timestamp>) = tx
-        .prepare_cached(
-            "SELECT MAX(seq), MAX(ts) FROM crsql_changes WHERE site_id = ? AND db_version = ?;",
-        )
-        .map_err(|source| ChangeError::Rusqlite {
-            source,
-            actor_id: Some(actor_id),
-            version: None,
-        })?
-        .query_row((agent.actor_id(), db_version), |row| {
-            Ok((row.get(0)?, row.get(1)?))
-        })
-        .map_err(|source| ChangeError::Rusqlite {
-            source,
-            actor_id: Some(actor_id),
-            version: None,
-        })?;
+    pub fn min(&self) -> usize {
+        self.min
+    }
 
-    match version_info {
-        (None, None) => Ok(None),
-        (None, Some(ts)) => {
-            warn!("found db_version {db_version} without seq, last ts: {ts:?})");
-            Ok(None)
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    /// Records that a chunk of `bytes` took `elapsed` to be accepted by the
+    /// peer, returns the size of the next chunks.
+    pub fn observe(&self, bytes: usize, elapsed: Duration) -> usize {
+        let size = self.size();
+        let next = if elapsed > self.target {
+            (size / 2).max(self.min)
+        } else if bytes >= size / 2 {
+            (size + self.min).min(self.max)
+        } else {
+            // a small chunk says nothing about how much more the peer takes
+            size
+        };
+
+        if next != size {
+            self.size.store(next, Ordering::Relaxed);
+            self.record(next);
         }
-        (Some(last_seq), ts) => {
-            let ts = ts.unwrap_or_else(|| {
-                warn!("found db_version {db_version} without seq, last ts: {ts:?}");
-                Timestamp::from(agent.clock().new_timestamp())
-            });
+        next
+    }
 
-            debug!("found db_version {db_version} (last seq: {last_seq}, last ts: {ts})");
+    fn record(&self, size: usize) {
+        gauge!("corro.sync.chunk.size.bytes", "actor_id" => self.peer.to_string()).set(size as f64);
+    }
+}
 
-            let db_versions = db_version..=db_version;
+/// A chunk handed out by [`ReliableSender`], tagged with its `chunk_seq`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeqChunk {
+    pub chunk_seq: u64,
+    pub changes: Vec<Change>,
+    pub seqs: CrsqlSeqRange,
+}
 
-            let mut snap = book_writer.snapshot();
-            snap.insert_db(tx, [db_versions].into())
-                .map_err(|source| ChangeError::Rusqlite {
-                    source,
-                    actor_id: Some(actor_id),
-                    version: Some(db_version),
-                })?;
+struct InFlightChunk {
+    changes: Vec<Change>,
+    seqs: CrsqlSeqRange,
+    sent_at: Instant,
+}
 
-            Ok(Some(InsertChangesInfo {
-                db_version,
-                last_seq,
-                ts,
-                snap,
-            }))
+/// Wraps a chunker for transfers over unreliable links: every chunk is
+/// retained until acknowledged by its `chunk_seq`, and unacknowledged chunks
+/// are handed out again once `retransmit_after` has elapsed.
+pub struct ReliableSender<I: Iterator> {
+    chunker: ChunkedChanges<I>,
+    next_chunk_seq: u64,
+    in_flight: BTreeMap<u64, InFlightChunk>,
+    retransmit_after: Duration,
+}
+
+impl<I> ReliableSender<I>
+where
+    I: Iterator<Item = rusqlite::Result<Change>>,
+{
+    pub fn new(chunker: ChunkedChanges<I>, retransmit_after: Duration) -> Self {
+        Self {
+            chunker,
+            next_chunk_seq: 0,
+            in_flight: BTreeMap::new(),
+            retransmit_after,
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::base::dbsr;
+    /// Pulls the next chunk from the chunker and keeps it in flight until acked.
+    pub fn send_next(&mut self) -> Option<rusqlite::Result<SeqChunk>> {
+        let (changes, seqs) = match self.chunker.next()? {
+            Ok(chunk) => chunk,
+            Err(e) => return Some(Err(e)),
+        };
 
-    #[test]
-    fn test_change_chunker() {
-        // empty interator
-        let mut chunker = ChunkedChanges::new(vec![].into_iter(), CrsqlSeq(0), CrsqlSeq(100), 50);
+        let chunk_seq = self.next_chunk_seq;
+        self.next_chunk_seq += 1;
 
-        assert_eq!(chunker.next(), Some(Ok((vec![], dbsr!(0, 100)))));
-        assert_eq!(chunker.next(), None);
+        self.in_flight.insert(
+            chunk_seq,
+            InFlightChunk {
+                changes: changes.clone(),
+                seqs,
+                sent_at: Instant::now(),
+            },
+        );
 
-        let changes: Vec<Change> = (0..100)
-            .map(|seq| Change {
-                seq: CrsqlSeq(seq),
-                ..Default::default()
-            })
-            .collect();
+        Some(Ok(SeqChunk {
+            chunk_seq,
+            changes,
+            seqs,
+        }))
+    }
 
-        // 2 iterations
-        let mut chunker = ChunkedChanges::new(
+    /// Marks a chunk as received, returns false if it wasn't in flight
+    /// (already acked or never sent).
+    pub fn ack(&mut self, chunk_seq: u64) -> bool {
+        self.in_flight.remove(&chunk_seq).is_some()
+    }
+
+    /// Waits for the oldest unacknowledged chunk to time out and returns it
+    /// for retransmission, resetting its timer. Returns `None` right away if
+    /// nothing is in flight.
+    pub async fn next_retransmit(&mut self) -> Option<SeqChunk> {
+        let (chunk_seq, sent_at) = self
+            .in_flight
+            .iter()
+            .map(|(chunk_seq, chunk)| (*chunk_seq, chunk.sent_at))
+            .min_by_key(|(_, sent_at)| *sent_at)?;
+
+        tokio::time::sleep_until(sent_at + self.retransmit_after).await;
+
+        let chunk = self.in_flight.get_mut(&chunk_seq)?;
+        chunk.sent_at = Instant::now();
+
+        debug!(chunk_seq, seqs = ?chunk.seqs, "retransmitting unacked chunk");
+
+        Some(SeqChunk {
+            chunk_seq,
+            changes: chunk.changes.clone(),
+            seqs: chunk.seqs,
+        })
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.len()
+    }
+
+    /// True once the chunker is exhausted and every chunk has been acked.
+    pub fn is_done(&self) -> bool {
+        self.chunker.done && self.in_flight.is_empty()
+    }
+}
+
+/// Credit pool shared between a [`CreditLimitedChanges`] sender and the
+/// receiver replenishing it.
+#[derive(Debug, Default)]
+pub struct Credits {
+    available: AtomicU64,
+    granted: Notify,
+}
+
+impl Credits {
+    pub fn new(available: u64) -> Self {
+        Self {
+            available: AtomicU64::new(available),
+            granted: Notify::new(),
+        }
+    }
+
+    /// Adds credits to the pool, waking up senders waiting for them.
+    pub fn grant(&self, credits: u64) {
+        self.available.fetch_add(credits, Ordering::AcqRel);
+        self.granted.notify_waiters();
+    }
+
+    pub fn available(&self) -> u64 {
+        self.available.load(Ordering::Acquire)
+    }
+
+    fn try_take(&self) -> bool {
+        self.available
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |credits| {
+                credits.checked_sub(1)
+            })
+            .is_ok()
+    }
+}
+
+/// Hands out changes only while the shared credit pool has credits, taking
+/// one per change. The receiver replenishes the pool as it consumes changes,
+/// when it's empty [`CreditLimitedChanges::next`] waits until it isn't.
+pub struct CreditLimitedChanges<I> {
+    changes: I,
+    credits: Arc<Credits>,
+}
+
+impl<I> CreditLimitedChanges<I>
+where
+    I: Iterator<Item = Change>,
+{
+    pub fn new(changes: I, credits: Arc<Credits>) -> Self {
+        Self { changes, credits }
+    }
+
+    /// Returns the next change once a credit is available, `None` when the
+    /// underlying changes are exhausted.
+    pub async fn next(&mut self) -> Option<Change> {
+        loop {
+            // register before checking so a grant in between isn't missed
+            let granted = self.credits.granted.notified();
+            tokio::pin!(granted);
+            granted.as_mut().enable();
+
+            if self.credits.try_take() {
+                break;
+            }
+            trace!("out of credits, waiting for the receiver");
+            granted.await;
+        }
+
+        let change = self.changes.next();
+        if change.is_none() {
+            // give back the credit we didn't use
+            self.credits.grant(1);
+        }
+        change
+    }
+
+    pub fn credits(&self) -> u64 {
+        self.credits.available()
+    }
+}
+
+/// Per-table priority used when chunking changes, higher goes first. Tables
+/// not in the map have a priority of 0.
+#[derive(Debug, Default, Clone)]
+pub struct TablePriority(HashMap<TableName, i32>);
+
+impl TablePriority {
+    pub fn new(priorities: impl IntoIterator<Item = (TableName, i32)>) -> Self {
+        Self(priorities.into_iter().collect())
+    }
+
+    pub fn get(&self, table: &TableName) -> i32 {
+        self.0.get(table).copied().unwrap_or_default()
+    }
+
+    /// Chunks pre-read `changes` so the higher priority tables' changes are
+    /// emitted first, in seq order within a priority.
+    pub fn chunk(&self, mut changes: Vec<Change>, max_buf_size: usize) -> PrioritizedChunks {
+        changes.sort_by_key(|change| (cmp::Reverse(self.get(&change.table)), change.seq));
+        PrioritizedChunks {
+            changes: changes.into_iter().peekable(),
+            max_buf_size,
+        }
+    }
+}
+
+/// Chunks produced by [`TablePriority::chunk`]. Reordering means a chunk's
+/// seqs are not necessarily contiguous, they're returned as sorted ranges.
+pub struct PrioritizedChunks {
+    changes: Peekable<std::vec::IntoIter<Change>>,
+    max_buf_size: usize,
+}
+
+impl Iterator for PrioritizedChunks {
+    type Item = (Vec<Change>, Vec<CrsqlSeqRange>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.changes.peek()?;
+
+        let mut chunk = vec![];
+        let mut buffered_size = 0;
+        while let Some(change) = self.changes.next_if(|_| buffered_size < self.max_buf_size) {
+            buffered_size += change.estimated_byte_size();
+            chunk.push(change);
+        }
+
+        let mut seqs: Vec<CrsqlSeq> = chunk.iter().map(|change| change.seq).collect();
+        seqs.sort_unstable();
+
+        let mut ranges: Vec<CrsqlSeqRange> = vec![];
+        for seq in seqs {
+            match ranges.last_mut() {
+                Some(range) if range.end() + 1 == seq => {
+                    *range = CrsqlSeqRange::new(range.start(), seq);
+                }
+                _ => ranges.push(CrsqlSeqRange::new(seq, seq)),
+            }
+        }
+
+        Some((chunk, ranges))
+    }
+}
+
+/// Per-table weights used to interleave the versions served by sync. Tables
+/// not in the map have a weight of 1, a weight of 0 is treated as 1.
+#[derive(Debug, Default, Clone)]
+pub struct TableWeights(HashMap<TableName, u32>);
+
+impl TableWeights {
+    pub fn new(weights: impl IntoIterator<Item = (TableName, u32)>) -> Self {
+        Self(weights.into_iter().collect())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get(&self, table: &TableName) -> u32 {
+        self.0.get(table).copied().unwrap_or(1).max(1)
+    }
+
+    /// Weight of a version changing `tables`, the heaviest of them
+    pub fn weight<'a>(&self, tables: impl IntoIterator<Item = &'a TableName>) -> u32 {
+        tables
+            .into_iter()
+            .map(|table| self.get(table))
+            .max()
+            .unwrap_or(1)
+    }
+
+    /// Groups `items` per weight class and interleaves the classes by weight
+    pub fn interleave<T>(
+        &self,
+        items: impl IntoIterator<Item = (T, u32)>,
+    ) -> WeightedChunks<std::vec::IntoIter<T>> {
+        let mut classes: BTreeMap<u32, Vec<T>> = BTreeMap::new();
+        for (item, weight) in items {
+            classes.entry(weight).or_default().push(item);
+        }
+
+        WeightedChunks::new(
+            classes
+                .into_iter()
+                .map(|(weight, items)| (items.into_iter(), weight)),
+        )
+    }
+}
+
+/// Merges chunk streams with a smooth weighted round-robin: heavier streams
+/// are picked more often, but every stream keeps making progress.
+pub struct WeightedChunks<I> {
+    // (stream, weight, current weight)
+    streams: Vec<(I, u32, i64)>,
+}
+
+impl<I> WeightedChunks<I> {
+    pub fn new(streams: impl IntoIterator<Item = (I, u32)>) -> Self {
+        let mut streams: Vec<_> = streams
+            .into_iter()
+            .map(|(iter, weight)| (iter, weight.max(1), 0))
+            .collect();
+        // ties go to the heaviest stream
+        streams.sort_by_key(|(_, weight, _)| cmp::Reverse(*weight));
+        Self { streams }
+    }
+}
+
+impl<I: Iterator> Iterator for WeightedChunks<I> {
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let total: i64 = self
+                .streams
+                .iter()
+                .map(|(_, weight, _)| *weight as i64)
+                .sum();
+
+            for (_, weight, current) in self.streams.iter_mut() {
+                *current += *weight as i64;
+            }
+
+            // first max wins, `max_by_key` would return the last one
+            let (idx, _) = self.streams.iter().enumerate().fold(
+                None::<(usize, i64)>,
+                |best, (idx, (_, _, current))| match best {
+                    Some((_, best_current)) if best_current >= *current => best,
+                    _ => Some((idx, *current)),
+                },
+            )?;
+
+            self.streams[idx].2 -= total;
+            match self.streams[idx].0.next() {
+                Some(item) => return Some(item),
+                None => {
+                    self.streams.remove(idx);
+                }
+            }
+        }
+    }
+}
+
+/// Only lets through changes strictly newer than a given timestamp, for
+/// "everything since T" incremental syncs. Meant to wrap the rows iterator
+/// before handing it to [`ChunkedChanges`].
+pub struct SinceTimestamp<I> {
+    iter: I,
+    since: Timestamp,
+}
+
+impl<I> SinceTimestamp<I> {
+    pub fn new(iter: I, since: Timestamp) -> Self {
+        Self { iter, since }
+    }
+}
+
+impl<I> Iterator for SinceTimestamp<I>
+where
+    I: Iterator<Item = rusqlite::Result<(Change, Timestamp)>>,
+{
+    type Item = rusqlite::Result<Change>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.iter.next()? {
+                Ok((change, ts)) => {
+                    // HLC timestamps keep the logical counter in the lowest bits of
+                    // the NTP64 fraction, comparing the raw values orders by physical
+                    // time first, then by logical counter. `Timestamp`'s `PartialEq`
+                    // truncates to nanoseconds and would lose the counter.
+                    if ts.as_u64() > self.since.as_u64() {
+                        return Some(Ok(change));
+                    }
+                    trace!(?ts, since = ?self.since, "skipping change older than since");
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Chunks of local changes recently emitted for broadcast, keyed by
+/// db_version and first seq. Lets sync serve a fresh version without reading
+/// it back from `crsql_changes`. Bounded by the total estimated size of the
+/// cached changes, oldest versions are evicted first.
+///
+/// A version is dropped as soon as a newer local or applied change touches
+/// one of its rows, `crsql_changes` wouldn't return the overwritten cells
+/// for it anymore.
+#[derive(Debug, Clone)]
+pub struct RecentChanges {
+    actor_id: ActorId,
+    max_bytes: usize,
+    inner: Arc<Mutex<RecentChangesInner>>,
+}
+
+#[derive(Debug, Default)]
+struct RecentChangesInner {
+    chunks: BTreeMap<(CrsqlDbVersion, CrsqlSeq), RecentChunk>,
+    // cached versions touching each row, for invalidation
+    rows: HashMap<TableName, HashMap<Vec<u8>, BTreeSet<CrsqlDbVersion>>>,
+    bytes: usize,
+}
+
+impl RecentChangesInner {
+    fn remove_chunk(&mut self, key: (CrsqlDbVersion, CrsqlSeq)) {
+        let Some(chunk) = self.chunks.remove(&key) else {
+            return;
+        };
+        self.bytes -= chunk.size;
+        for change in chunk.changes.iter() {
+            let Some(pks) = self.rows.get_mut(&change.table) else {
+                continue;
+            };
+            if let Some(versions) = pks.get_mut(change.pk.as_slice()) {
+                versions.remove(&key.0);
+                if versions.is_empty() {
+                    pks.remove(change.pk.as_slice());
+                }
+            }
+            if pks.is_empty() {
+                self.rows.remove(&change.table);
+            }
+        }
+    }
+
+    fn remove_version(&mut self, version: CrsqlDbVersion) {
+        let keys: Vec<_> = self
+            .chunks
+            .range((version, CrsqlSeq(0))..=(version, CrsqlSeq(u64::MAX)))
+            .map(|(key, _)| *key)
+            .collect();
+        for key in keys {
+            self.remove_chunk(key);
+        }
+    }
+
+    /// Drops the cached versions, other than `version`, touching the rows of
+    /// `changes`
+    fn invalidate(&mut self, version: Option<CrsqlDbVersion>, changes: &[Change]) {
+        let mut stale = BTreeSet::new();
+        for change in changes {
+            if let Some(versions) = self
+                .rows
+                .get(&change.table)
+                .and_then(|pks| pks.get(change.pk.as_slice()))
+            {
+                stale.extend(versions.iter().filter(|v| Some(**v) != version));
+            }
+        }
+        for version in stale {
+            self.remove_version(version);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct RecentChunk {
+    changes: Vec<Change>,
+    seqs: CrsqlSeqRange,
+    last_seq: CrsqlSeq,
+    ts: Timestamp,
+    size: usize,
+}
+
+/// Every change of a version, as served from [`RecentChanges`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecentVersion {
+    pub changes: Vec<Change>,
+    pub last_seq: CrsqlSeq,
+    pub ts: Timestamp,
+}
+
+impl RecentChanges {
+    /// A `max_bytes` of 0 disables the cache
+    pub fn new(actor_id: ActorId, max_bytes: usize) -> Self {
+        Self {
+            actor_id,
+            max_bytes,
+            inner: Default::default(),
+        }
+    }
+
+    /// Total estimated size of the cached changes
+    pub fn bytes(&self) -> usize {
+        self.inner.lock().bytes
+    }
+
+    pub fn insert(
+        &self,
+        version: CrsqlDbVersion,
+        seqs: CrsqlSeqRange,
+        changes: &[Change],
+        last_seq: CrsqlSeq,
+        ts: Timestamp,
+    ) {
+        if self.max_bytes == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock();
+        inner.invalidate(Some(version), changes);
+
+        let size: usize = changes.iter().map(Change::estimated_byte_size).sum();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let key = (version, seqs.start());
+        inner.remove_chunk(key);
+        for change in changes {
+            inner
+                .rows
+                .entry(change.table.clone())
+                .or_default()
+                .entry(change.pk.clone())
+                .or_default()
+                .insert(version);
+        }
+        inner.chunks.insert(
+            key,
+            RecentChunk {
+                changes: changes.to_vec(),
+                seqs,
+                last_seq,
+                ts,
+                size,
+            },
+        );
+        inner.bytes += size;
+
+        while inner.bytes > self.max_bytes {
+            let Some(oldest) = inner.chunks.keys().next().copied() else {
+                break;
+            };
+            inner.remove_chunk(oldest);
+        }
+    }
+
+    /// Drops the cached versions touching the rows of applied `changes`
+    pub fn invalidate(&self, changes: &[Change]) {
+        if self.max_bytes == 0 {
+            return;
+        }
+        self.inner.lock().invalidate(None, changes);
+    }
+
+    /// Returns every change of one of our versions, `None` unless all of its
+    /// chunks are still cached.
+    pub fn get(&self, actor_id: ActorId, version: CrsqlDbVersion) -> Option<RecentVersion> {
+        if actor_id != self.actor_id {
+            return None;
+        }
+
+        let inner = self.inner.lock();
+        let mut next_seq = CrsqlSeq(0);
+        let mut found: Option<RecentVersion> = None;
+        for chunk in inner
+            .chunks
+            .range((version, CrsqlSeq(0))..=(version, CrsqlSeq(u64::MAX)))
+            .map(|(_, chunk)| chunk)
+        {
+            if chunk.seqs.start() != next_seq {
+                return None;
+            }
+            next_seq = chunk.seqs.end() + 1;
+            match found.as_mut() {
+                Some(found) => found.changes.extend_from_slice(&chunk.changes),
+                None => {
+                    found = Some(RecentVersion {
+                        changes: chunk.changes.clone(),
+                        last_seq: chunk.last_seq,
+                        ts: chunk.ts,
+                    })
+                }
+            }
+        }
+
+        found.filter(|found| next_seq == found.last_seq + 1)
+    }
+}
+
+/// Changesets referencing a table or column the local schema doesn't have
+/// yet, most likely because the DDL hasn't reached this node. They're
+/// parked instead of failing the batch they came with, and retried once the
+/// schema changes. Bounded by the number of parked changesets.
+#[derive(Debug, Clone)]
+pub struct Quarantine {
+    max_len: usize,
+    inner: Arc<Mutex<VecDeque<QuarantinedChange>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct QuarantinedChange {
+    pub change: ChangeV1,
+    pub src: ChangeSource,
+    pub mismatch: SchemaMismatch,
+    pub since: Instant,
+}
+
+impl Quarantine {
+    pub fn new(max_len: usize) -> Self {
+        Self {
+            max_len,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.lock().is_empty()
+    }
+
+    /// Parks a changeset, handing it back if the quarantine is full. A
+    /// changeset already parked is not parked twice.
+    pub fn push(
+        &self,
+        change: ChangeV1,
+        src: ChangeSource,
+        mismatch: SchemaMismatch,
+    ) -> Result<(), ChangeV1> {
+        let mut inner = self.inner.lock();
+        if inner.iter().any(|parked| {
+            parked.change.actor_id == change.actor_id
+                && parked.change.versions() == change.versions()
+                && parked.change.seqs() == change.seqs()
+        }) {
+            return Ok(());
+        }
+        if inner.len() >= self.max_len {
+            return Err(change);
+        }
+        inner.push_back(QuarantinedChange {
+            change,
+            src,
+            mismatch,
+            since: Instant::now(),
+        });
+        gauge!("corro.quarantined.changes").set(inner.len() as f64);
+        Ok(())
+    }
+
+    /// Takes out the changesets `schema` now has every table and column for
+    pub fn take_ready(&self, schema: &Schema) -> Vec<QuarantinedChange> {
+        self.take_where(|parked| {
+            !parked
+                .change
+                .changes()
+                .iter()
+                .any(|change| change.schema_mismatch(schema).is_some())
+        })
+    }
+
+    /// Takes out the changesets parked for longer than `timeout`
+    pub fn take_expired(&self, timeout: Duration) -> Vec<QuarantinedChange> {
+        self.take_where(|parked| parked.since.elapsed() >= timeout)
+    }
+
+    fn take_where(&self, f: impl Fn(&QuarantinedChange) -> bool) -> Vec<QuarantinedChange> {
+        let mut inner = self.inner.lock();
+        let (taken, kept) = inner.drain(..).partition(f);
+        *inner = kept;
+        gauge!("corro.quarantined.changes").set(inner.len() as f64);
+        taken
+    }
+}
+
+/// A chunk as received: its actor, versions and seqs
+pub type ChunkKey = (ActorId, CrsqlDbVersionRange, Option<CrsqlSeqRange>);
+
+/// Versions with apply failures tracked at once. Versions that fail and
+/// never get booked, the failure of the oldest is forgotten past this.
+pub const MAX_TRACKED_CHUNK_FAILURES: usize = 10_000;
+
+/// Apply failures per version, so a chunk that keeps failing isn't retried
+/// with every sync. After `max_attempts` failures it's quarantined in
+/// `__corro_quarantined_chunks` and its version skipped until requeued.
+/// Failures and quarantines are keyed by actor and version, whichever way
+/// the version gets chunked, and failures are forgotten once the version is
+/// booked.
+#[derive(Debug)]
+pub struct ChunkRetries {
+    max_attempts: u32,
+    max_tracked: usize,
+    inner: Mutex<ChunkRetriesInner>,
+}
+
+#[derive(Debug, Default)]
+struct ChunkRetriesInner {
+    failures: HashMap<(ActorId, CrsqlDbVersion), ChunkFailures>,
+    quarantined: HashSet<(ActorId, CrsqlDbVersion)>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ChunkFailures {
+    attempts: u32,
+    last: Instant,
+}
+
+fn failure_key((actor_id, versions, _): &ChunkKey) -> (ActorId, CrsqlDbVersion) {
+    (*actor_id, versions.start())
+}
+
+impl ChunkRetries {
+    pub fn new(max_attempts: u32) -> Self {
+        Self {
+            max_attempts: cmp::max(max_attempts, 1),
+            max_tracked: MAX_TRACKED_CHUNK_FAILURES,
+            inner: Default::default(),
+        }
+    }
+
+    pub fn is_quarantined(&self, key: &ChunkKey) -> bool {
+        self.inner.lock().quarantined.contains(&failure_key(key))
+    }
+
+    /// Whether another failure would quarantine the chunk, so a copy of it
+    /// should be kept around
+    pub fn is_last_attempt(&self, key: &ChunkKey) -> bool {
+        let inner = self.inner.lock();
+        inner
+            .failures
+            .get(&failure_key(key))
+            .map(|failures| failures.attempts)
+            .unwrap_or_default()
+            + 1
+            >= self.max_attempts
+    }
+
+    /// Counts a failure, returns whether the chunk is out of attempts
+    pub fn record_failure(&self, key: &ChunkKey) -> bool {
+        let mut inner = self.inner.lock();
+        let key = failure_key(key);
+        if !inner.failures.contains_key(&key) && inner.failures.len() >= self.max_tracked {
+            let oldest = inner
+                .failures
+                .iter()
+                .min_by_key(|(_, failures)| failures.last)
+                .map(|(key, _)| *key);
+            if let Some(oldest) = oldest {
+                inner.failures.remove(&oldest);
+            }
+        }
+        let failures = inner.failures.entry(key).or_insert(ChunkFailures {
+            attempts: 0,
+            last: Instant::now(),
+        });
+        failures.attempts += 1;
+        failures.last = Instant::now();
+        failures.attempts >= self.max_attempts
+    }
+
+    /// Forgets the failures of versions now booked
+    pub fn booked(&self, actor_id: ActorId, versions: CrsqlDbVersionRange) {
+        let mut inner = self.inner.lock();
+        if !inner.failures.is_empty() {
+            inner
+                .failures
+                .retain(|(actor, version), _| *actor != actor_id || !versions.contains(*version));
+        }
+    }
+
+    pub fn failures(&self, key: &ChunkKey) -> u32 {
+        self.inner
+            .lock()
+            .failures
+            .get(&failure_key(key))
+            .map(|failures| failures.attempts)
+            .unwrap_or_default()
+    }
+
+    pub fn quarantine(&self, key: ChunkKey) {
+        let mut inner = self.inner.lock();
+        let key = failure_key(&key);
+        inner.failures.remove(&key);
+        inner.quarantined.insert(key);
+        gauge!("corro.chunk.quarantined.current").set(inner.quarantined.len() as f64);
+    }
+
+    /// Lets a chunk be applied again, with all its attempts
+    pub fn release(&self, key: &ChunkKey) {
+        let mut inner = self.inner.lock();
+        let key = failure_key(key);
+        inner.failures.remove(&key);
+        inner.quarantined.remove(&key);
+        gauge!("corro.chunk.quarantined.current").set(inner.quarantined.len() as f64);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct QuarantinedChunk {
+    pub id: i64,
+    pub actor_id: ActorId,
+    pub versions: CrsqlDbVersionRange,
+    pub seqs: Option<CrsqlSeqRange>,
+    pub attempts: u32,
+    pub error: String,
+    /// Unix timestamp, in seconds
+    pub quarantined_at: i64,
+}
+
+impl QuarantinedChunk {
+    pub fn key(&self) -> ChunkKey {
+        (self.actor_id, self.versions, self.seqs)
+    }
+}
+
+/// Persists a chunk that ran out of attempts, returns its id
+pub fn insert_quarantined_chunk(
+    conn: &Connection,
+    change: &ChangeV1,
+    attempts: u32,
+    error: &str,
+) -> rusqlite::Result<i64> {
+    let versions = change.versions();
+    let seqs = change.seqs();
+    let encoded = change
+        .write_to_vec()
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+
+    conn.prepare_cached(
+        "INSERT INTO __corro_quarantined_chunks (site_id, start_version, end_version, start_seq, end_seq, attempts, error, change, quarantined_at)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )?
+    .execute(rusqlite::params![
+        change.actor_id,
+        versions.start(),
+        versions.end(),
+        seqs.map(|seqs| seqs.start()),
+        seqs.map(|seqs| seqs.end()),
+        attempts,
+        error,
+        encoded,
+        std::time::SystemTime::UNIX_EPOCH
+            .elapsed()
+            .map_or(0, |elapsed| elapsed.as_secs() as i64),
+    ])?;
+
+    Ok(conn.last_insert_rowid())
+}
+
+pub fn quarantined_chunks(conn: &Connection) -> rusqlite::Result<Vec<QuarantinedChunk>> {
+    conn.prepare_cached(
+        "SELECT id, site_id, start_version, end_version, start_seq, end_seq, attempts, error, quarantined_at
+            FROM __corro_quarantined_chunks ORDER BY id",
+    )?
+    .query_map([], |row| {
+        let start_seq: Option<CrsqlSeq> = row.get(4)?;
+        let end_seq: Option<CrsqlSeq> = row.get(5)?;
+        Ok(QuarantinedChunk {
+            id: row.get(0)?,
+            actor_id: row.get(1)?,
+            versions: CrsqlDbVersionRange::new(row.get(2)?, row.get(3)?),
+            seqs: start_seq
+                .zip(end_seq)
+                .map(|(start, end)| CrsqlSeqRange::new(start, end)),
+            attempts: row.get(6)?,
+            error: row.get(7)?,
+            quarantined_at: row.get(8)?,
+        })
+    })?
+    .collect()
+}
+
+/// Removes a quarantined chunk, handing back the changeset to apply again
+pub fn take_quarantined_chunk(conn: &Connection, id: i64) -> rusqlite::Result<Option<ChangeV1>> {
+    let Some(encoded) = conn
+        .prepare_cached("DELETE FROM __corro_quarantined_chunks WHERE id = ? RETURNING change")?
+        .query_row([id], |row| row.get::<_, Vec<u8>>(0))
+        .optional()?
+    else {
+        return Ok(None);
+    };
+
+    ChangeV1::read_from_buffer(&encoded).map(Some).map_err(|e| {
+        rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Blob, Box::new(e))
+    })
+}
+
+pub const MAX_CHANGES_BYTE_SIZE: usize = 8 * 1024;
+
+pub struct InsertChangesInfo {
+    pub db_version: CrsqlDbVersion,
+    pub last_seq: CrsqlSeq,
+    pub ts: Timestamp,
+    pub snap: VersionsSnapshot,
+}
+
+pub fn insert_local_changes(
+    agent: &Agent,
+    tx: &Connection,
+    book_writer: &mut tokio::sync::RwLockWriteGuard<'_, BookedVersions>,
+) -> Result<Option<InsertChangesInfo>, ChangeError> {
+    let actor_id = agent.actor_id();
+
+    let db_version: CrsqlDbVersion = tx
+        .prepare_cached("SELECT crsql_peek_next_db_version()")
+        .and_then(|mut prepped| prepped.query_row((), |row| row.get(0)))
+        .map_err(ChangeError::NextDbVersion)?;
+
+    let version_info: (Option<CrsqlSeq>, Option<Timestamp>) = tx
+        .prepare_cached(
+            "SELECT MAX(seq), MAX(ts) FROM crsql_changes WHERE site_id = ? AND db_version = ?;",
+        )
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: None,
+        })?
+        .query_row((agent.actor_id(), db_version), |row| {
+            Ok((row.get(0)?, row.get(1)?))
+        })
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(actor_id),
+            version: None,
+        })?;
+
+    match version_info {
+        (None, None) => Ok(None),
+        (None, Some(ts)) => {
+            warn!("found db_version {db_version} without seq, last ts: {ts:?})");
+            Ok(None)
+        }
+        (Some(last_seq), ts) => {
+            let ts = ts.unwrap_or_else(|| fabricate_timestamp(agent, db_version));
+
+            debug!("found db_version {db_version} (last seq: {last_seq}, last ts: {ts})");
+
+            if let Some(limit) = agent.config().db.max_value_bytes {
+                check_value_sizes(agent, tx, db_version, limit)?;
+            }
+
+            let mut snap = book_writer.snapshot();
+            snap.insert_db(tx, [db_version.range_to(db_version)])
+                .map_err(|source| ChangeError::Rusqlite {
+                    source,
+                    actor_id: Some(actor_id),
+                    version: Some(db_version),
+                })?;
+
+            Ok(Some(InsertChangesInfo {
+                db_version,
+                last_seq,
+                ts,
+                snap,
+            }))
+        }
+    }
+}
+
+/// Rejects a local version with a text or blob value over `limit` bytes
+fn check_value_sizes(
+    agent: &Agent,
+    tx: &Connection,
+    db_version: CrsqlDbVersion,
+    limit: usize,
+) -> Result<(), ChangeError> {
+    let too_large: Option<(ColumnName, i64)> = tx
+        .prepare_cached(
+            "SELECT cid, length(CAST(val AS BLOB)) FROM crsql_changes
+                WHERE site_id = ? AND db_version = ?
+                  AND typeof(val) IN ('text', 'blob')
+                  AND length(CAST(val AS BLOB)) > ?
+                LIMIT 1",
+        )
+        .and_then(|mut prepped| {
+            prepped
+                .query_row((agent.actor_id(), db_version, limit as i64), |row| {
+                    Ok((row.get(0)?, row.get(1)?))
+                })
+                .optional()
+        })
+        .map_err(|source| ChangeError::Rusqlite {
+            source,
+            actor_id: Some(agent.actor_id()),
+            version: Some(db_version),
+        })?;
+
+    match too_large {
+        Some((cid, size)) => Err(ChangeError::ValueTooLarge {
+            cid,
+            size: size as usize,
+            limit,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Timestamp for a local version whose changes have none: a new one, which
+/// may not order the changes right against other nodes'. Counted and
+/// reported to the change observers, from their own thread.
+pub fn fabricate_timestamp(agent: &Agent, db_version: CrsqlDbVersion) -> Timestamp {
+    warn!("found db_version {db_version} without ts, fabricating one");
+    counter!("corro.fabricated.timestamps.total").increment(1);
+    agent
+        .change_observers()
+        .enqueue_fabricated_timestamp(db_version);
+    Timestamp::from(agent.clock().new_timestamp())
+}
+
+/// Side of a conflict on a cell that cr-sqlite keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum ConflictWinner {
+    Existing,
+    Incoming,
+}
+
+/// First clock field that differed between two changes to the same cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum ConflictReason {
+    Cl,
+    ColVersion,
+    Value,
+    SiteId,
+}
+
+/// Which of two changes to the same cell cr-sqlite keeps, comparing in its
+/// order: the higher causal length, then the higher `col_version`, then the
+/// greater value, then the greater site id (with `merge-equal-values`, set by
+/// our migrations). Identical clocks keep the existing change.
+pub fn conflict_winner(existing: &Change, incoming: &Change) -> (ConflictWinner, ConflictReason) {
+    let (ordering, reason) = [
+        (existing.cl.cmp(&incoming.cl), ConflictReason::Cl),
+        (
+            existing.col_version.cmp(&incoming.col_version),
+            ConflictReason::ColVersion,
+        ),
+        (
+            crsql_cmp(&existing.val, &incoming.val),
+            ConflictReason::Value,
+        ),
+        (
+            existing.site_id.cmp(&incoming.site_id),
+            ConflictReason::SiteId,
+        ),
+    ]
+    .into_iter()
+    .find(|(ordering, _)| ordering.is_ne())
+    .unwrap_or((cmp::Ordering::Equal, ConflictReason::SiteId));
+
+    let winner = if ordering.is_lt() {
+        ConflictWinner::Incoming
+    } else {
+        ConflictWinner::Existing
+    };
+    (winner, reason)
+}
+
+/// cr-sqlite compares values by their SQLite type code first (integer, real,
+/// text, blob, null), unlike SQLite's sort order
+fn crsql_cmp(a: &SqliteValue, b: &SqliteValue) -> cmp::Ordering {
+    fn type_code(value: &SqliteValue) -> u8 {
+        match value {
+            SqliteValue::Integer(_) => 1,
+            SqliteValue::Real(_) => 2,
+            SqliteValue::Text(_) => 3,
+            SqliteValue::Blob(_) => 4,
+            SqliteValue::Null => 5,
+        }
+    }
+
+    match (a, b) {
+        (SqliteValue::Real(a), SqliteValue::Real(b)) => {
+            a.0.partial_cmp(&b.0).unwrap_or(cmp::Ordering::Equal)
+        }
+        _ if type_code(a) == type_code(b) => a.sqlite_cmp(b),
+        _ => type_code(a).cmp(&type_code(b)),
+    }
+}
+
+/// Value of a cell as of `as_of`, replaying the changes `site_id` made to it up
+/// to that version, row deletes included. Only the history still in the db is
+/// replayed: the last applied change (`crsql_changes`) and the buffered ones,
+/// so older values can be unrecoverable. `None` if the cell had no value.
+pub fn cell_value_at(
+    conn: &Connection,
+    site_id: ActorId,
+    table: &TableName,
+    pk: &[u8],
+    cid: &ColumnName,
+    as_of: CrsqlDbVersion,
+) -> Result<Option<SqliteValue>, ChangeError> {
+    let map_err = |source| ChangeError::Rusqlite {
+        source,
+        actor_id: Some(site_id),
+        version: Some(as_of),
+    };
+
+    let mut prepped = conn
+        .prepare_cached(
+            r#"
+            SELECT cid, val, cl FROM (
+                SELECT cid, val, cl, db_version, seq FROM crsql_changes
+                    WHERE "table" = :table AND pk = :pk AND site_id = :site_id AND db_version <= :as_of AND cid IN (:cid, '-1')
+                UNION ALL
+                SELECT cid, val, cl, db_version, seq FROM __corro_buffered_changes
+                    WHERE "table" = :table AND pk = :pk AND site_id = :site_id AND db_version <= :as_of AND cid IN (:cid, '-1')
+            ) ORDER BY db_version ASC, seq ASC
+        "#,
+        )
+        .map_err(map_err)?;
+
+    let rows = prepped
+        .query_map(
+            rusqlite::named_params! {
+                ":table": table,
+                ":pk": pk,
+                ":site_id": site_id,
+                ":as_of": as_of,
+                ":cid": cid,
+            },
+            |row| {
+                Ok((
+                    row.get::<_, ColumnName>(0)?,
+                    row.get::<_, SqliteValue>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            },
+        )
+        .map_err(map_err)?;
+
+    let mut value = None;
+    for row in rows {
+        let (change_cid, val, cl) = row.map_err(map_err)?;
+        if change_cid.is_crsql_sentinel() {
+            // an even causal length means the row was deleted
+            if cl % 2 == 0 {
+                value = None;
+            }
+        } else {
+            value = Some(val);
+        }
+    }
+
+    Ok(value)
+}
+
+/// Node of a [`MerkleTree`], hashing the changes of its seq range.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleNode {
+    pub seqs: CrsqlSeqRange,
+    pub hash: u64,
+    pub children: Vec<MerkleNode>,
+}
+
+impl MerkleNode {
+    fn build(leaves: &[(CrsqlSeq, u64)], fanout: usize) -> Self {
+        if let [(seq, hash)] = leaves {
+            return MerkleNode {
+                seqs: CrsqlSeqRange::single(*seq),
+                hash: *hash,
+                children: vec![],
+            };
+        }
+
+        let children: Vec<MerkleNode> = leaves
+            .chunks(leaves.len().div_ceil(fanout))
+            .map(|leaves| Self::build(leaves, fanout))
+            .collect();
+
+        let mut bytes = Vec::with_capacity(children.len() * 8);
+        for child in children.iter() {
+            bytes.extend_from_slice(&child.hash.to_le_bytes());
+        }
+
+        MerkleNode {
+            seqs: CrsqlSeqRange::new(leaves[0].0, leaves[leaves.len() - 1].0),
+            hash: seahash::hash(&bytes),
+            children,
+        }
+    }
+
+    fn diff_into(&self, other: &MerkleNode, out: &mut Vec<CrsqlSeqRange>) {
+        if self.hash == other.hash && self.seqs == other.seqs {
+            return;
+        }
+
+        if self.children.is_empty()
+            || self.seqs != other.seqs
+            || self.children.len() != other.children.len()
+        {
+            // leaf, or trees built differently: the whole range mismatches
+            match out.last_mut() {
+                Some(last) if last.end_int() + 1 == self.seqs.start_int() => {
+                    *last = CrsqlSeqRange::new(last.start(), self.seqs.end());
+                }
+                _ => out.push(self.seqs),
+            }
+            return;
+        }
+
+        for (ours, theirs) in self.children.iter().zip(other.children.iter()) {
+            ours.diff_into(theirs, out);
+        }
+    }
+}
+
+/// Merkle tree over the seqs of a single version, leaves are single seqs.
+/// Peers compare roots and only descend into mismatching subtrees.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleTree {
+    pub root: MerkleNode,
+}
+
+impl MerkleTree {
+    pub fn root_hash(&self) -> u64 {
+        self.root.hash
+    }
+
+    /// Seq ranges whose leaves differ between the two trees, adjacent
+    /// mismatching leaves are merged into a single range.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<CrsqlSeqRange> {
+        let mut out = vec![];
+        self.root.diff_into(&other.root, &mut out);
+        out
+    }
+}
+
+/// Max seqs [`build_merkle`] hashes at once, one leaf each.
+pub const MAX_MERKLE_LEAVES: usize = 1 << 20;
+
+/// Builds a [`MerkleTree`] with `fanout` children per node over the changes
+/// in `range` of a version, buffered changes included. Seqs without changes
+/// hash to 0. Ranges over [`MAX_MERKLE_LEAVES`] seqs are rejected, they come
+/// from peers.
+pub fn build_merkle(
+    conn: &Connection,
+    site_id: ActorId,
+    db_version: CrsqlDbVersion,
+    range: CrsqlSeqRange,
+    fanout: usize,
+) -> Result<MerkleTree, ChangeError> {
+    let map_err = |source| ChangeError::Rusqlite {
+        source,
+        actor_id: Some(site_id),
+        version: Some(db_version),
+    };
+
+    if range.len() > MAX_MERKLE_LEAVES {
+        return Err(ChangeError::MerkleRangeTooLarge {
+            range,
+            limit: MAX_MERKLE_LEAVES,
+        });
+    }
+
+    let mut leaves: Vec<(CrsqlSeq, u64)> = Vec::with_capacity(range.len());
+    leaves.extend(range.iter().map(|seq| (seq, 0)));
+
+    let mut prepped = conn
+        .prepare_cached(
+            r#"
+            SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl FROM crsql_changes
+                WHERE site_id = :site_id AND db_version = :db_version AND seq >= :start AND seq <= :end
+            UNION ALL
+            SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl FROM __corro_buffered_changes
+                WHERE site_id = :site_id AND db_version = :db_version AND seq >= :start AND seq <= :end
+        "#,
+        )
+        .map_err(map_err)?;
+
+    let rows = prepped
+        .query_map(
+            rusqlite::named_params! {
+                ":site_id": site_id,
+                ":db_version": db_version,
+                ":start": range.start(),
+                ":end": range.end(),
+            },
+            row_to_change,
+        )
+        .map_err(map_err)?;
+
+    for change in rows {
+        let change = change.map_err(map_err)?;
+        let index = (change.seq.0 - range.start_int()) as usize;
+        if let Some((_, hash)) = leaves.get_mut(index) {
+            // order independent in case a seq has more than one change
+            *hash ^= change.content_hash();
+        }
+    }
+
+    if leaves.is_empty() {
+        return Ok(MerkleTree {
+            root: MerkleNode {
+                seqs: range,
+                hash: 0,
+                children: vec![],
+            },
+        });
+    }
+
+    Ok(MerkleTree {
+        root: MerkleNode::build(&leaves, cmp::max(fanout, 2)),
+    })
+}
+
+/// Rows inserted, updated or deleted by the connection since it was opened,
+/// including the ones written by triggers and by cr-sqlite itself.
+pub fn total_changes(conn: &Connection) -> rusqlite::Result<u64> {
+    conn.prepare_cached("SELECT total_changes()")?
+        .query_row([], |row| row.get(0))
+}
+
+/// Rows written while applying a batch of changes, clock entries and rows
+/// written by triggers included, against the number of changes applied.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteAmplification {
+    pub changes_applied: u64,
+    pub rows_changed: u64,
+}
+
+impl WriteAmplification {
+    pub fn write_amplification(&self) -> Option<f64> {
+        (self.changes_applied > 0).then(|| self.rows_changed as f64 / self.changes_applied as f64)
+    }
+}
+
+/// Inserts a peer's change into `crsql_changes`, where cr-sqlite merges it.
+/// Returns the number of rows inserted, see `crsql_rows_impacted()` for
+/// whether it won.
+pub fn insert_change(conn: &Connection, change: &Change, ts: Timestamp) -> rusqlite::Result<usize> {
+    conn.prepare_cached(
+        r#"
+            INSERT INTO crsql_changes
+                ("table", pk, cid, val, col_version, db_version, site_id, cl, seq, ts)
+            VALUES
+                (?,       ?,  ?,   ?,   ?,           ?,          ?,       ?,  ?, ?)
+        "#,
+    )?
+    .execute(rusqlite::params![
+        change.table.as_str(),
+        change.pk,
+        change.cid.as_str(),
+        &change.val,
+        change.col_version,
+        change.db_version,
+        &change.site_id,
+        change.cl,
+        change.seq,
+        ts,
+    ])
+}
+
+/// Outcome of [`validate_changes`], one result per change in input order.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ValidationReport {
+    pub results: Vec<Result<(), String>>,
+}
+
+impl ValidationReport {
+    pub fn is_valid(&self) -> bool {
+        self.results.iter().all(Result::is_ok)
+    }
+
+    /// Index and error of every change that would fail to apply
+    pub fn failures(&self) -> impl Iterator<Item = (usize, &str)> + '_ {
+        self.results
+            .iter()
+            .enumerate()
+            .filter_map(|(i, res)| res.as_ref().err().map(|e| (i, e.as_str())))
+    }
+}
+
+/// Applies `changes` in order within a savepoint that is always rolled back,
+/// reporting which would fail. A failed change is rolled back on its own so
+/// the following ones are still checked against the earlier successes.
+pub fn validate_changes(
+    tx: &Connection,
+    changes: &[Change],
+) -> Result<ValidationReport, ChangeError> {
+    let sql_err = |source| ChangeError::Rusqlite {
+        source,
+        actor_id: None,
+        version: None,
+    };
+
+    tx.execute_batch("SAVEPOINT validate_changes")
+        .map_err(sql_err)?;
+
+    let mut report = ValidationReport::default();
+    let res = changes.iter().try_for_each(|change| {
+        tx.execute_batch("SAVEPOINT validate_change")?;
+        match insert_change(tx, change, Timestamp::default()) {
+            Ok(_) => report.results.push(Ok(())),
+            Err(e) => {
+                tx.execute_batch("ROLLBACK TO validate_change")?;
+                report.results.push(Err(e.to_string()));
+            }
+        }
+        tx.execute_batch("RELEASE validate_change")
+    });
+
+    // undo everything, even if validation itself failed
+    let rollback = tx.execute_batch("ROLLBACK TO validate_changes; RELEASE validate_changes");
+    res.and(rollback).map_err(sql_err)?;
+
+    Ok(report)
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactionReport {
+    pub dry_run: bool,
+    /// tombstones removed (or that would be removed) per clock table
+    pub tables: BTreeMap<String, usize>,
+}
+
+impl CompactionReport {
+    pub fn rows(&self) -> usize {
+        self.tables.values().sum()
+    }
+}
+
+/// Removes the clock tombstones left behind by deleted rows for versions
+/// every peer has acknowledged, up to the per-actor version in `acked`.
+/// Nothing above those versions is touched so a peer still needing them can
+/// sync the deletes, nor anything `retention` keeps as of `now`. With
+/// `dry_run`, only counts the rows.
+pub fn compact_changes(
+    tx: &Connection,
+    acked: &HashMap<ActorId, CrsqlDbVersion>,
+    retention: &RetentionConfig,
+    now: Timestamp,
+    dry_run: bool,
+) -> rusqlite::Result<CompactionReport> {
+    let mut report = CompactionReport {
+        dry_run,
+        ..Default::default()
+    };
+
+    let tables: Vec<String> = tx
+        .prepare(
+            "SELECT name FROM sqlite_schema WHERE type = 'table' AND name LIKE '%__crsql_clock'",
+        )?
+        .query_map([], |row| row.get(0))?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+
+    // latest version of each actor still in a clock table
+    let mut latest: HashMap<i64, CrsqlDbVersion> = HashMap::new();
+    if retention.min_retain_versions > 0 {
+        for table in tables.iter() {
+            let mut prepped = tx.prepare(&format!(
+                "SELECT site_id, MAX(db_version) FROM \"{table}\" GROUP BY site_id"
+            ))?;
+            let mut rows = prepped.query([])?;
+            while let Some(row) = rows.next()? {
+                let version: CrsqlDbVersion = row.get(1)?;
+                let max = latest.entry(row.get(0)?).or_default();
+                *max = cmp::max(*max, version);
+            }
+        }
+    }
+
+    let mut ordinals = vec![];
+    for (actor_id, version) in acked {
+        let ordinal: Option<i64> = tx
+            .prepare_cached("SELECT ordinal FROM crsql_site_id WHERE site_id = ?")?
+            .query_row([actor_id], |row| row.get(0))
+            .optional()?;
+        let Some(ordinal) = ordinal else {
+            continue;
+        };
+        let mut version = version.0;
+        if retention.min_retain_versions > 0 {
+            let latest = latest.get(&ordinal).map_or(0, |max| max.0);
+            version = cmp::min(
+                version,
+                latest.saturating_sub(retention.min_retain_versions),
+            );
+        }
+        if version > 0 {
+            ordinals.push((ordinal, CrsqlDbVersion(version)));
+        }
+    }
+
+    // changes from this timestamp on are kept
+    let retained_after = if retention.min_retain_duration > 0 {
+        let window = NTP64::from(Duration::from_secs(retention.min_retain_duration));
+        now.to_ntp64().as_u64().saturating_sub(window.as_u64())
+    } else {
+        u64::MAX
+    };
+    // timestamps are u64s stored as text, they don't fit in sqlite's i64
+    // past 2038: compare them zero-padded instead
+    let retained_after = format!("{retained_after:020}");
+
+    for table in tables {
+        // a deleted row only keeps its sentinel, with an even causal length
+        let filter = "site_id = ? AND db_version <= ? AND col_name = '-1' AND col_version % 2 = 0 AND substr('00000000000000000000' || ts, -20) < ?";
+
+        let mut count = 0;
+        for (ordinal, version) in ordinals.iter() {
+            count += if dry_run {
+                tx.query_row(
+                    &format!("SELECT COUNT(*) FROM \"{table}\" WHERE {filter}"),
+                    (ordinal, version, &retained_after),
+                    |row| row.get::<_, usize>(0),
+                )?
+            } else {
+                tx.execute(
+                    &format!("DELETE FROM \"{table}\" WHERE {filter}"),
+                    (ordinal, version, &retained_after),
+                )?
+            };
+        }
+
+        if count > 0 && !dry_run {
+            let pks_table = format!("{}__crsql_pks", table.trim_end_matches("__crsql_clock"));
+            tx.execute(
+                &format!("DELETE FROM \"{pks_table}\" WHERE __crsql_key NOT IN (SELECT key FROM \"{table}\")"),
+                [],
+            )?;
+        }
+
+        debug!("compacted {count} tombstones from {table} (dry run: {dry_run})");
+        report.tables.insert(table, count);
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::base::{dbsr, dbvr};
+
+    #[test]
+    fn test_change_chunker() {
+        // empty interator
+        let mut chunker = ChunkedChanges::new(vec![].into_iter(), CrsqlSeq(0), CrsqlSeq(100), 50);
+
+        assert_eq!(chunker.next(), Some(Ok((vec![], dbsr!(0, 100)))));
+        assert_eq!(chunker.next(), None);
+
+        let changes: Vec<Change> = (0..100)
+            .map(|seq| Change {
+                seq: CrsqlSeq(seq),
+                ..Default::default()
+            })
+            .collect();
+
+        // 2 iterations
+        let mut chunker = ChunkedChanges::new(
+            vec![
+                Ok(changes[0].clone()),
+                Ok(changes[1].clone()),
+                Ok(changes[2].clone()),
+            ]
+            .into_iter(),
+            CrsqlSeq(0),
+            CrsqlSeq(100),
+            changes[0].estimated_byte_size() + changes[1].estimated_byte_size(),
+        );
+
+        assert_eq!(
+            chunker.next(),
+            Some(Ok((
+                vec![changes[0].clone(), changes[1].clone()],
+                dbsr!(0, 1)
+            )))
+        );
+        assert_eq!(
+            chunker.next(),
+            Some(Ok((vec![changes[2].clone()], dbsr!(2, 100))))
+        );
+        assert_eq!(chunker.next(), None);
+
+        let mut chunker = ChunkedChanges::new(
+            vec![Ok(changes[0].clone()), Ok(changes[1].clone())].into_iter(),
+            CrsqlSeq(0),
+            CrsqlSeq(0),
+            changes[0].estimated_byte_size(),
+        );
+
+        assert_eq!(
+            chunker.next(),
+            Some(Ok((vec![changes[0].clone()], dbsr!(0, 0))))
+        );
+        assert_eq!(chunker.next(), None);
+
+        // gaps
+        let mut chunker = ChunkedChanges::new(
+            vec![Ok(changes[0].clone()), Ok(changes[2].clone())].into_iter(),
+            CrsqlSeq(0),
+            CrsqlSeq(100),
+            changes[0].estimated_byte_size() + changes[2].estimated_byte_size(),
+        );
+
+        assert_eq!(
+            chunker.next(),
+            Some(Ok((
+                vec![changes[0].clone(), changes[2].clone()],
+                dbsr!(0, 100)
+            )))
+        );
+
+        assert_eq!(chunker.next(), None);
+
+        // gaps
+        let mut chunker = ChunkedChanges::new(
             vec![
-                Ok(changes[0].clone()),
-                Ok(changes[1].clone()),
                 Ok(changes[2].clone()),
+                Ok(changes[4].clone()),
+                Ok(changes[7].clone()),
+                Ok(changes[8].clone()),
             ]
             .into_iter(),
             CrsqlSeq(0),
             CrsqlSeq(100),
-            changes[0].estimated_byte_size() + changes[1].estimated_byte_size(),
+            100000, // just send them all!
+        );
+
+        assert_eq!(
+            chunker.next(),
+            Some(Ok((
+                vec![
+                    changes[2].clone(),
+                    changes[4].clone(),
+                    changes[7].clone(),
+                    changes[8].clone()
+                ],
+                dbsr!(0, 100)
+            )))
+        );
+
+        assert_eq!(chunker.next(), None);
+
+        // gaps
+        let mut chunker = ChunkedChanges::new(
+            vec![
+                Ok(changes[2].clone()),
+                Ok(changes[4].clone()),
+                Ok(changes[7].clone()),
+                Ok(changes[8].clone()),
+            ]
+            .into_iter(),
+            CrsqlSeq(0),
+            CrsqlSeq(10),
+            changes[2].estimated_byte_size() + changes[4].estimated_byte_size(),
+        );
+
+        assert_eq!(
+            chunker.next(),
+            Some(Ok((
+                vec![changes[2].clone(), changes[4].clone(),],
+                dbsr!(0, 4)
+            )))
+        );
+
+        assert_eq!(
+            chunker.next(),
+            Some(Ok((
+                vec![changes[7].clone(), changes[8].clone(),],
+                dbsr!(5, 10)
+            )))
+        );
+
+        assert_eq!(chunker.next(), None);
+    }
+
+    #[test]
+    fn test_batches_commute() {
+        let change = |table: &str, pk: u8, cid: &str| Change {
+            table: TableName::from(table),
+            pk: vec![pk],
+            cid: ColumnName::from(cid),
+            ..Default::default()
+        };
+
+        let a = vec![change("tests", 1, "text"), change("tests", 2, "text")];
+
+        // same table and pk, different column
+        let b = vec![change("tests", 1, "num"), change("tests2", 2, "text")];
+        assert!(batches_commute(&a, &b));
+        assert!(batches_commute(&b, &a));
+
+        // overlapping cell
+        let c = vec![change("tests3", 1, "text"), change("tests", 2, "text")];
+        assert!(!batches_commute(&a, &c));
+        assert!(!batches_commute(&c, &a));
+
+        assert!(batches_commute(&a, &[]));
+    }
+
+    #[test]
+    fn test_change_chunker_oversized_change() {
+        let small = |seq| Change {
+            seq: CrsqlSeq(seq),
+            ..Default::default()
+        };
+        let small_size = small(0).estimated_byte_size();
+        let max_buf_size = small_size * 3;
+
+        let huge = Change {
+            seq: CrsqlSeq(2),
+            val: SqliteValue::Blob(vec![0u8; 4 * 1024 * 1024].into()),
+            ..Default::default()
+        };
+        assert!(huge.estimated_byte_size() > max_buf_size);
+
+        // sent alone, without growing the chunk it comes after
+        let changes = vec![small(0), small(1), huge.clone(), small(3), small(4)];
+        let chunks = ChunkedChanges::new(
+            changes.into_iter().map(Ok),
+            CrsqlSeq(0),
+            CrsqlSeq(4),
+            max_buf_size,
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![
+                (vec![small(0), small(1)], dbsr!(0, 1)),
+                (vec![huge.clone()], dbsr!(2, 2)),
+                (vec![small(3), small(4)], dbsr!(3, 4)),
+            ]
+        );
+
+        // first and last of a version
+        let changes = vec![
+            Change {
+                seq: CrsqlSeq(0),
+                ..huge.clone()
+            },
+            small(1),
+            Change {
+                seq: CrsqlSeq(2),
+                ..huge.clone()
+            },
+        ];
+        let chunks = ChunkedChanges::new(
+            changes.clone().into_iter().map(Ok),
+            CrsqlSeq(0),
+            CrsqlSeq(2),
+            max_buf_size,
+        )
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+        assert_eq!(
+            chunks,
+            vec![
+                (vec![changes[0].clone()], dbsr!(0, 0)),
+                (vec![changes[1].clone()], dbsr!(1, 1)),
+                (vec![changes[2].clone()], dbsr!(2, 2)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_change_chunker_read_permits() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        const PERMITS: usize = 2;
+
+        let permits = Arc::new(Semaphore::new(PERMITS));
+        let reading = Arc::new(AtomicUsize::new(0));
+        let max_reading = Arc::new(AtomicUsize::new(0));
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let permits = permits.clone();
+                let reading = reading.clone();
+                let max_reading = max_reading.clone();
+                std::thread::spawn(move || {
+                    let rows = (0..10).map(move |i| {
+                        let current = reading.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_reading.fetch_max(current, Ordering::SeqCst);
+                        std::thread::sleep(std::time::Duration::from_millis(1));
+                        reading.fetch_sub(1, Ordering::SeqCst);
+                        Ok::<_, rusqlite::Error>(Change {
+                            seq: CrsqlSeq(i),
+                            ..Default::default()
+                        })
+                    });
+
+                    ChunkedChanges::new(rows, CrsqlSeq(0), CrsqlSeq(9), 1)
+                        .with_read_permits(permits)
+                        .map(|res| res.unwrap().0.len())
+                        .sum::<usize>()
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 10);
+        }
+
+        let max_reading = max_reading.load(Ordering::SeqCst);
+        assert!(max_reading > 0 && max_reading <= PERMITS);
+    }
+
+    #[test]
+    fn test_render_timeline() {
+        let change = |db_version, cid: &str, val: i64| Change {
+            table: TableName("tests".into()),
+            pk: vec![1],
+            cid: ColumnName(cid.into()),
+            val: SqliteValue::Integer(val),
+            db_version: CrsqlDbVersion(db_version),
+            site_id: [0; 16],
+            ..Default::default()
+        };
+
+        let timeline = render_timeline(
+            vec![change(1, "a", 1), change(1, "b", 2), change(2, "a", 3)].into_iter(),
+        );
+
+        let site = ActorId::from_bytes([0; 16]);
+        assert_eq!(
+            timeline,
+            format!(
+                "[1]\n  tests 01: a=1 ({site})\n  tests 01: b=2 ({site})\n[2]\n  tests 01: a=3 ({site})\n"
+            )
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_reliable_sender_retransmits_unacked() {
+        let changes: Vec<Change> = (0..4)
+            .map(|seq| Change {
+                seq: CrsqlSeq(seq),
+                ..Default::default()
+            })
+            .collect();
+
+        let chunker = ChunkedChanges::new(
+            changes.iter().cloned().map(Ok),
+            CrsqlSeq(0),
+            CrsqlSeq(3),
+            changes[0].estimated_byte_size() * 2,
+        );
+        let retransmit_after = Duration::from_secs(5);
+        let mut sender = ReliableSender::new(chunker, retransmit_after);
+
+        let first = sender.send_next().unwrap().unwrap();
+        assert_eq!(first.chunk_seq, 0);
+        assert_eq!(first.changes, changes[0..2]);
+        let second = sender.send_next().unwrap().unwrap();
+        assert_eq!(second.chunk_seq, 1);
+        assert_eq!(second.seqs, dbsr!(2, 3));
+        assert!(sender.send_next().is_none());
+        assert_eq!(sender.in_flight(), 2);
+
+        // the second chunk's ack arrives, the first one's is dropped
+        assert!(sender.ack(1));
+        assert!(!sender.is_done());
+
+        let start = Instant::now();
+        let retransmitted = sender.next_retransmit().await.unwrap();
+        assert_eq!(retransmitted, first);
+        assert!(start.elapsed() >= retransmit_after);
+
+        assert!(sender.ack(0));
+        assert!(!sender.ack(0));
+        assert_eq!(sender.in_flight(), 0);
+        assert!(sender.is_done());
+
+        // nothing left in flight, no further retransmission
+        assert!(sender.next_retransmit().await.is_none());
+    }
+
+    #[test]
+    fn test_change_set_difference() {
+        let change = |seq: u64, val: i64| Change {
+            table: TableName("tests".into()),
+            pk: vec![1],
+            cid: ColumnName("text".into()),
+            val: SqliteValue::Integer(val),
+            db_version: CrsqlDbVersion(1),
+            seq: CrsqlSeq(seq),
+            ..Default::default()
+        };
+
+        let a = vec![change(0, 1), change(1, 2), change(2, 3)];
+        // same seq, different value: still missing from b
+        let b = vec![change(0, 1), change(2, 4), change(3, 5)];
+
+        assert_eq!(
+            change_set_difference(&a, &b),
+            vec![change(1, 2), change(2, 3)]
+        );
+        assert_eq!(
+            change_set_difference(&b, &a),
+            vec![change(2, 4), change(3, 5)]
+        );
+        assert!(change_set_difference(&a, &a).is_empty());
+        assert_eq!(change_set_difference(&a, &[]), a);
+    }
+
+    #[test]
+    fn test_estimate_sort_memory() {
+        let changes: Vec<Change> = (0..10)
+            .map(|i| Change {
+                table: TableName("tests".into()),
+                pk: vec![i],
+                cid: ColumnName("text".into()),
+                val: SqliteValue::Text("hello".into()),
+                ..Default::default()
+            })
+            .collect();
+
+        let avg = sample_avg_change_bytes(&changes, 4);
+        assert_eq!(avg, changes[0].estimated_byte_size());
+        assert_eq!(sample_avg_change_bytes(&[], 4), 0);
+
+        let small = estimate_sort_memory(100, avg);
+        let large = estimate_sort_memory(1_000, avg);
+        assert!(small > 0);
+        assert_eq!(large, small * 10);
+
+        assert_eq!(estimate_sort_memory(u64::MAX, avg), MAX_SORT_MEMORY_BYTES);
+        assert_eq!(estimate_sort_memory(10_000_000, avg), MAX_SORT_MEMORY_BYTES);
+    }
+
+    #[test]
+    fn test_since_timestamp() {
+        // physical time in the upper bits, logical counter in the lowest ones
+        let ts = |secs: u64, counter: u64| Timestamp::from((secs << 32) | counter);
+
+        let rows: Vec<(Change, Timestamp)> = [(1, 0), (2, 0), (2, 1), (2, 2), (3, 0), (1, 5)]
+            .into_iter()
+            .enumerate()
+            .map(|(seq, (secs, counter))| {
+                (
+                    Change {
+                        seq: CrsqlSeq(seq as u64),
+                        ..Default::default()
+                    },
+                    ts(secs, counter),
+                )
+            })
+            .collect();
+
+        let seqs = |since: Timestamp| {
+            SinceTimestamp::new(rows.clone().into_iter().map(Ok), since)
+                .map(|res| res.map(|change| change.seq.0))
+                .collect::<rusqlite::Result<Vec<_>>>()
+                .unwrap()
+        };
+
+        // same physical time, only higher logical counters pass
+        assert_eq!(seqs(ts(2, 1)), vec![3, 4]);
+        assert_eq!(seqs(ts(2, 0)), vec![2, 3, 4]);
+        assert_eq!(seqs(ts(0, 0)), vec![0, 1, 2, 3, 4, 5]);
+        assert!(seqs(ts(3, 0)).is_empty());
+
+        // used before chunking
+        let mut chunker = ChunkedChanges::new(
+            SinceTimestamp::new(rows.into_iter().map(Ok), ts(2, 2)),
+            CrsqlSeq(4),
+            CrsqlSeq(4),
+            MAX_CHANGES_BYTE_SIZE,
+        );
+        let (changes, seqs) = chunker.next().unwrap().unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(seqs, dbsr!(4, 4));
+    }
+
+    #[test]
+    fn test_chunk_size_tuner_converges() {
+        let tuner = || {
+            ChunkSizeTuner::new(
+                ActorId(uuid::Uuid::new_v4()),
+                1024,
+                64 * 1024,
+                Duration::from_millis(100),
+            )
+        };
+
+        // a peer slow to apply gets smaller chunks, down to the minimum
+        let slow = tuner();
+        let mut sizes = vec![slow.size()];
+        for _ in 0..10 {
+            let size = slow.size();
+            sizes.push(slow.observe(size, Duration::from_millis(300)));
+        }
+        assert!(sizes.windows(2).all(|w| w[1] <= w[0]), "{sizes:?}");
+        assert_eq!(slow.size(), 1024);
+
+        // a fast one gets larger chunks, up to the maximum
+        let fast = tuner();
+        let mut sizes = vec![fast.size()];
+        for _ in 0..100 {
+            let size = fast.size();
+            sizes.push(fast.observe(size, Duration::from_millis(5)));
+        }
+        assert!(sizes.windows(2).all(|w| w[1] >= w[0]), "{sizes:?}");
+        assert_eq!(fast.size(), 64 * 1024);
+
+        // one slow chunk backs off multiplicatively
+        assert_eq!(
+            fast.observe(64 * 1024, Duration::from_millis(300)),
+            32 * 1024
+        );
+        // chunks that don't fill their budget don't grow it
+        assert_eq!(fast.observe(100, Duration::from_millis(5)), 32 * 1024);
+
+        // clones share the size
+        let shared = fast.clone();
+        shared.observe(0, Duration::from_secs(1));
+        assert_eq!(fast.size(), 16 * 1024);
+    }
+
+    #[test]
+    fn test_compact_changes() -> rusqlite::Result<()> {
+        use crate::sqlite::CrConn;
+        use crate::sync::{acked_versions, SyncStateV1};
+
+        let conn = CrConn::init(Connection::open_in_memory()?)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, text TEXT);
+            SELECT crsql_as_crr('foo');
+            INSERT INTO foo (id, text) VALUES (1, 'one');
+            INSERT INTO foo (id, text) VALUES (2, 'two');
+            DELETE FROM foo WHERE id = 1;
+            DELETE FROM foo WHERE id = 2;
+            ",
+        )?;
+
+        let actor_id: ActorId = conn.query_row("SELECT crsql_site_id()", [], |row| row.get(0))?;
+
+        // both peers have applied the first delete (version 3), one is missing the second
+        let peers: Vec<ActorId> = (0..2).map(|_| ActorId(uuid::Uuid::new_v4())).collect();
+        let states: HashMap<ActorId, SyncStateV1> = peers
+            .iter()
+            .zip([4, 3])
+            .map(|(peer, head)| {
+                let mut state = SyncStateV1 {
+                    actor_id: *peer,
+                    ..Default::default()
+                };
+                state.heads.insert(actor_id, CrsqlDbVersion(head));
+                (*peer, state)
+            })
+            .collect();
+        let acked = acked_versions(&peers, &states);
+        assert_eq!(acked, [(actor_id, CrsqlDbVersion(3))].into());
+
+        let tombstones = |conn: &Connection| -> rusqlite::Result<Vec<CrsqlDbVersion>> {
+            conn.prepare(
+                "SELECT db_version FROM foo__crsql_clock WHERE col_name = '-1' ORDER BY db_version",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect()
+        };
+        assert_eq!(
+            tombstones(&conn)?,
+            vec![CrsqlDbVersion(3), CrsqlDbVersion(4)]
+        );
+
+        let retention = RetentionConfig::default();
+        let report = compact_changes(&conn, &acked, &retention, Timestamp::zero(), true)?;
+        assert!(report.dry_run);
+        assert_eq!(report.rows(), 1);
+        assert_eq!(tombstones(&conn)?.len(), 2);
+
+        let report = compact_changes(&conn, &acked, &retention, Timestamp::zero(), false)?;
+        assert_eq!(report.tables.get("foo__crsql_clock"), Some(&1));
+        assert_eq!(tombstones(&conn)?, vec![CrsqlDbVersion(4)]);
+
+        // the unacked delete is still replicated
+        let changes: i64 = conn.query_row(
+            "SELECT COUNT(*) FROM crsql_changes WHERE db_version = 4",
+            [],
+            |row| row.get(0),
+        )?;
+        assert_eq!(changes, 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_compact_changes_retention() -> rusqlite::Result<()> {
+        use crate::sqlite::CrConn;
+
+        let conn = CrConn::init(Connection::open_in_memory()?)?;
+        conn.execute_batch(
+            "
+            CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, text TEXT);
+            SELECT crsql_as_crr('foo');
+            INSERT INTO foo (id, text) VALUES (1, 'one');
+            INSERT INTO foo (id, text) VALUES (2, 'two');
+            ",
+        )?;
+
+        // past 2038, timestamps are larger than an i64
+        let at = |secs: u64| NTP64::from(Duration::from_secs(2_200_000_000 + secs));
+        let hour = 3600;
+        // deletes as versions 3 and 4, an hour apart
+        for (id, ts) in [(1, at(0)), (2, at(hour))] {
+            conn.execute_batch(&format!(
+                "BEGIN; SELECT crsql_set_ts('{}'); DELETE FROM foo WHERE id = {id}; COMMIT;",
+                ts.as_u64()
+            ))?;
+        }
+
+        let actor_id: ActorId = conn.query_row("SELECT crsql_site_id()", [], |row| row.get(0))?;
+        // every peer has applied both deletes
+        let acked: HashMap<ActorId, CrsqlDbVersion> = [(actor_id, CrsqlDbVersion(4))].into();
+
+        let tombstones = |conn: &Connection| -> rusqlite::Result<Vec<CrsqlDbVersion>> {
+            conn.prepare(
+                "SELECT db_version FROM foo__crsql_clock WHERE col_name = '-1' ORDER BY db_version",
+            )?
+            .query_map([], |row| row.get(0))?
+            .collect()
+        };
+
+        // the latest version is kept
+        let retention = RetentionConfig {
+            min_retain_duration: 0,
+            min_retain_versions: 1,
+        };
+        let report = compact_changes(&conn, &acked, &retention, at(0).into(), true)?;
+        assert_eq!(report.rows(), 1);
+
+        let retention = RetentionConfig {
+            min_retain_duration: 2 * hour,
+            min_retain_versions: 0,
+        };
+
+        // both deletes are within the window
+        let report = compact_changes(&conn, &acked, &retention, at(2 * hour).into(), false)?;
+        assert_eq!(report.rows(), 0);
+        assert_eq!(
+            tombstones(&conn)?,
+            vec![CrsqlDbVersion(3), CrsqlDbVersion(4)]
+        );
+
+        // the first delete left the window
+        let report = compact_changes(&conn, &acked, &retention, at(3 * hour).into(), false)?;
+        assert_eq!(report.rows(), 1);
+        assert_eq!(tombstones(&conn)?, vec![CrsqlDbVersion(4)]);
+
+        let report = compact_changes(&conn, &acked, &retention, at(4 * hour).into(), false)?;
+        assert_eq!(report.rows(), 1);
+        assert!(tombstones(&conn)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_conflict_winner() {
+        let existing = Change {
+            val: SqliteValue::Text("b".into()),
+            col_version: 2,
+            site_id: [0x80; 16],
+            cl: 1,
+            ..Default::default()
+        };
+        let incoming = |f: fn(&mut Change)| {
+            let mut change = existing.clone();
+            f(&mut change);
+            conflict_winner(&existing, &change)
+        };
+
+        // equal col_version and value, the greater site id wins
+        assert_eq!(
+            incoming(|c| c.site_id = [0xff; 16]),
+            (ConflictWinner::Incoming, ConflictReason::SiteId)
+        );
+        assert_eq!(
+            incoming(|c| c.site_id = [0x00; 16]),
+            (ConflictWinner::Existing, ConflictReason::SiteId)
+        );
+        assert_eq!(
+            incoming(|_| {}),
+            (ConflictWinner::Existing, ConflictReason::SiteId)
+        );
+
+        // the causal length goes first, even over a higher col_version
+        assert_eq!(
+            incoming(|c| {
+                c.cl = 3;
+                c.col_version = 1;
+                c.site_id = [0x00; 16];
+            }),
+            (ConflictWinner::Incoming, ConflictReason::Cl)
         );
+        let resurrected = Change {
+            cl: 3,
+            ..existing.clone()
+        };
+        assert_eq!(
+            conflict_winner(
+                &resurrected,
+                &Change {
+                    col_version: 10,
+                    site_id: [0xff; 16],
+                    ..existing.clone()
+                }
+            ),
+            (ConflictWinner::Existing, ConflictReason::Cl)
+        );
+
+        // then col_version, then the value
+        assert_eq!(
+            incoming(|c| c.col_version = 1),
+            (ConflictWinner::Existing, ConflictReason::ColVersion)
+        );
+        assert_eq!(
+            incoming(|c| {
+                c.val = SqliteValue::Text("c".into());
+                c.site_id = [0x00; 16];
+            }),
+            (ConflictWinner::Incoming, ConflictReason::Value)
+        );
+        // by type code first: an integer loses to text, null wins over all
+        assert_eq!(
+            incoming(|c| c.val = SqliteValue::Integer(i64::MAX)),
+            (ConflictWinner::Existing, ConflictReason::Value)
+        );
+        assert_eq!(
+            incoming(|c| c.val = SqliteValue::Null),
+            (ConflictWinner::Incoming, ConflictReason::Value)
+        );
+    }
+
+    #[test]
+    fn test_conflict_winner_matches_crsqlite() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::agent::migrate;
+        use crate::sqlite::CrConn;
+
+        let cases: [(&str, i64, [u8; 16]); 5] = [
+            // equal col_version and cl, only the site id differs
+            ("b", 1, [0xff; 16]),
+            ("b", 1, [0x00; 16]),
+            // differing values
+            ("c", 1, [0x00; 16]),
+            ("a", 1, [0xff; 16]),
+            // differing cl
+            ("a", 3, [0x00; 16]),
+        ];
+
+        for (val, cl, site_id) in cases {
+            let mut conn = CrConn::init(Connection::open_in_memory()?)?;
+            migrate(Arc::new(uhlc::HLC::default()), &mut conn)?;
+            conn.execute_batch(
+                "CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, text TEXT);
+                SELECT crsql_as_crr('foo');
+                INSERT INTO foo (id, text) VALUES (1, 'b');",
+            )?;
+
+            let read = |conn: &Connection| {
+                conn.query_row(
+                    r#"SELECT "table", pk, cid, val, col_version, db_version, seq, site_id, cl
+                        FROM crsql_changes WHERE cid = 'text'"#,
+                    [],
+                    row_to_change,
+                )
+            };
+            let existing = read(&conn)?;
+            let incoming = Change {
+                val: SqliteValue::Text(val.into()),
+                site_id,
+                cl,
+                ..existing.clone()
+            };
+            let (expected, reason) = conflict_winner(&existing, &incoming);
+
+            conn.execute(
+                r#"INSERT INTO crsql_changes ("table", pk, cid, val, col_version, db_version, site_id, cl, seq, ts)
+                    VALUES ('foo', ?, 'text', ?, ?, 1, ?, ?, 0, '0')"#,
+                rusqlite::params![incoming.pk, val, incoming.col_version, &site_id[..], cl],
+            )?;
+
+            let stored = read(&conn)?;
+            let kept = if stored.site_id == site_id {
+                ConflictWinner::Incoming
+            } else {
+                ConflictWinner::Existing
+            };
+            assert_eq!(
+                kept, expected,
+                "value {val}, cl {cl}, site id {site_id:?}: expected to be decided by {reason:?}"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_priority_chunks() {
+        let changes: Vec<Change> = (0..10)
+            .map(|seq| Change {
+                table: TableName::from(if seq % 2 == 0 { "bulk" } else { "config" }),
+                seq: CrsqlSeq(seq),
+                ..Default::default()
+            })
+            .collect();
+        let change_size = changes[0].estimated_byte_size();
+
+        let priority = TablePriority::new([(TableName::from("config"), 10)]);
+        let chunks: Vec<_> = priority.chunk(changes.clone(), change_size * 2).collect();
+
+        // byte limits are respected
+        assert_eq!(chunks.len(), 5);
+        assert!(chunks.iter().all(|(chunk, _)| chunk.len() == 2));
 
+        let emitted: Vec<Change> = chunks.iter().flat_map(|(chunk, _)| chunk.clone()).collect();
+        assert!(emitted[..5]
+            .iter()
+            .all(|change| change.table.as_str() == "config"));
+        assert!(emitted[5..]
+            .iter()
+            .all(|change| change.table.as_str() == "bulk"));
         assert_eq!(
-            chunker.next(),
-            Some(Ok((
-                vec![changes[0].clone(), changes[1].clone()],
-                dbsr!(0, 1)
-            )))
+            emitted.iter().map(|change| change.seq).collect::<Vec<_>>()[..5],
+            [
+                CrsqlSeq(1),
+                CrsqlSeq(3),
+                CrsqlSeq(5),
+                CrsqlSeq(7),
+                CrsqlSeq(9)
+            ]
         );
+
+        assert_eq!(chunks[0].1, vec![dbsr!(1, 1), dbsr!(3, 3)]);
+
+        // equal priorities keep the seq order
+        let chunks: Vec<_> = TablePriority::default()
+            .chunk(changes.clone(), usize::MAX)
+            .collect();
+        assert_eq!(chunks, vec![(changes, vec![dbsr!(0, 9)])]);
+    }
+
+    #[test]
+    fn test_table_weights_interleave() {
+        let bulk = TableName::from("bulk");
+        let config = TableName::from("config");
+        let weights = TableWeights::new([(config.clone(), 3)]);
+        assert_eq!(weights.weight([&bulk]), 1);
+        assert_eq!(weights.weight([&bulk, &config]), 3);
+        assert_eq!(weights.weight([]), 1);
+
+        // versions 0..20 only change bulk, the next ones config
+        let versions: Vec<(u64, u32)> = (0..40)
+            .map(|version| {
+                let table = if version < 20 { &bulk } else { &config };
+                (version, weights.weight([table]))
+            })
+            .collect();
+        let sent: Vec<u64> = weights.interleave(versions).collect();
+        assert_eq!(sent.len(), 40);
+
+        let positions = |table: &str| -> Vec<usize> {
+            sent.iter()
+                .enumerate()
+                .filter(|(_, version)| (**version < 20) == (table == "bulk"))
+                .map(|(i, _)| i)
+                .collect()
+        };
+        let avg = |positions: &[usize]| positions.iter().sum::<usize>() as f64 / 20.0;
+
+        let config = positions("config");
+        let bulk = positions("bulk");
+        assert_eq!(config.len(), 20);
+        assert_eq!(bulk.len(), 20);
+
+        // config goes first on average, bulk isn't starved
+        assert!(avg(&config) < avg(&bulk));
+        assert_eq!(config[0], 0);
+        assert!(bulk[0] < 4);
+        // each class keeps its order
+        assert!(sent.iter().filter(|v| **v < 20).is_sorted());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_credit_limited_changes() {
+        let changes: Vec<Change> = (0..4)
+            .map(|seq| Change {
+                seq: CrsqlSeq(seq),
+                ..Default::default()
+            })
+            .collect();
+
+        let credits = Arc::new(Credits::new(2));
+        let mut stream = CreditLimitedChanges::new(changes.clone().into_iter(), credits.clone());
+
+        assert_eq!(stream.next().await.as_ref(), Some(&changes[0]));
+        assert_eq!(stream.next().await.as_ref(), Some(&changes[1]));
+        assert_eq!(stream.credits(), 0);
+
+        // out of credits, the stream stalls until the receiver grants more
+        let stalled = tokio::time::timeout(Duration::from_secs(1), stream.next()).await;
+        assert!(stalled.is_err());
+
+        let granter = tokio::spawn({
+            let credits = credits.clone();
+            async move {
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                credits.grant(5);
+            }
+        });
+
+        let start = Instant::now();
+        assert_eq!(stream.next().await.as_ref(), Some(&changes[2]));
+        assert!(start.elapsed() >= Duration::from_secs(5));
+        granter.await.unwrap();
+
+        assert_eq!(stream.next().await.as_ref(), Some(&changes[3]));
+        assert_eq!(stream.next().await, None);
+        // exhausting the stream doesn't consume a credit
+        assert_eq!(stream.credits(), 3);
+    }
+
+    #[test]
+    fn test_required_predecessors() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::agent::migrate;
+        use crate::base::dbvri;
+        use crate::sqlite::CrConn;
+        use rangemap::range_inclusive_set;
+
+        let mut conn = CrConn::init(Connection::open_in_memory()?)?;
+        migrate(Arc::new(uhlc::HLC::default()), &mut conn)?;
+
+        let mut booked = BookedVersions::new(ActorId::default());
+        let mut snap = booked.snapshot();
+        snap.insert_db(&conn, range_inclusive_set![dbvri!(1, 2)])?;
+        booked.commit_snapshot(snap);
+
+        let at = |db_version: u64| Change {
+            db_version: CrsqlDbVersion(db_version),
+            ..Default::default()
+        };
+
+        assert_eq!(required_predecessors(&[at(5)], &booked), vec![dbvri!(3, 4)]);
+        // versions in the batch don't need to be applied beforehand
         assert_eq!(
-            chunker.next(),
-            Some(Ok((vec![changes[2].clone()], dbsr!(2, 100))))
+            required_predecessors(&[at(5), at(4)], &booked),
+            vec![dbvri!(3, 3)]
         );
-        assert_eq!(chunker.next(), None);
+        assert!(required_predecessors(&[at(3)], &booked).is_empty());
+        assert!(required_predecessors(&[], &booked).is_empty());
 
-        let mut chunker = ChunkedChanges::new(
-            vec![Ok(changes[0].clone()), Ok(changes[1].clone())].into_iter(),
-            CrsqlSeq(0),
-            CrsqlSeq(0),
-            changes[0].estimated_byte_size(),
+        // known gaps are required too
+        let mut snap = booked.snapshot();
+        snap.insert_db(&conn, range_inclusive_set![dbvri!(6, 7)])?;
+        booked.commit_snapshot(snap);
+
+        assert_eq!(
+            required_predecessors(&[at(10)], &booked),
+            vec![dbvri!(3, 5), dbvri!(8, 9)]
         );
 
+        Ok(())
+    }
+
+    #[test]
+    fn test_interned_changes() {
+        let site_ids = [
+            ActorId(uuid::Uuid::new_v4()).to_bytes(),
+            ActorId(uuid::Uuid::new_v4()).to_bytes(),
+        ];
+        let changes: Vec<Change> = (0..100)
+            .map(|seq| Change {
+                table: TableName::from(["foo", "bar"][seq as usize % 2]),
+                pk: vec![1, 2, seq as u8],
+                cid: ColumnName::from(["text", "num", "other"][seq as usize % 3]),
+                val: SqliteValue::Integer(seq),
+                db_version: CrsqlDbVersion(1),
+                seq: CrsqlSeq(seq as u64),
+                site_id: site_ids[seq as usize / 50],
+                ..Default::default()
+            })
+            .collect();
+
+        let plain = changes.write_to_vec().unwrap();
+        let interned = InternedChanges::from(changes.clone());
         assert_eq!(
-            chunker.next(),
-            Some(Ok((vec![changes[0].clone()], dbsr!(0, 0))))
+            interned.tables,
+            vec![TableName::from("foo"), TableName::from("bar")]
         );
-        assert_eq!(chunker.next(), None);
+        assert_eq!(interned.columns.len(), 3);
+        assert_eq!(interned.site_ids, site_ids.to_vec());
+        let encoded = interned.write_to_vec().unwrap();
 
-        // gaps
-        let mut chunker = ChunkedChanges::new(
-            vec![Ok(changes[0].clone()), Ok(changes[2].clone())].into_iter(),
-            CrsqlSeq(0),
-            CrsqlSeq(100),
-            changes[0].estimated_byte_size() + changes[2].estimated_byte_size(),
+        // names behind a u32 length and a 16 bytes site_id per change, down
+        // to three 4 bytes indexes
+        assert!(
+            plain.len() - encoded.len() >= 100 * (4 + 3 + 4 + 3 + 16 - 12) - 100,
+            "{} vs {}",
+            encoded.len(),
+            plain.len()
         );
 
+        let decoded = InternedChanges::read_from_buffer(&encoded).unwrap();
+        assert_eq!(Vec::<Change>::try_from(decoded).unwrap(), changes);
+
+        // every kind of index is checked
+        let corruptions: [fn(&mut InternedChange); 3] = [
+            |change| change.table = 2,
+            |change| change.cid = 3,
+            |change| change.site_idx = 2,
+        ];
+        for corrupt in corruptions {
+            let mut bad = interned.clone();
+            corrupt(&mut bad.changes[0]);
+            assert!(bad.clone().into_changes().is_err());
+            assert!(decode_changes(&bad.write_to_vec().unwrap()).is_err());
+        }
+    }
+
+    fn fixture_changes(count: i64) -> Vec<Change> {
+        (0..count)
+            .map(|seq| Change {
+                table: TableName::from("foo"),
+                pk: vec![1, 2, seq as u8],
+                cid: ColumnName::from("text"),
+                val: SqliteValue::Text(format!("value {seq}").into()),
+                col_version: 1,
+                db_version: CrsqlDbVersion(1),
+                seq: CrsqlSeq(seq as u64),
+                site_id: [7; 16],
+                cl: 1,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_encode_decode_changes() {
+        let changes = fixture_changes(50);
+        let encoded = encode_changes(&changes).unwrap();
+        // same layout as an `InternedChanges`
         assert_eq!(
-            chunker.next(),
-            Some(Ok((
-                vec![changes[0].clone(), changes[2].clone()],
-                dbsr!(0, 100)
-            )))
+            encoded,
+            InternedChanges::from(changes.clone())
+                .write_to_vec()
+                .unwrap()
+        );
+        assert_eq!(decode_changes(&encoded).unwrap(), changes);
+        assert_eq!(
+            decode_changes(&encode_changes(&[]).unwrap()).unwrap(),
+            vec![]
         );
 
-        assert_eq!(chunker.next(), None);
+        // every truncation errors out instead of panicking
+        let mut rng = rand::thread_rng();
+        for _ in 0..500 {
+            let len = rand::Rng::gen_range(&mut rng, 0..encoded.len());
+            assert!(decode_changes(&encoded[..len]).is_err());
+        }
 
-        // gaps
-        let mut chunker = ChunkedChanges::new(
-            vec![
-                Ok(changes[2].clone()),
-                Ok(changes[4].clone()),
-                Ok(changes[7].clone()),
-                Ok(changes[8].clone()),
-            ]
-            .into_iter(),
-            CrsqlSeq(0),
-            CrsqlSeq(100),
-            100000, // just send them all!
+        // a bogus count doesn't allocate for it
+        let dictionaries = InternedChanges {
+            changes: vec![],
+            ..InternedChanges::from(changes)
+        }
+        .write_to_vec()
+        .unwrap();
+        let count_at = dictionaries.len() - 4;
+        let mut bogus = encoded.clone();
+        bogus[count_at..count_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(decode_changes(&bogus).is_err());
+    }
+
+    // cargo test -p corro-types --release -- --ignored bench_decode_changes --nocapture
+    #[test]
+    #[ignore]
+    fn bench_decode_changes() {
+        let changes = fixture_changes(10_000);
+        let encoded = encode_changes(&changes).unwrap();
+        let one_by_one: Vec<Vec<u8>> = changes
+            .iter()
+            .map(|change| change.write_to_vec().unwrap())
+            .collect();
+
+        let start = std::time::Instant::now();
+        for _ in 0..20 {
+            assert_eq!(decode_changes(&encoded).unwrap().len(), changes.len());
+        }
+        let chunked = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for _ in 0..20 {
+            let decoded: Vec<Change> = one_by_one
+                .iter()
+                .map(|buf| Change::read_from_buffer(buf).unwrap())
+                .collect();
+            assert_eq!(decoded.len(), changes.len());
+        }
+        let individually = start.elapsed();
+
+        println!("decode_changes: {chunked:?}, one by one: {individually:?}");
+        // a single buffer for the chunk, no bigger than the changes apart
+        assert!(encoded.len() <= one_by_one.iter().map(Vec::len).sum::<usize>());
+        // and a single pass over it, not slower than a decode per change
+        assert!(
+            chunked < individually * 2,
+            "{chunked:?} vs {individually:?}"
+        );
+    }
+
+    // cargo test -p corro-types --release -- --ignored bench_interned_changes --nocapture
+    //
+    // interning only shrinks the wire encoding: decoded chunks are flat
+    // changes again, so in-flight memory is the same either way
+    #[test]
+    #[ignore]
+    fn bench_interned_changes() {
+        // a single-table 50k-change chunk, alternating between two columns
+        let changes: Vec<Change> = (0..50_000)
+            .map(|i| Change {
+                table: TableName::from("machines_instances_status"),
+                pk: (i as u64).to_be_bytes().to_vec(),
+                cid: ColumnName::from(["state", "updated_at"][i % 2]),
+                val: SqliteValue::Text(format!("value {i}").into()),
+                col_version: 1,
+                db_version: CrsqlDbVersion(1),
+                seq: CrsqlSeq(i as u64),
+                site_id: [7; 16],
+                cl: 1,
+            })
+            .collect();
+
+        let start = std::time::Instant::now();
+        let flat = changes.write_to_vec().unwrap();
+        let flat_encoding = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let interned = encode_changes(&changes).unwrap();
+        let interned_encoding = start.elapsed();
+
+        let start = std::time::Instant::now();
+        let decoded = decode_changes(&interned).unwrap();
+        let interned_decoding = start.elapsed();
+        assert_eq!(decoded, changes);
+
+        println!(
+            "encoded: {} -> {} bytes, encoding: {flat_encoding:?} -> {interned_encoding:?}, decoding: {interned_decoding:?}",
+            flat.len(),
+            interned.len()
         );
+        // 56 bytes of fixed fields per change instead of ~100
+        assert!(interned.len() * 10 < flat.len() * 7);
+    }
+
+    #[test]
+    fn test_tier_changes() {
+        let changes: Vec<Change> = [3, 7, 5, 1, 6, 4]
+            .into_iter()
+            .map(|db_version| Change {
+                db_version: CrsqlDbVersion(db_version),
+                ..Default::default()
+            })
+            .collect();
+
+        let (recent, historical) = tier_changes(changes.into_iter(), CrsqlDbVersion(5));
+        let versions =
+            |changes: &[Change]| changes.iter().map(|c| c.db_version.0).collect::<Vec<_>>();
+        assert_eq!(versions(&recent), vec![7, 5, 6]);
+        assert_eq!(versions(&historical), vec![3, 1, 4]);
+    }
+
+    #[test]
+    fn test_merkle_diff() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::agent::migrate;
+        use crate::sqlite::CrConn;
 
+        let mut conn = CrConn::init(Connection::open_in_memory()?)?;
+        migrate(Arc::new(uhlc::HLC::default()), &mut conn)?;
+
+        // the same version buffered for two actors, one seq differs
+        let ours = ActorId(uuid::Uuid::new_v4());
+        let theirs = ActorId(uuid::Uuid::new_v4());
+        for (site_id, changed_seq) in [(ours, None), (theirs, Some(5))] {
+            for mut change in fixture_changes(8) {
+                if Some(change.seq.0) == changed_seq {
+                    change.val = SqliteValue::Text("changed".into());
+                }
+                conn.execute(
+                    r#"INSERT INTO __corro_buffered_changes ("table", pk, cid, val, col_version, db_version, site_id, seq, cl, ts)
+                        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, '0')"#,
+                    rusqlite::params![
+                        change.table,
+                        change.pk,
+                        change.cid,
+                        change.val,
+                        change.col_version,
+                        change.db_version,
+                        site_id,
+                        change.seq,
+                        change.cl
+                    ],
+                )?;
+            }
+        }
+
+        let tree = |site_id| build_merkle(&conn, site_id, CrsqlDbVersion(1), dbsr!(0, 7), 2);
+        let a = tree(ours)?;
+        let b = tree(theirs)?;
+
+        assert_eq!(a.root.seqs, dbsr!(0, 7));
+        assert_eq!(a.root.children.len(), 2);
+        assert_eq!(a, tree(ours)?);
+        assert!(a.diff(&tree(ours)?).is_empty());
+
+        assert_ne!(a.root_hash(), b.root_hash());
+        assert_eq!(a.diff(&b), vec![dbsr!(5, 5)]);
+        assert_eq!(b.diff(&a), vec![dbsr!(5, 5)]);
+
+        // a seq missing on one side mismatches too, adjacent leaves get merged
+        conn.execute(
+            "DELETE FROM __corro_buffered_changes WHERE site_id = ? AND seq IN (6, 7)",
+            [theirs],
+        )?;
+        assert_eq!(a.diff(&tree(theirs)?), vec![dbsr!(5, 7)]);
+
+        // a peer can't make us allocate a leaf per seq of a huge range
+        assert!(matches!(
+            build_merkle(&conn, ours, CrsqlDbVersion(1), dbsr!(0, u64::MAX - 1), 2),
+            Err(ChangeError::MerkleRangeTooLarge { .. })
+        ));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_chunker_metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let changes: Vec<Change> = (0..10)
+            .map(|seq| Change {
+                seq: CrsqlSeq(seq),
+                ..Default::default()
+            })
+            .collect();
+        let change_size = changes[0].estimated_byte_size();
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+
+        let chunks = metrics::with_local_recorder(&recorder, || {
+            // 3 changes per chunk over 10 changes
+            ChunkedChanges::new(
+                changes.clone().into_iter().map(Ok),
+                CrsqlSeq(0),
+                CrsqlSeq(9),
+                change_size * 3,
+            )
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+        });
+        assert_eq!(chunks.len(), 4);
+
+        let snapshot = snapshotter.snapshot().into_hashmap();
+        let value = |name: &str| {
+            snapshot
+                .iter()
+                .find(|(key, _)| key.key().name() == name)
+                .map(|(_, (_, _, value))| value.clone())
+                .unwrap()
+        };
+
+        let DebugValue::Histogram(counts) = value("corro.chunk.changes") else {
+            panic!("expected a histogram");
+        };
+        let counts: Vec<f64> = counts.into_iter().map(|v| v.into_inner()).collect();
+        assert_eq!(counts, vec![3.0, 3.0, 3.0, 1.0]);
+
+        let DebugValue::Histogram(bytes) = value("corro.chunk.bytes") else {
+            panic!("expected a histogram");
+        };
+        assert_eq!(bytes.len(), 4);
+        assert_eq!(bytes[0].into_inner(), (change_size * 3) as f64);
+
+        assert_eq!(value("corro.chunk.versions.total"), DebugValue::Counter(1));
+    }
+
+    #[test]
+    fn test_validate_changes() -> Result<(), Box<dyn std::error::Error>> {
+        use crate::agent::migrate;
+        use crate::sqlite::CrConn;
+
+        let mut conn = CrConn::init(Connection::open_in_memory()?)?;
+        migrate(Arc::new(uhlc::HLC::default()), &mut conn)?;
+        conn.execute_batch(
+            "CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, n INTEGER NOT NULL DEFAULT 1 CHECK (n > 0));
+            SELECT crsql_as_crr('foo');",
+        )?;
+
+        let site_id = ActorId(uuid::Uuid::new_v4());
+        let change = |id: i64, version: u64, n: i64| -> rusqlite::Result<Change> {
+            Ok(Change {
+                table: TableName::from("foo"),
+                pk: conn.query_row("SELECT crsql_pack_columns(?)", [id], |row| row.get(0))?,
+                cid: ColumnName::from("n"),
+                val: SqliteValue::Integer(n),
+                col_version: 1,
+                db_version: CrsqlDbVersion(version),
+                seq: CrsqlSeq(0),
+                site_id: site_id.to_bytes(),
+                cl: 1,
+            })
+        };
+
+        // the second one violates the CHECK constraint
+        let changes = vec![change(1, 1, 10)?, change(2, 2, -1)?];
+
+        let tx = conn.transaction()?;
+        let report = validate_changes(&tx, &changes)?;
+        assert!(!report.is_valid());
+        assert_eq!(report.results.len(), 2);
+        assert_eq!(report.results[0], Ok(()));
+        let failures: Vec<_> = report.failures().collect();
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, 1);
+
+        // nothing was applied, the transaction is still usable
+        let count: i64 = tx.query_row("SELECT COUNT(*) FROM foo", [], |row| row.get(0))?;
+        assert_eq!(count, 0);
+        let count: i64 = tx.query_row(
+            "SELECT COUNT(*) FROM crsql_changes WHERE site_id = ?",
+            [site_id],
+            |row| row.get(0),
+        )?;
+        assert_eq!(count, 0);
+        tx.commit()?;
+
+        let report = validate_changes(&conn, &changes[..1])?;
+        assert!(report.is_valid());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_recent_changes_evicts_by_bytes() {
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let ts = Timestamp::default();
+        let change = |version: u64, seq, len| Change {
+            pk: version.to_be_bytes().to_vec(),
+            db_version: CrsqlDbVersion(version),
+            seq: CrsqlSeq(seq),
+            val: SqliteValue::Blob(vec![0u8; len].into()),
+            ..Default::default()
+        };
+        let big = change(1, 0, 1024);
+        let max_bytes = big.estimated_byte_size() * 2;
+        let recent = RecentChanges::new(actor_id, max_bytes);
+
+        // version 1 in 2 chunks
+        let v1 = vec![big.clone(), change(1, 1, 1024)];
+        recent.insert(CrsqlDbVersion(1), dbsr!(0, 0), &v1[..1], CrsqlSeq(1), ts);
+        assert_eq!(recent.get(actor_id, CrsqlDbVersion(1)), None);
+        recent.insert(CrsqlDbVersion(1), dbsr!(1, 1), &v1[1..], CrsqlSeq(1), ts);
         assert_eq!(
-            chunker.next(),
-            Some(Ok((
-                vec![
-                    changes[2].clone(),
-                    changes[4].clone(),
-                    changes[7].clone(),
-                    changes[8].clone()
-                ],
-                dbsr!(0, 100)
-            )))
+            recent.get(actor_id, CrsqlDbVersion(1)),
+            Some(RecentVersion {
+                changes: v1.clone(),
+                last_seq: CrsqlSeq(1),
+                ts
+            })
+        );
+        assert_eq!(
+            recent.get(ActorId(uuid::Uuid::new_v4()), CrsqlDbVersion(1)),
+            None
         );
 
-        assert_eq!(chunker.next(), None);
+        // many small versions fit in the space of the oldest chunk
+        for version in 2..=4 {
+            recent.insert(
+                CrsqlDbVersion(version),
+                dbsr!(0, 0),
+                &[change(version, 0, 10)],
+                CrsqlSeq(0),
+                ts,
+            );
+        }
+        assert!(recent.bytes() <= max_bytes);
+        // first chunk evicted, the version is incomplete
+        assert_eq!(recent.get(actor_id, CrsqlDbVersion(1)), None);
+        for version in 2..=4 {
+            assert!(recent.get(actor_id, CrsqlDbVersion(version)).is_some());
+        }
 
-        // gaps
-        let mut chunker = ChunkedChanges::new(
-            vec![
-                Ok(changes[2].clone()),
-                Ok(changes[4].clone()),
-                Ok(changes[7].clone()),
-                Ok(changes[8].clone()),
-            ]
-            .into_iter(),
+        // larger than the whole cache
+        recent.insert(
+            CrsqlDbVersion(5),
+            dbsr!(0, 0),
+            &[change(5, 0, max_bytes)],
             CrsqlSeq(0),
-            CrsqlSeq(10),
-            changes[2].estimated_byte_size() + changes[4].estimated_byte_size(),
+            ts,
+        );
+        assert_eq!(recent.get(actor_id, CrsqlDbVersion(5)), None);
+        assert!(recent.get(actor_id, CrsqlDbVersion(4)).is_some());
+
+        // disabled
+        let recent = RecentChanges::new(actor_id, 0);
+        recent.insert(CrsqlDbVersion(1), dbsr!(0, 0), &v1[..1], CrsqlSeq(0), ts);
+        assert_eq!(recent.get(actor_id, CrsqlDbVersion(1)), None);
+        assert_eq!(recent.bytes(), 0);
+    }
+
+    #[test]
+    fn test_recent_changes_invalidated_by_newer_changes() {
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let ts = Timestamp::default();
+        let change = |version, pk: u8| Change {
+            pk: vec![pk],
+            db_version: CrsqlDbVersion(version),
+            ..Default::default()
+        };
+        let recent = RecentChanges::new(actor_id, 1024 * 1024);
+
+        recent.insert(
+            CrsqlDbVersion(1),
+            dbsr!(0, 1),
+            &[change(1, 1), change(1, 2)],
+            CrsqlSeq(1),
+            ts,
+        );
+        recent.insert(
+            CrsqlDbVersion(2),
+            dbsr!(0, 0),
+            &[change(2, 3)],
+            CrsqlSeq(0),
+            ts,
         );
+        assert!(recent.get(actor_id, CrsqlDbVersion(1)).is_some());
+
+        // a local write to one of version 1's rows
+        recent.insert(
+            CrsqlDbVersion(3),
+            dbsr!(0, 0),
+            &[change(3, 2)],
+            CrsqlSeq(0),
+            ts,
+        );
+        assert_eq!(recent.get(actor_id, CrsqlDbVersion(1)), None);
+        assert!(recent.get(actor_id, CrsqlDbVersion(2)).is_some());
+        assert!(recent.get(actor_id, CrsqlDbVersion(3)).is_some());
+
+        // a change applied from another actor
+        recent.invalidate(&[change(10, 3)]);
+        assert_eq!(recent.get(actor_id, CrsqlDbVersion(2)), None);
+        assert!(recent.get(actor_id, CrsqlDbVersion(3)).is_some());
 
+        recent.invalidate(&[change(11, 2)]);
+        assert_eq!(recent.bytes(), 0);
+    }
+
+    #[test]
+    fn test_decode_pk() {
+        use crate::{pubsub::pack_columns, schema::parse_sql};
+
+        let schema = parse_sql(
+            "
+            CREATE TABLE composite (
+                id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                value TEXT,
+                PRIMARY KEY (id, name)
+            );
+            CREATE TABLE single (id INTEGER NOT NULL PRIMARY KEY, value TEXT);
+        ",
+        )
+        .unwrap();
+        let composite = &schema.tables["composite"];
+
+        let pk = vec![SqliteValue::Integer(42), SqliteValue::Text("foo".into())];
+        let change = Change {
+            table: TableName("composite".into()),
+            pk: pack_columns(&pk).unwrap(),
+            cid: ColumnName("value".into()),
+            ..Default::default()
+        };
+        assert_eq!(change.decode_pk(composite).unwrap(), pk);
+        assert_eq!(change.decode_pk_in(&schema).unwrap(), pk);
+
+        let change = Change {
+            table: TableName("single".into()),
+            pk: pack_columns(&[SqliteValue::Integer(-1)]).unwrap(),
+            ..Default::default()
+        };
         assert_eq!(
-            chunker.next(),
-            Some(Ok((
-                vec![changes[2].clone(), changes[4].clone(),],
-                dbsr!(0, 4)
-            )))
+            change.decode_pk_in(&schema).unwrap(),
+            vec![SqliteValue::Integer(-1)]
+        );
+        assert!(matches!(
+            change.decode_pk(composite),
+            Err(DecodePkError::TableMismatch { .. })
+        ));
+
+        // 1 value for a 2 columns pk
+        let change = Change {
+            table: TableName("composite".into()),
+            ..change
+        };
+        assert!(matches!(
+            change.decode_pk(composite),
+            Err(DecodePkError::ColumnCount {
+                expected: 2,
+                got: 1,
+                ..
+            })
+        ));
+
+        let change = Change {
+            table: TableName("nope".into()),
+            ..change
+        };
+        assert!(matches!(
+            change.decode_pk_in(&schema),
+            Err(DecodePkError::UnknownTable(_))
+        ));
+
+        // truncated
+        let mut pk = pack_columns(&pk).unwrap();
+        pk.truncate(pk.len() - 1);
+        let change = Change {
+            table: TableName("composite".into()),
+            pk,
+            ..Default::default()
+        };
+        assert!(matches!(
+            change.decode_pk(composite),
+            Err(DecodePkError::Unpack(_))
+        ));
+        let change = Change {
+            pk: vec![],
+            ..change
+        };
+        assert!(change.decode_pk(composite).is_err());
+    }
+
+    #[test]
+    fn test_quarantine() {
+        use crate::{base::CrsqlSeqRange, broadcast::Changeset, schema::parse_sql};
+
+        let schema =
+            parse_sql("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, a TEXT);").unwrap();
+        let altered =
+            parse_sql("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, a TEXT, b TEXT);")
+                .unwrap();
+
+        let change = |cid: &str| Change {
+            table: TableName("foo".into()),
+            cid: ColumnName(cid.into()),
+            ..Default::default()
+        };
+        assert_eq!(change("a").schema_mismatch(&schema), None);
+        assert_eq!(change("-1").schema_mismatch(&schema), None);
+        assert_eq!(
+            change("b").schema_mismatch(&schema),
+            Some(SchemaMismatch::UnknownColumn {
+                table: TableName("foo".into()),
+                column: ColumnName("b".into()),
+            })
         );
+        assert_eq!(change("b").schema_mismatch(&altered), None);
+        let unknown = Change {
+            table: TableName("bar".into()),
+            ..change("a")
+        };
+        assert_eq!(
+            unknown.schema_mismatch(&altered),
+            Some(SchemaMismatch::UnknownTable(TableName("bar".into())))
+        );
+
+        let actor_id = ActorId::default();
+        let changeset = |version: u64, changes: Vec<Change>| ChangeV1 {
+            actor_id,
+            changeset: Changeset::Full {
+                version: CrsqlDbVersion(version),
+                changes,
+                seqs: CrsqlSeqRange::empty(),
+                last_seq: CrsqlSeq(0),
+                ts: Default::default(),
+            },
+        };
+        let mismatch = |c: &ChangeV1| c.changes()[0].schema_mismatch(&schema).unwrap();
+
+        let quarantine = Quarantine::new(2);
+        let column = changeset(1, vec![change("b")]);
+        let table = changeset(2, vec![unknown.clone()]);
+        quarantine
+            .push(column.clone(), ChangeSource::Sync, mismatch(&column))
+            .unwrap();
+        // parked once
+        quarantine
+            .push(column.clone(), ChangeSource::Broadcast, mismatch(&column))
+            .unwrap();
+        quarantine
+            .push(table.clone(), ChangeSource::Sync, mismatch(&table))
+            .unwrap();
+        assert_eq!(quarantine.len(), 2);
 
+        // full
+        let other = changeset(3, vec![change("b")]);
         assert_eq!(
-            chunker.next(),
-            Some(Ok((
-                vec![changes[7].clone(), changes[8].clone(),],
-                dbsr!(5, 10)
-            )))
+            quarantine.push(other.clone(), ChangeSource::Sync, mismatch(&other)),
+            Err(other)
         );
 
-        assert_eq!(chunker.next(), None);
+        assert!(quarantine.take_ready(&schema).is_empty());
+        let ready = quarantine.take_ready(&altered);
+        assert_eq!(ready.len(), 1);
+        assert_eq!(ready[0].change, column);
+        assert_eq!(quarantine.len(), 1);
+
+        assert!(quarantine.take_expired(Duration::from_secs(60)).is_empty());
+        let expired = quarantine.take_expired(Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].change, table);
+        assert!(quarantine.is_empty());
+    }
+
+    #[test]
+    fn test_chunk_retries_per_version() {
+        let retries = ChunkRetries::new(3);
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let first = (
+            actor_id,
+            CrsqlDbVersionRange::single(CrsqlDbVersion(1)),
+            Some(dbsr!(0, 9)),
+        );
+        let second = (
+            actor_id,
+            CrsqlDbVersionRange::single(CrsqlDbVersion(1)),
+            Some(dbsr!(0, 4)),
+        );
+        let other = (
+            actor_id,
+            CrsqlDbVersionRange::single(CrsqlDbVersion(2)),
+            Some(dbsr!(0, 9)),
+        );
+
+        // however the version is chunked, its failures add up
+        assert!(!retries.record_failure(&first));
+        assert!(!retries.record_failure(&second));
+        assert!(retries.is_last_attempt(&first));
+        assert!(!retries.record_failure(&other));
+        assert_eq!(retries.failures(&second), 2);
+
+        // and are dropped once the version is booked
+        retries.booked(actor_id, dbvr!(1, 1));
+        assert_eq!(retries.failures(&first), 0);
+        assert_eq!(retries.failures(&other), 1);
+        retries.booked(actor_id, dbvr!(2, 5));
+        assert!(retries.inner.lock().failures.is_empty());
+
+        // quarantining a chunk skips every chunk of its version
+        retries.quarantine(first);
+        assert!(retries.is_quarantined(&second));
+        assert!(!retries.is_quarantined(&other));
+        retries.release(&second);
+        assert!(!retries.is_quarantined(&first));
+    }
+
+    #[test]
+    fn test_chunk_retries_bounded() {
+        let mut retries = ChunkRetries::new(3);
+        retries.max_tracked = 2;
+        let actor_id = ActorId(uuid::Uuid::new_v4());
+        let key = |version| {
+            (
+                actor_id,
+                CrsqlDbVersionRange::single(CrsqlDbVersion(version)),
+                None,
+            )
+        };
+
+        retries.record_failure(&key(1));
+        std::thread::sleep(Duration::from_millis(1));
+        retries.record_failure(&key(2));
+        retries.record_failure(&key(2));
+        // version 1 never gets booked, its failure is the oldest
+        retries.record_failure(&key(3));
+        assert_eq!(retries.inner.lock().failures.len(), 2);
+        assert_eq!(retries.failures(&key(1)), 0);
+        assert_eq!(retries.failures(&key(2)), 2);
+        assert_eq!(retries.failures(&key(3)), 1);
     }
 }
@@ -1,8 +1,19 @@
-use std::net::{Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    net::{IpAddr, Ipv6Addr, SocketAddr, SocketAddrV6},
+    str::FromStr,
+    time::Duration,
+};
 
 use camino::Utf8PathBuf;
+use corro_api_types::{ColumnName, TableName};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use serde_with::{formats::PreferOne, serde_as, OneOrMany};
+use serde_with::{formats::PreferOne, serde_as, DeserializeFromStr, OneOrMany, SerializeDisplay};
+use uuid::Uuid;
+
+use crate::actor::ActorId;
 
 pub const DEFAULT_GOSSIP_PORT: u16 = 4001;
 const DEFAULT_GOSSIP_IDLE_TIMEOUT: u32 = 30;
@@ -21,6 +32,68 @@ const fn default_wal_threshold() -> usize {
     5 * 1024
 }
 
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 100 * 1_024 * 1_024;
+
+const fn default_max_frame_bytes() -> usize {
+    DEFAULT_MAX_FRAME_BYTES
+}
+
+pub const DEFAULT_READ_POOL_SIZE: usize = 20;
+
+const fn default_read_pool_size() -> usize {
+    DEFAULT_READ_POOL_SIZE
+}
+
+pub const DEFAULT_RECENT_CHANGES_CACHE_BYTES: usize = 8 * 1_024 * 1_024;
+
+const fn default_recent_changes_cache_bytes() -> usize {
+    DEFAULT_RECENT_CHANGES_CACHE_BYTES
+}
+
+const fn default_max_partial_versions() -> usize {
+    1024
+}
+
+const fn default_partial_version_timeout() -> usize {
+    10
+}
+
+const fn default_max_quarantined_changes() -> usize {
+    1024
+}
+
+const fn default_quarantine_timeout() -> usize {
+    300
+}
+
+const fn default_max_chunk_attempts() -> u32 {
+    5
+}
+
+const fn default_max_inbound_syncs() -> usize {
+    3
+}
+
+const fn default_max_outbound_syncs() -> usize {
+    10
+}
+
+const fn default_anti_entropy_interval() -> u32 {
+    600
+}
+
+const fn default_sync_chunk_min_bytes() -> usize {
+    1024
+}
+
+const fn default_sync_chunk_max_bytes() -> usize {
+    64 * 1024
+}
+
+const fn default_sync_chunk_target_ms() -> u64 {
+    500
+}
+
 const fn default_processing_queue() -> usize {
     20000
 }
@@ -121,6 +194,81 @@ pub struct DbConfig {
     pub schema_paths: Vec<Utf8PathBuf>,
     #[serde(default)]
     pub subscriptions_path: Option<Utf8PathBuf>,
+    /// Columns never sent to peers, by table. Peers never learn about these
+    /// columns' values so they intentionally diverge from ours.
+    #[serde(default)]
+    pub excluded_columns: ExcludedColumns,
+    #[serde(default)]
+    pub retention: RetentionConfig,
+    /// Derives this node's actor id when its database is created, instead
+    /// of a random one. Must be unique in the cluster.
+    #[serde(default)]
+    pub node_name: Option<String>,
+    #[serde(default)]
+    pub seq_mode: SeqMode,
+    /// Largest text or blob value, in bytes, written locally or decoded from
+    /// peers. Unlimited by default.
+    #[serde(default)]
+    pub max_value_bytes: Option<usize>,
+    /// Tests only: reads the clock of every column a remote change touches
+    /// to check cr-sqlite kept the expected side of conflicts.
+    #[serde(skip)]
+    pub check_conflict_winners: bool,
+}
+
+/// How seqs of the changes received from peers are checked before they're
+/// applied and booked
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SeqMode {
+    /// Seqs missing from a chunk are tolerated, e.g. overwritten within
+    /// their version or excluded by the sender
+    #[default]
+    Lenient,
+    /// Chunks missing seqs within their range are rejected instead of booked
+    /// as applied, and changes are applied in seq order
+    Strict,
+}
+
+/// Changes compaction keeps even once every peer has acknowledged them, for
+/// nodes rejoining after an outage or debugging
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetentionConfig {
+    /// Seconds, changes more recent than this are kept
+    #[serde(default)]
+    pub min_retain_duration: u64,
+    /// Latest versions of each actor kept
+    #[serde(default)]
+    pub min_retain_versions: u64,
+}
+
+/// `(table, column)` pairs left out of the changes sent to peers
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ExcludedColumns(HashMap<TableName, HashSet<ColumnName>>);
+
+impl ExcludedColumns {
+    pub fn new(columns: impl IntoIterator<Item = (TableName, ColumnName)>) -> Self {
+        let mut excluded = Self::default();
+        for (table, column) in columns {
+            excluded.0.entry(table).or_default().insert(column);
+        }
+        excluded
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Row inserts and deletes (the sentinel column) are never excluded,
+    /// peers would keep rows deleted here forever
+    pub fn excludes(&self, table: &TableName, column: &ColumnName) -> bool {
+        !column.is_crsql_sentinel()
+            && self
+                .0
+                .get(table)
+                .is_some_and(|columns| columns.contains(column))
+    }
 }
 
 impl DbConfig {
@@ -148,6 +296,54 @@ pub struct ApiConfig {
     #[serde_as(deserialize_as = "Option<OneOrMany<_, PreferOne>>")]
     #[serde(default)]
     pub pg: Option<Vec<PgConfig>>,
+    /// Maximum number of concurrent subscriptions a single client connection
+    /// can register, unlimited if unset
+    #[serde(default)]
+    pub max_subscriptions_per_conn: Option<usize>,
+    /// Interval between heartbeat frames on subscription streams, 0 disables them
+    #[serde(default)]
+    pub subscription_heartbeat_secs: u64,
+    /// Statements per second accepted by the transactions endpoint, past
+    /// which writes are refused until the bucket refills. Unlimited if unset.
+    #[serde(default)]
+    pub write_ops_per_sec: Option<u32>,
+    /// Like `write_ops_per_sec`, for the estimated size of the statements
+    /// and their parameters.
+    #[serde(default)]
+    pub write_bytes_per_sec: Option<u32>,
+    #[serde(default)]
+    pub health: HealthConfig,
+}
+
+/// Readiness thresholds of the `/v1/health` endpoint
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthConfig {
+    /// Most versions we can be missing, across actors, and still be ready
+    #[serde(default = "default_health_max_gaps")]
+    pub max_gaps: u64,
+    /// Fewest gossip members, besides us, to be ready
+    #[serde(default)]
+    pub min_members: usize,
+}
+
+const fn default_health_max_gaps() -> u64 {
+    100
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        Self {
+            max_gaps: default_health_max_gaps(),
+            min_members: 0,
+        }
+    }
+}
+
+impl ApiConfig {
+    pub fn subscription_heartbeat(&self) -> Option<Duration> {
+        (self.subscription_heartbeat_secs > 0)
+            .then(|| Duration::from_secs(self.subscription_heartbeat_secs))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -195,6 +391,90 @@ pub struct GossipConfig {
     pub idle_timeout_secs: u32,
     #[serde(default)]
     pub disable_gso: bool,
+    #[serde(default)]
+    pub peer_access: PeerAccessConfig,
+}
+
+/// Peers this node syncs with, checked when a sync starts. Deny entries
+/// take precedence, when there are allow entries a peer has to match one.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeerAccessConfig {
+    #[serde(default)]
+    pub allow: Vec<PeerMatcher>,
+    #[serde(default)]
+    pub deny: Vec<PeerMatcher>,
+}
+
+impl PeerAccessConfig {
+    pub fn check(&self, actor_id: ActorId, ip: IpAddr) -> Result<(), PeerRejection> {
+        self.check_addr(ip, &[actor_id])
+    }
+
+    /// Checks a peer only known by its address, e.g. the sender of a
+    /// broadcast stream, as any of the `actor_ids` gossiping from it.
+    pub fn check_addr(&self, ip: IpAddr, actor_ids: &[ActorId]) -> Result<(), PeerRejection> {
+        let ip = ip.to_canonical();
+        let matches = |m: &PeerMatcher| match m {
+            PeerMatcher::Actor(id) => actor_ids.contains(id),
+            PeerMatcher::Cidr(net) => net.contains(&ip),
+        };
+        if self.deny.iter().any(matches) {
+            return Err(PeerRejection::Denied);
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(matches) {
+            return Err(PeerRejection::NotAllowed);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error, strum::IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum PeerRejection {
+    #[error("peer is denied")]
+    Denied,
+    #[error("peer is not in the allow list")]
+    NotAllowed,
+}
+
+/// An actor id, an address or a range of addresses, as written in the config
+#[derive(Debug, Clone, Copy, PartialEq, Eq, SerializeDisplay, DeserializeFromStr)]
+pub enum PeerMatcher {
+    Actor(ActorId),
+    Cidr(IpNet),
+}
+
+impl fmt::Display for PeerMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerMatcher::Actor(id) => id.fmt(f),
+            PeerMatcher::Cidr(net) => net.fmt(f),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid peer '{0}', expected an actor id, an address or a CIDR range")]
+pub struct InvalidPeerMatcher(String);
+
+impl FromStr for PeerMatcher {
+    type Err = InvalidPeerMatcher;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(id) = Uuid::from_str(s) {
+            return Ok(PeerMatcher::Actor(ActorId(id)));
+        }
+        if let Ok(net) = IpNet::from_str(s) {
+            return Ok(PeerMatcher::Cidr(net));
+        }
+        if let Ok(ip) = IpAddr::from_str(s) {
+            let prefix_len = if ip.is_ipv4() { 32 } else { 128 };
+            if let Ok(net) = IpNet::new(ip, prefix_len) {
+                return Ok(PeerMatcher::Cidr(net));
+            }
+        }
+        Err(InvalidPeerMatcher(s.to_owned()))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -217,12 +497,65 @@ pub struct PerfConfig {
     pub bcast_channel_len: usize,
     #[serde(default = "default_small_channel")]
     pub foca_channel_len: usize,
+    /// Broadcast changes read from peers but not yet queued for processing,
+    /// past which reading from their streams is paused.
+    #[serde(default = "default_huge_channel")]
+    pub broadcast_ingress_len: usize,
     #[serde(default = "default_apply_timeout")]
     pub apply_queue_timeout: usize,
     #[serde(default = "default_apply_queue")]
     pub apply_queue_len: usize,
+    /// Estimated bytes of changes after which a batch is applied, on top of
+    /// `apply_queue_len`. Unbounded by default.
+    #[serde(default)]
+    pub apply_queue_bytes: Option<usize>,
+    /// WAL size over which it gets checkpointed, in the `truncate` mode only
     #[serde(default = "default_wal_threshold")]
     pub wal_threshold_mb: usize,
+    #[serde(default)]
+    pub wal_checkpoint_mode: WalCheckpointMode,
+    /// Seconds after which the WAL gets checkpointed whatever its size. Only
+    /// `wal_threshold_mb` triggers checkpoints by default.
+    #[serde(default)]
+    pub wal_checkpoint_interval: Option<usize>,
+    /// Longest a checkpoint waits on readers, in milliseconds. Applies wait
+    /// on the checkpoint, so this bounds how long they're held back. By
+    /// default the wait grows with the WAL size.
+    #[serde(default)]
+    pub wal_checkpoint_max_block_ms: Option<u64>,
+    /// Largest sync frame or HTTP request body accepted, checked before
+    /// allocating a buffer for it.
+    #[serde(default = "default_max_frame_bytes")]
+    pub max_frame_bytes: usize,
+    /// Read-only connections serving queries and subscriptions, separate
+    /// from the single write connection.
+    #[serde(default = "default_read_pool_size")]
+    pub read_pool_size: usize,
+    /// Total size of the recently broadcast local changes kept in memory to
+    /// serve sync requests, 0 disables the cache.
+    #[serde(default = "default_recent_changes_cache_bytes")]
+    pub recent_changes_cache_bytes: usize,
+    /// Incomplete versions buffered per actor while waiting for their
+    /// missing chunks, chunks of further versions are dropped and left to
+    /// sync.
+    #[serde(default = "default_max_partial_versions")]
+    pub max_partial_versions: usize,
+    /// Seconds an incomplete version can go without receiving a chunk
+    /// before its missing chunks are requested with a sync.
+    #[serde(default = "default_partial_version_timeout")]
+    pub partial_version_timeout: usize,
+    /// Changesets parked because they reference tables or columns missing
+    /// from the local schema, further ones are dropped and left to sync.
+    #[serde(default = "default_max_quarantined_changes")]
+    pub max_quarantined_changes: usize,
+    /// Seconds a changeset can stay parked waiting for the schema before
+    /// it's reported as an error and dropped.
+    #[serde(default = "default_quarantine_timeout")]
+    pub quarantine_timeout: usize,
+    /// Times a chunk can fail to apply before it's quarantined and skipped
+    /// until requeued through the admin.
+    #[serde(default = "default_max_chunk_attempts")]
+    pub max_chunk_attempts: u32,
     #[serde(default = "default_processing_queue")]
     pub processing_queue_len: usize,
     #[serde(default = "default_sql_tx_timeout")]
@@ -231,6 +564,33 @@ pub struct PerfConfig {
     pub min_sync_backoff: u32,
     #[serde(default = "default_max_sync_backoff")]
     pub max_sync_backoff: u32,
+    /// Sync sessions served at once, syncs past the limit are rejected and
+    /// the peer backs off.
+    #[serde(default = "default_max_inbound_syncs")]
+    pub max_inbound_syncs: usize,
+    /// Sync sessions started at once, syncs past the limit wait for a
+    /// session to end.
+    #[serde(default = "default_max_outbound_syncs")]
+    pub max_outbound_syncs: usize,
+    /// Seconds between anti-entropy passes, comparing digests of the versions
+    /// booked and applied with a peer and syncing again the ones that
+    /// differ. 0 disables them.
+    #[serde(default = "default_anti_entropy_interval")]
+    pub anti_entropy_interval: u32,
+    /// Relative weight of tables when serving sync, the versions of heavier
+    /// tables are sent more often. Unlisted tables have a weight of 1.
+    #[serde(default)]
+    pub sync_table_weights: HashMap<TableName, u32>,
+    /// Bounds of the size of the chunks synced to a peer, tuned per peer
+    /// from how quickly it accepts them.
+    #[serde(default = "default_sync_chunk_min_bytes")]
+    pub sync_chunk_min_bytes: usize,
+    #[serde(default = "default_sync_chunk_max_bytes")]
+    pub sync_chunk_max_bytes: usize,
+    /// Milliseconds a peer can take to accept a synced chunk before chunks
+    /// sent to it are made smaller.
+    #[serde(default = "default_sync_chunk_target_ms")]
+    pub sync_chunk_target_ms: u64,
 }
 
 impl Default for PerfConfig {
@@ -245,13 +605,62 @@ impl Default for PerfConfig {
             clearbuf_channel_len: default_mid_channel(),
             bcast_channel_len: default_mid_channel(),
             foca_channel_len: default_small_channel(),
+            broadcast_ingress_len: default_huge_channel(),
             apply_queue_timeout: default_apply_timeout(),
             apply_queue_len: default_apply_queue(),
+            apply_queue_bytes: None,
             wal_threshold_mb: default_wal_threshold(),
+            wal_checkpoint_mode: WalCheckpointMode::default(),
+            wal_checkpoint_interval: None,
+            wal_checkpoint_max_block_ms: None,
+            max_frame_bytes: default_max_frame_bytes(),
+            read_pool_size: default_read_pool_size(),
+            recent_changes_cache_bytes: default_recent_changes_cache_bytes(),
+            max_partial_versions: default_max_partial_versions(),
+            partial_version_timeout: default_partial_version_timeout(),
+            max_quarantined_changes: default_max_quarantined_changes(),
+            quarantine_timeout: default_quarantine_timeout(),
+            max_chunk_attempts: default_max_chunk_attempts(),
             processing_queue_len: default_processing_queue(),
             sql_tx_timeout: default_sql_tx_timeout(),
             min_sync_backoff: default_min_sync_backoff(),
             max_sync_backoff: default_max_sync_backoff(),
+            max_inbound_syncs: default_max_inbound_syncs(),
+            max_outbound_syncs: default_max_outbound_syncs(),
+            anti_entropy_interval: default_anti_entropy_interval(),
+            sync_table_weights: HashMap::new(),
+            sync_chunk_min_bytes: default_sync_chunk_min_bytes(),
+            sync_chunk_max_bytes: default_sync_chunk_max_bytes(),
+            sync_chunk_target_ms: default_sync_chunk_target_ms(),
+        }
+    }
+}
+
+/// Mode of the `PRAGMA wal_checkpoint` run by the WAL maintenance task
+#[derive(
+    Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, strum::IntoStaticStr,
+)]
+#[serde(rename_all = "lowercase")]
+#[strum(serialize_all = "lowercase")]
+pub enum WalCheckpointMode {
+    /// Checkpoints as many frames as possible without waiting on readers
+    #[serde(alias = "PASSIVE")]
+    Passive,
+    /// Waits on readers to checkpoint every frame
+    #[serde(alias = "FULL")]
+    Full,
+    /// Like `Full`, then truncates the WAL file
+    #[default]
+    #[serde(alias = "TRUNCATE")]
+    Truncate,
+}
+
+impl WalCheckpointMode {
+    pub fn as_pragma_arg(&self) -> &'static str {
+        match self {
+            WalCheckpointMode::Passive => "PASSIVE",
+            WalCheckpointMode::Full => "FULL",
+            WalCheckpointMode::Truncate => "TRUNCATE",
         }
     }
 }
@@ -304,6 +713,10 @@ pub struct LogConfig {
     pub format: LogFormat,
     #[serde(default = "default_as_true")]
     pub colors: bool,
+    /// Log, rate-limited, which change wins when a remote change conflicts
+    /// with another actor's value for the same column
+    #[serde(default)]
+    pub conflicts: bool,
 }
 
 fn default_as_true() -> bool {
@@ -314,6 +727,8 @@ fn default_as_true() -> bool {
 pub enum ConfigError {
     #[error(transparent)]
     Config(#[from] config::ConfigError),
+    #[error("perf.read_pool_size must be at least 1")]
+    NoReadConnections,
 }
 
 impl Config {
@@ -328,7 +743,17 @@ impl Config {
             .add_source(config::File::new(config_path, config::FileFormat::Toml))
             .add_source(config::Environment::default().separator("__"))
             .build()?;
-        Ok(config.try_deserialize()?)
+        let config: Config = config.try_deserialize()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Rejects settings that deserialize fine but the agent can't run with.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.perf.read_pool_size == 0 {
+            return Err(ConfigError::NoReadConnections);
+        }
+        Ok(())
     }
 }
 
@@ -343,10 +768,20 @@ pub struct ConfigBuilder {
     bootstrap: Option<Vec<String>>,
     log: Option<LogConfig>,
     schema_paths: Vec<Utf8PathBuf>,
+    excluded_columns: Vec<(TableName, ColumnName)>,
+    node_name: Option<String>,
+    seq_mode: Option<SeqMode>,
+    max_value_bytes: Option<usize>,
+    check_conflict_winners: bool,
     max_change_size: Option<i64>,
     consul: Option<ConsulConfig>,
     tls: Option<TlsConfig>,
     perf: Option<PerfConfig>,
+    max_subscriptions_per_conn: Option<usize>,
+    subscription_heartbeat_secs: Option<u64>,
+    write_ops_per_sec: Option<u32>,
+    write_bytes_per_sec: Option<u32>,
+    health: Option<HealthConfig>,
 }
 
 impl ConfigBuilder {
@@ -385,6 +820,35 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn exclude_column<T: Into<TableName>, C: Into<ColumnName>>(
+        mut self,
+        table: T,
+        column: C,
+    ) -> Self {
+        self.excluded_columns.push((table.into(), column.into()));
+        self
+    }
+
+    pub fn node_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.node_name = Some(name.into());
+        self
+    }
+
+    pub fn seq_mode(mut self, mode: SeqMode) -> Self {
+        self.seq_mode = Some(mode);
+        self
+    }
+
+    pub fn max_value_bytes(mut self, bytes: usize) -> Self {
+        self.max_value_bytes = Some(bytes);
+        self
+    }
+
+    pub fn check_conflict_winners(mut self, check: bool) -> Self {
+        self.check_conflict_winners = check;
+        self
+    }
+
     pub fn admin_path<S: Into<Utf8PathBuf>>(mut self, path: S) -> Self {
         self.admin_path = Some(path.into());
         self
@@ -405,6 +869,36 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn perf(mut self, perf: PerfConfig) -> Self {
+        self.perf = Some(perf);
+        self
+    }
+
+    pub fn max_subscriptions_per_conn(mut self, max: usize) -> Self {
+        self.max_subscriptions_per_conn = Some(max);
+        self
+    }
+
+    pub fn subscription_heartbeat_secs(mut self, secs: u64) -> Self {
+        self.subscription_heartbeat_secs = Some(secs);
+        self
+    }
+
+    pub fn write_ops_per_sec(mut self, ops: u32) -> Self {
+        self.write_ops_per_sec = Some(ops);
+        self
+    }
+
+    pub fn write_bytes_per_sec(mut self, bytes: u32) -> Self {
+        self.write_bytes_per_sec = Some(bytes);
+        self
+    }
+
+    pub fn health(mut self, health: HealthConfig) -> Self {
+        self.health = Some(health);
+        self
+    }
+
     pub fn build(self) -> Result<Config, ConfigBuilderError> {
         let db_path = self.db_path.ok_or(ConfigBuilderError::DbPathRequired)?;
 
@@ -424,11 +918,22 @@ impl ConfigBuilder {
                 path: db_path,
                 schema_paths: self.schema_paths,
                 subscriptions_path: None,
+                excluded_columns: ExcludedColumns::new(self.excluded_columns),
+                retention: Default::default(),
+                node_name: self.node_name,
+                seq_mode: self.seq_mode.unwrap_or_default(),
+                max_value_bytes: self.max_value_bytes,
+                check_conflict_winners: self.check_conflict_winners,
             },
             api: ApiConfig {
                 bind_addr: self.api_addr,
                 authorization: None,
                 pg: None,
+                max_subscriptions_per_conn: self.max_subscriptions_per_conn,
+                subscription_heartbeat_secs: self.subscription_heartbeat_secs.unwrap_or_default(),
+                write_ops_per_sec: self.write_ops_per_sec,
+                write_bytes_per_sec: self.write_bytes_per_sec,
+                health: self.health.unwrap_or_default(),
             },
             gossip: GossipConfig {
                 bind_addr: self
@@ -442,6 +947,7 @@ impl ConfigBuilder {
                 idle_timeout_secs: default_gossip_idle_timeout(),
                 max_mtu: None, // TODO: add a builder function for it
                 disable_gso: false,
+                peer_access: Default::default(),
             },
             perf: self.perf.unwrap_or_default(),
             admin: AdminConfig {
@@ -7,8 +7,10 @@ pub mod change;
 pub mod channel;
 pub mod config;
 pub mod members;
+pub mod observer;
 pub mod pubsub;
 pub mod schema;
+pub mod snapshot;
 pub mod sqlite;
 pub mod sync;
 pub mod tls;
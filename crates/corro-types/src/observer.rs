@@ -0,0 +1,291 @@
+//! Hooks for side effects of applied changes
+//!
+//! Observers are registered when the agent is set up and called with the
+//! changes of every version applied from a peer, once committed, on a
+//! thread of their own. They're also told about local versions whose
+//! timestamp had to be fabricated.
+
+use std::{
+    fmt,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use metrics::counter;
+use parking_lot::{Condvar, Mutex};
+use tracing::error;
+
+use crate::{base::CrsqlDbVersion, change::Change};
+
+pub trait ChangeObserver: Send + Sync + 'static {
+    /// Called with the changes of a version once it's committed, in apply
+    /// order. Blocking is fine, applies only wait on the observers once
+    /// [`OBSERVER_QUEUE_BYTES`] of changes are queued.
+    fn on_applied(&self, changes: &[Change]);
+
+    /// Called when a local version's changes had no timestamp and one was
+    /// made up on the spot, so they may be ordered wrongly against other
+    /// nodes' changes. It shouldn't happen, alerting on it is a good idea.
+    fn on_fabricated_timestamp(&self, _db_version: CrsqlDbVersion) {}
+}
+
+impl<F> ChangeObserver for F
+where
+    F: Fn(&[Change]) + Send + Sync + 'static,
+{
+    fn on_applied(&self, changes: &[Change]) {
+        self(changes)
+    }
+}
+
+/// Estimated bytes of applied changes waiting for the observers before
+/// applies block
+pub const OBSERVER_QUEUE_BYTES: usize = 64 * 1024 * 1024;
+
+#[derive(Default)]
+struct QueuedBytes {
+    bytes: Mutex<usize>,
+    drained: Condvar,
+}
+
+impl QueuedBytes {
+    /// Waits for `size` to fit under [`OBSERVER_QUEUE_BYTES`], a version
+    /// larger than that goes through once the queue is empty.
+    fn reserve(&self, size: usize) {
+        let mut bytes = self.bytes.lock();
+        while *bytes > 0 && *bytes + size > OBSERVER_QUEUE_BYTES {
+            self.drained.wait(&mut bytes);
+        }
+        *bytes += size;
+    }
+
+    fn release(&self, size: usize) {
+        let mut bytes = self.bytes.lock();
+        *bytes = bytes.saturating_sub(size);
+        self.drained.notify_all();
+    }
+}
+
+enum Queued {
+    Applied(Vec<Change>, usize),
+    FabricatedTimestamp(CrsqlDbVersion),
+}
+
+#[derive(Clone)]
+struct Queue {
+    tx: mpsc::Sender<Queued>,
+    queued: Arc<QueuedBytes>,
+}
+
+#[derive(Clone, Default)]
+pub struct ChangeObservers {
+    observers: Arc<[Arc<dyn ChangeObserver>]>,
+    queue: Option<Queue>,
+}
+
+impl ChangeObservers {
+    /// Starts the thread calling `observers`, it stops once every clone is
+    /// dropped.
+    pub fn new(observers: Vec<Arc<dyn ChangeObserver>>) -> Self {
+        let observers: Arc<[Arc<dyn ChangeObserver>]> = observers.into();
+        if observers.is_empty() {
+            return Self::default();
+        }
+
+        let (tx, rx) = mpsc::channel::<Queued>();
+        let queued = Arc::new(QueuedBytes::default());
+        let spawned = thread::Builder::new()
+            .name("corro-observers".into())
+            .spawn({
+                let observers = Self {
+                    observers: observers.clone(),
+                    queue: None,
+                };
+                let queued = queued.clone();
+                move || {
+                    while let Ok(msg) = rx.recv() {
+                        match msg {
+                            Queued::Applied(changes, size) => {
+                                observers.notify(&changes);
+                                queued.release(size);
+                            }
+                            Queued::FabricatedTimestamp(db_version) => {
+                                observers.notify_fabricated_timestamp(db_version);
+                            }
+                        }
+                    }
+                }
+            });
+        if let Err(e) = spawned {
+            error!("could not spawn the change observers thread, they'll be called inline: {e}");
+            return Self {
+                observers,
+                queue: None,
+            };
+        }
+
+        Self {
+            observers,
+            queue: Some(Queue { tx, queued }),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    /// Hands applied changes to the observers' thread, blocks while
+    /// [`OBSERVER_QUEUE_BYTES`] of changes are already waiting.
+    pub fn enqueue(&self, changes: Vec<Change>) {
+        if changes.is_empty() {
+            return;
+        }
+        match self.queue.as_ref() {
+            Some(queue) => {
+                let size = changes.iter().map(Change::estimated_byte_size).sum();
+                queue.queued.reserve(size);
+                if let Err(mpsc::SendError(Queued::Applied(changes, size))) =
+                    queue.tx.send(Queued::Applied(changes, size))
+                {
+                    // the thread is gone, only a panic outside of an
+                    // observer gets there
+                    queue.queued.release(size);
+                    self.notify(&changes);
+                }
+            }
+            None => self.notify(&changes),
+        }
+    }
+
+    /// Hands a fabricated timestamp to the observers' thread, so they're not
+    /// called from within the write transaction that needed it.
+    pub fn enqueue_fabricated_timestamp(&self, db_version: CrsqlDbVersion) {
+        match self.queue.as_ref() {
+            Some(queue) => {
+                if queue
+                    .tx
+                    .send(Queued::FabricatedTimestamp(db_version))
+                    .is_err()
+                {
+                    self.notify_fabricated_timestamp(db_version);
+                }
+            }
+            None => self.notify_fabricated_timestamp(db_version),
+        }
+    }
+
+    /// Calls every observer, a panicking observer is logged and the others
+    /// still get called.
+    pub fn notify(&self, changes: &[Change]) {
+        if changes.is_empty() {
+            return;
+        }
+        for observer in self.observers.iter() {
+            if catch_unwind(AssertUnwindSafe(|| observer.on_applied(changes))).is_err() {
+                counter!("corro.change.observer.panics").increment(1);
+                error!("change observer panicked on {} changes", changes.len());
+            }
+        }
+    }
+
+    /// Like [`ChangeObservers::notify`], for
+    /// [`ChangeObserver::on_fabricated_timestamp`]
+    pub fn notify_fabricated_timestamp(&self, db_version: CrsqlDbVersion) {
+        for observer in self.observers.iter() {
+            if catch_unwind(AssertUnwindSafe(|| {
+                observer.on_fabricated_timestamp(db_version)
+            }))
+            .is_err()
+            {
+                counter!("corro.change.observer.panics").increment(1);
+                error!("change observer panicked on fabricated timestamp for {db_version}");
+            }
+        }
+    }
+}
+
+impl fmt::Debug for ChangeObservers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ChangeObservers")
+            .field("len", &self.observers.len())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::base::CrsqlSeq;
+
+    #[test]
+    fn test_observer_panics_are_isolated() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counted = {
+            let calls = calls.clone();
+            move |changes: &[Change]| {
+                calls.fetch_add(changes.len(), Ordering::SeqCst);
+            }
+        };
+        let failing: Arc<dyn ChangeObserver> = Arc::new(|_: &[Change]| panic!("observer failure"));
+        let observers = ChangeObservers::new(vec![failing, Arc::new(counted)]);
+
+        observers.notify(&[Change::default(), Change::default()]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // nothing to notify about
+        observers.notify(&[]);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_queued_bytes_bound() {
+        let queued = Arc::new(QueuedBytes::default());
+
+        // over the limit alone, goes through on an empty queue
+        queued.reserve(OBSERVER_QUEUE_BYTES + 1);
+
+        let (tx, rx) = mpsc::channel();
+        let waiting = thread::spawn({
+            let queued = queued.clone();
+            move || {
+                queued.reserve(1);
+                tx.send(()).unwrap();
+            }
+        });
+        assert!(rx
+            .recv_timeout(std::time::Duration::from_millis(50))
+            .is_err());
+
+        queued.release(OBSERVER_QUEUE_BYTES + 1);
+        rx.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+        waiting.join().unwrap();
+        assert_eq!(*queued.bytes.lock(), 1);
+    }
+
+    #[test]
+    fn test_observers_called_in_order_off_thread() {
+        let (tx, rx) = mpsc::channel();
+        let recording = move |changes: &[Change]| {
+            tx.send((thread::current().id(), changes[0].seq)).unwrap();
+        };
+        let observers = ChangeObservers::new(vec![Arc::new(recording)]);
+
+        for seq in 0..10 {
+            observers.enqueue(vec![Change {
+                seq: CrsqlSeq(seq),
+                ..Default::default()
+            }]);
+        }
+        drop(observers);
+
+        let received: Vec<_> = rx.iter().collect();
+        assert!(received.iter().all(|(id, _)| *id != thread::current().id()));
+        assert_eq!(
+            received.into_iter().map(|(_, seq)| seq).collect::<Vec<_>>(),
+            (0..10).map(CrsqlSeq).collect::<Vec<_>>()
+        );
+    }
+}
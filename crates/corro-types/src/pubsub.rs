@@ -1,6 +1,7 @@
 use std::{
     cmp,
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
 };
@@ -15,7 +16,7 @@ use corro_api_types::{
 use enquote::unquote;
 use fallible_iterator::FallibleIterator;
 use indexmap::{IndexMap, IndexSet};
-use metrics::{counter, histogram};
+use metrics::{counter, gauge, histogram};
 use parking_lot::{Condvar, Mutex, RwLock};
 use rusqlite::{
     params_from_iter,
@@ -59,6 +60,7 @@ pub struct SubsManager(Arc<RwLock<InnerSubsManager>>);
 struct InnerSubsManager {
     handles: BTreeMap<Uuid, MatcherHandle>,
     queries: HashMap<String, Uuid>,
+    conn_subs: HashMap<SocketAddr, usize>,
 }
 
 // tools to bootstrap a new subscriber or notifier
@@ -198,6 +200,55 @@ impl SubsManager {
         let mut inner = self.0.write();
         inner.remove(id)
     }
+
+    /// Reserves a subscription slot for a client connection, the slot is
+    /// released when the returned guard is dropped.
+    pub fn acquire_conn_slot(
+        &self,
+        addr: SocketAddr,
+        max: Option<usize>,
+    ) -> Result<ConnSubGuard, MatcherError> {
+        let mut inner = self.0.write();
+        let count = inner.conn_subs.get(&addr).copied().unwrap_or(0);
+
+        if let Some(max) = max {
+            if count >= max {
+                counter!("corro.subscriptions.rejected", "reason" => "max_per_conn").increment(1);
+                return Err(MatcherError::TooManySubscriptions { max });
+            }
+        }
+
+        inner.conn_subs.insert(addr, count + 1);
+        gauge!("corro.active_subscriptions").increment(1.0);
+
+        Ok(ConnSubGuard {
+            subs: self.clone(),
+            addr,
+        })
+    }
+
+    pub fn conn_subs_count(&self, addr: &SocketAddr) -> usize {
+        self.0.read().conn_subs.get(addr).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Debug)]
+pub struct ConnSubGuard {
+    subs: SubsManager,
+    addr: SocketAddr,
+}
+
+impl Drop for ConnSubGuard {
+    fn drop(&mut self) {
+        let mut inner = self.subs.0.write();
+        if let Entry::Occupied(mut entry) = inner.conn_subs.entry(self.addr) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+        gauge!("corro.active_subscriptions").decrement(1.0);
+    }
 }
 
 #[derive(Debug)]
@@ -409,17 +460,17 @@ impl MatcherHandle {
         since: ChangeId,
         conn: &Connection,
         tx: mpsc::Sender<QueryEvent>,
-    ) -> rusqlite::Result<ChangeId> {
+    ) -> Result<ChangeId, MatcherError> {
         self.wait_for_running_state();
 
         let mut prepped = conn.prepare_cached("SELECT MIN(id) FROM changes")?;
-        let min_change_id: u64 = prepped.query_row([], |row| row.get(0))?;
+        let min_change_id: Option<ChangeId> = prepped.query_row([], |row| row.get(0))?;
 
         // return error if we've cleared changes after the received change id
-        if since.0 + 1 < min_change_id {
-            return Err(rusqlite::Error::ModuleError(format!(
-                "subscription already deleted older changes, min change id: {min_change_id}",
-            )));
+        if let Some(min_change_id) = min_change_id {
+            if since + 1 < min_change_id {
+                return Err(MatcherError::ChangesCompacted { min_change_id });
+            }
         }
 
         let mut query_cols = vec![];
@@ -515,6 +566,7 @@ impl MatcherHandle {
         tx.blocking_send(QueryEvent::EndOfQuery {
             time: elapsed.as_secs_f64(),
             change_id: Some(max_change_id),
+            cursor: None,
         })
         .map_err(|_| MatcherError::EventReceiverClosed)?;
 
@@ -1391,6 +1443,7 @@ impl Matcher {
                     .send(QueryEvent::EndOfQuery {
                         time: elapsed.as_secs_f64(),
                         change_id: Some(ChangeId(0)),
+                        cursor: None,
                     })
                     .await
                 {
@@ -1861,6 +1914,19 @@ fn insert_col(set: &mut HashSet<String>, schema: &Schema, tbl_name: &str, name:
     }
 }
 
+fn check_col_exists(schema: &Schema, tbl_name: &str, col_name: &str) -> Result<(), MatcherError> {
+    let col_name = unquote(col_name)
+        .ok()
+        .unwrap_or_else(|| col_name.to_owned());
+    match schema.tables.get(tbl_name) {
+        Some(tbl) if !tbl.columns.contains_key(&col_name) => Err(MatcherError::ColumnNotFound {
+            tbl_name: tbl_name.to_owned(),
+            col_name,
+        }),
+        _ => Ok(()),
+    }
+}
+
 fn extract_expr_columns(
     expr: &Expr,
     schema: &Schema,
@@ -1870,6 +1936,7 @@ fn extract_expr_columns(
         // simplest case
         Expr::Qualified(tblname, colname) => {
             let resolved_name = parsed.aliases.get(&tblname.0).unwrap_or(&tblname.0);
+            check_col_exists(schema, resolved_name, &colname.0)?;
             // println!("adding column: {resolved_name} => {colname:?}");
             insert_col(
                 parsed
@@ -1884,6 +1951,7 @@ fn extract_expr_columns(
         // simplest case but also mentioning the schema
         Expr::DoublyQualified(schema_name, tblname, colname) if schema_name.0 == "main" => {
             let resolved_name = parsed.aliases.get(&tblname.0).unwrap_or(&tblname.0);
+            check_col_exists(schema, resolved_name, &colname.0)?;
             // println!("adding column: {resolved_name} => {colname:?}");
             insert_col(
                 parsed
@@ -2167,6 +2235,8 @@ pub enum MatcherError {
     QualificationRequired { col_name: String },
     #[error("could not find table for column {col_name}")]
     TableForColumnNotFound { col_name: String },
+    #[error("column {col_name} does not exist in table {tbl_name}")]
+    ColumnNotFound { tbl_name: String, col_name: String },
     #[error("missing primary keys, this shouldn't happen")]
     MissingPrimaryKeys,
     #[error("change queue has been closed or is full")]
@@ -2195,6 +2265,10 @@ pub enum MatcherError {
     NotRunning,
     #[error("subscription restore is missing SQL query")]
     MissingSql,
+    #[error("subscription already deleted older changes, min change id: {min_change_id}")]
+    ChangesCompacted { min_change_id: ChangeId },
+    #[error("too many subscriptions for this connection (max: {max})")]
+    TooManySubscriptions { max: usize },
 }
 
 impl MatcherError {
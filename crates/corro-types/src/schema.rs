@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     fmt,
     time::{Instant, SystemTime},
 };
@@ -7,14 +7,18 @@ use std::{
 use enquote::unquote;
 use fallible_iterator::FallibleIterator;
 use indexmap::{IndexMap, IndexSet};
+use itertools::Itertools;
 use rusqlite::{Connection, Transaction};
 use serde::{Deserialize, Serialize};
+use speedy::{Readable, Writable};
 use sqlite3_parser::ast::{
     Cmd, ColumnConstraint, ColumnDefinition, CreateTableBody, Expr, Name, NamedTableConstraint,
     QualifiedName, SortedColumn, Stmt, TableConstraint, TableOptions, ToTokens,
 };
 use tracing::{debug, info, trace};
 
+use crate::api::{SqliteValue, TableDigest};
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Column {
     pub name: String,
@@ -95,6 +99,56 @@ impl fmt::Display for Table {
     }
 }
 
+/// Content hash of `table`, independent of row order. Only the columns of the
+/// table's schema are hashed, cr-sqlite's clocks and metadata aren't: nodes
+/// holding the same data get the same digest whatever history led there.
+pub fn table_digest(conn: &Connection, table: &Table) -> rusqlite::Result<TableDigest> {
+    // pk first, then in name order so declaration order doesn't matter
+    let mut columns: Vec<&str> = table
+        .columns
+        .keys()
+        .map(String::as_str)
+        .filter(|name| !table.pk.contains(*name) && !name.starts_with("__crsql"))
+        .collect();
+    columns.sort_unstable();
+    let columns: Vec<&str> = table.pk.iter().map(String::as_str).chain(columns).collect();
+
+    let quote = |name: &str| format!("\"{}\"", name.replace('"', "\"\""));
+    let mut prepped = conn.prepare(&format!(
+        "SELECT {} FROM {}",
+        columns.iter().map(|name| quote(name)).join(", "),
+        quote(&table.name)
+    ))?;
+
+    let mut digest = TableDigest {
+        table: table.name.clone(),
+        ..Default::default()
+    };
+    let mut rows = prepped.query([])?;
+    while let Some(row) = rows.next()? {
+        let mut pk_buf = vec![];
+        let mut row_buf = vec![];
+        for (i, name) in columns.iter().enumerate() {
+            let value = row
+                .get::<_, SqliteValue>(i)?
+                .write_to_vec()
+                .expect("encoding a value in memory should not fail");
+            if i < table.pk.len() {
+                pk_buf.extend_from_slice(&value);
+            }
+            row_buf.extend_from_slice(&(name.len() as u32).to_le_bytes());
+            row_buf.extend_from_slice(name.as_bytes());
+            row_buf.extend_from_slice(&value);
+        }
+
+        let row_hash = seahash::hash(&row_buf);
+        digest.hash ^= row_hash;
+        digest.rows.insert(seahash::hash(&pk_buf), row_hash);
+    }
+
+    Ok(digest)
+}
+
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Index {
     pub name: String,
@@ -110,6 +164,17 @@ pub struct Schema {
 }
 
 impl Schema {
+    /// Per-table hashes of the replicated schema, used to detect drift between nodes
+    pub fn digest(&self) -> SchemaDigest {
+        SchemaDigest {
+            tables: self
+                .tables
+                .iter()
+                .map(|(name, table)| (name.clone(), table.digest()))
+                .collect(),
+        }
+    }
+
     pub fn constrain(&mut self) -> Result<(), ConstrainedSchemaError> {
         self.tables.retain(|name, _table| {
             !(name.contains("crsql") && name.contains("sqlite") && name.starts_with("__corro"))
@@ -170,6 +235,63 @@ impl Schema {
     }
 }
 
+impl Table {
+    /// Stable hash of the table's logical definition: column names, types,
+    /// nullability, defaults and primary keys. Column order does not matter.
+    pub fn digest(&self) -> u64 {
+        let mut columns: Vec<&Column> = self.columns.values().collect();
+        columns.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let mut buf = String::new();
+        buf.push_str(&self.name);
+        for col in columns {
+            let (sql_type, declared) = col.sql_type();
+            buf.push_str(&format!(
+                "|{}:{sql_type:?}:{}:{}:{}:{}",
+                col.name,
+                declared.unwrap_or_default(),
+                col.nullable,
+                col.default_value.as_deref().unwrap_or_default(),
+                col.primary_key
+            ));
+        }
+        // primary key order is significant
+        buf.push_str(&format!(
+            "|pk:{}",
+            self.pk.iter().cloned().collect::<Vec<_>>().join(",")
+        ));
+
+        seahash::hash(buf.as_bytes())
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize, Readable, Writable)]
+pub struct SchemaDigest {
+    pub tables: BTreeMap<String, u64>,
+}
+
+impl SchemaDigest {
+    /// Hash of the whole schema, independent of table order
+    pub fn hash(&self) -> u64 {
+        let mut buf = Vec::with_capacity(self.tables.len() * 8);
+        for (name, hash) in self.tables.iter() {
+            buf.extend_from_slice(name.as_bytes());
+            buf.extend_from_slice(&hash.to_be_bytes());
+        }
+        seahash::hash(&buf)
+    }
+
+    /// Names of tables missing on either side or defined differently
+    pub fn mismatched_tables(&self, other: &SchemaDigest) -> BTreeSet<String> {
+        self.tables
+            .keys()
+            .chain(other.tables.keys())
+            .filter(|name| self.tables.get(*name) != other.tables.get(*name))
+            .cloned()
+            .collect()
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum SchemaError {
     #[error(transparent)]
@@ -903,3 +1025,43 @@ fn extract_expr_columns(expr: &Expr, cols: &mut Vec<String>) {
         _ => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schema_digest() -> Result<(), Box<SchemaError>> {
+        let schema = parse_sql(
+            "CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, a TEXT, b INTEGER);
+            CREATE TABLE bar (id INTEGER NOT NULL PRIMARY KEY);",
+        )?;
+
+        // same logical definition, different column and table order
+        let reordered = parse_sql(
+            "CREATE TABLE bar (id INTEGER NOT NULL PRIMARY KEY);
+            CREATE TABLE foo (b INTEGER, id INTEGER NOT NULL PRIMARY KEY, a TEXT);",
+        )?;
+        assert_eq!(schema.digest(), reordered.digest());
+        assert_eq!(schema.digest().hash(), reordered.digest().hash());
+
+        let changed_type = parse_sql(
+            "CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, a TEXT, b BLOB);
+            CREATE TABLE bar (id INTEGER NOT NULL PRIMARY KEY);",
+        )?;
+        assert_ne!(schema.digest().hash(), changed_type.digest().hash());
+        assert_eq!(
+            schema.digest().mismatched_tables(&changed_type.digest()),
+            BTreeSet::from(["foo".to_string()])
+        );
+
+        let missing_table =
+            parse_sql("CREATE TABLE foo (id INTEGER NOT NULL PRIMARY KEY, a TEXT, b INTEGER);")?;
+        assert_eq!(
+            missing_table.digest().mismatched_tables(&schema.digest()),
+            BTreeSet::from(["bar".to_string()])
+        );
+
+        Ok(())
+    }
+}
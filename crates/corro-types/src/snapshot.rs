@@ -0,0 +1,285 @@
+//! Portable change log files
+//!
+//! A change log holds complete versions as [`ChangeV1`]s, the same way they
+//! are sent during a sync, so a node can be seeded from a file instead of
+//! syncing everything from its peers.
+//!
+//! Layout: the [`CHANGE_LOG_MAGIC`], a format version byte and a flags byte,
+//! followed by the (optionally zstd-compressed) body. The body is a
+//! [`ChangeLogHeader`] and the changesets, each record speedy-encoded behind
+//! a u32 little-endian length, and ends with a zero length so a truncated
+//! file is an error rather than a shorter log.
+
+use std::io::{self, BufReader, Read, Write};
+
+use speedy::{Readable, Writable};
+
+use crate::{api::TableName, broadcast::ChangeV1};
+
+pub const CHANGE_LOG_MAGIC: &[u8; 8] = b"CORROLOG";
+pub const CHANGE_LOG_FORMAT_VERSION: u8 = 1;
+
+const FLAG_ZSTD: u8 = 1;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Readable, Writable)]
+pub struct ChangeLogHeader {
+    /// Tables the log was restricted to, `None` for the whole database. Only
+    /// whole database logs have complete versions.
+    pub tables: Option<Vec<TableName>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChangeLogError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Speedy(#[from] speedy::Error),
+    #[error("not a change log file")]
+    BadMagic,
+    #[error("unsupported change log format version {0}")]
+    UnsupportedVersion(u8),
+    #[error("change log record is {0} bytes, more than the remaining data")]
+    Truncated(u32),
+}
+
+enum Sink<W: Write> {
+    Plain(W),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Write for Sink<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Sink::Plain(w) => w.write(buf),
+            Sink::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Sink::Plain(w) => w.flush(),
+            Sink::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+pub struct ChangeLogWriter<W: Write> {
+    sink: Sink<W>,
+}
+
+impl<W: Write> ChangeLogWriter<W> {
+    pub fn new(mut w: W, header: &ChangeLogHeader, compress: bool) -> Result<Self, ChangeLogError> {
+        w.write_all(CHANGE_LOG_MAGIC)?;
+        w.write_all(&[
+            CHANGE_LOG_FORMAT_VERSION,
+            if compress { FLAG_ZSTD } else { 0 },
+        ])?;
+
+        let sink = if compress {
+            Sink::Zstd(zstd::Encoder::new(w, 0)?)
+        } else {
+            Sink::Plain(w)
+        };
+
+        let mut writer = Self { sink };
+        writer.write_record(&header.write_to_vec()?)?;
+        Ok(writer)
+    }
+
+    pub fn write(&mut self, change: &ChangeV1) -> Result<(), ChangeLogError> {
+        self.write_record(&change.write_to_vec()?)
+    }
+
+    fn write_record(&mut self, buf: &[u8]) -> Result<(), ChangeLogError> {
+        let len = u32::try_from(buf.len())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "record too large"))?;
+        self.sink.write_all(&len.to_le_bytes())?;
+        self.sink.write_all(buf)?;
+        Ok(())
+    }
+
+    /// Writes the end marker and finishes compression, returns the inner
+    /// writer flushed.
+    pub fn finish(mut self) -> Result<W, ChangeLogError> {
+        self.sink.write_all(&0u32.to_le_bytes())?;
+        let mut w = match self.sink {
+            Sink::Plain(w) => w,
+            Sink::Zstd(encoder) => encoder.finish()?,
+        };
+        w.flush()?;
+        Ok(w)
+    }
+}
+
+enum Source<R: Read> {
+    Plain(BufReader<R>),
+    Zstd(zstd::Decoder<'static, BufReader<R>>),
+}
+
+impl<R: Read> Read for Source<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Source::Plain(r) => r.read(buf),
+            Source::Zstd(r) => r.read(buf),
+        }
+    }
+}
+
+/// Reads the changesets of a change log, in the order they were written.
+pub struct ChangeLogReader<R: Read> {
+    source: Source<R>,
+    header: ChangeLogHeader,
+    done: bool,
+}
+
+impl<R: Read> ChangeLogReader<R> {
+    pub fn new(r: R) -> Result<Self, ChangeLogError> {
+        let mut r = BufReader::new(r);
+
+        let mut magic = [0u8; 8];
+        r.read_exact(&mut magic)?;
+        if &magic != CHANGE_LOG_MAGIC {
+            return Err(ChangeLogError::BadMagic);
+        }
+
+        let mut prelude = [0u8; 2];
+        r.read_exact(&mut prelude)?;
+        let [version, flags] = prelude;
+        if version != CHANGE_LOG_FORMAT_VERSION {
+            return Err(ChangeLogError::UnsupportedVersion(version));
+        }
+
+        let mut source = if flags & FLAG_ZSTD != 0 {
+            Source::Zstd(zstd::Decoder::with_buffer(r)?)
+        } else {
+            Source::Plain(r)
+        };
+
+        let header = match read_record(&mut source)? {
+            Some(buf) => ChangeLogHeader::read_from_buffer(&buf)?,
+            None => return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into()),
+        };
+
+        Ok(Self {
+            source,
+            header,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> &ChangeLogHeader {
+        &self.header
+    }
+}
+
+fn read_record<R: Read>(r: &mut R) -> Result<Option<Vec<u8>>, ChangeLogError> {
+    let mut len = [0u8; 4];
+    r.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len);
+    if len == 0 {
+        return Ok(None);
+    }
+
+    // don't trust the length for the allocation
+    let mut buf = vec![];
+    r.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len as usize {
+        return Err(ChangeLogError::Truncated(len));
+    }
+
+    Ok(Some(buf))
+}
+
+impl<R: Read> Iterator for ChangeLogReader<R> {
+    type Item = Result<ChangeV1, ChangeLogError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let res = read_record(&mut self.source).and_then(|buf| {
+            buf.map(|buf| ChangeV1::read_from_buffer(&buf).map_err(ChangeLogError::from))
+                .transpose()
+        });
+
+        match res {
+            Ok(Some(change)) => Some(Ok(change)),
+            Ok(None) => {
+                self.done = true;
+                None
+            }
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use corro_api_types::{ColumnName, SqliteValue};
+    use corro_base_types::{CrsqlDbVersion, CrsqlSeq, CrsqlSeqRange};
+
+    use super::*;
+    use crate::{
+        actor::ActorId,
+        broadcast::{Changeset, Timestamp},
+        change::Change,
+    };
+
+    #[test]
+    fn test_change_log_roundtrip() -> Result<(), ChangeLogError> {
+        let actor_id = ActorId::default();
+        let changes: Vec<ChangeV1> = (1..=3)
+            .map(|i| ChangeV1 {
+                actor_id,
+                changeset: Changeset::Full {
+                    version: CrsqlDbVersion(i),
+                    changes: vec![Change {
+                        table: TableName::from("tests"),
+                        pk: vec![1, 9, i as u8],
+                        cid: ColumnName::from("text"),
+                        val: SqliteValue::Text(format!("row {i}").into()),
+                        col_version: 1,
+                        db_version: CrsqlDbVersion(i),
+                        seq: CrsqlSeq(0),
+                        site_id: actor_id.to_bytes(),
+                        cl: 1,
+                    }],
+                    seqs: CrsqlSeqRange::single(CrsqlSeq(0)),
+                    last_seq: CrsqlSeq(0),
+                    ts: Timestamp::zero(),
+                },
+            })
+            .collect();
+
+        for compress in [false, true] {
+            let header = ChangeLogHeader {
+                tables: compress.then(|| vec![TableName::from("tests")]),
+            };
+            let mut writer = ChangeLogWriter::new(vec![], &header, compress)?;
+            for change in changes.iter() {
+                writer.write(change)?;
+            }
+            let buf = writer.finish()?;
+
+            let reader = ChangeLogReader::new(buf.as_slice())?;
+            assert_eq!(reader.header(), &header);
+            let read = reader.collect::<Result<Vec<_>, _>>()?;
+            assert_eq!(read, changes);
+
+            // cut before the end marker
+            let reader = ChangeLogReader::new(&buf[..buf.len() - 8])?;
+            assert!(reader.collect::<Result<Vec<_>, _>>().is_err());
+        }
+
+        assert!(matches!(
+            ChangeLogReader::new(&b"NOTALOG\0\x01\0"[..]),
+            Err(ChangeLogError::BadMagic)
+        ));
+
+        Ok(())
+    }
+}
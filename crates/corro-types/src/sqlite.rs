@@ -1,10 +1,16 @@
 use std::{
+    cell::Cell,
+    fmt,
     ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU64, Ordering},
     time::{Duration, Instant},
 };
 
+use metrics::counter;
 use once_cell::sync::Lazy;
-use rusqlite::{params, trace::TraceEventCodes, Connection, Transaction};
+use rusqlite::{
+    params, trace::TraceEventCodes, CachedStatement, Connection, StatementStatus, Transaction,
+};
 use sqlite_pool::{Committable, SqliteConn};
 use tempfile::TempDir;
 use tracing::{error, info, trace, warn};
@@ -60,22 +66,120 @@ pub fn rusqlite_to_crsqlite(mut conn: rusqlite::Connection) -> rusqlite::Result<
         }),
     );
 
-    Ok(CrConn(conn))
+    Ok(CrConn::wrap(conn))
+}
+
+/// Max number of prepared statements cached per connection.
+pub const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+// bumped on every schema change, connections flush their statement cache
+// the next time they use it
+static SCHEMA_GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// Invalidates the prepared statement cache of every connection.
+pub fn invalidate_statement_caches() {
+    SCHEMA_GENERATION.fetch_add(1, Ordering::AcqRel);
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct StatementCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+// Bookkeeping for rusqlite's statement cache, which does the caching
+#[derive(Debug, Default)]
+struct StatementCache {
+    generation: Cell<u64>,
+    stats: Cell<StatementCacheStats>,
+}
+
+/// `BEGIN` mode used when opening a write transaction.
+///
+/// Defaults to `Immediate`, which is what change application has always used.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TxIsolation {
+    /// Acquire locks lazily, on first read / write
+    Deferred,
+    /// Acquire the write lock upfront, readers are not blocked
+    #[default]
+    Immediate,
+    /// Acquire an exclusive lock upfront, readers are blocked unless in WAL mode
+    Exclusive,
+}
+
+impl From<TxIsolation> for rusqlite::TransactionBehavior {
+    fn from(isolation: TxIsolation) -> Self {
+        match isolation {
+            TxIsolation::Deferred => rusqlite::TransactionBehavior::Deferred,
+            TxIsolation::Immediate => rusqlite::TransactionBehavior::Immediate,
+            TxIsolation::Exclusive => rusqlite::TransactionBehavior::Exclusive,
+        }
+    }
 }
 
 #[derive(Debug)]
-pub struct CrConn(Connection);
+pub struct CrConn(Connection, StatementCache);
 
 impl CrConn {
     pub fn init(mut conn: Connection) -> Result<Self, rusqlite::Error> {
         init_cr_conn(&mut conn)?;
-        Ok(Self(conn))
+        Ok(Self::wrap(conn))
+    }
+
+    fn wrap(conn: Connection) -> Self {
+        conn.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        let cache = StatementCache {
+            generation: Cell::new(SCHEMA_GENERATION.load(Ordering::Acquire)),
+            ..Default::default()
+        };
+        Self(conn, cache)
+    }
+
+    /// Prepares a statement through the connection's bounded statement
+    /// cache, keyed by its SQL text.
+    pub fn prepare_cached_query(&self, sql: &str) -> rusqlite::Result<CachedStatement<'_>> {
+        let cache = &self.1;
+
+        let generation = SCHEMA_GENERATION.load(Ordering::Acquire);
+        if cache.generation.replace(generation) != generation {
+            trace!("schema changed, flushing prepared statement cache");
+            self.0.flush_prepared_statement_cache();
+        }
+
+        let stmt = self.0.prepare_cached(sql)?;
+
+        // statements come back from the cache reset after having run, a
+        // freshly prepared one never ran. asking the statement keeps the
+        // stats right whatever else uses or evicts from the cache.
+        let mut stats = cache.stats.get();
+        if stmt.get_status(StatementStatus::Run) > 0 {
+            stats.hits += 1;
+            counter!("corro.sqlite.statement_cache.hits").increment(1);
+        } else {
+            stats.misses += 1;
+            counter!("corro.sqlite.statement_cache.misses").increment(1);
+        }
+        cache.stats.set(stats);
+
+        Ok(stmt)
+    }
+
+    pub fn statement_cache_stats(&self) -> StatementCacheStats {
+        self.1.stats.get()
     }
 
     pub fn immediate_transaction(&mut self) -> rusqlite::Result<Transaction<'_>> {
         self.0
             .transaction_with_behavior(rusqlite::TransactionBehavior::Immediate)
     }
+
+    pub fn transaction_with_isolation(
+        &mut self,
+        isolation: TxIsolation,
+    ) -> rusqlite::Result<Transaction<'_>> {
+        self.0.transaction_with_behavior(isolation.into())
+    }
 }
 
 impl SqliteConn for CrConn {
@@ -138,6 +242,83 @@ fn init_cr_conn(conn: &mut Connection) -> Result<(), rusqlite::Error> {
     Ok(())
 }
 
+/// cr-sqlite version the way it records it, e.g. `160000` for 0.16.0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CrsqlVersion(pub i64);
+
+impl fmt::Display for CrsqlVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}.{}.{}",
+            self.0 / 1_000_000,
+            self.0 / 10_000 % 100,
+            self.0 / 100 % 100
+        )
+    }
+}
+
+/// Oldest cr-sqlite version we work with, 0.17 stores timestamps as TEXT
+/// in clock tables
+pub const CRSQL_MIN_VERSION: CrsqlVersion = CrsqlVersion(170000);
+/// First cr-sqlite version with breaking changes
+pub const CRSQL_BREAKING_VERSION: CrsqlVersion = CrsqlVersion(180000);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ExtensionError {
+    #[error("the cr-sqlite extension is not loaded, crsql_peek_next_db_version() failed: {0}")]
+    NotLoaded(#[source] rusqlite::Error),
+    #[error("could not read the cr-sqlite extension version: {0}")]
+    UnknownVersion(#[source] rusqlite::Error),
+    #[error(
+        "cr-sqlite extension version {detected} is incompatible, {required} or later before {} is required",
+        CRSQL_BREAKING_VERSION
+    )]
+    Incompatible {
+        detected: CrsqlVersion,
+        required: CrsqlVersion,
+    },
+}
+
+/// Checks that the cr-sqlite extension is loaded on `conn` and that its
+/// version is one we work with, so a broken setup fails at startup instead of
+/// on the first write.
+pub fn probe_crsqlite(conn: &Connection) -> Result<CrsqlVersion, ExtensionError> {
+    conn.query_row("SELECT crsql_peek_next_db_version()", [], |row| {
+        row.get::<_, i64>(0)
+    })
+    .map_err(ExtensionError::NotLoaded)?;
+
+    let detected = extension_version().map_err(ExtensionError::UnknownVersion)?;
+    check_crsqlite_version(detected)?;
+
+    Ok(detected)
+}
+
+/// Version of the extension itself. It only records it in `crsql_master`,
+/// where an existing database keeps the version that created or last
+/// migrated it, so it's read from a fresh one.
+fn extension_version() -> rusqlite::Result<CrsqlVersion> {
+    let mut conn = Connection::open_in_memory()?;
+    init_cr_conn(&mut conn)?;
+    conn.query_row(
+        "SELECT value FROM crsql_master WHERE key = 'crsqlite_version'",
+        [],
+        |row| row.get(0),
+    )
+    .map(CrsqlVersion)
+}
+
+fn check_crsqlite_version(detected: CrsqlVersion) -> Result<(), ExtensionError> {
+    if detected < CRSQL_MIN_VERSION || detected >= CRSQL_BREAKING_VERSION {
+        return Err(ExtensionError::Incompatible {
+            detected,
+            required: CRSQL_MIN_VERSION,
+        });
+    }
+    Ok(())
+}
+
 pub fn setup_conn(conn: &Connection) -> Result<(), rusqlite::Error> {
     // WAL journal mode and synchronous NORMAL for best performance / crash resilience compromise
     conn.execute_batch(
@@ -328,4 +509,147 @@ mod tests {
         #[error(transparent)]
         Join(#[from] tokio::task::JoinError),
     }
+
+    #[test]
+    fn test_transaction_isolation() -> Result<(), Box<dyn std::error::Error>> {
+        let tmpdir = tempfile::tempdir()?;
+        let path = tmpdir.path().join("db.sqlite");
+
+        // rollback journal mode so that exclusive locks also block readers
+        let mut conn = CrConn::init(Connection::open(&path)?)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = DELETE; CREATE TABLE foo (a INTEGER PRIMARY KEY, b INTEGER);",
+        )?;
+
+        let other = Connection::open(&path)?;
+        other.busy_timeout(Duration::ZERO)?;
+
+        let can_read = |other: &Connection| {
+            other
+                .query_row("SELECT COUNT(*) FROM foo", (), |row| row.get::<_, i64>(0))
+                .is_ok()
+        };
+        let can_write = |other: &Connection| {
+            let ok = other.execute_batch("BEGIN IMMEDIATE").is_ok();
+            if ok {
+                other.execute_batch("ROLLBACK").unwrap();
+            }
+            ok
+        };
+
+        assert_eq!(TxIsolation::default(), TxIsolation::Immediate);
+
+        {
+            let tx = conn.transaction_with_isolation(TxIsolation::Deferred)?;
+            assert!(can_read(&other));
+            assert!(can_write(&other));
+            tx.rollback()?;
+        }
+
+        {
+            let tx = conn.transaction_with_isolation(TxIsolation::Immediate)?;
+            tx.execute("INSERT INTO foo (a, b) VALUES (1, 1)", ())?;
+            assert!(!can_write(&other));
+            tx.rollback()?;
+        }
+
+        {
+            let tx = conn.transaction_with_isolation(TxIsolation::Exclusive)?;
+            assert!(!can_read(&other));
+            assert!(!can_write(&other));
+            tx.rollback()?;
+        }
+
+        assert!(can_read(&other));
+        assert!(can_write(&other));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_statement_cache() -> Result<(), Box<dyn std::error::Error>> {
+        let conn = CrConn::init(Connection::open_in_memory()?)?;
+        conn.execute_batch("CREATE TABLE foo (a INTEGER PRIMARY KEY, b TEXT);")?;
+
+        let run = |sql: &str| -> rusqlite::Result<usize> {
+            let mut stmt = conn.prepare_cached_query(sql)?;
+            let count = stmt.column_count();
+            stmt.query([])?.next()?;
+            Ok(count)
+        };
+
+        let sql = "SELECT a, b FROM foo";
+        run(sql)?;
+        assert_eq!(
+            conn.statement_cache_stats(),
+            StatementCacheStats { hits: 0, misses: 1 }
+        );
+
+        run(sql)?;
+        assert_eq!(
+            conn.statement_cache_stats(),
+            StatementCacheStats { hits: 1, misses: 1 }
+        );
+
+        // a statement in use isn't in the cache, preparing it again misses
+        let in_use = conn.prepare_cached_query(sql)?;
+        run(sql)?;
+        drop(in_use);
+        assert_eq!(
+            conn.statement_cache_stats(),
+            StatementCacheStats { hits: 2, misses: 2 }
+        );
+
+        // a schema change flushes the cache
+        conn.execute_batch("ALTER TABLE foo ADD COLUMN c INTEGER;")?;
+        invalidate_statement_caches();
+
+        assert_eq!(run("SELECT * FROM foo")?, 3);
+        run(sql)?;
+        assert_eq!(
+            conn.statement_cache_stats(),
+            StatementCacheStats { hits: 2, misses: 4 }
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_probe_crsqlite() -> Result<(), Box<dyn std::error::Error>> {
+        let conn = Connection::open_in_memory()?;
+        let err = probe_crsqlite(&conn).unwrap_err();
+        assert!(matches!(err, ExtensionError::NotLoaded(_)));
+        assert!(err
+            .to_string()
+            .contains("cr-sqlite extension is not loaded"));
+
+        // the bundled extension is accepted
+        let conn = CrConn::init(Connection::open_in_memory()?)?;
+        let version = probe_crsqlite(&conn)?;
+        assert!(version >= CRSQL_MIN_VERSION && version < CRSQL_BREAKING_VERSION);
+
+        // whatever version the database recorded
+        conn.execute(
+            "UPDATE crsql_master SET value = 150000 WHERE key = 'crsqlite_version'",
+            [],
+        )?;
+        assert_eq!(probe_crsqlite(&conn)?, version);
+
+        let err = check_crsqlite_version(CrsqlVersion(160000)).unwrap_err();
+        assert!(matches!(
+            err,
+            ExtensionError::Incompatible {
+                detected: CrsqlVersion(160000),
+                required: CRSQL_MIN_VERSION,
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "cr-sqlite extension version 0.16.0 is incompatible, 0.17.0 or later before 0.18.0 is required"
+        );
+        assert!(check_crsqlite_version(CrsqlVersion(170100)).is_ok());
+        assert!(check_crsqlite_version(CRSQL_BREAKING_VERSION).is_err());
+
+        Ok(())
+    }
 }
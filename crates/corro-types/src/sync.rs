@@ -1,4 +1,9 @@
-use std::{cmp, collections::HashMap, io, ops::RangeInclusive};
+use std::{
+    cmp,
+    collections::HashMap,
+    io::{self, Write},
+    ops::RangeInclusive,
+};
 
 use bytes::BytesMut;
 use opentelemetry::propagation::{Extractor, Injector};
@@ -11,8 +16,11 @@ use tracing::warn;
 use crate::{
     actor::ActorId,
     agent::{Booked, Bookie},
+    api::TableName,
     base::{CrsqlDbVersion, CrsqlDbVersionRange, CrsqlSeq, CrsqlSeqRange},
-    broadcast::{ChangeV1, Timestamp},
+    broadcast::{ChangeV1, Changeset, Timestamp},
+    change::{decode_changes, encode_changes},
+    schema::SchemaDigest,
 };
 
 #[derive(Debug, Clone, PartialEq, Readable, Writable)]
@@ -27,6 +35,35 @@ pub enum SyncMessageV1 {
     Clock(Timestamp),
     Rejection(SyncRejectionV1),
     Request(SyncRequestV1),
+    /// Same as `Request`, but only for the changes of the given tables
+    TableRequest(SyncTableRequestV1),
+    /// Answer to a [`BiPayloadV1::Digests`](crate::broadcast::BiPayloadV1::Digests)
+    Digests(Vec<VersionsDigestV1>),
+    /// Sent before a changeset with [`SyncEncoding::V2`]
+    ClearedSeqs(ClearedSeqsV1),
+}
+
+/// Seqs within the range of the changeset that follows which its sender has
+/// no change for, overwritten by later versions or excluded. The seqs
+/// missing from a changeset without being marked cleared were lost.
+#[derive(Debug, Clone, PartialEq, Eq, Readable, Writable)]
+pub struct ClearedSeqsV1 {
+    pub actor_id: ActorId,
+    pub version: CrsqlDbVersion,
+    pub seqs: Vec<RangeInclusive<CrsqlSeq>>,
+}
+
+/// What a node booked and applied of an actor's versions in a range, for
+/// anti-entropy to find the versions booked without their changes.
+#[derive(Debug, Clone, PartialEq, Eq, Readable, Writable)]
+pub struct VersionsDigestV1 {
+    pub actor_id: ActorId,
+    pub versions: RangeInclusive<CrsqlDbVersion>,
+    /// Whether every version of the range is booked, cleared or applied
+    pub booked: bool,
+    /// Hash of the versions of the range with changes in `crsql_changes`,
+    /// with their number of changes and last seq
+    pub applied: u64,
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Readable, Writable)]
@@ -68,12 +105,20 @@ impl Extractor for SyncTraceContextV1 {
 
 pub type SyncRequestV1 = Vec<(ActorId, Vec<SyncNeedV1>)>;
 
+#[derive(Debug, Clone, PartialEq, Readable, Writable)]
+pub struct SyncTableRequestV1 {
+    pub tables: Vec<TableName>,
+    pub needs: SyncRequestV1,
+}
+
 #[derive(Debug, thiserror::Error, Clone, PartialEq, Readable, Writable)]
 pub enum SyncRejectionV1 {
     #[error("max concurrency reached")]
     MaxConcurrencyReached,
     #[error("different cluster")]
     DifferentCluster,
+    #[error("not allowed to sync")]
+    NotAllowed,
 }
 
 #[derive(Debug, Default, Clone, PartialEq, Readable, Writable, Serialize, Deserialize)]
@@ -84,6 +129,17 @@ pub struct SyncStateV1 {
     pub partial_need: HashMap<ActorId, HashMap<CrsqlDbVersion, Vec<RangeInclusive<CrsqlSeq>>>>,
     #[speedy(default_on_eof)]
     pub last_cleared_ts: Option<Timestamp>,
+    /// Digest of the sender's replicated schema, absent for older peers
+    #[speedy(default_on_eof)]
+    pub schema_digest: Option<SchemaDigest>,
+    /// Encoding the server picked for the sync messages following this
+    /// state, absent when they're bare speedy (older peers)
+    #[speedy(default_on_eof)]
+    pub encoding: Option<SyncEncoding>,
+    /// Whether the sender serves `TableRequest`s, false for older peers
+    #[speedy(default_on_eof)]
+    #[serde(default)]
+    pub table_requests: bool,
 }
 
 impl SyncStateV1 {
@@ -124,6 +180,30 @@ impl SyncStateV1 {
                 .unwrap_or(0)
     }
 
+    /// Highest version of `actor_id` this state has applied along with all
+    /// the versions before it, `None` if it has none.
+    pub fn applied_up_to(&self, actor_id: &ActorId) -> Option<CrsqlDbVersion> {
+        let mut applied = *self.heads.get(actor_id)?;
+
+        if let Some(first) = self
+            .need
+            .get(actor_id)
+            .and_then(|needs| needs.iter().map(|range| *range.start()).min())
+        {
+            applied = cmp::min(applied, CrsqlDbVersion(first.0.saturating_sub(1)));
+        }
+
+        if let Some(first) = self
+            .partial_need
+            .get(actor_id)
+            .and_then(|partials| partials.keys().min())
+        {
+            applied = cmp::min(applied, CrsqlDbVersion(first.0.saturating_sub(1)));
+        }
+
+        (applied.0 > 0).then_some(applied)
+    }
+
     pub fn compute_available_needs(
         &self,
         other: &SyncStateV1,
@@ -329,12 +409,67 @@ pub async fn generate_sync(bookie: &Bookie, self_actor_id: ActorId) -> SyncState
     state
 }
 
+/// Prefix of framed sync messages, followed by their [`SyncEncoding`]
+pub const SYNC_FRAME_MAGIC: [u8; 2] = *b"CS";
+
+/// Version of a framed sync message's body encoding
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Readable,
+    Writable,
+    Serialize,
+    Deserialize,
+)]
+pub struct SyncEncoding(pub u8);
+
+impl SyncEncoding {
+    /// speedy-encoded [`SyncMessage`]
+    pub const V1: SyncEncoding = SyncEncoding(1);
+    /// Same as [`SyncEncoding::V1`], with the seqs a changeset has no change
+    /// for marked by a [`SyncMessageV1::ClearedSeqs`]
+    pub const V2: SyncEncoding = SyncEncoding(2);
+    /// Same as [`SyncEncoding::V2`], with the changes of full changesets
+    /// written as [`InternedChanges`](crate::change::InternedChanges)
+    pub const V3: SyncEncoding = SyncEncoding(3);
+}
+
+impl std::fmt::Display for SyncEncoding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// Encodings we can read and write
+pub const SUPPORTED_SYNC_ENCODINGS: &[SyncEncoding] =
+    &[SyncEncoding::V1, SyncEncoding::V2, SyncEncoding::V3];
+
+/// Highest encoding both sides support, `None` if the peer didn't advertise
+/// any (an older peer) or there's none in common.
+pub fn negotiate_sync_encoding(
+    ours: &[SyncEncoding],
+    theirs: &[SyncEncoding],
+) -> Option<SyncEncoding> {
+    ours.iter()
+        .filter(|encoding| theirs.contains(encoding))
+        .max()
+        .copied()
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum SyncMessageEncodeError {
     #[error(transparent)]
     Encode(#[from] speedy::Error),
     #[error(transparent)]
     Io(#[from] io::Error),
+    #[error("unknown sync encoding {0}")]
+    UnknownEncoding(SyncEncoding),
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -345,6 +480,25 @@ pub enum SyncMessageDecodeError {
     Corrupted(u32, u32),
     #[error(transparent)]
     Io(#[from] io::Error),
+    #[error("not a sync message frame")]
+    BadMagic,
+    #[error("unknown sync encoding {0}")]
+    UnknownEncoding(SyncEncoding),
+}
+
+// kinds of message bodies with `SyncEncoding::V3`
+const PLAIN_MESSAGE: u8 = 0;
+const INTERNED_CHANGESET: u8 = 1;
+
+/// A full changeset without its changes, which follow it encoded by
+/// [`encode_changes`]
+#[derive(Readable, Writable)]
+struct InternedChangesetHeader {
+    actor_id: ActorId,
+    version: CrsqlDbVersion,
+    seqs: CrsqlSeqRange,
+    last_seq: CrsqlSeq,
+    ts: Timestamp,
 }
 
 impl SyncMessage {
@@ -363,6 +517,103 @@ impl SyncMessage {
         Ok(Self::from_slice(buf)?)
     }
 
+    /// Writes the message behind a header of [`SYNC_FRAME_MAGIC`] and
+    /// `encoding`
+    pub fn write_framed<W: Write>(
+        &self,
+        encoding: SyncEncoding,
+        mut writer: W,
+    ) -> Result<(), SyncMessageEncodeError> {
+        if !SUPPORTED_SYNC_ENCODINGS.contains(&encoding) {
+            return Err(SyncMessageEncodeError::UnknownEncoding(encoding));
+        }
+        writer.write_all(&SYNC_FRAME_MAGIC)?;
+        writer.write_all(&[encoding.0])?;
+        if encoding >= SyncEncoding::V3 {
+            return self.write_interned(writer);
+        }
+        self.write_to_stream(writer)?;
+        Ok(())
+    }
+
+    fn write_interned<W: Write>(&self, mut writer: W) -> Result<(), SyncMessageEncodeError> {
+        let SyncMessage::V1(SyncMessageV1::Changeset(ChangeV1 {
+            actor_id,
+            changeset:
+                Changeset::Full {
+                    version,
+                    changes,
+                    seqs,
+                    last_seq,
+                    ts,
+                },
+        })) = self
+        else {
+            writer.write_all(&[PLAIN_MESSAGE])?;
+            self.write_to_stream(writer)?;
+            return Ok(());
+        };
+
+        writer.write_all(&[INTERNED_CHANGESET])?;
+        InternedChangesetHeader {
+            actor_id: *actor_id,
+            version: *version,
+            seqs: *seqs,
+            last_seq: *last_seq,
+            ts: *ts,
+        }
+        .write_to_stream(&mut writer)?;
+        writer.write_all(&encode_changes(changes)?)?;
+        Ok(())
+    }
+
+    fn read_interned(body: &[u8]) -> Result<Self, SyncMessageDecodeError> {
+        match body.split_first() {
+            Some((&PLAIN_MESSAGE, body)) => Ok(Self::from_slice(body)?),
+            Some((&INTERNED_CHANGESET, body)) => {
+                let (header, len) = InternedChangesetHeader::read_with_length_from_buffer(body);
+                let header = header?;
+                Ok(SyncMessage::V1(SyncMessageV1::Changeset(ChangeV1 {
+                    actor_id: header.actor_id,
+                    changeset: Changeset::Full {
+                        version: header.version,
+                        changes: decode_changes(&body[len..])?,
+                        seqs: header.seqs,
+                        last_seq: header.last_seq,
+                        ts: header.ts,
+                    },
+                })))
+            }
+            _ => Err(speedy::Error::custom("unknown sync message kind").into()),
+        }
+    }
+
+    /// Reads a message written by [`SyncMessage::write_framed`], rejecting
+    /// encodings that aren't in `known`
+    pub fn from_framed<S: AsRef<[u8]>>(
+        slice: S,
+        known: &[SyncEncoding],
+    ) -> Result<Self, SyncMessageDecodeError> {
+        let slice = slice.as_ref();
+        let Some((header, body)) = slice.split_at_checked(SYNC_FRAME_MAGIC.len() + 1) else {
+            return Err(SyncMessageDecodeError::BadMagic);
+        };
+        if header[..SYNC_FRAME_MAGIC.len()] != SYNC_FRAME_MAGIC {
+            return Err(SyncMessageDecodeError::BadMagic);
+        }
+
+        let encoding = SyncEncoding(header[SYNC_FRAME_MAGIC.len()]);
+        if !known.contains(&encoding) {
+            return Err(SyncMessageDecodeError::UnknownEncoding(encoding));
+        }
+        match encoding {
+            SyncEncoding::V1 | SyncEncoding::V2 => Ok(Self::from_slice(body)?),
+            SyncEncoding::V3 => Self::read_interned(body),
+            // known but newer than this build
+            encoding => Err(SyncMessageDecodeError::UnknownEncoding(encoding)),
+        }
+    }
+
     pub fn decode(
         codec: &mut LengthDelimitedCodec,
         buf: &mut BytesMut,
@@ -374,9 +625,47 @@ impl SyncMessage {
     }
 }
 
+/// Versions, per actor, that every one of `peers` has applied without gaps.
+/// Nothing is returned if a peer's sync state is unknown.
+pub fn acked_versions(
+    peers: &[ActorId],
+    states: &HashMap<ActorId, SyncStateV1>,
+) -> HashMap<ActorId, CrsqlDbVersion> {
+    let Some(peer_states) = peers
+        .iter()
+        .map(|peer| states.get(peer))
+        .collect::<Option<Vec<_>>>()
+    else {
+        return HashMap::new();
+    };
+
+    let mut acked = HashMap::new();
+    let Some(first) = peer_states.first() else {
+        return acked;
+    };
+
+    for actor_id in first.heads.keys() {
+        // `None` sorts first, so a single peer missing the actor wins
+        let applied = peer_states
+            .iter()
+            .map(|state| state.applied_up_to(actor_id))
+            .min()
+            .flatten();
+        if let Some(version) = applied {
+            acked.insert(*actor_id, version);
+        }
+    }
+
+    acked
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::base::{dbsr, dbsri, dbvr, dbvri};
+    use crate::{
+        api::{ColumnName, SqliteValue},
+        base::{dbsr, dbsri, dbvr, dbvri},
+        change::Change,
+    };
     use uuid::Uuid;
 
     use super::*;
@@ -480,4 +769,156 @@ mod tests {
             .into()
         );
     }
+
+    #[test]
+    fn test_acked_versions() {
+        let actor1 = ActorId(Uuid::new_v4());
+        let peer1 = ActorId(Uuid::new_v4());
+        let peer2 = ActorId(Uuid::new_v4());
+
+        let mut state1 = SyncStateV1 {
+            actor_id: peer1,
+            ..Default::default()
+        };
+        state1.heads.insert(actor1, CrsqlDbVersion(10));
+
+        let mut state2 = SyncStateV1 {
+            actor_id: peer2,
+            ..Default::default()
+        };
+        state2.heads.insert(actor1, CrsqlDbVersion(12));
+        state2.need.insert(actor1, vec![dbvri!(7, 8)]);
+        state2
+            .partial_need
+            .insert(actor1, [(CrsqlDbVersion(4), vec![dbsri!(0, 3)])].into());
+
+        let states: HashMap<_, _> = [(peer1, state1), (peer2, state2)].into();
+
+        assert_eq!(
+            acked_versions(&[peer1, peer2], &states),
+            [(actor1, CrsqlDbVersion(3))].into()
+        );
+        assert_eq!(
+            acked_versions(&[peer1], &states),
+            [(actor1, CrsqlDbVersion(10))].into()
+        );
+
+        // a peer we know nothing about hasn't acked anything
+        let peer3 = ActorId(Uuid::new_v4());
+        assert!(acked_versions(&[peer1, peer3], &states).is_empty());
+    }
+
+    #[test]
+    fn test_framed_sync_message_encodings() {
+        let v4 = SyncEncoding(4);
+        let msg = SyncMessage::V1(SyncMessageV1::State(SyncStateV1 {
+            actor_id: ActorId(Uuid::new_v4()),
+            encoding: Some(SyncEncoding::V1),
+            ..Default::default()
+        }));
+
+        let mut buf = vec![];
+        msg.write_framed(SyncEncoding::V1, &mut buf).unwrap();
+        assert_eq!(buf[..3], [b'C', b'S', 1]);
+
+        // a decoder that also knows a newer encoding reads older frames
+        let decoded = SyncMessage::from_framed(&buf, &[SyncEncoding::V1, v4]).unwrap();
+        assert_eq!(decoded, msg);
+
+        // but not frames from the future
+        let mut future = buf.clone();
+        future[2] = 5;
+        assert!(matches!(
+            SyncMessage::from_framed(&future, &[SyncEncoding::V1, v4]),
+            Err(SyncMessageDecodeError::UnknownEncoding(SyncEncoding(5)))
+        ));
+        assert!(matches!(
+            SyncMessage::from_framed(&buf, &[v4]),
+            Err(SyncMessageDecodeError::UnknownEncoding(SyncEncoding::V1))
+        ));
+
+        // bare messages aren't frames
+        let bare = msg.write_to_vec().unwrap();
+        assert!(matches!(
+            SyncMessage::from_framed(&bare, SUPPORTED_SYNC_ENCODINGS),
+            Err(SyncMessageDecodeError::BadMagic)
+        ));
+        assert!(matches!(
+            SyncMessage::from_framed(b"CS", SUPPORTED_SYNC_ENCODINGS),
+            Err(SyncMessageDecodeError::BadMagic)
+        ));
+
+        assert!(matches!(
+            msg.write_framed(v4, vec![]),
+            Err(SyncMessageEncodeError::UnknownEncoding(SyncEncoding(4)))
+        ));
+
+        // v2 frames are the same messages
+        let mut buf = vec![];
+        msg.write_framed(SyncEncoding::V2, &mut buf).unwrap();
+        assert_eq!(buf[..3], [b'C', b'S', 2]);
+        assert_eq!(
+            SyncMessage::from_framed(&buf, SUPPORTED_SYNC_ENCODINGS).unwrap(),
+            msg
+        );
+
+        // v3 frames other messages as-is, and full changesets interned
+        let mut buf = vec![];
+        msg.write_framed(SyncEncoding::V3, &mut buf).unwrap();
+        assert_eq!(
+            SyncMessage::from_framed(&buf, SUPPORTED_SYNC_ENCODINGS).unwrap(),
+            msg
+        );
+
+        let site_id = ActorId(Uuid::new_v4()).to_bytes();
+        let changeset = SyncMessage::V1(SyncMessageV1::Changeset(ChangeV1 {
+            actor_id: ActorId(Uuid::new_v4()),
+            changeset: Changeset::Full {
+                version: CrsqlDbVersion(1),
+                changes: (0..100)
+                    .map(|seq| Change {
+                        table: TableName::from("tests"),
+                        pk: vec![1, seq as u8],
+                        cid: ColumnName::from("text"),
+                        val: SqliteValue::Integer(seq),
+                        db_version: CrsqlDbVersion(1),
+                        seq: CrsqlSeq(seq as u64),
+                        site_id,
+                        ..Default::default()
+                    })
+                    .collect(),
+                seqs: dbsr!(0, 99),
+                last_seq: CrsqlSeq(99),
+                ts: Default::default(),
+            },
+        }));
+        let mut v2 = vec![];
+        changeset.write_framed(SyncEncoding::V2, &mut v2).unwrap();
+        let mut v3 = vec![];
+        changeset.write_framed(SyncEncoding::V3, &mut v3).unwrap();
+        assert!(v3.len() < v2.len(), "{} vs {}", v3.len(), v2.len());
+        assert_eq!(
+            SyncMessage::from_framed(&v3, SUPPORTED_SYNC_ENCODINGS).unwrap(),
+            changeset
+        );
+
+        // a truncated interned changeset doesn't decode
+        assert!(SyncMessage::from_framed(&v3[..v3.len() - 1], SUPPORTED_SYNC_ENCODINGS).is_err());
+    }
+
+    #[test]
+    fn test_negotiate_sync_encoding() {
+        let v2 = SyncEncoding(2);
+        assert_eq!(
+            negotiate_sync_encoding(&[SyncEncoding::V1, v2], &[v2, SyncEncoding::V1]),
+            Some(v2)
+        );
+        assert_eq!(
+            negotiate_sync_encoding(&[SyncEncoding::V1], &[SyncEncoding::V1, v2]),
+            Some(SyncEncoding::V1)
+        );
+        // older peers don't advertise any
+        assert_eq!(negotiate_sync_encoding(SUPPORTED_SYNC_ENCODINGS, &[]), None);
+        assert_eq!(negotiate_sync_encoding(&[SyncEncoding::V1], &[v2]), None);
+    }
 }
@@ -13,7 +13,7 @@ use metrics::{counter, histogram, Counter};
 use parking_lot::RwLock;
 use rusqlite::Connection;
 use spawn::spawn_counted;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::sync::Arc;
 use std::time::Duration;
@@ -44,6 +44,11 @@ pub trait Handle {
     fn changes_tx(&self) -> mpsc::Sender<MatchCandidates>;
     async fn cleanup(&self);
     fn get_counter(&self, table: &str) -> &HandleMetrics;
+    /// Changes not matching this filter aren't given to
+    /// [`Handle::filter_matchable_change`], `None` gets every change.
+    fn subscription_filter(&self) -> Option<SubscriptionFilter> {
+        None
+    }
 }
 
 #[derive(Clone)]
@@ -85,6 +90,16 @@ impl Handle for UpdateHandle {
         self.inner.id
     }
 
+    fn subscription_filter(&self) -> Option<SubscriptionFilter> {
+        Some(SubscriptionFilter {
+            id: self.inner.id,
+            table: TableName::from(self.inner.name.as_str()),
+            columns: None,
+            pks: None,
+            predicate: None,
+        })
+    }
+
     fn cancelled(&self) -> WaitForCancellationFuture<'_> {
         self.inner.cancel.cancelled()
     }
@@ -417,6 +432,68 @@ async fn batch_candidates(
     debug!(id = %id, "update loop is done");
 }
 
+pub type SubscriptionId = Uuid;
+
+/// Arbitrary check on a change, e.g. its value, on top of table / column / pk filters
+pub type ChangePredicate = Arc<dyn Fn(&Change) -> bool + Send + Sync>;
+
+/// What a subscription cares about, used to decide which subscriptions a
+/// change fans out to. `None` filters match everything.
+#[derive(Clone)]
+pub struct SubscriptionFilter {
+    pub id: SubscriptionId,
+    pub table: TableName,
+    pub columns: Option<HashSet<ColumnName>>,
+    pub pks: Option<HashSet<Vec<u8>>>,
+    pub predicate: Option<ChangePredicate>,
+}
+
+impl Debug for SubscriptionFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SubscriptionFilter")
+            .field("id", &self.id)
+            .field("table", &self.table)
+            .field("columns", &self.columns)
+            .field("pks", &self.pks)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+impl SubscriptionFilter {
+    pub fn matches(&self, change: &Change) -> bool {
+        if self.table != change.table {
+            return false;
+        }
+
+        // row inserts / deletes (sentinel column) concern every column
+        if let Some(columns) = self.columns.as_ref() {
+            if !change.cid.is_crsql_sentinel() && !columns.contains(&change.cid) {
+                return false;
+            }
+        }
+
+        if let Some(pks) = self.pks.as_ref() {
+            if !pks.contains(&change.pk) {
+                return false;
+            }
+        }
+
+        self.predicate
+            .as_ref()
+            .map(|predicate| predicate(change))
+            .unwrap_or(true)
+    }
+}
+
+/// Ids of the subscriptions a change should be fanned out to, in `subs` order
+pub fn matching_subscriptions(change: &Change, subs: &[SubscriptionFilter]) -> Vec<SubscriptionId> {
+    subs.iter()
+        .filter(|sub| sub.matches(change))
+        .map(|sub| sub.id)
+        .collect()
+}
+
 pub fn match_changes<H>(manager: &impl Manager<H>, changes: &[Change], db_version: CrsqlDbVersion)
 where
     H: Handle + Send + 'static,
@@ -436,13 +513,27 @@ where
         return;
     }
 
+    let filters: Vec<SubscriptionFilter> = handles
+        .values()
+        .filter_map(Handle::subscription_filter)
+        .collect();
+    // subscriptions with a filter each change fans out to
+    let fan_out: Vec<Vec<SubscriptionId>> = changes
+        .iter()
+        .map(|change| matching_subscriptions(change, &filters))
+        .collect();
+
     assert_sometimes!(true, "Corrosion matches changes for updates");
     for (id, handle) in handles.iter() {
         trace!(sub_id = %id, %db_version, "attempting to match changes to a subscription");
+        let filtered = filters.iter().any(|filter| filter.id == *id);
         let mut candidates = MatchCandidates::new();
         let mut match_count = 0;
-        for change in changes.iter().map(MatchableChange::from) {
-            if handle.filter_matchable_change(&mut candidates, change) {
+        for (change, subs) in changes.iter().zip(fan_out.iter()) {
+            if filtered && !subs.contains(id) {
+                continue;
+            }
+            if handle.filter_matchable_change(&mut candidates, MatchableChange::from(change)) {
                 match_count += 1;
             }
         }
@@ -578,3 +669,87 @@ where
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use corro_api_types::SqliteValue;
+
+    use super::*;
+
+    #[test]
+    fn test_matching_subscriptions() {
+        let sub = |table: &str| SubscriptionFilter {
+            id: Uuid::new_v4(),
+            table: table.into(),
+            columns: None,
+            pks: None,
+            predicate: None,
+        };
+
+        let all_tests = sub("tests");
+        let other_table = sub("other");
+        let text_column = SubscriptionFilter {
+            columns: Some(["text".into()].into()),
+            ..sub("tests")
+        };
+        let other_column = SubscriptionFilter {
+            columns: Some(["id".into()].into()),
+            ..sub("tests")
+        };
+        let same_pk = SubscriptionFilter {
+            pks: Some([vec![1]].into()),
+            ..sub("tests")
+        };
+        let other_pk = SubscriptionFilter {
+            pks: Some([vec![2]].into()),
+            ..sub("tests")
+        };
+        let big_values = SubscriptionFilter {
+            predicate: Some(Arc::new(
+                |change: &Change| matches!(change.val, SqliteValue::Integer(i) if i > 10),
+            )),
+            ..sub("tests")
+        };
+
+        let subs = vec![
+            all_tests.clone(),
+            other_table,
+            text_column.clone(),
+            other_column,
+            same_pk.clone(),
+            other_pk,
+            big_values.clone(),
+        ];
+
+        let change = Change {
+            table: "tests".into(),
+            pk: vec![1],
+            cid: "text".into(),
+            val: SqliteValue::Integer(42),
+            ..Default::default()
+        };
+        assert_eq!(
+            matching_subscriptions(&change, &subs),
+            vec![all_tests.id, text_column.id, same_pk.id, big_values.id]
+        );
+
+        // predicate no longer matches
+        let change = Change {
+            val: SqliteValue::Integer(1),
+            ..change
+        };
+        assert_eq!(
+            matching_subscriptions(&change, &subs),
+            vec![all_tests.id, text_column.id, same_pk.id]
+        );
+
+        // row deletes concern every column
+        let delete = Change {
+            cid: ColumnName("-1".into()),
+            val: SqliteValue::Null,
+            ..change
+        };
+        assert!(delete.cid.is_crsql_sentinel());
+        assert_eq!(matching_subscriptions(&delete, &subs).len(), 4);
+    }
+}
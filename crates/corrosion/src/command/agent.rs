@@ -60,7 +60,7 @@ pub async fn run(
 
     let (tripwire, tripwire_worker) = tripwire::Tripwire::new_signals();
 
-    let (agent, bookie, _, handles) =
+    let (agent, bookie, transport, handles) =
         corro_agent::agent::start_with_config(config.clone(), tripwire.clone())
             .await
             .expect("could not start agent");
@@ -68,6 +68,7 @@ pub async fn run(
     corro_admin::start_server(
         agent.clone(),
         bookie.clone(),
+        transport,
         AdminConfig {
             listen_path: config.admin.uds_path.clone(),
             config_path: config_path.clone(),
@@ -97,6 +98,12 @@ pub async fn run(
     antithesis_init();
     tripwire_worker.await;
 
+    if let Err(e) =
+        corro_agent::agent::shutdown(&agent, corro_agent::agent::SHUTDOWN_DRAIN_TIMEOUT).await
+    {
+        error!("could not flush state on shutdown: {e}");
+    }
+
     // wait for handles to finish
     for handle in handles {
         if let Err(e) = handle.await {
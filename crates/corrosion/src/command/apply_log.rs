@@ -0,0 +1,95 @@
+use std::{io::Read, path::Path};
+
+use camino::{Utf8Path, Utf8PathBuf};
+
+use crate::admin::AdminConn;
+
+/// Has the agent behind `admin_path` replay the change log at `path`, read
+/// from `stdin` when it's `-`.
+pub async fn run<P: AsRef<Path>>(
+    admin_path: P,
+    path: &Utf8Path,
+    mut stdin: impl Read,
+) -> eyre::Result<()> {
+    // the agent reads the log itself, stdin is spooled to a file
+    let spooled;
+    let path = if path == "-" {
+        let mut file = tempfile::NamedTempFile::new()?;
+        std::io::copy(&mut stdin, &mut file)?;
+        spooled = file.into_temp_path();
+        Utf8PathBuf::try_from(spooled.to_path_buf())?
+    } else {
+        // the agent resolves relative paths from its own working directory
+        path.canonicalize_utf8()?
+    };
+    let mut conn = AdminConn::connect(admin_path).await?;
+    conn.send_command(corro_admin::Command::ApplyLog { path })
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use corro_admin::AdminConfig;
+    use corro_agent::agent::export_change_log;
+    use corro_api_types::Statement;
+    use corro_tests::launch_test_agent;
+    use spawn::wait_for_all_pending_handles;
+    use tripwire::Tripwire;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn apply_log_from_stdin() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta1 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+        let ta2 = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let client = corro_client::CorrosionApiClient::new(ta1.agent.api_addr());
+        for i in 1..=5i64 {
+            client
+                .execute(
+                    &[Statement::WithParams(
+                        "INSERT INTO tests (id, text) VALUES (?, ?)".into(),
+                        vec![i.into(), format!("service-{i}").into()],
+                    )],
+                    None,
+                )
+                .await?;
+        }
+
+        let log_path = ta1.tmpdir.path().join("capture.log");
+        export_change_log(&ta1.agent, None, &log_path, true).await?;
+
+        let admin_path = ta2.config.admin.uds_path.clone();
+        corro_admin::start_server(
+            ta2.agent.clone(),
+            ta2.bookie.clone(),
+            ta2.transport.clone(),
+            AdminConfig {
+                listen_path: admin_path.clone(),
+                config_path: Utf8PathBuf::from("config.toml"),
+            },
+            None,
+            tripwire.clone(),
+        )?;
+
+        run(
+            &admin_path,
+            Utf8Path::new("-"),
+            std::fs::File::open(&log_path)?,
+        )
+        .await?;
+
+        let conn = ta2.agent.pool().read().await?;
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM tests", [], |row| row.get(0))?;
+        assert_eq!(count, 5);
+        drop(conn);
+
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
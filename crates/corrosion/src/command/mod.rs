@@ -1,4 +1,5 @@
 pub mod agent;
+pub mod apply_log;
 pub mod consul;
 pub mod reload;
 pub mod tls;
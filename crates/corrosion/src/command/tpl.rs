@@ -92,6 +92,10 @@ pub async fn run(
             let mut engine = corro_tpl::Engine::new::<std::fs::File>(client.clone());
 
             let mut tpl = engine.compile_mut(&input)?;
+            let mut current_input = input;
+            // last template that rendered fine, kept until a changed one does
+            let mut fallback: Option<String> = None;
+            let mut rendered_hash = None;
             let tmp_filepath = dir.path().join(Uuid::new_v4().as_simple().to_string());
 
             info!("Watching and rendering {src} to {dst}");
@@ -125,25 +129,44 @@ pub async fn run(
                 });
 
                 if let Err(e) = res {
-                    error!("could not render template '{src}': {e}");
-                    break;
+                    match fallback.take() {
+                        Some(previous) => {
+                            error!("could not render changed template '{src}', keeping the previous version: {e}");
+                            tpl = engine.compile_mut(&previous)?;
+                            checksum = crc32fast::hash(previous.as_bytes());
+                            current_input = previous;
+                            continue;
+                        }
+                        None => {
+                            error!("could not render template '{src}': {e}");
+                            break;
+                        }
+                    }
                 }
+                fallback = None;
 
                 debug!("rendered template");
 
-                tokio::fs::rename(&tmp_filepath, &dst).await?;
-
-                debug!("wrote file");
+                let hash = corro_tpl::sha256_hex(&tokio::fs::read(&tmp_filepath).await?);
+                if rendered_hash.as_ref() == Some(&hash) {
+                    debug!("rendered output did not change, not rewriting {dst}");
+                    tokio::fs::remove_file(&tmp_filepath).await?;
+                } else {
+                    tokio::fs::rename(&tmp_filepath, &dst).await?;
+                    rendered_hash = Some(hash);
+
+                    debug!("wrote file");
+
+                    if let Some(ref args) = cmd {
+                        let mut iter = args.iter();
+                        if let Some(cmd) = iter.next() {
+                            let mut cmd = tokio::process::Command::new(cmd);
+                            for arg in iter {
+                                cmd.arg(arg);
+                            }
 
-                if let Some(ref args) = cmd {
-                    let mut iter = args.iter();
-                    if let Some(cmd) = iter.next() {
-                        let mut cmd = tokio::process::Command::new(cmd);
-                        for arg in iter {
-                            cmd.arg(arg);
+                            cmd.spawn()?.wait().await?;
                         }
-
-                        cmd.spawn()?.wait().await?;
                     }
                 }
 
@@ -195,13 +218,20 @@ pub async fn run(
                                 let input = tokio::fs::read_to_string(&src).await?;
                                 let new_checksum = crc32fast::hash(input.as_bytes());
                                 if checksum != new_checksum {
-                                    info!(
-                                        "Template at {src} changed, re-compiling and re-rendering"
-                                    );
-                                    tpl = engine.compile_mut(&input)?;
-                                    checksum = new_checksum;
-                                    // break from inner loop
-                                    break;
+                                    match engine.compile_mut(&input) {
+                                        Ok(new_tpl) => {
+                                            info!("Template at {src} changed, re-compiling and re-rendering");
+                                            tpl = new_tpl;
+                                            checksum = new_checksum;
+                                            fallback =
+                                                Some(std::mem::replace(&mut current_input, input));
+                                            // break from inner loop
+                                            break;
+                                        }
+                                        Err(e) => {
+                                            error!("could not compile changed template '{src}', keeping the previous version: {e}");
+                                        }
+                                    }
                                 } else {
                                     debug!("checksum did not change");
                                 }
@@ -315,3 +345,158 @@ async fn async_watch(paths: Vec<(Utf8PathBuf, Sender<()>)>) -> notify::Result<()
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use corro_api_types::Statement;
+    use corro_tests::launch_test_agent;
+    use spawn::wait_for_all_pending_handles;
+    use tripwire::Tripwire;
+
+    async fn wait_for_output(dst: &Utf8PathBuf, prefix: &str) -> eyre::Result<String> {
+        let start = Instant::now();
+        loop {
+            if let Ok(output) = tokio::fs::read_to_string(dst).await {
+                if output.starts_with(prefix) {
+                    return Ok(output);
+                }
+            }
+            if start.elapsed() > Duration::from_secs(10) {
+                eyre::bail!("timed out waiting for {dst} to start with {prefix:?}");
+            }
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_template_hot_reload() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let client = CorrosionApiClient::new(ta.agent.api_addr());
+        client
+            .schema(&[Statement::Simple(corro_tests::TEST_SCHEMA.into())])
+            .await?;
+        client
+            .execute(
+                &[Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![1i64.into(), "service-name".into()],
+                )],
+                None,
+            )
+            .await?;
+
+        let base_path = Utf8PathBuf::try_from(ta.tmpdir.path().to_path_buf())?;
+        let src = base_path.join("test.rhai");
+        let dst = base_path.join("out/test.json");
+
+        tokio::fs::write(&src, r#"v1 <%= sql("select text from tests").to_json() %>"#).await?;
+
+        let handle = tokio::spawn({
+            let template = vec![format!("{src}:{dst}")];
+            let api_addr = ta.agent.api_addr();
+            async move { run(api_addr, &template, &TemplateFlags { once: false }).await }
+        });
+
+        let output = wait_for_output(&dst, "v1 ").await?;
+        assert!(output.contains("service-name"));
+
+        // a template that doesn't compile is ignored, the last output stays
+        tokio::fs::write(&src, r#"v2 <%= sql("select text from tests" %>"#).await?;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(tokio::fs::read_to_string(&dst).await?, output);
+
+        // a good template replaces the output
+        tokio::fs::write(
+            &src,
+            r#"v3 <%= sql("select id, text from tests").to_json() %>"#,
+        )
+        .await?;
+        let output = wait_for_output(&dst, "v3 ").await?;
+        assert!(output.contains("service-name"));
+
+        // and is still re-rendered on data changes
+        client
+            .execute(
+                &[Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![2i64.into(), "service-name-2".into()],
+                )],
+                None,
+            )
+            .await?;
+        let start = Instant::now();
+        while !tokio::fs::read_to_string(&dst)
+            .await?
+            .contains("service-name-2")
+        {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+
+        handle.abort();
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_template_unchanged_output_not_rewritten() -> eyre::Result<()> {
+        _ = tracing_subscriber::fmt::try_init();
+        let (tripwire, tripwire_worker, tripwire_tx) = Tripwire::new_simple();
+        let ta = launch_test_agent(|conf| conf.build(), tripwire.clone()).await?;
+
+        let client = CorrosionApiClient::new(ta.agent.api_addr());
+        client
+            .schema(&[Statement::Simple(corro_tests::TEST_SCHEMA.into())])
+            .await?;
+        client
+            .execute(
+                &[Statement::WithParams(
+                    "insert into tests (id, text) values (?,?)".into(),
+                    vec![1i64.into(), "service-name".into()],
+                )],
+                None,
+            )
+            .await?;
+
+        let base_path = Utf8PathBuf::try_from(ta.tmpdir.path().to_path_buf())?;
+        let src = base_path.join("test.rhai");
+        let dst = base_path.join("out/test.json");
+
+        tokio::fs::write(&src, r#"v1 <%= sql("select text from tests").to_json() %>"#).await?;
+
+        let handle = tokio::spawn({
+            let template = vec![format!("{src}:{dst}")];
+            let api_addr = ta.agent.api_addr();
+            async move { run(api_addr, &template, &TemplateFlags { once: false }).await }
+        });
+
+        let output = wait_for_output(&dst, "v1 ").await?;
+        let mtime = tokio::fs::metadata(&dst).await?.modified()?;
+
+        // a different template rendering the same content
+        tokio::fs::write(&src, r#"v1 <%= sql("SELECT text FROM tests").to_json() %>"#).await?;
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(tokio::fs::read_to_string(&dst).await?, output);
+        assert_eq!(tokio::fs::metadata(&dst).await?.modified()?, mtime);
+
+        // changed content is still written
+        tokio::fs::write(&src, r#"v2 <%= sql("select text from tests").to_json() %>"#).await?;
+        wait_for_output(&dst, "v2 ").await?;
+        assert_ne!(tokio::fs::metadata(&dst).await?.modified()?, mtime);
+
+        handle.abort();
+        tripwire_tx.send(()).await.ok();
+        tripwire_worker.await;
+        wait_for_all_pending_handles().await;
+
+        Ok(())
+    }
+}
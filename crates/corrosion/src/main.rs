@@ -13,13 +13,14 @@ use command::{
     tpl::TemplateFlags,
 };
 use corro_admin::TracingHandle;
-use corro_api_types::SqliteParam;
+use corro_api_types::{SqliteParam, SqliteValue};
 use corro_client::CorrosionApiClient;
 use corro_types::{
     actor::{ActorId, ClusterId},
+    agent::ReplicationDirection,
     api::{ExecResult, QueryEvent, Statement},
     base::CrsqlDbVersion,
-    config::{default_admin_path, Config, ConfigError, LogFormat, OtelConfig},
+    config::{default_admin_path, Config, ConfigError, LogFormat, OtelConfig, PeerMatcher},
 };
 use futures::StreamExt;
 use once_cell::sync::OnceCell;
@@ -386,6 +387,7 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
             let mut query = cli.api_client()?.query(&stmt, *timeout).await?;
             while let Some(res) = query.next().await {
                 match res {
+                    Ok(QueryEvent::Metadata { .. }) | Ok(QueryEvent::Deleted(_)) => {}
                     Ok(QueryEvent::Columns(cols)) => {
                         if *show_columns {
                             println!("{}", cols.join("|"));
@@ -406,9 +408,10 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
                             println!("time: {time}s");
                         }
                     }
-                    Ok(QueryEvent::Change(_, _, _, _)) => {
+                    Ok(QueryEvent::Change(_, _, _, _)) | Ok(QueryEvent::Resync { .. }) => {
                         break;
                     }
+                    Ok(QueryEvent::Heartbeat { .. }) => {}
                     Ok(QueryEvent::Error(e)) => {
                         eyre::bail!("{e}");
                     }
@@ -474,6 +477,52 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
             conn.send_command(corro_admin::Command::Locks { top: *top })
                 .await?;
         }
+        Command::Gaps { actor_id } => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Gaps {
+                actor_id: actor_id.map(ActorId),
+            })
+            .await?;
+        }
+        Command::Resync { peer_addr, tables } => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Resync {
+                addr: *peer_addr,
+                tables: tables.clone(),
+            })
+            .await?;
+        }
+        Command::Compact { dry_run } => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Compact { dry_run: *dry_run })
+                .await?;
+        }
+        Command::CompareTable {
+            table,
+            peer_api_addr,
+        } => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::CompareTable {
+                table: table.clone(),
+                peer_api_addr: *peer_api_addr,
+            })
+            .await?;
+        }
+        Command::RowHistory { table, pk } => {
+            let pk = pk
+                .iter()
+                .map(|value| {
+                    serde_json::from_str(value)
+                        .unwrap_or_else(|_| SqliteValue::Text(value.as_str().into()))
+                })
+                .collect();
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::RowHistory {
+                table: table.clone(),
+                pk,
+            })
+            .await?;
+        }
         Command::Template { template, flags } => {
             command::tpl::run(cli.api_addr()?, template, flags).await?;
         }
@@ -557,6 +606,59 @@ async fn process_cli(cli: Cli) -> eyre::Result<()> {
             conn.send_command(corro_admin::Command::Log(corro_admin::LogCommand::Reset))
                 .await?;
         }
+        Command::Peers(cmd) => {
+            let cmd = match cmd {
+                PeersCommand::Show => corro_admin::PeerAccessCommand::Show,
+                PeersCommand::Allow { peer } => corro_admin::PeerAccessCommand::Allow(*peer),
+                PeersCommand::Deny { peer } => corro_admin::PeerAccessCommand::Deny(*peer),
+                PeersCommand::Remove { peer } => corro_admin::PeerAccessCommand::Remove(*peer),
+            };
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::PeerAccess(cmd))
+                .await?;
+        }
+        Command::Snapshot(cmd) => {
+            let cmd = match cmd {
+                SnapshotCommand::Export {
+                    path,
+                    tables,
+                    compress,
+                } => corro_admin::SnapshotCommand::Export {
+                    path: path.clone(),
+                    tables: tables.clone(),
+                    compress: *compress,
+                },
+                SnapshotCommand::Import { path } => {
+                    corro_admin::SnapshotCommand::Import { path: path.clone() }
+                }
+            };
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Snapshot(cmd))
+                .await?;
+        }
+        Command::ApplyLog { path } => {
+            command::apply_log::run(cli.admin_path(), path, std::io::stdin().lock()).await?
+        }
+        Command::Quarantine(cmd) => {
+            let cmd = match cmd {
+                QuarantineCommand::List => corro_admin::QuarantineCommand::List,
+                QuarantineCommand::Requeue { id } => {
+                    corro_admin::QuarantineCommand::Requeue { id: *id }
+                }
+            };
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Quarantine(cmd))
+                .await?;
+        }
+        Command::Pause { direction } => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Pause(*direction))
+                .await?;
+        }
+        Command::Resume => {
+            let mut conn = AdminConn::connect(cli.admin_path()).await?;
+            conn.send_command(corro_admin::Command::Resume).await?;
+        }
     }
 
     Ok(())
@@ -707,6 +809,39 @@ enum Command {
         top: usize,
     },
 
+    /// Dump missing version ranges as JSON, for one actor or all of them
+    Gaps {
+        actor_id: Option<Uuid>,
+    },
+
+    /// Immediately sync from a peer, by gossip address, optionally only some tables
+    Resync {
+        peer_addr: SocketAddr,
+        tables: Vec<String>,
+    },
+
+    /// Remove tombstones of versions every peer has applied
+    Compact {
+        /// Only report how many rows would be removed
+        #[arg(long, default_value = "false")]
+        dry_run: bool,
+    },
+
+    /// Compare a table's content with a peer's, by API address
+    CompareTable {
+        table: String,
+        peer_api_addr: SocketAddr,
+    },
+
+    /// Show the changes behind a row, by primary key
+    RowHistory {
+        table: String,
+        /// Primary key values in the table's primary key order, read as JSON
+        /// or as text if they aren't valid JSON
+        #[arg(required = true)]
+        pk: Vec<String>,
+    },
+
     /// Actor-related commands
     #[command(subcommand)]
     Actor(ActorCommand),
@@ -732,6 +867,33 @@ enum Command {
     /// Log related commands
     #[command(subcommand)]
     Log(LogCommand),
+
+    /// Peers allowed to sync with this node
+    #[command(subcommand)]
+    Peers(PeersCommand),
+
+    /// Export or import a change log to seed a node
+    #[command(subcommand)]
+    Snapshot(SnapshotCommand),
+
+    /// Replay a change log for recovery, skipping what's already applied
+    ApplyLog {
+        /// File on the agent's host, `-` to read it from stdin
+        path: Utf8PathBuf,
+    },
+
+    /// Chunks that kept failing to apply
+    #[command(subcommand)]
+    Quarantine(QuarantineCommand),
+
+    /// Stop applying changes from peers (inbound) and/or broadcasting changes (outbound)
+    Pause {
+        #[arg(default_value = "both")]
+        direction: ReplicationDirection,
+    },
+
+    /// Resume replication paused with `pause`
+    Resume,
 }
 
 #[derive(Subcommand)]
@@ -835,3 +997,39 @@ enum LogCommand {
     /// Reset the log filter to default
     Reset,
 }
+
+#[derive(Subcommand)]
+enum PeersCommand {
+    /// Show the allow and deny lists
+    Show,
+    /// Allow a peer, by actor id, address or CIDR range
+    Allow { peer: PeerMatcher },
+    /// Deny a peer, by actor id, address or CIDR range
+    Deny { peer: PeerMatcher },
+    /// Remove a peer from both lists
+    Remove { peer: PeerMatcher },
+}
+
+#[derive(Subcommand)]
+enum SnapshotCommand {
+    /// Write the current changes to a file on the agent's host
+    Export {
+        path: Utf8PathBuf,
+        /// Only export these tables, the whole database by default
+        #[arg(long = "table")]
+        tables: Vec<String>,
+        /// Compress the file with zstd
+        #[arg(long, default_value = "false")]
+        compress: bool,
+    },
+    /// Apply a change log from a file on the agent's host
+    Import { path: Utf8PathBuf },
+}
+
+#[derive(Subcommand)]
+enum QuarantineCommand {
+    /// List the quarantined chunks
+    List,
+    /// Apply a quarantined chunk again
+    Requeue { id: i64 },
+}
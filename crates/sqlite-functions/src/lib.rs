@@ -1,9 +1,14 @@
-use rusqlite::{functions::FunctionFlags, Connection, Error, Result};
+use rusqlite::{
+    functions::FunctionFlags,
+    types::{ToSqlOutput, Value as SqlValue},
+    Connection, Error, Result,
+};
 use serde_json::Value;
 
 /// Add custom Corrosion functions into SQLite connection.
 pub fn add_to_connection(db: &Connection) -> Result<()> {
     add_corro_json_contains(db)?;
+    add_corro_json_path(db)?;
 
     Ok(())
 }
@@ -50,9 +55,93 @@ fn corro_json_contains(selector: Value, object: Value) -> bool {
     }
 }
 
+// corro_json_path returns the value found at the JSONPath-style expression
+// (second argument) in the JSON text (first argument), or NULL if the
+// text isn't JSON or nothing is found at that path
+fn add_corro_json_path(db: &Connection) -> Result<()> {
+    db.create_scalar_function(
+        "corro_json_path",
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        move |ctx| {
+            assert_eq!(ctx.len(), 2, "called with unexpected number of arguments");
+
+            let (Ok(json), Ok(path)) = (ctx.get_raw(0).as_str(), ctx.get_raw(1).as_str()) else {
+                return Ok(ToSqlOutput::Owned(SqlValue::Null));
+            };
+
+            Ok(ToSqlOutput::Owned(match json_path_str(json, path) {
+                None | Some(Value::Null) => SqlValue::Null,
+                Some(Value::Bool(b)) => SqlValue::Integer(b as i64),
+                Some(Value::Number(n)) => match n.as_i64() {
+                    Some(i) => SqlValue::Integer(i),
+                    None => n.as_f64().map(SqlValue::Real).unwrap_or(SqlValue::Null),
+                },
+                Some(Value::String(s)) => SqlValue::Text(s),
+                Some(v) => SqlValue::Text(v.to_string()),
+            }))
+        },
+    )
+}
+
+/// Parses `json` and evaluates `path` against it, see [`json_path`].
+/// Returns `None` if `json` isn't valid JSON.
+pub fn json_path_str(json: &str, path: &str) -> Option<Value> {
+    let value: Value = serde_json::from_str(json).ok()?;
+    json_path(&value, path).cloned()
+}
+
+/// Evaluates a JSONPath-style expression like `$.a.b[0]["c d"]` against
+/// `value`. Negative indexes count from the end of an array. Returns `None`
+/// for a malformed path or if nothing is found.
+pub fn json_path<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    let mut rest = path.trim().strip_prefix('$')?;
+    let mut current = value;
+
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix('.') {
+            let end = after.find(['.', '[']).unwrap_or(after.len());
+            let key = &after[..end];
+            if key.is_empty() {
+                return None;
+            }
+            current = current.as_object()?.get(key)?;
+            rest = &after[end..];
+        } else if let Some(after) = rest.strip_prefix('[') {
+            let end = after.find(']')?;
+            let selector = after[..end].trim();
+            current = match selector
+                .strip_prefix('"')
+                .and_then(|s| s.strip_suffix('"'))
+                .or_else(|| {
+                    selector
+                        .strip_prefix('\'')
+                        .and_then(|s| s.strip_suffix('\''))
+                }) {
+                Some(key) => current.as_object()?.get(key)?,
+                None => {
+                    let arr = current.as_array()?;
+                    let index: i64 = selector.parse().ok()?;
+                    let index = if index < 0 {
+                        arr.len().checked_sub(index.unsigned_abs() as usize)?
+                    } else {
+                        index as usize
+                    };
+                    arr.get(index)?
+                }
+            };
+            rest = &after[end + 1..];
+        } else {
+            return None;
+        }
+    }
+
+    Some(current)
+}
+
 #[cfg(test)]
 mod test {
-    use rusqlite::{Connection, Result};
+    use rusqlite::{types::Value as SqlValue, Connection, Result};
 
     fn get_conn() -> Connection {
         let conn = Connection::open_in_memory().expect("cannot open in-memory connection");
@@ -124,4 +213,77 @@ mod test {
             )
         );
     }
+
+    fn query_corro_json_path(conn: &Connection, json: &str, path: &str) -> Result<SqlValue> {
+        conn.query_row("SELECT corro_json_path(?1, ?2)", [json, path], |row| {
+            row.get(0)
+        })
+    }
+
+    #[test]
+    fn test_corro_json_path() {
+        let conn = Connection::open_in_memory().expect("cannot open in-memory connection");
+        super::add_corro_json_path(&conn).expect("cannot add corrosion functions to connection");
+
+        let json = r#"{"meta": {"name": "app", "port": 8080, "weight": 0.5, "ok": true, "tags": ["a", "b"], "with space": null}}"#;
+
+        // nested objects
+        assert_eq!(
+            Ok(SqlValue::Text("app".into())),
+            query_corro_json_path(&conn, json, "$.meta.name")
+        );
+        assert_eq!(
+            Ok(SqlValue::Integer(8080)),
+            query_corro_json_path(&conn, json, "$.meta.port")
+        );
+        assert_eq!(
+            Ok(SqlValue::Real(0.5)),
+            query_corro_json_path(&conn, json, r#"$["meta"]['weight']"#)
+        );
+        assert_eq!(
+            Ok(SqlValue::Integer(1)),
+            query_corro_json_path(&conn, json, "$.meta.ok")
+        );
+        // non-scalars come back as JSON
+        assert_eq!(
+            Ok(SqlValue::Text(r#"["a","b"]"#.into())),
+            query_corro_json_path(&conn, json, "$.meta.tags")
+        );
+
+        // array indexing
+        assert_eq!(
+            Ok(SqlValue::Text("a".into())),
+            query_corro_json_path(&conn, json, "$.meta.tags[0]")
+        );
+        assert_eq!(
+            Ok(SqlValue::Text("b".into())),
+            query_corro_json_path(&conn, json, "$.meta.tags[-1]")
+        );
+        assert_eq!(
+            Ok(SqlValue::Null),
+            query_corro_json_path(&conn, json, "$.meta.tags[2]")
+        );
+
+        // missing paths, malformed paths and non-JSON values are NULL
+        assert_eq!(
+            Ok(SqlValue::Null),
+            query_corro_json_path(&conn, json, "$.meta.nope")
+        );
+        assert_eq!(
+            Ok(SqlValue::Null),
+            query_corro_json_path(&conn, json, r#"$.meta["with space"]"#)
+        );
+        assert_eq!(
+            Ok(SqlValue::Null),
+            query_corro_json_path(&conn, json, "meta.name")
+        );
+        assert_eq!(
+            Ok(SqlValue::Null),
+            query_corro_json_path(&conn, "not json", "$.meta")
+        );
+        assert_eq!(
+            Ok(SqlValue::Null),
+            conn.query_row("SELECT corro_json_path(42, '$.a')", [], |row| row.get(0))
+        );
+    }
 }